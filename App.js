@@ -8,6 +8,23 @@ const API_URL = 'http://localhost:3000/api';
 
 axios.defaults.withCredentials = true;
 
+// Every request after login carries the session token
+const setSessionToken = (token) => {
+  axios.defaults.headers.common['Authorization'] = `Bearer ${token}`;
+};
+
+// Lists come a page at a time; follows `next` until the last page
+const fetchAll = async (url) => {
+  const items = [];
+  let cursor = null;
+  do {
+    const response = await axios.get(url, { params: cursor ? { cursor } : {} });
+    items.push(...response.data.items);
+    cursor = response.data.next;
+  } while (cursor);
+  return items;
+};
+
 const Stack = createStackNavigator();
 
 
@@ -43,6 +60,7 @@ const StudentLoginScreen = ({ navigation }) => {
     try {
       const response = await axios.post(`${API_URL}/login`, { email, password });
       console.log('Login response:', response.data);
+      setSessionToken(response.data.token);
       navigation.navigate('Dorms');
     } catch (error) {
       console.error('Login failed:', error);
@@ -85,9 +103,9 @@ const DormsScreen = ({ navigation }) => {
   useEffect(() => {
     const fetchDorms = async () => {
       try {
-        const response = await axios.get(`${API_URL}/dorms`);
-        console.log('Dorms data:', response.data);
-        setDorms(response.data);
+        const dorms = await fetchAll(`${API_URL}/dorms`);
+        console.log('Dorms data:', dorms);
+        setDorms(dorms);
       } catch (error) {
         console.error('Failed to fetch dorms:', error);
       }
@@ -136,9 +154,9 @@ const RoomsScreen = ({ route, navigation }) => {
   const fetchRooms = async () => {
     try {
       console.log('Fetching rooms for dorm ID:', dormId);
-      const response = await axios.get(`${API_URL}/dorms/${dormId}/rooms`);
-      console.log('Rooms response:', response.data);
-      setRooms(response.data);
+      const rooms = await fetchAll(`${API_URL}/dorms/${dormId}/rooms`);
+      console.log('Rooms response:', rooms);
+      setRooms(rooms);
     } catch (error) {
       console.error('Failed to fetch rooms:', error.response?.data);
       alert(error.response?.data?.error || 'Failed to fetch rooms');
@@ -245,6 +263,7 @@ const AdminLoginScreen = ({ navigation }) => {
         school_id: schoolId,
       });
       console.log('Admin login successful:', response.data);
+      setSessionToken(response.data.token);
       navigation.navigate('AdminDashboard', { 
         schoolId, 
         schoolName: response.data.school.name 
@@ -304,6 +323,8 @@ const AdminDashboardScreen = ({ route, navigation }) => {
   const [dorms, setDorms] = useState([]);
   const [loading, setLoading] = useState(false);
   const [jsonData, setJsonData] = useState('');
+  // Emails for students listed without one, e.g. s{id}@students.example.edu
+  const [emailPattern, setEmailPattern] = useState('');
   const [importLoading, setImportLoading] = useState(false);
 
   const createDorm = async () => {
@@ -358,8 +379,7 @@ const AdminDashboardScreen = ({ route, navigation }) => {
 
   const fetchDorms = async () => {
     try {
-      const response = await axios.get(`${API_URL}/dorms`);
-      setDorms(response.data);
+      setDorms(await fetchAll(`${API_URL}/dorms`));
     } catch (error) {
       console.error('Failed to fetch dorms:', error);
       alert('Failed to fetch dorms');
@@ -384,7 +404,8 @@ const AdminDashboardScreen = ({ route, navigation }) => {
 
       const response = await axios.post(`${API_URL}/admin/import-rooms`, {
         dorm_id: selectedDorm,
-        room_data: parsedData
+        room_data: parsedData,
+        email_pattern: emailPattern.trim() || undefined
       });
 
       alert(`Import successful!\nCreated ${response.data.rooms_created} rooms\nCreated ${response.data.students_created} students`);
@@ -486,6 +507,13 @@ const AdminDashboardScreen = ({ route, navigation }) => {
                 multiline={true}
                 numberOfLines={6}
               />
+              <TextInput
+                style={styles.input}
+                placeholder="Email pattern for students without one, e.g. s{id}@students.example.edu"
+                value={emailPattern}
+                onChangeText={setEmailPattern}
+                autoCapitalize="none"
+              />
               <TouchableOpacity 
                 style={[styles.button, importLoading && styles.buttonDisabled]}
                 onPress={handleImportRooms}
@@ -0,0 +1,736 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, to_document, DateTime, Document},
+    options::UpdateOptions,
+    Collection, Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::passwords;
+use crate::rate_limit::{client_ip, LoginAccount, LoginGuard};
+use crate::roles::{authorize, Permission, Principal, Role, RoleGrant, Scope};
+use crate::tokens;
+use crate::two_factor::{self, TwoFactor};
+use crate::School;
+
+// Students and staff alike. Emails are unique within a school; what an
+// account may do comes from its role grants.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub email: String,
+    pub password: String,
+    // Missing on students created before accounts belonged to a school
+    #[serde(default)]
+    pub school_id: Option<ObjectId>,
+    #[serde(default)]
+    pub roles: Vec<RoleGrant>,
+    #[serde(default)]
+    pub assigned_room: Option<String>,
+    // Set for generated passwords; login is refused until it's changed
+    #[serde(default)]
+    pub must_change_password: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub two_factor: Option<TwoFactor>,
+    // The identity provider's `sub` for accounts that have signed in with SSO
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sso_subject: Option<String>,
+    // Full name, when a provisioning system supplies one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // The provisioning system's own id for the account (SCIM externalId)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    // Deactivated accounts can't log in; they're kept rather than deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deactivated_at: Option<DateTime>,
+    // The import batch that created the account, so the batch can be rolled
+    // back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_batch: Option<ObjectId>,
+    // The school's id for the student, used to match them on re-import
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub student_id: Option<i32>,
+    // The account this one was merged into as a duplicate; merged accounts
+    // are deactivated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merged_into: Option<ObjectId>,
+    // Duplicates merged into this account, so their history stays findable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_from: Vec<MergedAccount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergedAccount {
+    pub account_id: ObjectId,
+    pub email: String,
+    pub merged_at: DateTime,
+}
+
+pub fn collection(db: &Database) -> Collection<Account> {
+    db.collection::<Account>("accounts")
+}
+
+// The account that stands for `account` now: the one it was merged into, if
+// it was merged
+pub async fn follow_merge(db: &Database, account: Account) -> Result<Account, mongodb::error::Error> {
+    match account.merged_into {
+        Some(primary_id) => Ok(collection(db)
+            .find_one(doc! { "_id": primary_id }, None)
+            .await?
+            .unwrap_or(account)),
+        None => Ok(account),
+    }
+}
+
+impl Account {
+    pub fn student(email: String, password_hash: String, school_id: Option<ObjectId>) -> Self {
+        Account {
+            id: None,
+            email,
+            password: password_hash,
+            school_id,
+            roles: vec![RoleGrant {
+                role: Role::Student,
+                school_id,
+                dorm_id: None,
+            }],
+            assigned_room: None,
+            must_change_password: false,
+            two_factor: None,
+            sso_subject: None,
+            name: None,
+            external_id: None,
+            deactivated_at: None,
+            import_batch: None,
+            student_id: None,
+            merged_into: None,
+            merged_from: Vec::new(),
+        }
+    }
+
+    // Accounts without any grants are treated as students of their school
+    pub fn grants(&self) -> Vec<RoleGrant> {
+        if self.roles.is_empty() {
+            return vec![RoleGrant {
+                role: Role::Student,
+                school_id: self.school_id,
+                dorm_id: None,
+            }];
+        }
+        self.roles.clone()
+    }
+
+    // The lockout record for this account
+    pub fn login_account(&self) -> LoginAccount<'_> {
+        LoginAccount {
+            email: &self.email,
+            school_id: self.school_id,
+        }
+    }
+
+    pub fn is_staff(&self) -> bool {
+        self.grants().iter().any(|grant| grant.role != Role::Student)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.deactivated_at.is_none()
+    }
+
+    pub fn has_two_factor(&self) -> bool {
+        self.two_factor.as_ref().is_some_and(TwoFactor::is_enabled)
+    }
+
+    // What the API returns for an account; never includes the password hash
+    pub fn profile(&self) -> Document {
+        doc! {
+            "_id": self.id,
+            "email": &self.email,
+            "name": &self.name,
+            "school_id": self.school_id,
+            "roles": to_bson(&self.grants()).expect("Failed to serialize roles"),
+            "assigned_room": &self.assigned_room,
+            "must_change_password": self.must_change_password,
+            "two_factor_enabled": self.has_two_factor(),
+        }
+    }
+}
+
+// Active accounts with this email, in the given school or, without one, in
+// any school
+pub fn login_filter(email: &str, school_id: Option<ObjectId>) -> Document {
+    let mut filter = doc! { "email": email, "deactivated_at": null };
+    if let Some(school_id) = school_id {
+        filter.insert("school_id", school_id);
+    }
+    filter
+}
+
+// The accounts someone giving this email (and school, if any) could mean
+pub async fn login_candidates(
+    db: &Database,
+    email: &str,
+    school_id: Option<ObjectId>,
+) -> Result<Vec<Account>, mongodb::error::Error> {
+    let mut cursor = collection(db).find(login_filter(email, school_id), None).await?;
+    let mut candidates = Vec::new();
+    while let Some(account) = cursor.next().await {
+        candidates.push(account?);
+    }
+    Ok(candidates)
+}
+
+// Finds which of the candidates the password belongs to. Without a school,
+// the same email may exist in several schools; the password decides which
+// one.
+pub async fn authenticate(db: &Database, candidates: Vec<Account>, password: &str) -> Option<Account> {
+    let accounts = collection(db);
+    for account in candidates {
+        if passwords::verify_and_upgrade(&accounts, account.id, password, &account.password).await {
+            return Some(account);
+        }
+    }
+    None
+}
+
+// The lockout records an attempt counts against: those of every account it
+// could be for, or when there's none, the one the account would have. So an
+// account gets the same number of guesses whether or not a school is sent.
+pub fn lockout_accounts<'a>(
+    email: &'a str,
+    school_id: Option<ObjectId>,
+    candidates: &[Account],
+) -> Vec<LoginAccount<'a>> {
+    let mut schools: Vec<Option<ObjectId>> = candidates.iter().map(|account| account.school_id).collect();
+    if schools.is_empty() {
+        schools.push(school_id);
+    }
+    schools.sort();
+    schools.dedup();
+    schools
+        .into_iter()
+        .map(|school_id| LoginAccount { email, school_id })
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginCredentials {
+    email: String,
+    password: String,
+    // Needed for staff; students only need it if their email exists in
+    // more than one school
+    school_id: Option<String>,
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_school_id(school_id: Option<&str>) -> Result<Option<ObjectId>, HttpResponse> {
+    school_id
+        .map(ObjectId::parse_str)
+        .transpose()
+        .map_err(|_| HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }))
+}
+
+// The part of logging in both endpoints share: rate limiting, lockout and
+// the password check. Failures are only cleared once the whole login has
+// succeeded, so a known password doesn't reset the count for TOTP guesses.
+async fn password_login(
+    req: &HttpRequest,
+    db: &Database,
+    guard: &LoginGuard,
+    credentials: &LoginCredentials,
+    school_id: Option<ObjectId>,
+    staff_only: bool,
+) -> Result<Account, HttpResponse> {
+    let ip = client_ip(req);
+    let candidates = match login_candidates(db, &credentials.email, school_id).await {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            println!("Database error: {:?}", e);
+            return Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }));
+        }
+    };
+    let login_accounts = lockout_accounts(&credentials.email, school_id, &candidates);
+    if let Some(blocked) = guard.check(db, &login_accounts, ip.as_deref()).await.blocked_response() {
+        return Err(blocked);
+    }
+
+    match authenticate(db, candidates, &credentials.password).await {
+        Some(account) if !staff_only || account.is_staff() => Ok(account),
+        _ => {
+            println!("No account found with provided credentials");
+            guard.record_failure(db, &login_accounts, ip.as_deref()).await;
+            Err(HttpResponse::Unauthorized().json(doc! {
+                "error": "Invalid credentials"
+            }))
+        }
+    }
+}
+
+// When the password alone isn't enough, the response that asks for the
+// second step: a TOTP challenge if the account has 2FA, or an enrollment token
+// for staff whose school requires 2FA but who haven't set it up yet
+async fn second_factor_response(
+    db: &Database,
+    account: &Account,
+) -> Result<Option<HttpResponse>, HttpResponse> {
+    let account_id = account.id.expect("Stored accounts have an ID");
+    let internal_error = |e: mongodb::error::Error| {
+        println!("Error starting second login step: {:?}", e);
+        HttpResponse::InternalServerError().json(doc! {
+            "error": "Internal server error"
+        })
+    };
+
+    if account.has_two_factor() {
+        let challenge = tokens::issue(db, "login_2fa", account_id, two_factor::CHALLENGE_TTL)
+            .await
+            .map_err(internal_error)?;
+        return Ok(Some(HttpResponse::Ok().json(doc! {
+            "two_factor_required": true,
+            "challenge": challenge,
+        })));
+    }
+
+    let required = two_factor::school_requires_2fa(db, account.school_id)
+        .await
+        .map_err(internal_error)?;
+    if account.is_staff() && required {
+        let enrollment_token =
+            tokens::issue(db, "two_factor_enrollment", account_id, two_factor::ENROLLMENT_TTL)
+                .await
+                .map_err(internal_error)?;
+        return Ok(Some(HttpResponse::Forbidden().json(doc! {
+            "error": "Your school requires two-factor authentication; set it up to continue",
+            "two_factor_setup_required": true,
+            "enrollment_token": enrollment_token,
+        })));
+    }
+
+    Ok(None)
+}
+
+async fn start_session(
+    req: &HttpRequest,
+    db: &Database,
+    account: &Account,
+) -> Result<String, HttpResponse> {
+    let account_id = account.id.expect("Stored accounts have an ID");
+    auth::create_session(db, account_id, req).await.map_err(|e| {
+        println!("Error creating session: {:?}", e);
+        HttpResponse::InternalServerError().json(doc! {
+            "error": "Internal server error"
+        })
+    })
+}
+
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    credentials: web::Json<LoginCredentials>,
+    db: web::Data<Database>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    println!("Login attempt with email: {}", credentials.email);
+
+    let school_id = match parse_school_id(credentials.school_id.as_deref()) {
+        Ok(school_id) => school_id,
+        Err(response) => return response,
+    };
+    let account = match password_login(&req, &db, &guard, &credentials, school_id, false).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    println!("Account found, login successful");
+    if account.must_change_password {
+        return HttpResponse::Forbidden().json(doc! {
+            "error": "Password change required",
+            "must_change_password": true,
+        });
+    }
+    match second_factor_response(&db, &account).await {
+        Ok(Some(response)) | Err(response) => return response,
+        Ok(None) => {}
+    }
+    guard.record_success(&db, &account.login_account()).await;
+
+    match start_session(&req, &db, &account).await {
+        Ok(token) => {
+            let mut body = account.profile();
+            body.insert("token", token);
+            HttpResponse::Ok().json(body)
+        }
+        Err(response) => response,
+    }
+}
+
+// Staff login. Same accounts as `login`, but the school is required and the
+// account needs a staff role there.
+#[post("/admin/login")]
+async fn admin_login(
+    req: HttpRequest,
+    credentials: web::Json<LoginCredentials>,
+    db: web::Data<Database>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    let schools_collection = db.collection::<School>("schools");
+
+    let school_oid = match parse_school_id(credentials.school_id.as_deref()) {
+        Ok(Some(oid)) => oid,
+        Ok(None) | Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+
+    let account = match password_login(&req, &db, &guard, &credentials, Some(school_oid), true).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    // If admin exists, get school info
+    match schools_collection.find_one(doc! { "_id": school_oid }, None).await {
+        Ok(Some(school)) => {
+            match second_factor_response(&db, &account).await {
+                Ok(Some(response)) | Err(response) => return response,
+                Ok(None) => {}
+            }
+            guard.record_success(&db, &account.login_account()).await;
+            let token = match start_session(&req, &db, &account).await {
+                Ok(token) => token,
+                Err(response) => return response,
+            };
+            HttpResponse::Ok().json(doc! {
+                "message": "Login successful",
+                "token": token,
+                "school": {
+                    "id": school.id,
+                    "name": school.name
+                }
+            })
+        },
+        Ok(None) => HttpResponse::NotFound().json(doc! {
+            "error": "School not found"
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SecondFactorRequest {
+    challenge: String,
+    // A TOTP code or one of the recovery codes
+    code: String,
+}
+
+// Second step of a login for accounts with 2FA. Wrong codes count towards
+// the account's lockout like wrong passwords do.
+#[post("/login/2fa")]
+async fn login_second_factor(
+    req: HttpRequest,
+    body: web::Json<SecondFactorRequest>,
+    db: web::Data<Database>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    let challenge = match tokens::find_valid(&db, "login_2fa", &body.challenge).await {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => return HttpResponse::Unauthorized().json(doc! {
+            "error": "Login expired, please start again"
+        }),
+        Err(e) => {
+            println!("Error looking up login challenge: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+    let account = match collection(&db).find_one(doc! { "_id": challenge.account_id }, None).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return HttpResponse::Unauthorized().json(doc! {
+            "error": "Invalid credentials"
+        }),
+        Err(e) => {
+            println!("Database error: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    let ip = client_ip(&req);
+    let login_account = account.login_account();
+    if let Some(blocked) = guard.check(&db, &[login_account], ip.as_deref()).await.blocked_response() {
+        return blocked;
+    }
+    match two_factor::verify_code(&db, &account, &body.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            guard.record_failure(&db, &[account.login_account()], ip.as_deref()).await;
+            return HttpResponse::Unauthorized().json(doc! {
+                "error": "Invalid code"
+            });
+        }
+        Err(e) => {
+            println!("Error verifying 2FA code: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    }
+
+    // Consuming is what makes the challenge single use
+    match tokens::consume(&db, "login_2fa", &body.challenge).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::Unauthorized().json(doc! {
+            "error": "Login expired, please start again"
+        }),
+        Err(e) => {
+            println!("Error consuming login challenge: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    }
+    guard.record_success(&db, &account.login_account()).await;
+
+    let token = match start_session(&req, &db, &account).await {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    let mut response = account.profile();
+    response.insert("token", token);
+
+    // Staff logins also get their school, like `admin_login` returns
+    if let Some(school_id) = account.school_id {
+        if let Ok(Some(school)) = db
+            .collection::<School>("schools")
+            .find_one(doc! { "_id": school_id }, None)
+            .await
+        {
+            response.insert("school", doc! { "id": school.id, "name": school.name });
+        }
+    }
+    HttpResponse::Ok().json(response)
+}
+
+#[get("/user")]
+async fn get_user(db: web::Data<Database>, principal: Principal) -> impl Responder {
+    println!("Fetching user info");
+    if let Err(denied) = authorize(&principal, Permission::ViewOwnAccount, &Scope::Own) {
+        return denied;
+    }
+
+    let auth = match principal.session() {
+        Ok(auth) => auth,
+        Err(denied) => return denied,
+    };
+
+    match collection(&db).find_one(doc! { "_id": auth.account_id }, None).await {
+        Ok(Some(account)) => {
+            println!("User found");
+            HttpResponse::Ok().json(account.profile())
+        }
+        Ok(None) => {
+            println!("No user found");
+            HttpResponse::NotFound().finish()
+        }
+        Err(e) => {
+            println!("Error fetching user: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Shape of both `users` and `admin_credentials` documents before the merge
+#[derive(Debug, Deserialize)]
+struct LegacyAccount {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    email: String,
+    password: String,
+    #[serde(default)]
+    school_id: Option<ObjectId>,
+    #[serde(default)]
+    roles: Vec<RoleGrant>,
+    #[serde(default)]
+    assigned_room: Option<String>,
+    #[serde(default)]
+    must_change_password: bool,
+}
+
+impl LegacyAccount {
+    // Documents without roles get the collection's role in their school
+    fn into_account(self, default_role: Role) -> Account {
+        let roles = if self.roles.is_empty() {
+            vec![RoleGrant {
+                role: default_role,
+                school_id: self.school_id,
+                dorm_id: None,
+            }]
+        } else {
+            self.roles
+        };
+        Account {
+            id: Some(self.id),
+            roles,
+            assigned_room: self.assigned_room,
+            must_change_password: self.must_change_password,
+            ..Account::student(self.email, self.password, self.school_id)
+        }
+    }
+}
+
+// Copies `users` and `admin_credentials` into `accounts`, keeping each
+// document's _id so sessions, tokens and audit entries still point at the
+// right account. Passwords are copied as stored, hashed or not, and upgraded
+// on the next login. Accounts that already exist are left alone, so it's safe
+// to run on every start; the old collections are kept as a backup.
+pub async fn migrate_accounts(db: &Database) -> Result<(), mongodb::error::Error> {
+    let migrations = db.collection::<Document>("migrations");
+    if migrations
+        .find_one(doc! { "_id": "unified_accounts" }, None)
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    println!("Migrating users and admin credentials into accounts...");
+    let accounts = collection(db);
+    let mut counts = Document::new();
+
+    for (legacy_collection, default_role) in [
+        ("users", Role::Student),
+        ("admin_credentials", Role::SchoolAdmin),
+    ] {
+        let mut copied = 0;
+        let mut cursor = db
+            .collection::<LegacyAccount>(legacy_collection)
+            .find(None, None)
+            .await?;
+
+        while let Some(legacy) = cursor.next().await {
+            let legacy = match legacy {
+                Ok(legacy) => legacy,
+                Err(e) => {
+                    println!("Skipping unreadable {} document: {:?}", legacy_collection, e);
+                    continue;
+                }
+            };
+
+            let legacy_id = legacy.id;
+            let account = legacy.into_account(default_role);
+
+            let options = UpdateOptions::builder().upsert(true).build();
+            let result = accounts
+                .update_one(
+                    doc! { "_id": legacy_id },
+                    doc! { "$setOnInsert": to_document(&account).expect("Failed to serialize account") },
+                    options,
+                )
+                .await?;
+            if result.upserted_id.is_some() {
+                copied += 1;
+            }
+        }
+
+        println!("Copied {} accounts from {}", copied, legacy_collection);
+        counts.insert(legacy_collection, copied);
+    }
+
+    migrations
+        .insert_one(
+            doc! {
+                "_id": "unified_accounts",
+                "completed_at": DateTime::now(),
+                "copied": counts,
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logins_are_limited_to_the_school_when_given() {
+        let school = ObjectId::new();
+        assert_eq!(
+            login_filter("ada@x.edu", Some(school)),
+            doc! { "email": "ada@x.edu", "deactivated_at": null, "school_id": school }
+        );
+        assert_eq!(login_filter("ada@x.edu", None), doc! { "email": "ada@x.edu", "deactivated_at": null });
+
+        assert_eq!(parse_school_id(Some(&school.to_hex())).unwrap(), Some(school));
+        assert_eq!(parse_school_id(None).unwrap(), None);
+        assert!(parse_school_id(Some("not-an-id")).is_err());
+    }
+
+    #[test]
+    fn attempts_count_against_every_account_they_could_be_for() {
+        let school = ObjectId::new();
+        let other_school = ObjectId::new();
+        let in_school = |school_id| Account::student("ada@x.edu".to_string(), String::new(), school_id);
+        let schools = |accounts: Vec<LoginAccount>| -> Vec<Option<ObjectId>> {
+            accounts.into_iter().map(|account| account.school_id).collect()
+        };
+
+        // Sending the school or not makes no difference to which records count
+        let candidates = [in_school(Some(school))];
+        assert_eq!(schools(lockout_accounts("ada@x.edu", None, &candidates)), vec![Some(school)]);
+        assert_eq!(schools(lockout_accounts("ada@x.edu", Some(school), &candidates)), vec![Some(school)]);
+
+        let candidates = [in_school(Some(school)), in_school(Some(other_school))];
+        assert_eq!(schools(lockout_accounts("ada@x.edu", None, &candidates)).len(), 2);
+
+        // Unknown emails count where the account would be
+        assert_eq!(schools(lockout_accounts("ada@x.edu", Some(school), &[])), vec![Some(school)]);
+        assert_eq!(schools(lockout_accounts("ada@x.edu", None, &[])), vec![None]);
+    }
+
+    fn legacy(school_id: Option<ObjectId>, roles: Vec<RoleGrant>) -> LegacyAccount {
+        LegacyAccount {
+            id: ObjectId::new(),
+            email: "ada@x.edu".to_string(),
+            password: "plaintext".to_string(),
+            school_id,
+            roles,
+            assigned_room: Some("513".to_string()),
+            must_change_password: true,
+        }
+    }
+
+    #[test]
+    fn migrated_accounts_keep_their_id_school_and_roles() {
+        let school = ObjectId::new();
+        let old = legacy(Some(school), Vec::new());
+        let id = old.id;
+        let account = old.into_account(Role::SchoolAdmin);
+        assert_eq!(account.id, Some(id));
+        assert_eq!(account.school_id, Some(school));
+        assert_eq!(account.password, "plaintext");
+        assert_eq!(account.assigned_room.as_deref(), Some("513"));
+        assert!(account.must_change_password);
+        // Without roles, the collection's role within the account's school
+        assert_eq!(
+            account.roles,
+            vec![RoleGrant { role: Role::SchoolAdmin, school_id: Some(school), dorm_id: None }]
+        );
+
+        let grants = vec![RoleGrant { role: Role::Auditor, school_id: Some(school), dorm_id: None }];
+        assert_eq!(legacy(Some(school), grants.clone()).into_account(Role::SchoolAdmin).roles, grants);
+
+        // Students from before schools stay unscoped
+        let account = legacy(None, Vec::new()).into_account(Role::Student);
+        assert_eq!(account.roles, vec![RoleGrant { role: Role::Student, school_id: None, dorm_id: None }]);
+    }
+}
@@ -0,0 +1,337 @@
+// API keys let scripts (e.g. the registrar's nightly import) call the admin
+// API without a person logging in. A key belongs to one school and can only
+// do what its scopes allow there.
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, DateTime, Document},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{self, Account};
+use crate::audit::{self, AuditEntry};
+use crate::pagination::{Page, PageQuery};
+use crate::roles::{self, authorize, Permission, Principal, Scope};
+use crate::tokens;
+
+// Keys carry a prefix so they can be told apart from session tokens, and
+// from each other in listings, without storing them
+pub const KEY_PREFIX: &str = "dk_";
+const DISPLAY_PREFIX_LEN: usize = 8;
+const DEFAULT_EXPIRY_DAYS: u32 = 90;
+const MAX_EXPIRY_DAYS: u32 = 365;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    ReadOnly,
+    // Room imports and creating students
+    Import,
+    // Rooms and who lives in them
+    Assignments,
+}
+
+impl ApiScope {
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            ApiScope::ReadOnly => &[ViewDorms, ExportRosters, ViewLockouts],
+            ApiScope::Import => &[ViewDorms, ImportRooms, CreateStudents],
+            ApiScope::Assignments => &[ViewDorms, ManageRooms, ExportRosters],
+        }
+    }
+}
+
+pub fn scopes_allow(scopes: &[ApiScope], permission: Permission) -> bool {
+    scopes
+        .iter()
+        .any(|scope| scope.permissions().contains(&permission))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub school_id: ObjectId,
+    pub name: String,
+    // The first characters of the key, to recognise it by
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_by: ObjectId,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub last_used_at: Option<DateTime>,
+    pub revoked_at: Option<DateTime>,
+}
+
+// Looks up a live key and records that it was used. A key only works while
+// whoever created it is active and may still manage the school's keys.
+pub async fn authenticate(db: &Database, key: &str) -> Result<Option<ApiKey>, mongodb::error::Error> {
+    let now = DateTime::now();
+    let collection = db.collection::<ApiKey>("api_keys");
+    let filter = doc! {
+        "key_hash": tokens::hash_token(key),
+        "revoked_at": null,
+        "expires_at": { "$gt": now },
+    };
+    let Some(key) = collection.find_one(filter, None).await? else {
+        return Ok(None);
+    };
+
+    let creator = accounts::collection(db)
+        .find_one(doc! { "_id": key.created_by, "deactivated_at": null }, None)
+        .await?;
+    if !creator.is_some_and(|creator| creator_may_use(&creator, &key)) {
+        println!("Refused API key {}: its creator can no longer manage keys", key.prefix);
+        return Ok(None);
+    }
+
+    collection
+        .update_one(doc! { "_id": key.id }, doc! { "$set": { "last_used_at": now } }, None)
+        .await?;
+    Ok(Some(key))
+}
+
+fn creator_may_use(creator: &Account, key: &ApiKey) -> bool {
+    roles::allows(&creator.grants(), Permission::ManageApiKeys, &Scope::School(key.school_id))
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_school(school_id: &str) -> Result<ObjectId, HttpResponse> {
+    ObjectId::parse_str(school_id).map_err(|_| {
+        HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        })
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateKeyRequest {
+    name: String,
+    scopes: Vec<ApiScope>,
+    expires_in_days: Option<u32>,
+}
+
+// Creates a key. The key itself is only shown in this response.
+#[post("/admin/schools/{school_id}/api-keys")]
+async fn create_key(
+    req: HttpRequest,
+    school_id: web::Path<String>,
+    body: web::Json<CreateKeyRequest>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match parse_school(&school_id) {
+        Ok(oid) => oid,
+        Err(response) => return response,
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageApiKeys, &Scope::School(school_oid)) {
+        return denied;
+    }
+    // Keys stay tied to a person, so they die with that person's access
+    let creator = match principal.session() {
+        Ok(auth) => auth.account_id,
+        Err(denied) => return denied,
+    };
+
+    if body.scopes.is_empty() {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "A key needs at least one scope"
+        });
+    }
+    let days = body.expires_in_days.unwrap_or(DEFAULT_EXPIRY_DAYS);
+    if days == 0 || days > MAX_EXPIRY_DAYS {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": format!("Keys must expire within 1 to {} days", MAX_EXPIRY_DAYS)
+        });
+    }
+
+    let key = format!("{}{}", KEY_PREFIX, tokens::generate_token());
+    let now = DateTime::now();
+    let record = ApiKey {
+        id: None,
+        school_id: school_oid,
+        name: body.name.clone(),
+        prefix: key[..KEY_PREFIX.len() + DISPLAY_PREFIX_LEN].to_string(),
+        key_hash: tokens::hash_token(&key),
+        scopes: body.scopes.clone(),
+        created_by: creator,
+        created_at: now,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + days as i64 * 24 * 60 * 60 * 1000),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    let expires_at = record.expires_at;
+
+    let key_id = match db.collection::<ApiKey>("api_keys").insert_one(&record, None).await {
+        Ok(result) => result.inserted_id,
+        Err(e) => {
+            println!("Failed to create API key: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to create API key"
+            });
+        }
+    };
+
+    let scopes = to_bson(&body.scopes).unwrap_or_default();
+    audit::record(
+        &db,
+        AuditEntry::new("api_key_created")
+            .actor(&principal.email)
+            .school(Some(school_oid))
+            .target(&key_id.as_object_id().map(|id| id.to_hex()).unwrap_or_default())
+            .request(&req)
+            .details(doc! { "name": &body.name, "scopes": scopes, "expires_at": expires_at }),
+    )
+    .await;
+
+    HttpResponse::Ok().json(doc! {
+        "id": key_id,
+        "key": key,
+        "prefix": record.prefix,
+        "expires_at": expires_at,
+    })
+}
+
+#[get("/admin/schools/{school_id}/api-keys")]
+async fn list_keys(
+    school_id: web::Path<String>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match parse_school(&school_id) {
+        Ok(oid) => oid,
+        Err(response) => return response,
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageApiKeys, &Scope::School(school_oid)) {
+        return denied;
+    }
+    let page = match Page::new(&page, &["created_at", "name"], "created_at") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    let collection = db.collection::<Document>("api_keys");
+    let keys = page.respond(&collection, doc! { "school_id": school_oid }, |key: ApiKey| {
+        let scopes = to_bson(&key.scopes).unwrap_or_default();
+        doc! {
+            "_id": key.id,
+            "name": key.name,
+            "prefix": key.prefix,
+            "scopes": scopes,
+            "created_by": key.created_by,
+            "created_at": key.created_at,
+            "expires_at": key.expires_at,
+            "last_used_at": key.last_used_at,
+            "revoked_at": key.revoked_at,
+        }
+    });
+    match keys.await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error fetching API keys: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/admin/api-keys/{key_id}")]
+async fn revoke_key(
+    req: HttpRequest,
+    key_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let oid = match ObjectId::parse_str(key_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid key ID"
+        }),
+    };
+    let collection = db.collection::<ApiKey>("api_keys");
+
+    let key = match collection.find_one(doc! { "_id": oid }, None).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "API key not found"
+        }),
+        Err(e) => {
+            println!("Error finding API key: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageApiKeys, &Scope::School(key.school_id)) {
+        return denied;
+    }
+
+    match collection
+        .update_one(
+            doc! { "_id": oid, "revoked_at": null },
+            doc! { "$set": { "revoked_at": DateTime::now() } },
+            None,
+        )
+        .await
+    {
+        Ok(_) => {
+            audit::record(
+                &db,
+                AuditEntry::new("api_key_revoked")
+                    .actor(&principal.email)
+                    .school(Some(key.school_id))
+                    .target(&oid.to_hex())
+                    .request(&req)
+                    .details(doc! { "name": &key.name }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "API key revoked"
+            })
+        }
+        Err(e) => {
+            println!("Error revoking API key: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to revoke API key"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::{Role, RoleGrant};
+
+    fn key(school: ObjectId) -> ApiKey {
+        ApiKey {
+            id: Some(ObjectId::new()),
+            school_id: school,
+            name: "registrar".to_string(),
+            prefix: "dk_00000000".to_string(),
+            key_hash: String::new(),
+            scopes: vec![ApiScope::Import],
+            created_by: ObjectId::new(),
+            created_at: DateTime::now(),
+            expires_at: DateTime::now(),
+            last_used_at: None,
+            revoked_at: None,
+        }
+    }
+
+    fn account(role: Role, school: ObjectId) -> Account {
+        let mut account = Account::student("admin@x.edu".to_string(), String::new(), Some(school));
+        account.roles = vec![RoleGrant { role, school_id: Some(school), dorm_id: None }];
+        account
+    }
+
+    #[test]
+    fn keys_need_a_creator_who_still_manages_keys() {
+        let school = ObjectId::new();
+        let key = key(school);
+
+        assert!(creator_may_use(&account(Role::SchoolAdmin, school), &key));
+        assert!(!creator_may_use(&account(Role::SchoolAdmin, ObjectId::new()), &key));
+        assert!(!creator_may_use(&account(Role::Student, school), &key));
+    }
+}
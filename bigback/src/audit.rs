@@ -0,0 +1,319 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
+    Database,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::accounts;
+use crate::pagination::{Page, PageQuery};
+use crate::rate_limit::client_ip;
+use crate::roles::{authorize, Permission, Principal, Scope};
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// The audit log is append-only: entries are inserted here and nothing in the
+// API updates or deletes them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub timestamp: DateTime,
+    pub actor: Option<String>,
+    pub school_id: Option<ObjectId>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    pub details: Option<Document>,
+    // Fields the action changed, as { field: { before, after } }
+    #[serde(default)]
+    pub changes: Option<Document>,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str) -> Self {
+        AuditEntry {
+            id: None,
+            timestamp: DateTime::now(),
+            actor: None,
+            school_id: None,
+            action: action.to_string(),
+            target: None,
+            ip: None,
+            request_id: None,
+            details: None,
+            changes: None,
+        }
+    }
+
+    pub fn actor(mut self, actor: &str) -> Self {
+        self.actor = Some(actor.to_string());
+        self
+    }
+
+    pub fn school(mut self, school_id: Option<ObjectId>) -> Self {
+        self.school_id = school_id;
+        self
+    }
+
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<&str>) -> Self {
+        self.ip = ip.map(str::to_string);
+        self
+    }
+
+    // The caller's IP and the ID the request was tagged with
+    pub fn request(mut self, req: &HttpRequest) -> Self {
+        self.ip = client_ip(req);
+        self.request_id = request_id(req);
+        self
+    }
+
+    pub fn details(mut self, details: Document) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    // Records what changed between two versions of the target. Use None for
+    // the side that doesn't exist, e.g. `before` of something just created.
+    pub fn diff(mut self, before: Option<&Document>, after: Option<&Document>) -> Self {
+        self.changes = Some(diff(before, after));
+        self
+    }
+}
+
+pub fn diff(before: Option<&Document>, after: Option<&Document>) -> Document {
+    let empty = Document::new();
+    let before = before.unwrap_or(&empty);
+    let after = after.unwrap_or(&empty);
+
+    let mut changes = Document::new();
+    let fields = before.keys().chain(after.keys().filter(|key| !before.contains_key(*key)));
+    for field in fields {
+        let old = before.get(field).cloned().unwrap_or(Bson::Null);
+        let new = after.get(field).cloned().unwrap_or(Bson::Null);
+        if old != new {
+            changes.insert(field, doc! { "before": old, "after": new });
+        }
+    }
+    changes
+}
+
+// Appends to the audit log. A failed write is logged but never fails the
+// request that triggered it.
+pub async fn record(db: &Database, entry: AuditEntry) {
+    let collection = db.collection::<AuditEntry>("audit_log");
+    if let Err(e) = collection.insert_one(&entry, None).await {
+        println!("Failed to write audit entry '{}': {:?}", entry.action, e);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+pub fn request_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
+
+// A request ID from a proxy in front of us is kept so entries can be matched
+// with its logs; anything odd-looking is replaced
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| id.to_string())
+}
+
+// Middleware tagging every request with an ID, echoed back in X-Request-Id
+pub async fn tag_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = incoming_request_id(&req).unwrap_or_else(|| {
+        let mut bytes = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        hex::encode(bytes)
+    });
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = next.call(req).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    school_id: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    request_id: Option<String>,
+    // RFC 3339 timestamps
+    since: Option<String>,
+    until: Option<String>,
+}
+
+// An actor (email) or target (account ID) to filter on, widened to the
+// accounts merged into that account so their history shows up with it
+async fn with_merged(db: &Database, field: &str, value: &str) -> Result<Bson, mongodb::error::Error> {
+    let mut filter = match (field, ObjectId::parse_str(value)) {
+        ("target", Ok(account_id)) => doc! { "_id": account_id },
+        ("actor", _) => doc! { "email": value },
+        _ => return Ok(Bson::String(value.to_string())),
+    };
+    filter.insert("merged_from.0", doc! { "$exists": true });
+    let mut values = vec![value.to_string()];
+    let mut cursor = accounts::collection(db).find(filter, None).await?;
+    while let Some(account) = cursor.next().await {
+        for merged in account?.merged_from {
+            values.push(match field {
+                "target" => merged.account_id.to_hex(),
+                _ => merged.email,
+            });
+        }
+    }
+    Ok(match values.len() {
+        1 => Bson::String(value.to_string()),
+        _ => doc! { "$in": values }.into(),
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_time(value: &str) -> Result<DateTime, HttpResponse> {
+    DateTime::parse_rfc3339_str(value).map_err(|_| {
+        HttpResponse::BadRequest().json(doc! {
+            "error": format!("Invalid timestamp '{}', expected RFC 3339", value)
+        })
+    })
+}
+
+// Newest entries first, optionally filtered
+#[get("/admin/audit-log")]
+async fn query_log(
+    query: web::Query<AuditQuery>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let mut filter = Document::new();
+
+    match &query.school_id {
+        Some(school_id) => {
+            let school_oid = match ObjectId::parse_str(school_id) {
+                Ok(oid) => oid,
+                Err(_) => return HttpResponse::BadRequest().json(doc! {
+                    "error": "Invalid school ID"
+                }),
+            };
+            if let Err(denied) = authorize(&principal, Permission::ViewAuditLog, &Scope::School(school_oid)) {
+                return denied;
+            }
+            filter.insert("school_id", school_oid);
+        }
+        // Entries without a school are only visible to super admins
+        None => match principal.schools_with(Permission::ViewAuditLog) {
+            None => {}
+            Some(schools) if schools.is_empty() => {
+                return HttpResponse::Forbidden().json(doc! {
+                    "error": "You don't have permission to do that"
+                });
+            }
+            Some(schools) => {
+                filter.insert("school_id", doc! { "$in": schools });
+            }
+        },
+    }
+
+    for (field, value) in [("action", &query.action), ("request_id", &query.request_id)] {
+        if let Some(value) = value {
+            filter.insert(field, value);
+        }
+    }
+    for (field, value) in [("actor", &query.actor), ("target", &query.target)] {
+        if let Some(value) = value {
+            match with_merged(&db, field, value).await {
+                Ok(values) => filter.insert(field, values),
+                Err(e) => {
+                    println!("Error finding merged accounts: {:?}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            };
+        }
+    }
+
+    let mut timestamp = Document::new();
+    if let Some(since) = &query.since {
+        match parse_time(since) {
+            Ok(since) => timestamp.insert("$gte", since),
+            Err(response) => return response,
+        };
+    }
+    if let Some(until) = &query.until {
+        match parse_time(until) {
+            Ok(until) => timestamp.insert("$lt", until),
+            Err(response) => return response,
+        };
+    }
+    if !timestamp.is_empty() {
+        filter.insert("timestamp", timestamp);
+    }
+
+    let page = match Page::new(&page, &["_id"], "-_id") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    let collection = db.collection::<Document>("audit_log");
+    match page.respond(&collection, filter, |entry: AuditEntry| entry).await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error querying audit log: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_keeps_only_changed_fields() {
+        let before = doc! { "number": "101", "capacity": 2, "students": ["a"] };
+        let after = doc! { "number": "101", "capacity": 3, "students": ["a", "b"], "note": "x" };
+
+        assert_eq!(
+            diff(Some(&before), Some(&after)),
+            doc! {
+                "capacity": { "before": 2, "after": 3 },
+                "students": { "before": ["a"], "after": ["a", "b"] },
+                "note": { "before": null, "after": "x" },
+            }
+        );
+        assert_eq!(
+            diff(None, Some(&doc! { "name": "North" })),
+            doc! { "name": { "before": null, "after": "North" } }
+        );
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+    }
+}
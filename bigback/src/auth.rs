@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use actix_web::{
+    dev::Payload, error::InternalError, web, FromRequest, HttpRequest, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::rate_limit::client_ip;
+use crate::tokens;
+
+// A login. The token itself is only ever handed to the client; we keep its
+// SHA-256 so a database leak doesn't leak live sessions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub token_hash: String,
+    pub account_id: ObjectId,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub revoked_at: Option<DateTime>,
+    // The client's User-Agent, so people can tell their sessions apart
+    #[serde(default)]
+    pub device: Option<String>,
+    // Where the session was last used from
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub last_seen_at: Option<DateTime>,
+}
+
+// last_seen_at is only written when it's at least this stale, so busy
+// clients don't turn every request into a write
+const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(60);
+
+fn device(req: &HttpRequest) -> Option<String> {
+    let user_agent = req.headers().get("User-Agent")?.to_str().ok()?;
+    Some(user_agent.chars().take(256).collect())
+}
+
+fn session_ttl() -> Duration {
+    let hours = std::env::var("SESSION_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse::<u64>().ok())
+        .unwrap_or(12);
+    Duration::from_secs(hours * 60 * 60)
+}
+
+// Starts a session for the client behind `req` and returns the bearer token
+// for it
+pub async fn create_session(
+    db: &Database,
+    account_id: ObjectId,
+    req: &HttpRequest,
+) -> Result<String, mongodb::error::Error> {
+    let token = tokens::generate_token();
+    let now = DateTime::now();
+    let session = Session {
+        id: None,
+        token_hash: tokens::hash_token(&token),
+        account_id,
+        created_at: now,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + session_ttl().as_millis() as i64),
+        revoked_at: None,
+        device: device(req),
+        ip: client_ip(req),
+        last_seen_at: Some(now),
+    };
+
+    db.collection::<Session>("sessions")
+        .insert_one(session, None)
+        .await?;
+    Ok(token)
+}
+
+fn revocable(account_id: ObjectId, except: Option<ObjectId>) -> Document {
+    let mut filter = doc! {
+        "account_id": account_id,
+        "revoked_at": null,
+    };
+    if let Some(session_id) = except {
+        filter.insert("_id", doc! { "$ne": session_id });
+    }
+    filter
+}
+
+// Revokes every live session of an account, e.g. after a password reset,
+// optionally keeping the one the request came in on
+pub async fn revoke_all_sessions(
+    db: &Database,
+    account_id: ObjectId,
+    except: Option<ObjectId>,
+) -> Result<u64, mongodb::error::Error> {
+    let result = db
+        .collection::<Session>("sessions")
+        .update_many(
+            revocable(account_id, except),
+            doc! { "$set": { "revoked_at": DateTime::now() } },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+// Whether last_seen_at is stale or the session has moved to another IP
+fn needs_touch(session: &Session, ip: Option<&str>, now: DateTime) -> bool {
+    let stale = now.timestamp_millis() - LAST_SEEN_RESOLUTION.as_millis() as i64;
+    !session
+        .last_seen_at
+        .is_some_and(|seen| seen.timestamp_millis() > stale && session.ip.as_deref() == ip)
+}
+
+// Records that a session was just used. Failing to do so shouldn't fail the
+// request.
+async fn touch(db: &Database, session: &Session, ip: Option<String>) {
+    let now = DateTime::now();
+    if !needs_touch(session, ip.as_deref(), now) {
+        return;
+    }
+
+    let result = db
+        .collection::<Session>("sessions")
+        .update_one(
+            doc! { "_id": session.id },
+            doc! { "$set": { "last_seen_at": now, "ip": ip } },
+            None,
+        )
+        .await;
+    if let Err(e) = result {
+        println!("Error updating session last seen: {:?}", e);
+    }
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let token = header.strip_prefix("Bearer ")?.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+// Extractor for handlers that need a logged-in caller. Responds 401 when the
+// Authorization header is missing or the session is unknown, expired or
+// revoked.
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+    pub session_id: ObjectId,
+    pub account_id: ObjectId,
+}
+
+fn unauthorized(message: &str) -> actix_web::Error {
+    InternalError::from_response(
+        message.to_string(),
+        HttpResponse::Unauthorized().json(doc! { "error": message }),
+    )
+    .into()
+}
+
+impl FromRequest for Authenticated {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let db = req.app_data::<web::Data<Database>>().cloned();
+        let token = bearer_token(req);
+        let ip = client_ip(req);
+
+        Box::pin(async move {
+            let (Some(db), Some(token)) = (db, token) else {
+                return Err(unauthorized("Not authenticated"));
+            };
+
+            let session = db
+                .collection::<Session>("sessions")
+                .find_one(
+                    doc! {
+                        "token_hash": tokens::hash_token(&token),
+                        "revoked_at": null,
+                        "expires_at": { "$gt": DateTime::now() },
+                    },
+                    None,
+                )
+                .await;
+
+            match session {
+                Ok(Some(session)) => {
+                    let session_id = session.id.expect("Stored sessions have an ID");
+                    touch(&db, &session, ip).await;
+                    Ok(Authenticated {
+                        session_id,
+                        account_id: session.account_id,
+                    })
+                }
+                Ok(None) => Err(unauthorized("Session expired or invalid")),
+                Err(e) => {
+                    println!("Error looking up session: {:?}", e);
+                    Err(actix_web::error::ErrorInternalServerError("Internal server error"))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn session(last_seen_at: Option<DateTime>, ip: Option<&str>) -> Session {
+        Session {
+            id: Some(ObjectId::new()),
+            token_hash: tokens::hash_token("token"),
+            account_id: ObjectId::new(),
+            created_at: DateTime::from_millis(0),
+            expires_at: DateTime::from_millis(i64::MAX),
+            revoked_at: None,
+            device: None,
+            ip: ip.map(str::to_string),
+            last_seen_at,
+        }
+    }
+
+    #[test]
+    fn sessions_are_touched_when_stale_or_moved() {
+        let now = DateTime::from_millis(1_700_000_000_000);
+        let seconds_ago = |seconds: i64| Some(DateTime::from_millis(now.timestamp_millis() - seconds * 1000));
+
+        assert!(!needs_touch(&session(seconds_ago(10), Some("10.0.0.1")), Some("10.0.0.1"), now));
+        assert!(needs_touch(&session(seconds_ago(61), Some("10.0.0.1")), Some("10.0.0.1"), now));
+        assert!(needs_touch(&session(seconds_ago(10), Some("10.0.0.1")), Some("10.0.0.2"), now));
+        assert!(needs_touch(&session(None, None), None, now));
+    }
+
+    #[test]
+    fn revoking_can_spare_the_current_session() {
+        let account = ObjectId::new();
+        let current = ObjectId::new();
+        assert_eq!(revocable(account, None), doc! { "account_id": account, "revoked_at": null });
+        assert_eq!(
+            revocable(account, Some(current)),
+            doc! { "account_id": account, "revoked_at": null, "_id": { "$ne": current } }
+        );
+    }
+
+    #[test]
+    fn bearer_tokens_come_from_the_authorization_header() {
+        let req = TestRequest::default().insert_header(("Authorization", "Bearer abc ")).to_http_request();
+        assert_eq!(bearer_token(&req).as_deref(), Some("abc"));
+        let req = TestRequest::default().insert_header(("Authorization", "Bearer ")).to_http_request();
+        assert_eq!(bearer_token(&req), None);
+        let req = TestRequest::default().insert_header(("Authorization", "Basic abc")).to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+}
@@ -0,0 +1,241 @@
+// A minimal OpenID Connect provider for trying out and testing school SSO
+// locally. It signs ID tokens with the client secret (HS256), asks for an
+// email instead of a password and accepts whatever is typed in.
+//
+//   cargo run --bin mock_idp
+//
+// then point a school at it:
+//
+//   PUT /api/admin/schools/{id}/sso
+//   { "oidc": { "issuer": "http://127.0.0.1:9000", "client_id": "dorms",
+//               "client_secret": "mock-secret" } }
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+struct Settings {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+}
+
+// What the token endpoint needs to know about an issued code
+struct Grant {
+    email: String,
+    nonce: Option<String>,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+}
+
+struct State {
+    settings: Settings,
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+fn random_string() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn discovery(state: web::Data<State>) -> impl Responder {
+    let issuer = &state.settings.issuer;
+    HttpResponse::Ok().json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+// Tokens are HMAC-signed, so there are no public keys to publish
+#[get("/jwks")]
+async fn jwks() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "keys": [] }))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    // Filled in by the form below; `login_hint` skips the form
+    email: Option<String>,
+    login_hint: Option<String>,
+}
+
+#[get("/authorize")]
+async fn authorize(query: web::Query<AuthorizeQuery>, state: web::Data<State>) -> impl Responder {
+    if query.client_id != state.settings.client_id {
+        return HttpResponse::BadRequest().body("Unknown client_id");
+    }
+
+    let Some(email) = query.email.clone().or_else(|| query.login_hint.clone()) else {
+        // Ask for an email, keeping every other parameter
+        let mut hidden = String::new();
+        for (name, value) in [
+            ("client_id", Some(&query.client_id)),
+            ("redirect_uri", Some(&query.redirect_uri)),
+            ("state", query.state.as_ref()),
+            ("nonce", query.nonce.as_ref()),
+            ("code_challenge", query.code_challenge.as_ref()),
+        ] {
+            if let Some(value) = value {
+                hidden.push_str(&format!(
+                    "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                    name,
+                    html_escape(value)
+                ));
+            }
+        }
+        return HttpResponse::Ok().content_type("text/html").body(format!(
+            "<!doctype html><title>Mock IdP</title><form method=\"get\">{}\
+             <label>Email <input name=\"email\" autofocus></label> \
+             <button>Sign in</button></form>",
+            hidden
+        ));
+    };
+
+    let code = random_string();
+    state.grants.lock().unwrap().insert(
+        code.clone(),
+        Grant {
+            email,
+            nonce: query.nonce.clone(),
+            redirect_uri: query.redirect_uri.clone(),
+            code_challenge: query.code_challenge.clone(),
+        },
+    );
+
+    let mut location = match with_query_separator(&query.redirect_uri) {
+        Some(location) => location,
+        None => return HttpResponse::BadRequest().body("Invalid redirect_uri"),
+    };
+    location.push_str(&format!("code={}", urlencoding::encode(&code)));
+    if let Some(client_state) = &query.state {
+        location.push_str(&format!("&state={}", urlencoding::encode(client_state)));
+    }
+    HttpResponse::Found().insert_header(("Location", location)).finish()
+}
+
+// The redirect URI with a separator ready for more query parameters
+fn with_query_separator(redirect_uri: &str) -> Option<String> {
+    if !(redirect_uri.starts_with("http://") || redirect_uri.starts_with("https://")) {
+        return None;
+    }
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+    Some(format!("{}{}", redirect_uri, separator))
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    client_secret: String,
+    code_verifier: Option<String>,
+}
+
+#[post("/token")]
+async fn token(form: web::Form<TokenRequest>, state: web::Data<State>) -> impl Responder {
+    let settings = &state.settings;
+    if form.client_id != settings.client_id || form.client_secret != settings.client_secret {
+        return HttpResponse::Unauthorized().json(json!({ "error": "invalid_client" }));
+    }
+    if form.grant_type != "authorization_code" {
+        return HttpResponse::BadRequest().json(json!({ "error": "unsupported_grant_type" }));
+    }
+
+    // Codes are single use
+    let Some(grant) = state.grants.lock().unwrap().remove(&form.code) else {
+        return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+    };
+    if grant.redirect_uri != form.redirect_uri {
+        return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+    }
+    if let Some(challenge) = &grant.code_challenge {
+        let verifier = form.code_verifier.as_deref().unwrap_or_default();
+        if &BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes())) != challenge {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+        }
+    }
+
+    let now = jsonwebtoken::get_current_timestamp();
+    let claims = json!({
+        "iss": settings.issuer,
+        "sub": format!("mock|{}", grant.email.to_lowercase()),
+        "aud": settings.client_id,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": grant.nonce,
+        "email": grant.email,
+        "email_verified": true,
+    });
+    let id_token = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(settings.client_secret.as_bytes()),
+    )
+    .expect("Failed to sign ID token");
+
+    HttpResponse::Ok().json(json!({
+        "access_token": random_string(),
+        "token_type": "Bearer",
+        "expires_in": 300,
+        "id_token": id_token,
+    }))
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let port: u16 = std::env::var("MOCK_IDP_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(9000);
+    let settings = Settings {
+        issuer: format!("http://127.0.0.1:{}", port),
+        client_id: std::env::var("MOCK_IDP_CLIENT_ID").unwrap_or_else(|_| "dorms".to_string()),
+        client_secret: std::env::var("MOCK_IDP_CLIENT_SECRET")
+            .unwrap_or_else(|_| "mock-secret".to_string()),
+    };
+    println!("Mock identity provider at {}", settings.issuer);
+
+    let state = web::Data::new(State {
+        settings,
+        grants: Mutex::new(HashMap::new()),
+    });
+    HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .service(discovery)
+            .service(jwks)
+            .service(authorize)
+            .service(token)
+    })
+    .bind(("127.0.0.1", port))?
+    .run()
+    .await
+}
@@ -0,0 +1,330 @@
+use std::time::Duration;
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts;
+use crate::audit::{self, AuditEntry};
+use crate::mail::{self, MailMessage};
+use crate::passwords;
+use crate::roles::{self, authorize, Permission, Principal};
+use crate::tokens;
+
+pub const INVITE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const SHEET_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const GENERATED_PASSWORD_LENGTH: usize = 12;
+
+// How imported students get their first credentials
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialDelivery {
+    // Generate one-time passwords and collect them in a downloadable sheet
+    #[default]
+    Sheet,
+    // Email each student a link to set their own password
+    Invite,
+}
+
+// School email patterns use `{id}` for the student id and optionally
+// `{name}`, e.g. "s{id}@students.example.edu"
+pub fn check_email_pattern(pattern: &str) -> Result<(), &'static str> {
+    if !pattern.contains("{id}") {
+        return Err("Email pattern must contain {id}");
+    }
+    if !pattern.contains('@') {
+        return Err("Email pattern must contain a domain");
+    }
+    Ok(())
+}
+
+pub fn render_email(pattern: &str, student_id: i32, name: &str) -> String {
+    let name: String = name
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '.')
+        .collect();
+    pattern
+        .replace("{id}", &student_id.to_string())
+        .replace("{name}", &name)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialRow {
+    pub room: String,
+    pub name: String,
+    pub student_id: i32,
+    pub email: String,
+    pub password: String,
+}
+
+// One-time passwords from an import, kept until an admin downloads them once
+// or they expire
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialSheet {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub dorm_id: ObjectId,
+    // The account that committed the import; only it gets the passwords.
+    // Missing on sheets saved before this was recorded.
+    #[serde(default)]
+    pub created_by: Option<ObjectId>,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub rows: Vec<CredentialRow>,
+}
+
+pub async fn save_sheet(
+    db: &Database,
+    dorm_id: ObjectId,
+    created_by: ObjectId,
+    rows: Vec<CredentialRow>,
+) -> Result<ObjectId, mongodb::error::Error> {
+    let collection = db.collection::<CredentialSheet>("credential_sheets");
+    let now = DateTime::now();
+
+    // Sheets that were never downloaded shouldn't keep passwords around
+    collection
+        .delete_many(doc! { "expires_at": { "$lte": now } }, None)
+        .await?;
+
+    let sheet = CredentialSheet {
+        id: None,
+        dorm_id,
+        created_by: Some(created_by),
+        created_at: now,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + SHEET_TTL.as_millis() as i64),
+        rows,
+    };
+    let result = collection.insert_one(sheet, None).await?;
+    Ok(result
+        .inserted_id
+        .as_object_id()
+        .expect("MongoDB should have generated an ObjectId"))
+}
+
+pub fn invite_message(email: &str, token: &str) -> MailMessage {
+    MailMessage {
+        to: email.to_string(),
+        subject: "Set up your dorm account".to_string(),
+        body: format!(
+            "An account has been created for you.\n\n\
+             Choose a password here within 7 days:\n{}/invite?token={}\n",
+            mail::app_base_url(),
+            token
+        ),
+    }
+}
+
+fn sheet_csv(rows: &[CredentialRow]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+// Returns the sheet as CSV and deletes it, so the passwords can only be
+// downloaded once
+#[get("/admin/credential-sheets/{sheet_id}")]
+async fn download_credential_sheet(
+    req: HttpRequest,
+    sheet_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let collection = db.collection::<CredentialSheet>("credential_sheets");
+
+    let oid = match ObjectId::parse_str(sheet_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid credential sheet ID"
+        }),
+    };
+
+    // Only whoever ran the import, and may still import into the dorm, gets
+    // its passwords. Sheet IDs aren't secret.
+    let dorm_id = match collection.find_one(doc! { "_id": oid }, None).await {
+        Ok(Some(sheet)) if sheet.created_by.is_some_and(|id| id != principal.id()) => {
+            return HttpResponse::Forbidden().json(doc! {
+                "error": "Only whoever ran the import can download its credentials"
+            });
+        }
+        Ok(Some(sheet)) => sheet.dorm_id,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Credential sheet not found or already downloaded"
+        }),
+        Err(e) => {
+            println!("Error fetching credential sheet: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to fetch credential sheet"
+            });
+        }
+    };
+    match roles::dorm_scope(&db, dorm_id).await {
+        Ok(Some(scope)) => {
+            if let Err(denied) = authorize(&principal, Permission::ImportRooms, &scope) {
+                return denied;
+            }
+        }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Dorm not found"
+        }),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to fetch credential sheet"
+            });
+        }
+    }
+
+    let sheet = match collection
+        .find_one_and_delete(
+            doc! { "_id": oid, "expires_at": { "$gt": DateTime::now() } },
+            None,
+        )
+        .await
+    {
+        Ok(Some(sheet)) => sheet,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Credential sheet not found or already downloaded"
+        }),
+        Err(e) => {
+            println!("Error fetching credential sheet: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to fetch credential sheet"
+            });
+        }
+    };
+
+    let body = match sheet_csv(&sheet.rows) {
+        Ok(body) => body,
+        Err(e) => {
+            println!("Error writing credential sheet: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    audit::record(
+        &db,
+        AuditEntry::new("credential_sheet_downloaded")
+            .actor(&principal.email)
+            .target(&oid.to_hex())
+            .request(&req)
+            .details(doc! { "dorm_id": sheet.dorm_id, "rows": sheet.rows.len() as i64 }),
+    )
+    .await;
+
+    HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"credentials-{}.csv\"", oid),
+        ))
+        .body(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct AcceptInviteRequest {
+    token: String,
+    new_password: String,
+}
+
+#[post("/invites/accept")]
+async fn accept_invite(
+    http_req: HttpRequest,
+    req: web::Json<AcceptInviteRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(error) = passwords::check_new_password(&req.new_password) {
+        return HttpResponse::BadRequest().json(doc! { "error": error });
+    }
+
+    let token = match tokens::consume(&db, "invite", &req.token).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invite is invalid or has expired"
+        }),
+        Err(e) => {
+            println!("Error redeeming invite: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    if let Err(e) = passwords::set_password(&db, token.account_id, &req.new_password).await {
+        println!("Error setting password from invite: {:?}", e);
+        return HttpResponse::InternalServerError().json(doc! {
+            "error": "Failed to set password"
+        });
+    }
+
+    // Only for the audit entry, which shouldn't fail the request
+    let account = accounts::collection(&db)
+        .find_one(doc! { "_id": token.account_id }, None)
+        .await
+        .unwrap_or_else(|e| {
+            println!("Error finding invited account: {:?}", e);
+            None
+        });
+    let mut entry = AuditEntry::new("invite_accepted")
+        .target(&token.account_id.to_hex())
+        .request(&http_req);
+    if let Some(account) = &account {
+        entry = entry.actor(&account.email).school(account.school_id);
+    }
+    audit::record(&db, entry).await;
+
+    HttpResponse::Ok().json(doc! {
+        "message": "Password set, you can now log in"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn email_patterns_need_an_id_and_a_domain() {
+        assert!(check_email_pattern("s{id}@students.example.edu").is_ok());
+        assert!(check_email_pattern("{name}.{id}@example.edu").is_ok());
+        assert!(check_email_pattern("{name}@example.edu").is_err());
+        assert!(check_email_pattern("s{id}").is_err());
+    }
+
+    #[test]
+    fn emails_are_rendered_from_the_pattern() {
+        assert_eq!(render_email("s{id}@students.example.edu", 170, "Rezwan"), "s170@students.example.edu");
+        // Names are lowercased and keep only letters, digits and dots
+        assert_eq!(render_email("{name}.{id}@example.edu", 7, " Ada O'Neil-Byron "), "adaoneilbyron.7@example.edu");
+        assert_eq!(render_email("{name}@example.edu", 7, "ada.lovelace"), "ada.lovelace@example.edu");
+    }
+
+    #[test]
+    fn sheets_are_written_as_csv_with_a_header() {
+        let password = passwords::generate_password(GENERATED_PASSWORD_LENGTH);
+        assert_eq!(password.len(), GENERATED_PASSWORD_LENGTH);
+        assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        let rows = vec![CredentialRow {
+            room: "513".to_string(),
+            name: "Rezwan, Jr.".to_string(),
+            student_id: 170,
+            email: "s170@students.example.edu".to_string(),
+            password: password.clone(),
+        }];
+        let csv = String::from_utf8(sheet_csv(&rows).unwrap()).unwrap();
+        assert_eq!(
+            csv,
+            format!(
+                "room,name,student_id,email,password\n513,\"Rezwan, Jr.\",170,s170@students.example.edu,{}\n",
+                password
+            )
+        );
+    }
+}
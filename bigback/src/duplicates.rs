@@ -0,0 +1,751 @@
+// The same student can end up with two accounts: an import under one email,
+// a manual create_student or an SSO sign-in under another. A scan runs daily
+// (and on request) to list likely pairs; an admin reviews them and either
+// dismisses a pair or merges the duplicate into the account to keep.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, Bson, DateTime, Document},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{self, Account, MergedAccount};
+use crate::audit::{self, AuditEntry};
+use crate::auth::Session;
+use crate::jobs::BackgroundJobs;
+use crate::pagination::{Page, PageQuery};
+use crate::roles::{authorize, Permission, Principal, Scope};
+use crate::Room;
+
+// Emails whose local parts are at least this alike are worth a look
+const EMAIL_SIMILARITY: f64 = 0.85;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MatchReason {
+    SameStudentId { student_id: i32 },
+    SameName { name: String },
+    SimilarEmail { similarity: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateStatus {
+    Open,
+    // An admin decided these are different people; scans leave it alone
+    Dismissed,
+    Merged,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateCandidate {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub school_id: ObjectId,
+    // Sorted, so each pair is stored once
+    pub account_ids: Vec<ObjectId>,
+    pub reasons: Vec<MatchReason>,
+    pub status: CandidateStatus,
+    pub found_at: DateTime,
+    pub resolved_by: Option<String>,
+    pub resolved_at: Option<DateTime>,
+}
+
+fn candidates(db: &Database) -> mongodb::Collection<DuplicateCandidate> {
+    db.collection::<DuplicateCandidate>("duplicate_candidates")
+}
+
+// Lowercase words in alphabetical order, so "Lovelace, Ada" and "ada
+// lovelace" are the same name
+pub fn normalize_name(name: &str) -> String {
+    let lowered: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let mut words: Vec<&str> = lowered.split_whitespace().collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+// The part of an email that identifies the person: the local part without a
+// +tag and without punctuation
+fn email_key(email: &str) -> String {
+    let local = email.split('@').next().unwrap_or_default();
+    let local = local.split('+').next().unwrap_or_default();
+    local.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// 1.0 for the same person-part of the email, falling towards 0.0 as the
+// parts differ. Very short parts say too little to compare.
+pub fn email_similarity(a: &str, b: &str) -> f64 {
+    let (a, b): (Vec<char>, Vec<char>) = (email_key(a).chars().collect(), email_key(b).chars().collect());
+    let longest = a.len().max(b.len());
+    if a.len().min(b.len()) < 3 {
+        return 0.0;
+    }
+    // Lengths this far apart can't reach the threshold, so skip the work
+    if a.len().abs_diff(b.len()) as f64 > longest as f64 * (1.0 - EMAIL_SIMILARITY) {
+        return 0.0;
+    }
+    1.0 - edit_distance(&a, &b) as f64 / longest as f64
+}
+
+fn reasons(a: &Account, b: &Account) -> Vec<MatchReason> {
+    let mut reasons = Vec::new();
+    if let (Some(id), Some(other)) = (a.student_id, b.student_id) {
+        if id == other {
+            reasons.push(MatchReason::SameStudentId { student_id: id });
+        }
+    }
+    if let (Some(name), Some(other)) = (&a.name, &b.name) {
+        let name = normalize_name(name);
+        if !name.is_empty() && name == normalize_name(other) {
+            reasons.push(MatchReason::SameName { name });
+        }
+    }
+    let similarity = email_similarity(&a.email, &b.email);
+    if similarity >= EMAIL_SIMILARITY {
+        reasons.push(MatchReason::SimilarEmail {
+            similarity: (similarity * 100.0).round() / 100.0,
+        });
+    }
+    reasons
+}
+
+// Pairs of student accounts that may be the same person, with why. Every
+// pair is compared; schools have thousands of students, not millions.
+pub fn find_duplicates(accounts: &[Account]) -> Vec<(Vec<ObjectId>, Vec<MatchReason>)> {
+    let students: Vec<&Account> = accounts
+        .iter()
+        .filter(|account| account.id.is_some() && !account.is_staff())
+        .collect();
+    let mut pairs = Vec::new();
+    for (i, a) in students.iter().enumerate() {
+        for b in &students[i + 1..] {
+            let reasons = reasons(a, b);
+            if reasons.is_empty() {
+                continue;
+            }
+            let mut ids = vec![a.id.expect("Filtered on id"), b.id.expect("Filtered on id")];
+            ids.sort();
+            pairs.push((ids, reasons));
+        }
+    }
+    pairs
+}
+
+// Finds the school's duplicate candidates again: new pairs are added, open
+// ones updated or, once they no longer match, dropped. Dismissed and merged
+// pairs stay as they are. Returns how many open pairs there are.
+pub async fn scan_school(db: &Database, school_id: ObjectId) -> Result<usize, mongodb::error::Error> {
+    let mut cursor = accounts::collection(db)
+        .find(doc! { "school_id": school_id, "deactivated_at": null, "merged_into": null }, None)
+        .await?;
+    let mut students = Vec::new();
+    while let Some(account) = cursor.next().await {
+        match account {
+            Ok(account) => students.push(account),
+            Err(e) => println!("Skipping unreadable account in duplicate scan: {:?}", e),
+        }
+    }
+    let found = tokio::task::spawn_blocking(move || find_duplicates(&students))
+        .await
+        .expect("Duplicate scan panicked");
+
+    let mut known = HashMap::new();
+    let mut cursor = candidates(db).find(doc! { "school_id": school_id }, None).await?;
+    while let Some(candidate) = cursor.next().await {
+        let candidate = candidate?;
+        known.insert(candidate.account_ids.clone(), (candidate.id, candidate.status));
+    }
+
+    let mut open = Vec::new();
+    for (account_ids, reasons) in found {
+        let reasons_bson = to_bson(&reasons).expect("Failed to serialize match reasons");
+        match known.get(&account_ids) {
+            Some((id, CandidateStatus::Open)) => {
+                candidates(db)
+                    .update_one(doc! { "_id": id }, doc! { "$set": { "reasons": reasons_bson } }, None)
+                    .await?;
+                open.extend(*id);
+            }
+            Some(_) => {}
+            None => {
+                let candidate = DuplicateCandidate {
+                    id: None,
+                    school_id,
+                    account_ids,
+                    reasons,
+                    status: CandidateStatus::Open,
+                    found_at: DateTime::now(),
+                    resolved_by: None,
+                    resolved_at: None,
+                };
+                let result = candidates(db).insert_one(&candidate, None).await?;
+                open.extend(result.inserted_id.as_object_id());
+            }
+        }
+    }
+    candidates(db)
+        .delete_many(
+            doc! { "school_id": school_id, "status": "open", "_id": { "$nin": &open } },
+            None,
+        )
+        .await?;
+    Ok(open.len())
+}
+
+async fn scan_all(db: &Database) -> Result<(), mongodb::error::Error> {
+    let mut cursor = db.collection::<Document>("schools").find(None, None).await?;
+    while let Some(school) = cursor.next().await {
+        let Ok(school_id) = school?.get_object_id("_id") else {
+            continue;
+        };
+        let open = scan_school(db, school_id).await?;
+        println!("Duplicate scan of school {}: {} possible duplicates", school_id, open);
+    }
+    Ok(())
+}
+
+// Scans every school now and then every `interval`. Runs until the process
+// exits.
+pub fn spawn_scanner(db: Database, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = scan_all(&db).await {
+                println!("Duplicate scan failed: {:?}", e);
+            }
+        }
+    });
+}
+
+// Why `duplicate` can't be merged into `primary`, if it can't
+pub fn check_merge(primary: &Account, duplicate: &Account) -> Result<(), &'static str> {
+    if primary.id == duplicate.id {
+        return Err("An account can't be merged into itself");
+    }
+    if primary.school_id != duplicate.school_id {
+        return Err("The accounts belong to different schools");
+    }
+    if primary.is_staff() || duplicate.is_staff() {
+        return Err("Only student accounts can be merged");
+    }
+    if duplicate.merged_into.is_some() {
+        return Err("The duplicate has already been merged");
+    }
+    if primary.merged_into.is_some() {
+        return Err("That account was merged into another; merge into that one instead");
+    }
+    if let (Some(id), Some(other)) = (primary.student_id, duplicate.student_id) {
+        if id != other {
+            return Err("The accounts have different student ids");
+        }
+    }
+    Ok(())
+}
+
+// Folds `duplicate` into `primary`: the primary takes over the duplicate's
+// room if it has none, any details it lacks, and the duplicate's sessions;
+// the duplicate is deactivated and remembered on the primary, so the audit
+// log finds both accounts' history under the primary. Returns what moved.
+async fn merge(
+    db: Database,
+    primary: Account,
+    duplicate: Account,
+    actor: String,
+) -> Result<Document, mongodb::error::Error> {
+    let primary_id = primary.id.expect("Stored accounts have an ID");
+    let duplicate_id = duplicate.id.expect("Stored accounts have an ID");
+    let now = DateTime::now();
+    let rooms = db.collection::<Room>("rooms");
+    let accounts_collection = accounts::collection(&db);
+
+    // One room per student: the primary's if it has one, else the
+    // duplicate's, keeping the date they moved in. Both accounts are in the
+    // school, and only its rooms are theirs.
+    let school_rooms = Room::in_school(&db, primary.school_id).await?;
+    let housing = |email: &str| {
+        let mut filter = school_rooms.clone();
+        filter.insert("current_students.name", email);
+        filter
+    };
+    let primary_housed = rooms.find_one(housing(&primary.email), None).await?.is_some();
+    let mut takes_room = false;
+    let mut cursor = rooms.find(housing(&duplicate.email), None).await?;
+    let mut duplicate_rooms = Vec::new();
+    while let Some(room) = cursor.next().await {
+        duplicate_rooms.push(room?.id);
+    }
+    for room_id in duplicate_rooms {
+        let update = if primary_housed || takes_room {
+            doc! { "$pull": { "current_students": { "name": &duplicate.email } } }
+        } else {
+            takes_room = true;
+            doc! { "$set": { "current_students.$.name": &primary.email } }
+        };
+        rooms
+            .update_one(doc! { "_id": room_id, "current_students.name": &duplicate.email }, update, None)
+            .await?;
+    }
+
+    // Details the primary lacks come from the duplicate. Identifiers used to
+    // find accounts are cleared on the duplicate first, so they're never on
+    // both.
+    let mut copied = Document::new();
+    let missing = [
+        ("name", primary.name.is_none(), to_bson(&duplicate.name)),
+        ("student_id", primary.student_id.is_none(), to_bson(&duplicate.student_id)),
+        ("external_id", primary.external_id.is_none(), to_bson(&duplicate.external_id)),
+        ("sso_subject", primary.sso_subject.is_none(), to_bson(&duplicate.sso_subject)),
+        ("assigned_room", takes_room, to_bson(&duplicate.assigned_room)),
+    ];
+    for (field, lacking, value) in missing {
+        let value = value.expect("Failed to serialize account field");
+        if lacking && value != Bson::Null {
+            copied.insert(field, value);
+        }
+    }
+    let mut cleared = doc! { "assigned_room": null };
+    for field in ["external_id", "sso_subject"] {
+        if copied.contains_key(field) {
+            cleared.insert(field, Bson::Null);
+        }
+    }
+    // Either the primary has the same student id or takes it; imports match
+    // on it, and should find the primary
+    if duplicate.student_id.is_some() {
+        cleared.insert("student_id", Bson::Null);
+    }
+    cleared.insert("merged_into", primary_id);
+    cleared.insert("deactivated_at", duplicate.deactivated_at.unwrap_or(now));
+    accounts_collection
+        .update_one(doc! { "_id": duplicate_id }, doc! { "$set": cleared }, None)
+        .await?;
+
+    // Duplicates merged into the duplicate come along
+    let mut merged = vec![MergedAccount {
+        account_id: duplicate_id,
+        email: duplicate.email.clone(),
+        merged_at: now,
+    }];
+    merged.extend(duplicate.merged_from.iter().cloned());
+    let mut update = doc! {
+        "$push": { "merged_from": { "$each": to_bson(&merged).expect("Failed to serialize merged accounts") } },
+    };
+    if !copied.is_empty() {
+        update.insert("$set", copied.clone());
+    }
+    accounts_collection.update_one(doc! { "_id": primary_id }, update, None).await?;
+    accounts_collection
+        .update_many(doc! { "merged_into": duplicate_id }, doc! { "$set": { "merged_into": primary_id } }, None)
+        .await?;
+
+    let sessions = db
+        .collection::<Session>("sessions")
+        .update_many(doc! { "account_id": duplicate_id }, doc! { "$set": { "account_id": primary_id } }, None)
+        .await?
+        .modified_count;
+    // Unused invites and resets for the duplicate would reach a dead account
+    db.collection::<Document>("one_time_tokens")
+        .delete_many(doc! { "account_id": duplicate_id, "used_at": null }, None)
+        .await?;
+
+    let mut pair = vec![primary_id, duplicate_id];
+    pair.sort();
+    candidates(&db)
+        .update_many(
+            doc! { "account_ids": &pair },
+            doc! { "$set": { "status": "merged", "resolved_by": &actor, "resolved_at": now } },
+            None,
+        )
+        .await?;
+    // The next scan pairs the primary with whoever the duplicate matched
+    candidates(&db)
+        .delete_many(doc! { "account_ids": duplicate_id, "status": "open" }, None)
+        .await?;
+
+    Ok(doc! {
+        "primary_id": primary_id,
+        "duplicate_id": duplicate_id,
+        "copied": copied.keys().cloned().collect::<Vec<String>>(),
+        "took_room": takes_room,
+        "sessions_moved": sessions as i64,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_id(id: &str, what: &str) -> Result<ObjectId, HttpResponse> {
+    ObjectId::parse_str(id).map_err(|_| {
+        HttpResponse::BadRequest().json(doc! {
+            "error": format!("Invalid {} ID", what)
+        })
+    })
+}
+
+// Starts a duplicate scan of the school; the results show up in the list
+#[post("/admin/schools/{school_id}/duplicates/scan")]
+async fn start_scan(
+    req: HttpRequest,
+    school_id: web::Path<String>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match parse_id(&school_id, "school") {
+        Ok(oid) => oid,
+        Err(response) => return response,
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageStudents, &Scope::School(school_oid)) {
+        return denied;
+    }
+
+    let scan_db = db.get_ref().clone();
+    jobs.spawn(async move {
+        match scan_school(&scan_db, school_oid).await {
+            Ok(open) => println!("Duplicate scan of school {}: {} possible duplicates", school_oid, open),
+            Err(e) => println!("Duplicate scan of school {} failed: {:?}", school_oid, e),
+        }
+    });
+    audit::record(
+        &db,
+        AuditEntry::new("duplicate_scan_started")
+            .actor(&principal.email)
+            .school(Some(school_oid))
+            .target(&school_oid.to_hex())
+            .request(&req),
+    )
+    .await;
+    HttpResponse::Accepted().json(doc! {
+        "message": "Duplicate scan started"
+    })
+}
+
+fn summary(account: Option<&Account>) -> Document {
+    match account {
+        Some(account) => doc! {
+            "_id": account.id,
+            "email": &account.email,
+            "name": &account.name,
+            "student_id": account.student_id,
+            "assigned_room": &account.assigned_room,
+            "sso": account.sso_subject.is_some(),
+        },
+        None => Document::new(),
+    }
+}
+
+// The school's open duplicate candidates, newest first, with both accounts
+#[get("/admin/schools/{school_id}/duplicates")]
+async fn list_duplicates(
+    school_id: web::Path<String>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match parse_id(&school_id, "school") {
+        Ok(oid) => oid,
+        Err(response) => return response,
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageStudents, &Scope::School(school_oid)) {
+        return denied;
+    }
+
+    let page = match Page::new(&page, &["found_at"], "-found_at") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    // Each page looks up its accounts together, which a stream can't do
+    if page.stream.is_some() {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "Duplicates can't be streamed"
+        });
+    }
+    let filter = doc! { "school_id": school_oid, "status": "open" };
+    let open = match page
+        .fetch::<DuplicateCandidate>(&db.collection::<Document>("duplicate_candidates"), filter)
+        .await
+    {
+        Ok(open) => open,
+        Err(e) => {
+            println!("Error listing duplicates: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let ids: Vec<ObjectId> = open.items.iter().flat_map(|candidate| candidate.account_ids.clone()).collect();
+    let mut accounts = HashMap::new();
+    match accounts::collection(&db).find(doc! { "_id": { "$in": ids } }, None).await {
+        Ok(mut cursor) => {
+            while let Some(account) = cursor.next().await {
+                match account {
+                    Ok(account) => {
+                        accounts.insert(account.id, account);
+                    }
+                    Err(e) => println!("Error reading account: {:?}", e),
+                }
+            }
+        }
+        Err(e) => {
+            println!("Error loading duplicate accounts: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let listed = open.map(|candidate| {
+        let pair: Vec<Document> = candidate
+            .account_ids
+            .iter()
+            .map(|id| summary(accounts.get(&Some(*id))))
+            .collect();
+        doc! {
+            "_id": candidate.id,
+            "accounts": pair,
+            "reasons": to_bson(&candidate.reasons).expect("Failed to serialize match reasons"),
+            "found_at": candidate.found_at,
+        }
+    });
+    HttpResponse::Ok().json(listed)
+}
+
+// Marks a pair as different people, so scans stop suggesting it
+#[post("/admin/duplicates/{candidate_id}/dismiss")]
+async fn dismiss_duplicate(
+    req: HttpRequest,
+    candidate_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let candidate_oid = match parse_id(&candidate_id, "candidate") {
+        Ok(oid) => oid,
+        Err(response) => return response,
+    };
+    let candidate = match candidates(&db).find_one(doc! { "_id": candidate_oid }, None).await {
+        Ok(Some(candidate)) => candidate,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Duplicate candidate not found"
+        }),
+        Err(e) => {
+            println!("Error finding duplicate candidate: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageStudents, &Scope::School(candidate.school_id)) {
+        return denied;
+    }
+    if candidate.status != CandidateStatus::Open {
+        return HttpResponse::Conflict().json(doc! {
+            "error": "Only open candidates can be dismissed"
+        });
+    }
+
+    let result = candidates(&db)
+        .update_one(
+            doc! { "_id": candidate_oid, "status": "open" },
+            doc! { "$set": {
+                "status": "dismissed",
+                "resolved_by": &principal.email,
+                "resolved_at": DateTime::now(),
+            } },
+            None,
+        )
+        .await;
+    match result {
+        Ok(_) => {
+            audit::record(
+                &db,
+                AuditEntry::new("duplicate_dismissed")
+                    .actor(&principal.email)
+                    .school(Some(candidate.school_id))
+                    .target(&candidate_oid.to_hex())
+                    .request(&req)
+                    .details(doc! { "account_ids": &candidate.account_ids }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Duplicate dismissed"
+            })
+        }
+        Err(e) => {
+            println!("Error dismissing duplicate: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to dismiss duplicate"
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MergeRequest {
+    // The account to keep
+    primary_id: String,
+    // The account folded into it and deactivated
+    duplicate_id: String,
+}
+
+#[post("/admin/accounts/merge")]
+async fn merge_accounts(
+    req: HttpRequest,
+    body: web::Json<MergeRequest>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    principal: Principal,
+) -> impl Responder {
+    let mut loaded = Vec::new();
+    for id in [&body.primary_id, &body.duplicate_id] {
+        let oid = match parse_id(id, "account") {
+            Ok(oid) => oid,
+            Err(response) => return response,
+        };
+        match accounts::collection(&db).find_one(doc! { "_id": oid }, None).await {
+            Ok(Some(account)) => loaded.push(account),
+            Ok(None) => return HttpResponse::NotFound().json(doc! {
+                "error": "Account not found"
+            }),
+            Err(e) => {
+                println!("Error finding account: {:?}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    let duplicate = loaded.pop().expect("Loaded both accounts");
+    let primary = loaded.pop().expect("Loaded both accounts");
+
+    for account in [&primary, &duplicate] {
+        if let Err(denied) = authorize(&principal, Permission::ManageStudents, &Scope::of_school(account.school_id)) {
+            return denied;
+        }
+    }
+    if let Err(error) = check_merge(&primary, &duplicate) {
+        return HttpResponse::Conflict().json(doc! { "error": error });
+    }
+
+    let school_id = primary.school_id;
+    let target = primary.id.expect("Stored accounts have an ID").to_hex();
+    let duplicate_email = duplicate.email.clone();
+    // Several collections change together; finish even if the client leaves
+    match jobs.run(merge(db.get_ref().clone(), primary, duplicate, principal.email.clone())).await {
+        Ok(Ok(merged)) => {
+            audit::record(
+                &db,
+                AuditEntry::new("accounts_merged")
+                    .actor(&principal.email)
+                    .school(school_id)
+                    .target(&target)
+                    .request(&req)
+                    .details(doc! { "duplicate_email": duplicate_email, "merge": merged.clone() }),
+            )
+            .await;
+            HttpResponse::Ok().json(merged)
+        }
+        Ok(Err(e)) => {
+            println!("Error merging accounts: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to merge accounts"
+            })
+        }
+        Err(e) => {
+            println!("Merge job failed: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to merge accounts"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::{Role, RoleGrant};
+
+    fn student(email: &str, name: Option<&str>, student_id: Option<i32>) -> Account {
+        Account {
+            id: Some(ObjectId::new()),
+            name: name.map(str::to_string),
+            student_id,
+            ..Account::student(email.to_string(), String::new(), None)
+        }
+    }
+
+    #[test]
+    fn names_and_emails_are_compared_loosely() {
+        assert_eq!(normalize_name("Lovelace, Ada"), "ada lovelace");
+        assert_eq!(normalize_name("  ADA   lovelace "), "ada lovelace");
+
+        assert_eq!(email_similarity("ada.lovelace@x.edu", "adalovelace+dorms@students.x.edu"), 1.0);
+        assert!(email_similarity("ada.lovelace@x.edu", "ada.lovelase@x.edu") >= EMAIL_SIMILARITY);
+        assert!(email_similarity("ada.lovelace@x.edu", "grace.hopper@x.edu") < EMAIL_SIMILARITY);
+        assert_eq!(email_similarity("ab@x.edu", "ab@y.edu"), 0.0);
+    }
+
+    #[test]
+    fn scans_pair_students_who_may_be_the_same_person() {
+        let mut staff = student("ada.lovelace@staff.x.edu", Some("Ada Lovelace"), None);
+        staff.roles = vec![RoleGrant {
+            role: Role::ResidentAssistant,
+            school_id: None,
+            dorm_id: None,
+        }];
+        let accounts = [
+            student("ada.lovelace@x.edu", Some("Ada Lovelace"), Some(7)),
+            student("s7@x.edu", Some("Lovelace, Ada"), Some(7)),
+            student("grace@x.edu", Some("Grace Hopper"), Some(8)),
+            staff,
+        ];
+
+        let pairs = find_duplicates(&accounts);
+        assert_eq!(pairs.len(), 1);
+        let mut ids = vec![accounts[0].id.unwrap(), accounts[1].id.unwrap()];
+        ids.sort();
+        assert_eq!(pairs[0].0, ids);
+        assert_eq!(
+            pairs[0].1,
+            vec![
+                MatchReason::SameStudentId { student_id: 7 },
+                MatchReason::SameName { name: "ada lovelace".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_unmerged_students_of_one_school_merge() {
+        let primary = student("ada@x.edu", None, Some(7));
+        let duplicate = student("s7@x.edu", None, None);
+        assert_eq!(check_merge(&primary, &duplicate), Ok(()));
+        assert!(check_merge(&primary, &primary).is_err());
+
+        let other = student("s8@x.edu", None, Some(8));
+        assert_eq!(check_merge(&primary, &other), Err("The accounts have different student ids"));
+
+        let elsewhere = Account {
+            school_id: Some(ObjectId::new()),
+            ..duplicate.clone()
+        };
+        assert_eq!(check_merge(&primary, &elsewhere), Err("The accounts belong to different schools"));
+
+        let merged = Account {
+            merged_into: primary.id,
+            ..duplicate
+        };
+        assert_eq!(check_merge(&primary, &merged), Err("The duplicate has already been merged"));
+    }
+}
@@ -0,0 +1,553 @@
+// Occupancy rosters for a dorm or a whole school. Every format can be fed
+// back in: the CSV and XLSX columns are ones the spreadsheet import
+// recognises, and the JSON is a list of `import_rooms` requests, one per dorm.
+// NDJSON has the spreadsheet's rows as objects and is streamed a room at a
+// time, for schools too big to build a file for in memory.
+use std::collections::HashMap;
+
+use actix_web::{
+    get,
+    http::header::{HeaderValue, CONTENT_DISPOSITION},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::FindOptions,
+    Database,
+};
+use rust_xlsxwriter::{Format as CellFormat, Workbook, XlsxError};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::accounts;
+use crate::audit::{self, AuditEntry};
+use crate::roles::{authorize, Permission, Principal, Scope};
+use crate::streaming::{self, Format as StreamFormat, Sender};
+use crate::{Dorm, Room};
+
+const HEADERS: [&str; 7] = ["Dorm", "Room", "Capacity", "Name", "Student ID", "Email", "Assigned At"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occupant {
+    pub email: String,
+    pub name: Option<String>,
+    pub student_id: Option<i32>,
+    pub assigned_at: Option<DateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportRoom {
+    pub number: String,
+    pub capacity: i32,
+    pub occupants: Vec<Occupant>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportDorm {
+    pub id: ObjectId,
+    pub school_id: Option<ObjectId>,
+    pub name: String,
+    pub rooms: Vec<ExportRoom>,
+}
+
+fn assigned_at(occupant: &Occupant) -> String {
+    occupant
+        .assigned_at
+        .and_then(|date| date.try_to_rfc3339_string().ok())
+        .unwrap_or_default()
+}
+
+// Occupants from before names were recorded go by their email
+fn display_name(occupant: &Occupant) -> &str {
+    occupant.name.as_deref().unwrap_or(&occupant.email)
+}
+
+// One row per occupant, and one for an empty room
+fn room_rows(room: &ExportRoom) -> Vec<Option<&Occupant>> {
+    match room.occupants.is_empty() {
+        true => vec![None],
+        false => room.occupants.iter().map(Some).collect(),
+    }
+}
+
+fn rows(dorms: &[ExportDorm]) -> impl Iterator<Item = (&ExportDorm, &ExportRoom, Option<&Occupant>)> {
+    dorms.iter().flat_map(|dorm| {
+        dorm.rooms
+            .iter()
+            .flat_map(move |room| room_rows(room).into_iter().map(move |occupant| (dorm, room, occupant)))
+    })
+}
+
+// A spreadsheet row as an NDJSON line
+fn line(dorm: &str, room: &ExportRoom, occupant: Option<&Occupant>) -> serde_json::Value {
+    json!({
+        "dorm": dorm,
+        "room": &room.number,
+        "capacity": room.capacity,
+        "name": occupant.map(display_name),
+        "student_id": occupant.and_then(|occupant| occupant.student_id),
+        "email": occupant.map(|occupant| &occupant.email),
+        "assigned_at": occupant.and_then(|occupant| occupant.assigned_at.and_then(|date| date.try_to_rfc3339_string().ok())),
+    })
+}
+
+pub fn to_csv(dorms: &[ExportDorm]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(HEADERS)?;
+    for (dorm, room, occupant) in rows(dorms) {
+        let capacity = room.capacity.to_string();
+        let (name, student_id, email, assigned) = match occupant {
+            Some(occupant) => (
+                display_name(occupant).to_string(),
+                occupant.student_id.map(|id| id.to_string()).unwrap_or_default(),
+                occupant.email.clone(),
+                assigned_at(occupant),
+            ),
+            None => Default::default(),
+        };
+        writer.write_record([&dorm.name, &room.number, &capacity, &name, &student_id, &email, &assigned])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+pub fn to_xlsx(dorms: &[ExportDorm]) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Roster")?;
+    let bold = CellFormat::new().set_bold();
+    for (column, header) in HEADERS.iter().enumerate() {
+        sheet.write_string_with_format(0, column as u16, *header, &bold)?;
+    }
+
+    for (index, (dorm, room, occupant)) in rows(dorms).enumerate() {
+        let row = index as u32 + 1;
+        sheet.write_string(row, 0, &dorm.name)?;
+        sheet.write_string(row, 1, &room.number)?;
+        sheet.write_number(row, 2, room.capacity)?;
+        let Some(occupant) = occupant else {
+            continue;
+        };
+        sheet.write_string(row, 3, display_name(occupant))?;
+        if let Some(student_id) = occupant.student_id {
+            sheet.write_number(row, 4, student_id)?;
+        }
+        sheet.write_string(row, 5, &occupant.email)?;
+        sheet.write_string(row, 6, assigned_at(occupant))?;
+    }
+    workbook.save_to_buffer()
+}
+
+// Occupants without a student id can't be imported, so they're listed apart
+// from `students`, where the import ignores them
+pub fn to_json(dorms: &[ExportDorm]) -> serde_json::Value {
+    let dorms: Vec<serde_json::Value> = dorms
+        .iter()
+        .map(|dorm| {
+            let room_data: serde_json::Map<String, serde_json::Value> = dorm
+                .rooms
+                .iter()
+                .map(|room| {
+                    let (students, without_id): (Vec<&Occupant>, Vec<&Occupant>) =
+                        room.occupants.iter().partition(|occupant| occupant.student_id.is_some());
+                    let student = |occupant: &&Occupant| {
+                        json!({
+                            "name": display_name(occupant),
+                            "id": occupant.student_id,
+                            "email": &occupant.email,
+                            "assigned_at": occupant.assigned_at.and_then(|date| date.try_to_rfc3339_string().ok()),
+                        })
+                    };
+                    let mut entry = json!({
+                        "capacity": room.capacity,
+                        "students": students.iter().map(student).collect::<Vec<_>>(),
+                    });
+                    if !without_id.is_empty() {
+                        entry["students_without_id"] = without_id
+                            .iter()
+                            .map(|occupant| {
+                                let mut occupant = student(occupant);
+                                occupant.as_object_mut().expect("Occupants are objects").remove("id");
+                                occupant
+                            })
+                            .collect();
+                    }
+                    (room.number.clone(), entry)
+                })
+                .collect();
+            json!({
+                "dorm_id": dorm.id.to_hex(),
+                "dorm_name": &dorm.name,
+                "room_data": room_data,
+            })
+        })
+        .collect();
+    serde_json::Value::Array(dorms)
+}
+
+// The dorms matching `filter` by name, without their rooms
+async fn find_dorms(db: &Database, filter: mongodb::bson::Document) -> Result<Vec<ExportDorm>, mongodb::error::Error> {
+    let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+    let mut cursor = db.collection::<Dorm>("dorms").find(filter, options).await?;
+    let mut dorms = Vec::new();
+    while let Some(dorm) = cursor.next().await {
+        let dorm = dorm?;
+        dorms.push(ExportDorm {
+            id: dorm.id.expect("Stored dorms have an ID"),
+            school_id: dorm.school_id,
+            name: dorm.name,
+            rooms: Vec::new(),
+        });
+    }
+    Ok(dorms)
+}
+
+fn export_room(room: Room) -> ExportRoom {
+    ExportRoom {
+        number: room.number,
+        capacity: room.capacity,
+        occupants: room
+            .current_students
+            .into_iter()
+            .map(|student| Occupant {
+                email: student.name,
+                name: student.display_name,
+                student_id: student.student_id,
+                assigned_at: student.assigned_at,
+            })
+            .collect(),
+    }
+}
+
+// Occupants assigned before rooms recorded names and ids get them from their
+// accounts. Emails are only unique within a school, so each room comes with
+// its dorm's school.
+async fn fill_in(db: &Database, rooms: Vec<(Option<ObjectId>, &mut ExportRoom)>) -> Result<(), mongodb::error::Error> {
+    let mut schools = Vec::new();
+    let mut emails = Vec::new();
+    for (school_id, room) in &rooms {
+        for occupant in &room.occupants {
+            if occupant.name.is_none() || occupant.student_id.is_none() {
+                emails.push(&occupant.email);
+                if !schools.contains(school_id) {
+                    schools.push(*school_id);
+                }
+            }
+        }
+    }
+    if emails.is_empty() {
+        return Ok(());
+    }
+    let mut known = HashMap::new();
+    let mut cursor = accounts::collection(db)
+        .find(doc! { "email": { "$in": emails }, "school_id": { "$in": schools } }, None)
+        .await?;
+    while let Some(account) = cursor.next().await {
+        let account = account?;
+        known.insert((account.school_id, account.email), (account.name, account.student_id));
+    }
+
+    for (school_id, room) in rooms {
+        for occupant in &mut room.occupants {
+            if let Some((name, student_id)) = known.get(&(school_id, occupant.email.clone())) {
+                occupant.name = occupant.name.take().or_else(|| name.clone());
+                occupant.student_id = occupant.student_id.or(*student_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn load_dorms(db: &Database, filter: mongodb::bson::Document) -> Result<Vec<ExportDorm>, mongodb::error::Error> {
+    let mut dorms = find_dorms(db, filter).await?;
+    let dorm_ids: Vec<ObjectId> = dorms.iter().map(|dorm| dorm.id).collect();
+    let options = FindOptions::builder().sort(doc! { "number": 1 }).build();
+    let mut cursor = db
+        .collection::<Room>("rooms")
+        .find(doc! { "dorm_id": { "$in": &dorm_ids } }, options)
+        .await?;
+    let mut rooms: HashMap<ObjectId, Vec<ExportRoom>> = HashMap::new();
+    while let Some(room) = cursor.next().await {
+        let room = room?;
+        rooms.entry(room.dorm_id).or_default().push(export_room(room));
+    }
+    for dorm in &mut dorms {
+        dorm.rooms = rooms.remove(&dorm.id).unwrap_or_default();
+    }
+    let rooms = dorms
+        .iter_mut()
+        .flat_map(|dorm| {
+            let school_id = dorm.school_id;
+            dorm.rooms.iter_mut().map(move |room| (school_id, room))
+        })
+        .collect();
+    fill_in(db, rooms).await?;
+    Ok(dorms)
+}
+
+// Writes the rows of each dorm in turn, holding one room at a time
+async fn stream_rows(db: Database, dorms: Vec<ExportDorm>, mut sender: Sender) -> Result<(), mongodb::error::Error> {
+    for dorm in dorms {
+        let options = FindOptions::builder().sort(doc! { "number": 1 }).build();
+        let mut cursor = db
+            .collection::<Room>("rooms")
+            .find(doc! { "dorm_id": dorm.id }, options)
+            .await?;
+        while let Some(room) = cursor.next().await {
+            let mut room = export_room(room?);
+            fill_in(&db, vec![(dorm.school_id, &mut room)]).await?;
+            for occupant in room_rows(&room) {
+                if !sender.send(&line(&dorm.name, &room, occupant)).await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    // One of these
+    school_id: Option<String>,
+    dorm_id: Option<String>,
+    // csv, xlsx, json (the default) or ndjson
+    format: Option<String>,
+}
+
+#[get("/admin/export")]
+async fn export_rosters(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let parse = |id: &str| ObjectId::parse_str(id);
+    let (filter, scope, school_id, label) = match (&query.school_id, &query.dorm_id) {
+        (None, Some(dorm_id)) => {
+            let Ok(dorm_oid) = parse(dorm_id) else {
+                return HttpResponse::BadRequest().json(doc! { "error": "Invalid dorm ID format" });
+            };
+            let dorm = match db.collection::<Dorm>("dorms").find_one(doc! { "_id": dorm_oid }, None).await {
+                Ok(Some(dorm)) => dorm,
+                Ok(None) => return HttpResponse::NotFound().json(doc! {
+                    "error": "Dorm not found"
+                }),
+                Err(e) => {
+                    println!("Error finding dorm: {:?}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            };
+            (doc! { "_id": dorm_oid }, Scope::of_dorm(&dorm), dorm.school_id, dorm_oid.to_hex())
+        }
+        (Some(school_id), None) => {
+            let Ok(school_oid) = parse(school_id) else {
+                return HttpResponse::BadRequest().json(doc! { "error": "Invalid school ID" });
+            };
+            (doc! { "school_id": school_oid }, Scope::School(school_oid), Some(school_oid), school_oid.to_hex())
+        }
+        _ => return HttpResponse::BadRequest().json(doc! {
+            "error": "Give either school_id or dorm_id"
+        }),
+    };
+    if let Err(denied) = authorize(&principal, Permission::ExportRosters, &scope) {
+        return denied;
+    }
+    let format = query.format.as_deref().unwrap_or("json");
+    if !["csv", "xlsx", "json", "ndjson"].contains(&format) {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "Format must be csv, xlsx, json or ndjson"
+        });
+    }
+    let filename = format!("roster-{}.{}", label, format);
+    let attachment = ("Content-Disposition", format!("attachment; filename=\"{}\"", filename));
+
+    if format == "ndjson" {
+        let dorms = match find_dorms(&db, filter).await {
+            Ok(dorms) => dorms,
+            Err(e) => {
+                println!("Error loading rosters: {:?}", e);
+                return HttpResponse::InternalServerError().json(doc! {
+                    "error": "Failed to export rosters"
+                });
+            }
+        };
+        audit::record(
+            &db,
+            AuditEntry::new("rosters_exported")
+                .actor(&principal.email)
+                .school(school_id)
+                .target(&label)
+                .request(&req)
+                .details(doc! { "format": format, "dorms": dorms.len() as i64 }),
+        )
+        .await;
+        let db = db.get_ref().clone();
+        let mut response = streaming::respond(StreamFormat::Ndjson, |sender| stream_rows(db, dorms, sender));
+        if let Ok(value) = HeaderValue::from_str(&attachment.1) {
+            response.headers_mut().insert(CONTENT_DISPOSITION, value);
+        }
+        return response;
+    }
+
+    let dorms = match load_dorms(&db, filter).await {
+        Ok(dorms) => dorms,
+        Err(e) => {
+            println!("Error loading rosters: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to export rosters"
+            });
+        }
+    };
+    let rooms: usize = dorms.iter().map(|dorm| dorm.rooms.len()).sum();
+    audit::record(
+        &db,
+        AuditEntry::new("rosters_exported")
+            .actor(&principal.email)
+            .school(school_id)
+            .target(&label)
+            .request(&req)
+            .details(doc! { "format": format, "dorms": dorms.len() as i64, "rooms": rooms as i64 }),
+    )
+    .await;
+
+    let (content_type, body) = match format {
+        "csv" => ("text/csv", to_csv(&dorms).map_err(|e| e.to_string())),
+        "xlsx" => (
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            to_xlsx(&dorms).map_err(|e| e.to_string()),
+        ),
+        _ => ("application/json", serde_json::to_vec(&to_json(&dorms)).map_err(|e| e.to_string())),
+    };
+    match body {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(attachment)
+            .body(body),
+        Err(e) => {
+            println!("Error writing roster export: {}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to export rosters"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::imports::{RoomEntry, Roster};
+    use crate::spreadsheets::{self, ColumnMapping, Format, TargetDorm};
+
+    fn dorm() -> ExportDorm {
+        ExportDorm {
+            id: ObjectId::new(),
+            school_id: Some(ObjectId::new()),
+            name: "North Hall".to_string(),
+            rooms: vec![
+                ExportRoom {
+                    number: "101".to_string(),
+                    capacity: 2,
+                    occupants: vec![
+                        Occupant {
+                            email: "ada@x.edu".to_string(),
+                            name: Some("Ada Lovelace".to_string()),
+                            student_id: Some(1),
+                            assigned_at: Some(DateTime::from_millis(0)),
+                        },
+                        Occupant {
+                            email: "old@x.edu".to_string(),
+                            name: None,
+                            student_id: None,
+                            assigned_at: None,
+                        },
+                    ],
+                },
+                ExportRoom {
+                    number: "102".to_string(),
+                    capacity: 3,
+                    occupants: vec![],
+                },
+            ],
+        }
+    }
+
+    // What the spreadsheet import makes of an exported file
+    fn reimport(format: Format, bytes: &[u8], dorm: &ExportDorm) -> (Roster, usize) {
+        let rows = spreadsheets::read_cells(format, bytes, None).unwrap();
+        let columns = spreadsheets::resolve_columns(&rows[0], &ColumnMapping::default()).unwrap();
+        let target = TargetDorm {
+            id: dorm.id,
+            name: dorm.name.clone(),
+        };
+        spreadsheets::parse_rows(&rows, &columns, &target)
+    }
+
+    #[test]
+    fn spreadsheets_read_back_as_imports() {
+        let dorms = [dorm()];
+        for (format, bytes) in [
+            (Format::Csv, to_csv(&dorms).unwrap()),
+            (Format::Xlsx, to_xlsx(&dorms).unwrap()),
+        ] {
+            let (roster, skipped) = reimport(format, &bytes, &dorms[0]);
+
+            assert_eq!(skipped, 0);
+            assert_eq!(roster.capacities, BTreeMap::from([("101".to_string(), 2), ("102".to_string(), 3)]));
+            assert_eq!(roster.room_data["101"].len(), 1);
+            assert_eq!(roster.room_data["101"][0].name, "Ada Lovelace");
+            assert_eq!(roster.room_data["101"][0].id, 1);
+            assert_eq!(roster.room_data["101"][0].email.as_deref(), Some("ada@x.edu"));
+            assert!(roster.room_data["102"].is_empty());
+            // The occupant without a student id is reported, not lost silently
+            assert_eq!(roster.row_errors.len(), 1, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn json_is_a_list_of_import_requests() {
+        let dorms = [dorm()];
+        let exported = to_json(&dorms);
+
+        assert_eq!(exported[0]["dorm_id"], dorms[0].id.to_hex());
+        let rooms: BTreeMap<String, RoomEntry> = serde_json::from_value(exported[0]["room_data"].clone()).unwrap();
+        match &rooms["101"] {
+            RoomEntry::Room { capacity, students } => {
+                assert_eq!(*capacity, Some(2));
+                assert_eq!(students.len(), 1);
+                assert_eq!(students[0].id, 1);
+            }
+            other => panic!("Unexpected room {:?}", other),
+        }
+        assert_eq!(exported[0]["room_data"]["101"]["students_without_id"][0]["email"], "old@x.edu");
+        assert_eq!(exported[0]["room_data"]["101"]["students"][0]["assigned_at"], "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn ndjson_lines_match_the_spreadsheet_rows() {
+        let dorm = dorm();
+        let lines: Vec<serde_json::Value> = dorm
+            .rooms
+            .iter()
+            .flat_map(|room| room_rows(room).into_iter().map(|occupant| line(&dorm.name, room, occupant)))
+            .collect();
+
+        assert_eq!(lines.len(), rows(std::slice::from_ref(&dorm)).count());
+        assert_eq!(lines[0]["name"], "Ada Lovelace");
+        assert_eq!(lines[0]["assigned_at"], "1970-01-01T00:00:00Z");
+        // Named by email, like in the spreadsheets
+        assert_eq!(lines[1]["name"], "old@x.edu");
+        assert_eq!(lines[1]["student_id"], serde_json::Value::Null);
+        assert_eq!(lines[2], json!({
+            "dorm": "North Hall",
+            "room": "102",
+            "capacity": 3,
+            "name": null,
+            "student_id": null,
+            "email": null,
+            "assigned_at": null,
+        }));
+    }
+}
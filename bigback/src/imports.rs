@@ -0,0 +1,1786 @@
+// Room imports run as batches. A batch is planned first: the plan lists, row
+// by row, what importing would create, update or leave alone, and which rows
+// can't be imported. Committing a batch applies its plan, and rolling it back
+// removes everything it created and restores what it changed. Rooms and
+// accounts a batch creates are tagged with the batch, and the old state of
+// anything it changes is saved before the change, so a rollback works even
+// if a commit died halfway. A batch whose rooms have changed again since its
+// commit can't be rolled back, since that would undo the later changes.
+//
+// Importing the same data twice is safe: rooms are matched by dorm and
+// number, students by their student id (or, for accounts that predate
+// student ids, their email), and the batch's policy decides what happens to
+// what already exists.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, DateTime, Document},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts::{self, Account};
+use crate::audit::{self, AuditEntry};
+use crate::auth;
+use crate::credentials::{self, CredentialDelivery, CredentialRow};
+use crate::jobs::BackgroundJobs;
+use crate::mail::{self, Mailer};
+use crate::pagination::{Page, PageQuery};
+use crate::passwords;
+use crate::roles::{self, authorize, Permission, Principal, Scope};
+use crate::tokens;
+use crate::{Room, Student};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StudentData {
+    pub name: String,
+    pub id: i32,
+    pub email: Option<String>,
+}
+
+// What to do with rooms and students that already exist
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    // Leave existing students where they are; only add new students
+    #[default]
+    Skip,
+    // Move existing students into their imported rooms, keeping everyone
+    // else already in those rooms
+    Merge,
+    // Make each imported room's roster exactly the imported students
+    Replace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    // Previewed, nothing written yet
+    Planned,
+    Committing,
+    Committed,
+    // A write failed during commit and whatever was written was undone
+    Failed,
+    RollingBack,
+    RolledBack,
+}
+
+// What importing a row does (or, in a plan, would do)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowStatus {
+    Created,
+    Updated,
+    Unchanged,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedStudent {
+    pub name: String,
+    pub student_id: i32,
+    pub email: Option<String>,
+    pub status: RowStatus,
+    // The existing account the row matched
+    pub account_id: Option<ObjectId>,
+    // Why the row failed, or what an update changes
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedRoom {
+    pub number: String,
+    pub status: RowStatus,
+    // The existing room the row matched
+    pub room_id: Option<ObjectId>,
+    pub capacity: i32,
+    // Emails of everyone in the room after the import
+    pub roster: Vec<String>,
+    // Emails taken out of the room by a replace
+    pub removed: Vec<String>,
+    pub students: Vec<PlannedStudent>,
+    // Problems worth a look that don't stop the import, like an
+    // over-capacity roster
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+// The state of a room or account before a batch changed it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RoomSnapshot {
+    room_id: ObjectId,
+    capacity: i32,
+    current_students: Vec<Student>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountSnapshot {
+    account_id: ObjectId,
+    assigned_room: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    student_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportBatch {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub dorm_id: ObjectId,
+    pub school_id: Option<ObjectId>,
+    pub status: BatchStatus,
+    pub created_by: String,
+    pub created_at: DateTime,
+    // What was uploaded, kept so the plan can be checked again at commit
+    pub room_data: BTreeMap<String, Vec<StudentData>>,
+    // Capacities given in the upload, by room number
+    #[serde(default)]
+    pub capacities: BTreeMap<String, i32>,
+    // For new rooms without a capacity of their own
+    #[serde(default)]
+    pub default_capacity: Option<i32>,
+    // Rows of an uploaded file that couldn't be read
+    #[serde(default)]
+    pub row_errors: Vec<RowError>,
+    // The text a room sheet import was read from, for checking it by eye
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_text: Option<String>,
+    // Occupants to take out of their rooms, by email, when a roster diff is
+    // applied
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removals: Vec<String>,
+    pub email_pattern: Option<String>,
+    pub delivery: CredentialDelivery,
+    #[serde(default)]
+    pub policy: MergePolicy,
+    pub plan: Vec<PlannedRoom>,
+    pub committed_at: Option<DateTime>,
+    pub rolled_back_at: Option<DateTime>,
+    pub credential_sheet_id: Option<ObjectId>,
+    #[serde(default)]
+    previous_rooms: Vec<RoomSnapshot>,
+    #[serde(default)]
+    previous_accounts: Vec<AccountSnapshot>,
+}
+
+// A row of an uploaded file that was left out of the import, numbered as the
+// file's owner sees it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowError {
+    pub row: usize,
+    pub message: String,
+}
+
+fn batches(db: &Database) -> mongodb::Collection<ImportBatch> {
+    db.collection::<ImportBatch>("import_batches")
+}
+
+fn internal_error(e: mongodb::error::Error) -> HttpResponse {
+    println!("Import database error: {:?}", e);
+    HttpResponse::InternalServerError().json(doc! {
+        "error": "Internal server error"
+    })
+}
+
+// A room of the dorm with the same number as an imported one
+#[derive(Debug, Clone)]
+pub struct ExistingRoom {
+    pub id: ObjectId,
+    pub capacity: i32,
+    pub occupants: Vec<String>,
+}
+
+// An account of the school an imported student might be
+#[derive(Debug, Clone)]
+pub struct ExistingStudent {
+    pub id: ObjectId,
+    pub email: String,
+    pub name: Option<String>,
+    pub student_id: Option<i32>,
+    pub assigned_room: Option<String>,
+    pub is_staff: bool,
+}
+
+fn failed(student: &StudentData, email: Option<String>, message: String) -> PlannedStudent {
+    PlannedStudent {
+        name: student.name.clone(),
+        student_id: student.id,
+        email,
+        status: RowStatus::Failed,
+        account_id: None,
+        message: Some(message),
+    }
+}
+
+// Whether the import puts the student in their room
+fn places(student: &PlannedStudent, policy: MergePolicy) -> bool {
+    match student.status {
+        RowStatus::Created | RowStatus::Updated => true,
+        RowStatus::Unchanged => policy != MergePolicy::Skip,
+        RowStatus::Failed => false,
+    }
+}
+
+// Works out what importing `room_data` under `policy` would do, given the
+// rooms with the same numbers in the dorm and the school's accounts with the
+// same student ids or emails. New rooms without a capacity in `capacities`
+// get `default_capacity`; existing ones keep theirs. Only when there's
+// neither does a new room fall back to holding its roster, and the report
+// warns about it so a full room isn't created unnoticed.
+pub fn plan_rows(
+    room_data: &BTreeMap<String, Vec<StudentData>>,
+    capacities: &BTreeMap<String, i32>,
+    default_capacity: Option<i32>,
+    email_pattern: Option<&str>,
+    policy: MergePolicy,
+    existing_rooms: &HashMap<String, ExistingRoom>,
+    existing_students: &[ExistingStudent],
+) -> Vec<PlannedRoom> {
+    let mut seen_ids = HashSet::new();
+    let mut seen_emails = HashSet::new();
+    // Existing students this import moves, by email, to their new room
+    let mut moving: HashMap<String, String> = HashMap::new();
+
+    let mut rooms: Vec<(String, Vec<PlannedStudent>)> = Vec::new();
+    for (number, rows) in room_data {
+        let mut students = Vec::new();
+        for row in rows {
+            let email = match (&row.email, email_pattern) {
+                (Some(email), _) => Some(email.trim().to_string()),
+                (None, Some(pattern)) => Some(credentials::render_email(pattern, row.id, &row.name)),
+                (None, None) => None,
+            };
+            if !seen_ids.insert(row.id) {
+                students.push(failed(row, email, format!("Student id {} appears more than once in this import", row.id)));
+                continue;
+            }
+
+            let existing = existing_students
+                .iter()
+                .find(|account| account.student_id == Some(row.id))
+                .or_else(|| {
+                    let email = email.as_ref()?;
+                    existing_students.iter().find(|account| &account.email == email)
+                });
+            let planned = match existing {
+                Some(account) if account.is_staff => failed(
+                    row,
+                    email,
+                    format!("{} belongs to a staff account", account.email),
+                ),
+                Some(account) if account.student_id.is_some_and(|id| id != row.id) => failed(
+                    row,
+                    email,
+                    format!("{} belongs to student {}", account.email, account.student_id.unwrap_or_default()),
+                ),
+                Some(account) if !seen_emails.insert(account.email.clone()) => failed(
+                    row,
+                    Some(account.email.clone()),
+                    format!("Email {} appears more than once in this import", account.email),
+                ),
+                Some(account) => {
+                    let in_place = account.assigned_room.as_deref() == Some(number.as_str())
+                        && account.student_id == Some(row.id)
+                        && account.name.as_deref() == Some(row.name.as_str());
+                    let (status, message) = match policy {
+                        MergePolicy::Skip => (RowStatus::Unchanged, None),
+                        _ if in_place => (RowStatus::Unchanged, None),
+                        _ => {
+                            moving.insert(account.email.clone(), number.clone());
+                            let message = match &account.assigned_room {
+                                Some(room) if room != number => format!("Moves from room {}", room),
+                                Some(_) => "Records the student's name and id".to_string(),
+                                None => "Assigns the student a room".to_string(),
+                            };
+                            (RowStatus::Updated, Some(message))
+                        }
+                    };
+                    PlannedStudent {
+                        name: row.name.clone(),
+                        student_id: row.id,
+                        email: Some(account.email.clone()),
+                        status,
+                        account_id: Some(account.id),
+                        message,
+                    }
+                }
+                None => match email {
+                    None => failed(row, None, "No email and no email pattern".to_string()),
+                    Some(email) if !seen_emails.insert(email.clone()) => {
+                        let message = format!("Email {} appears more than once in this import", email);
+                        failed(row, Some(email), message)
+                    }
+                    Some(email) => PlannedStudent {
+                        name: row.name.clone(),
+                        student_id: row.id,
+                        email: Some(email),
+                        status: RowStatus::Created,
+                        account_id: None,
+                        message: None,
+                    },
+                },
+            };
+            students.push(planned);
+        }
+        rooms.push((number.clone(), students));
+    }
+
+    rooms
+        .into_iter()
+        .map(|(number, students)| {
+            let placed: Vec<String> = students
+                .iter()
+                .filter(|student| places(student, policy))
+                .filter_map(|student| student.email.clone())
+                .collect();
+            let given = capacities.get(&number).copied().or(default_capacity);
+            let mut warnings = Vec::new();
+
+            let Some(existing) = existing_rooms.get(&number) else {
+                let capacity = match given {
+                    Some(capacity) => capacity,
+                    None => {
+                        warnings.push("No capacity given; the room holds only the students listed".to_string());
+                        room_data[&number].len() as i32
+                    }
+                };
+                if placed.len() as i32 > capacity {
+                    warnings.push(over_capacity(placed.len(), capacity));
+                }
+                return PlannedRoom {
+                    number,
+                    status: RowStatus::Created,
+                    room_id: None,
+                    capacity,
+                    roster: placed,
+                    removed: Vec::new(),
+                    students,
+                    warnings,
+                };
+            };
+
+            // A dorm-wide default is for new rooms only
+            let capacity = capacities.get(&number).copied().unwrap_or(existing.capacity);
+            let (roster, removed) = match policy {
+                MergePolicy::Replace => {
+                    let removed = existing
+                        .occupants
+                        .iter()
+                        .filter(|email| !placed.contains(email))
+                        .cloned()
+                        .collect();
+                    (placed.clone(), removed)
+                }
+                MergePolicy::Skip | MergePolicy::Merge => {
+                    // Keep current occupants, except those moving elsewhere
+                    let mut roster: Vec<String> = existing
+                        .occupants
+                        .iter()
+                        .filter(|email| moving.get(*email).is_none_or(|to| *to == number))
+                        .cloned()
+                        .collect();
+                    for email in &placed {
+                        if !roster.contains(email) {
+                            roster.push(email.clone());
+                        }
+                    }
+                    (roster, Vec::new())
+                }
+            };
+            if roster.len() as i32 > capacity {
+                warnings.push(over_capacity(roster.len(), capacity));
+            }
+
+            let same_roster = roster.len() == existing.occupants.len()
+                && roster.iter().all(|email| existing.occupants.contains(email));
+            let students_updated = students.iter().any(|student| student.status == RowStatus::Updated);
+            let status = if same_roster && capacity == existing.capacity && !students_updated {
+                RowStatus::Unchanged
+            } else {
+                RowStatus::Updated
+            };
+            PlannedRoom {
+                number,
+                status,
+                room_id: Some(existing.id),
+                capacity,
+                roster,
+                removed,
+                students,
+                warnings,
+            }
+        })
+        .collect()
+}
+
+fn over_capacity(students: usize, capacity: i32) -> String {
+    format!("{} students but a capacity of {}", students, capacity)
+}
+
+// Takes `removals` out of the planned rosters of existing rooms
+pub fn take_out(plan: &mut [PlannedRoom], removals: &[String]) {
+    for room in plan.iter_mut().filter(|room| room.room_id.is_some()) {
+        if !room.roster.iter().any(|email| removals.contains(email)) {
+            continue;
+        }
+        let before = room.roster.len();
+        let (removed, kept): (Vec<String>, Vec<String>) =
+            room.roster.drain(..).partition(|email| removals.contains(email));
+        room.roster = kept;
+        room.removed.extend(removed);
+        room.status = RowStatus::Updated;
+        let was_over = over_capacity(before, room.capacity);
+        room.warnings.retain(|warning| *warning != was_over);
+        if room.roster.len() as i32 > room.capacity {
+            room.warnings.push(over_capacity(room.roster.len(), room.capacity));
+        }
+    }
+}
+
+async fn plan(db: &Database, batch: &ImportBatch) -> Result<Vec<PlannedRoom>, mongodb::error::Error> {
+    let room_data = &batch.room_data;
+    let email_pattern = batch.email_pattern.as_deref();
+    let numbers: Vec<&String> = room_data.keys().collect();
+    let mut existing_rooms = HashMap::new();
+    let mut cursor = db
+        .collection::<Room>("rooms")
+        .find(doc! { "dorm_id": batch.dorm_id, "number": { "$in": numbers } }, None)
+        .await?;
+    while let Some(room) = cursor.next().await {
+        let room = room?;
+        existing_rooms.insert(
+            room.number.clone(),
+            ExistingRoom {
+                id: room.id.expect("Stored rooms have an ID"),
+                capacity: room.capacity,
+                occupants: room.current_students.into_iter().map(|student| student.name).collect(),
+            },
+        );
+    }
+
+    // Accounts the rows could match
+    let ids: Vec<i32> = room_data.values().flatten().map(|student| student.id).collect();
+    let emails: Vec<String> = plan_rows(room_data, &batch.capacities, batch.default_capacity, email_pattern, batch.policy, &HashMap::new(), &[])
+        .into_iter()
+        .flat_map(|room| room.students)
+        .filter_map(|student| student.email)
+        .collect();
+    let mut existing_students = Vec::new();
+    let mut cursor = accounts::collection(db)
+        .find(
+            doc! {
+                "school_id": batch.school_id,
+                "$or": [{ "student_id": { "$in": ids } }, { "email": { "$in": emails } }],
+            },
+            None,
+        )
+        .await?;
+    while let Some(account) = cursor.next().await {
+        let account = account?;
+        existing_students.push(ExistingStudent {
+            id: account.id.expect("Stored accounts have an ID"),
+            is_staff: account.is_staff(),
+            email: account.email,
+            name: account.name,
+            student_id: account.student_id,
+            assigned_room: account.assigned_room,
+        });
+    }
+
+    let mut planned = plan_rows(
+        room_data,
+        &batch.capacities,
+        batch.default_capacity,
+        email_pattern,
+        batch.policy,
+        &existing_rooms,
+        &existing_students,
+    );
+    take_out(&mut planned, &batch.removals);
+    Ok(planned)
+}
+
+fn failures(plan: &[PlannedRoom]) -> Vec<String> {
+    plan.iter()
+        .flat_map(|room| &room.students)
+        .filter(|student| student.status == RowStatus::Failed)
+        .map(|student| {
+            format!(
+                "Student {} ({}): {}",
+                student.student_id,
+                student.name,
+                student.message.as_deref().unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+// One line per room and per student, with counts of each outcome
+fn report(plan: &[PlannedRoom]) -> (Vec<Document>, Document) {
+    let mut rows = Vec::new();
+    let mut counts: BTreeMap<&str, i64> = BTreeMap::new();
+    let status_name = |status: RowStatus| match status {
+        RowStatus::Created => "created",
+        RowStatus::Updated => "updated",
+        RowStatus::Unchanged => "unchanged",
+        RowStatus::Failed => "failed",
+    };
+
+    for room in plan {
+        *counts.entry(status_name(room.status)).or_default() += 1;
+        let mut row = doc! {
+            "kind": "room",
+            "room": &room.number,
+            "status": status_name(room.status),
+        };
+        if !room.removed.is_empty() {
+            row.insert("removed", &room.removed);
+        }
+        if !room.warnings.is_empty() {
+            *counts.entry("warnings").or_default() += 1;
+            row.insert("warnings", &room.warnings);
+        }
+        rows.push(row);
+
+        for student in &room.students {
+            *counts.entry(status_name(student.status)).or_default() += 1;
+            rows.push(doc! {
+                "kind": "student",
+                "room": &room.number,
+                "student_id": student.student_id,
+                "name": &student.name,
+                "email": &student.email,
+                "status": status_name(student.status),
+                "message": &student.message,
+            });
+        }
+    }
+
+    let mut summary = doc! {
+        "created": 0_i64,
+        "updated": 0_i64,
+        "unchanged": 0_i64,
+        "failed": 0_i64,
+        // Rooms with warnings
+        "warnings": 0_i64,
+    };
+    for (status, count) in counts {
+        summary.insert(status, count);
+    }
+    (rows, summary)
+}
+
+fn count(plan: &[PlannedRoom], status: RowStatus) -> (i64, i64) {
+    let rooms = plan.iter().filter(|room| room.status == status).count();
+    let students = plan
+        .iter()
+        .flat_map(|room| &room.students)
+        .filter(|student| student.status == status)
+        .count();
+    (rooms as i64, students as i64)
+}
+
+fn batch_view(batch: &ImportBatch) -> Document {
+    let (rows, summary) = report(&batch.plan);
+    doc! {
+        "batch_id": batch.id,
+        "name": &batch.name,
+        "dorm_id": batch.dorm_id,
+        "status": to_bson(&batch.status).expect("Failed to serialize status"),
+        "policy": to_bson(&batch.policy).expect("Failed to serialize policy"),
+        "created_by": &batch.created_by,
+        "created_at": batch.created_at,
+        "committed_at": batch.committed_at,
+        "rolled_back_at": batch.rolled_back_at,
+        "summary": summary,
+        "report": rows,
+        "failures": failures(&batch.plan),
+        "row_errors": to_bson(&batch.row_errors).expect("Failed to serialize row errors"),
+        "source_text": &batch.source_text,
+    }
+}
+
+// What the batch left a room it changed as: the planned capacity and roster
+// for rooms in the plan, or the old roster minus the students it moved out
+fn after_commit(plan: &[PlannedRoom], snapshot: &RoomSnapshot) -> (i32, Vec<String>) {
+    let (capacity, mut occupants) = match plan.iter().find(|room| room.room_id == Some(snapshot.room_id)) {
+        Some(room) => (room.capacity, room.roster.clone()),
+        None => {
+            let moved: HashSet<&str> = plan
+                .iter()
+                .flat_map(|room| &room.students)
+                .filter(|student| student.status == RowStatus::Updated)
+                .filter_map(|student| student.email.as_deref())
+                .collect();
+            let occupants = snapshot
+                .current_students
+                .iter()
+                .map(|student| student.name.clone())
+                .filter(|email| !moved.contains(email.as_str()))
+                .collect();
+            (snapshot.capacity, occupants)
+        }
+    };
+    occupants.sort();
+    (capacity, occupants)
+}
+
+// Numbers of the rooms the batch changed that have changed again since it
+// was committed. Rolling back would overwrite those changes.
+async fn changed_since_commit(db: &Database, batch: &ImportBatch) -> Result<Vec<String>, mongodb::error::Error> {
+    let rooms_collection = db.collection::<Room>("rooms");
+    let mut changed = Vec::new();
+    for snapshot in &batch.previous_rooms {
+        let Some(room) = rooms_collection.find_one(doc! { "_id": snapshot.room_id }, None).await? else {
+            continue;
+        };
+        let mut occupants: Vec<String> = room.current_students.into_iter().map(|student| student.name).collect();
+        occupants.sort();
+        if (room.capacity, occupants) != after_commit(&batch.plan, snapshot) {
+            changed.push(room.number);
+        }
+    }
+    Ok(changed)
+}
+
+// Undoes a batch: restores what it changed, then removes its accounts (with
+// their sessions and invites) and rooms, including references to either from
+// rooms and accounts the batch didn't create
+async fn undo(db: &Database, batch_id: ObjectId) -> Result<Document, mongodb::error::Error> {
+    let Some(batch) = batches(db).find_one(doc! { "_id": batch_id }, None).await? else {
+        return Ok(doc! {});
+    };
+    let rooms_collection = db.collection::<Room>("rooms");
+    let accounts_collection = accounts::collection(db);
+
+    for snapshot in &batch.previous_rooms {
+        let students = to_bson(&snapshot.current_students).expect("Failed to serialize students");
+        rooms_collection
+            .update_one(
+                doc! { "_id": snapshot.room_id },
+                doc! { "$set": { "capacity": snapshot.capacity, "current_students": students } },
+                None,
+            )
+            .await?;
+    }
+    for snapshot in &batch.previous_accounts {
+        accounts_collection
+            .update_one(
+                doc! { "_id": snapshot.account_id },
+                doc! { "$set": {
+                    "assigned_room": &snapshot.assigned_room,
+                    "name": &snapshot.name,
+                    "student_id": snapshot.student_id,
+                } },
+                None,
+            )
+            .await?;
+    }
+
+    let mut account_ids = Vec::new();
+    let mut emails = Vec::new();
+    let mut cursor = accounts_collection.find(doc! { "import_batch": batch_id }, None).await?;
+    while let Some(account) = cursor.next().await {
+        let account = account?;
+        account_ids.push(account.id.expect("Stored accounts have an ID"));
+        emails.push(account.email);
+    }
+
+    let mut room_numbers = Vec::new();
+    let mut occupants = Vec::new();
+    let mut cursor = rooms_collection.find(doc! { "import_batch": batch_id }, None).await?;
+    while let Some(room) = cursor.next().await {
+        let room = room?;
+        occupants.extend(room.current_students.into_iter().map(|student| student.name));
+        room_numbers.push(room.number);
+    }
+
+    // Batch students may be in rooms the batch didn't create
+    rooms_collection
+        .update_many(
+            doc! { "dorm_id": batch.dorm_id, "current_students.name": { "$in": &emails } },
+            doc! { "$pull": { "current_students": { "name": { "$in": &emails } } } },
+            None,
+        )
+        .await?;
+    // ...and other students in the batch's rooms
+    accounts_collection
+        .update_many(
+            doc! {
+                "school_id": batch.school_id,
+                "email": { "$in": occupants },
+                "assigned_room": { "$in": &room_numbers },
+                "import_batch": { "$ne": batch_id },
+            },
+            doc! { "$set": { "assigned_room": null } },
+            None,
+        )
+        .await?;
+
+    for account_id in &account_ids {
+        auth::revoke_all_sessions(db, *account_id, None).await?;
+    }
+    db.collection::<Document>("one_time_tokens")
+        .delete_many(doc! { "account_id": { "$in": &account_ids } }, None)
+        .await?;
+    let accounts_deleted = accounts_collection
+        .delete_many(doc! { "import_batch": batch_id }, None)
+        .await?
+        .deleted_count;
+    let rooms_deleted = rooms_collection
+        .delete_many(doc! { "import_batch": batch_id }, None)
+        .await?
+        .deleted_count;
+    if let Some(sheet_id) = batch.credential_sheet_id {
+        db.collection::<Document>("credential_sheets")
+            .delete_one(doc! { "_id": sheet_id }, None)
+            .await?;
+    }
+
+    Ok(doc! {
+        "rooms_deleted": rooms_deleted as i64,
+        "students_deleted": accounts_deleted as i64,
+        "rooms_restored": batch.previous_rooms.len() as i64,
+        "students_restored": batch.previous_accounts.len() as i64,
+    })
+}
+
+// A student account a commit created, with what's needed to deliver its
+// credentials
+struct CreatedStudent {
+    account_id: ObjectId,
+    room: String,
+    name: String,
+    student_id: i32,
+    email: String,
+    password: String,
+}
+
+// Saves the old state of rooms and accounts before a commit changes them,
+// once per room or account
+struct Snapshots<'a> {
+    db: &'a Database,
+    batch_id: ObjectId,
+    rooms: HashSet<ObjectId>,
+    accounts: HashSet<ObjectId>,
+}
+
+impl Snapshots<'_> {
+    async fn room(&mut self, room: &Room) -> Result<(), mongodb::error::Error> {
+        let room_id = room.id.expect("Stored rooms have an ID");
+        if !self.rooms.insert(room_id) {
+            return Ok(());
+        }
+        let snapshot = RoomSnapshot {
+            room_id,
+            capacity: room.capacity,
+            current_students: room.current_students.clone(),
+        };
+        let snapshot = to_bson(&snapshot).expect("Failed to serialize snapshot");
+        batches(self.db)
+            .update_one(
+                doc! { "_id": self.batch_id },
+                doc! { "$push": { "previous_rooms": snapshot } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn account(&mut self, account: &Account) -> Result<(), mongodb::error::Error> {
+        let account_id = account.id.expect("Stored accounts have an ID");
+        if !self.accounts.insert(account_id) {
+            return Ok(());
+        }
+        let snapshot = AccountSnapshot {
+            account_id,
+            assigned_room: account.assigned_room.clone(),
+            name: account.name.clone(),
+            student_id: account.student_id,
+        };
+        let snapshot = to_bson(&snapshot).expect("Failed to serialize snapshot");
+        batches(self.db)
+            .update_one(
+                doc! { "_id": self.batch_id },
+                doc! { "$push": { "previous_accounts": snapshot } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+// Writes the batch's plan, returning the accounts it created. Stops at the
+// first failed write.
+async fn write_plan(db: &Database, batch: &ImportBatch) -> Result<Vec<CreatedStudent>, mongodb::error::Error> {
+    let batch_id = batch.id.expect("Stored batches have an ID");
+    let rooms_collection = db.collection::<Room>("rooms");
+    let accounts_collection = accounts::collection(db);
+    let mut snapshots = Snapshots {
+        db,
+        batch_id,
+        rooms: HashSet::new(),
+        accounts: HashSet::new(),
+    };
+    let planned_rooms: Vec<ObjectId> = batch.plan.iter().filter_map(|room| room.room_id).collect();
+    let school_rooms = Room::in_school(db, batch.school_id).await?;
+    let mut created = Vec::new();
+
+    for room in &batch.plan {
+        for student in &room.students {
+            match student.status {
+                RowStatus::Created => {
+                    let email = student.email.clone().expect("Created students have an email");
+                    // Imported students can't log in until they change or
+                    // set a password, so an invited student's password is a
+                    // hash nobody knows
+                    let password = passwords::generate_password(credentials::GENERATED_PASSWORD_LENGTH);
+                    let account = Account {
+                        assigned_room: Some(room.number.clone()),
+                        must_change_password: true,
+                        name: Some(student.name.clone()),
+                        student_id: Some(student.student_id),
+                        import_batch: Some(batch_id),
+                        ..Account::student(email.clone(), passwords::hash_password(&password), batch.school_id)
+                    };
+                    let account_id = accounts_collection
+                        .insert_one(account, None)
+                        .await?
+                        .inserted_id
+                        .as_object_id()
+                        .expect("MongoDB should have generated an ObjectId");
+                    created.push(CreatedStudent {
+                        account_id,
+                        room: room.number.clone(),
+                        name: student.name.clone(),
+                        student_id: student.student_id,
+                        email,
+                        password,
+                    });
+                }
+                RowStatus::Updated => {
+                    let account_id = student.account_id.expect("Updated students matched an account");
+                    let Some(account) = accounts_collection.find_one(doc! { "_id": account_id }, None).await? else {
+                        continue;
+                    };
+                    snapshots.account(&account).await?;
+                    accounts_collection
+                        .update_one(
+                            doc! { "_id": account_id },
+                            doc! { "$set": {
+                                "assigned_room": &room.number,
+                                "name": &student.name,
+                                "student_id": student.student_id,
+                            } },
+                            None,
+                        )
+                        .await?;
+
+                    // Take them out of the school's rooms outside this
+                    // import; rooms in it get their whole roster set below
+                    let mut filter = school_rooms.clone();
+                    filter.insert("current_students.name", &account.email);
+                    filter.insert("_id", doc! { "$nin": &planned_rooms });
+                    let mut cursor = rooms_collection.find(filter, None).await?;
+                    while let Some(old_room) = cursor.next().await {
+                        let old_room = old_room?;
+                        snapshots.room(&old_room).await?;
+                        rooms_collection
+                            .update_one(
+                                doc! { "_id": old_room.id },
+                                doc! { "$pull": { "current_students": { "name": &account.email } } },
+                                None,
+                            )
+                            .await?;
+                    }
+                }
+                RowStatus::Unchanged | RowStatus::Failed => {}
+            }
+        }
+
+        // Students a replace takes out of the room no longer live there
+        let mut cursor = accounts_collection
+            .find(
+                doc! {
+                    "school_id": batch.school_id,
+                    "email": { "$in": &room.removed },
+                    "assigned_room": &room.number,
+                },
+                None,
+            )
+            .await?;
+        while let Some(account) = cursor.next().await {
+            let account = account?;
+            snapshots.account(&account).await?;
+            accounts_collection
+                .update_one(
+                    doc! { "_id": account.id },
+                    doc! { "$set": { "assigned_room": null } },
+                    None,
+                )
+                .await?;
+        }
+
+        // Imported students carry their real names and ids
+        let imported: HashMap<&str, &PlannedStudent> = room
+            .students
+            .iter()
+            .filter(|student| places(student, batch.policy))
+            .filter_map(|student| Some((student.email.as_deref()?, student)))
+            .collect();
+        let now = DateTime::now();
+        let roster = |current: &[Student]| -> Vec<Student> {
+            room.roster
+                .iter()
+                .map(|email| {
+                    let occupant = current.iter().find(|occupant| &occupant.name == email);
+                    match imported.get(email.as_str()) {
+                        Some(student) => Student {
+                            id: None,
+                            name: email.clone(),
+                            display_name: Some(student.name.clone()),
+                            student_id: Some(student.student_id),
+                            // Students already in the room keep their date
+                            assigned_at: occupant.and_then(|occupant| occupant.assigned_at).or(Some(now)),
+                        },
+                        None => occupant.cloned().unwrap_or(Student {
+                            id: None,
+                            name: email.clone(),
+                            display_name: None,
+                            student_id: None,
+                            assigned_at: Some(now),
+                        }),
+                    }
+                })
+                .collect()
+        };
+        match (room.status, room.room_id) {
+            (RowStatus::Created, _) => {
+                rooms_collection
+                    .insert_one(
+                        Room {
+                            id: None,
+                            dorm_id: batch.dorm_id,
+                            number: room.number.clone(),
+                            capacity: room.capacity,
+                            current_students: roster(&[]),
+                            import_batch: Some(batch_id),
+                            floor: Room::floor_of(&room.number),
+                            attributes: Vec::new(),
+                        },
+                        None,
+                    )
+                    .await?;
+            }
+            (RowStatus::Updated, Some(room_id)) => {
+                let Some(existing) = rooms_collection.find_one(doc! { "_id": room_id }, None).await? else {
+                    continue;
+                };
+                snapshots.room(&existing).await?;
+                let students = to_bson(&roster(&existing.current_students)).expect("Failed to serialize students");
+                rooms_collection
+                    .update_one(
+                        doc! { "_id": room_id },
+                        doc! { "$set": { "capacity": room.capacity, "current_students": students } },
+                        None,
+                    )
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(created)
+}
+
+// Applies a planned batch, first checking the plan still holds. Responds with
+// the per-row report and how credentials were delivered.
+async fn commit(
+    req: &HttpRequest,
+    db: &Database,
+    jobs: &BackgroundJobs,
+    mailer: Arc<dyn Mailer>,
+    principal: &Principal,
+    batch_id: ObjectId,
+) -> HttpResponse {
+    // Claiming the batch makes a double commit impossible
+    let batch = match batches(db)
+        .find_one_and_update(
+            doc! { "_id": batch_id, "status": "planned" },
+            doc! { "$set": { "status": "committing" } },
+            None,
+        )
+        .await
+    {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return HttpResponse::Conflict().json(doc! {
+            "error": "Only planned batches can be committed"
+        }),
+        Err(e) => return internal_error(e),
+    };
+    let set_status = |status: BatchStatus, extra: Document| async move {
+        let mut set = doc! { "status": to_bson(&status).expect("Failed to serialize status") };
+        set.extend(extra);
+        if let Err(e) = batches(db).update_one(doc! { "_id": batch_id }, doc! { "$set": set }, None).await {
+            println!("Failed to update import batch {}: {:?}", batch_id, e);
+        }
+    };
+
+    // The dorm may have changed since the preview
+    let current = match plan(db, &batch).await {
+        Ok(current) => current,
+        Err(e) => {
+            set_status(BatchStatus::Planned, doc! {}).await;
+            return internal_error(e);
+        }
+    };
+    if current != batch.plan {
+        set_status(BatchStatus::Planned, doc! {}).await;
+        let mut changed = batch;
+        changed.plan = current;
+        let mut response = batch_view(&changed);
+        response.insert("error", "The dorm changed since this batch was planned; plan it again");
+        return HttpResponse::Conflict().json(response);
+    }
+
+    let created = match write_plan(db, &batch).await {
+        Ok(created) => created,
+        Err(e) => {
+            println!("Import batch {} failed, undoing it: {:?}", batch_id, e);
+            if let Err(e) = undo(db, batch_id).await {
+                println!("Failed to undo import batch {}: {:?}", batch_id, e);
+            }
+            set_status(BatchStatus::Failed, doc! {}).await;
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Import failed; nothing was imported",
+                "batch_id": batch_id,
+            });
+        }
+    };
+
+    let (rooms_created, students_created) = count(&batch.plan, RowStatus::Created);
+    let (rooms_updated, students_updated) = count(&batch.plan, RowStatus::Updated);
+    let (rows, summary) = report(&batch.plan);
+    let mut failures = failures(&batch.plan);
+    let mut response = doc! {
+        "message": "Import completed successfully",
+        "batch_id": batch_id,
+        "rooms_created": rooms_created,
+        "rooms_updated": rooms_updated,
+        "students_created": students_created,
+        "students_updated": students_updated,
+        "summary": summary,
+        "report": rows,
+        "row_errors": to_bson(&batch.row_errors).expect("Failed to serialize row errors"),
+    };
+
+    let mut committed = doc! { "committed_at": DateTime::now() };
+    match batch.delivery {
+        CredentialDelivery::Sheet if !created.is_empty() => {
+            let rows = created
+                .iter()
+                .map(|student| CredentialRow {
+                    room: student.room.clone(),
+                    name: student.name.clone(),
+                    student_id: student.student_id,
+                    email: student.email.clone(),
+                    password: student.password.clone(),
+                })
+                .collect();
+            match credentials::save_sheet(db, batch.dorm_id, principal.id(), rows).await {
+                Ok(sheet_id) => {
+                    committed.insert("credential_sheet_id", sheet_id);
+                    response.insert("credential_sheet_id", sheet_id);
+                }
+                Err(e) => {
+                    // The accounts exist but nobody knows their passwords;
+                    // they can still be recovered through a password reset
+                    println!("Failed to save credential sheet: {:?}", e);
+                    response.insert("error", "Failed to save credential sheet");
+                }
+            }
+        }
+        CredentialDelivery::Invite => {
+            let mut invites_queued = 0;
+            for student in &created {
+                match tokens::issue(db, "invite", student.account_id, credentials::INVITE_TTL).await {
+                    Ok(token) => {
+                        mail::queue(jobs, mailer.clone(), credentials::invite_message(&student.email, &token));
+                        invites_queued += 1;
+                    }
+                    Err(e) => {
+                        println!("Failed to create invite for {}: {:?}", student.email, e);
+                        failures.push(format!(
+                            "Student {} ({}): failed to create invite",
+                            student.student_id, student.name
+                        ));
+                    }
+                }
+            }
+            response.insert("invites_queued", invites_queued);
+        }
+        _ => {}
+    }
+    response.insert("failures", failures);
+    set_status(BatchStatus::Committed, committed).await;
+
+    let rosters: Document = batch
+        .plan
+        .iter()
+        .filter(|room| matches!(room.status, RowStatus::Created | RowStatus::Updated))
+        .map(|room| (room.number.clone(), to_bson(&room.roster).expect("Failed to serialize roster")))
+        .collect();
+    audit::record(
+        db,
+        AuditEntry::new("rooms_imported")
+            .actor(&principal.email)
+            .school(batch.school_id)
+            .target(&batch.dorm_id.to_hex())
+            .request(req)
+            .details(doc! {
+                "batch_id": batch_id,
+                "batch_name": &batch.name,
+                "policy": to_bson(&batch.policy).expect("Failed to serialize policy"),
+                "rooms_created": rooms_created,
+                "rooms_updated": rooms_updated,
+                "students_created": students_created,
+                "students_updated": students_updated,
+            })
+            .diff(None, Some(&doc! { "rooms": rosters })),
+    )
+    .await;
+
+    HttpResponse::Ok().json(response)
+}
+
+// How a roster is imported, whatever format it arrived in
+#[derive(Debug, Deserialize)]
+pub struct ImportOptions {
+    // Used for students without an email, e.g. "s{id}@students.example.edu"
+    pub email_pattern: Option<String>,
+    #[serde(default)]
+    pub delivery: CredentialDelivery,
+    // Defaults to a timestamped name
+    pub batch_name: Option<String>,
+    // Only plan the batch; commit it later with /admin/import-batches/{id}/commit
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub policy: MergePolicy,
+    // For new rooms without a capacity of their own; defaults to the dorm's
+    pub default_capacity: Option<i32>,
+}
+
+// A room in a JSON import: either just its students, or
+// { "capacity": 4, "students": [...] }
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RoomEntry {
+    Students(Vec<StudentData>),
+    Room {
+        capacity: Option<i32>,
+        #[serde(default)]
+        students: Vec<StudentData>,
+    },
+}
+
+// Reads JSON `room_data` into each room's students and the capacities given
+pub fn read_room_data(dorm_id: ObjectId, value: &serde_json::Value) -> Result<Roster, String> {
+    let rooms: BTreeMap<String, RoomEntry> =
+        serde_json::from_value(value.clone()).map_err(|e| format!("Invalid JSON format: {}", e))?;
+    let mut room_data = BTreeMap::new();
+    let mut capacities = BTreeMap::new();
+    for (number, room) in rooms {
+        let students = match room {
+            RoomEntry::Students(students) => students,
+            RoomEntry::Room { capacity, students } => {
+                if let Some(capacity) = capacity {
+                    capacities.insert(number.clone(), capacity);
+                }
+                students
+            }
+        };
+        room_data.insert(number, students);
+    }
+    Ok(Roster {
+        dorm_id,
+        room_data,
+        capacities,
+        row_errors: Vec::new(),
+        source_text: None,
+        removals: Vec::new(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomImportRequest {
+    dorm_id: String,
+    room_data: serde_json::Value,  // Raw JSON data
+    #[serde(flatten)]
+    options: ImportOptions,
+}
+
+// What an upload parsed into, and the dorm it's for, ready to be planned
+#[derive(Debug)]
+pub struct Roster {
+    pub dorm_id: ObjectId,
+    pub room_data: BTreeMap<String, Vec<StudentData>>,
+    pub capacities: BTreeMap<String, i32>,
+    pub row_errors: Vec<RowError>,
+    pub source_text: Option<String>,
+    pub removals: Vec<String>,
+}
+
+// Plans an import batch for a dorm and, unless it's a dry run, commits it
+// straight away. Every import format ends up here.
+pub async fn start(
+    req: &HttpRequest,
+    db: &Database,
+    jobs: &BackgroundJobs,
+    mailer: Arc<dyn Mailer>,
+    principal: &Principal,
+    roster: Roster,
+    options: &ImportOptions,
+) -> HttpResponse {
+    let dorm_id = roster.dorm_id;
+    // Imported students belong to the dorm's school
+    let school_id = match roles::dorm_scope(db, dorm_id).await {
+        Ok(Some(scope)) => {
+            if let Err(denied) = authorize(principal, Permission::ImportRooms, &scope) {
+                return denied;
+            }
+            match scope {
+                Scope::Dorm { school_id, .. } => school_id,
+                _ => None,
+            }
+        }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Dorm not found"
+        }),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    if let Some(pattern) = &options.email_pattern {
+        if let Err(error) = credentials::check_email_pattern(pattern) {
+            return HttpResponse::BadRequest().json(doc! { "error": error });
+        }
+    }
+    let invalid = roster
+        .capacities
+        .iter()
+        .map(|(number, capacity)| (format!("Room {}", number), *capacity))
+        .chain(options.default_capacity.map(|capacity| ("The default".to_string(), capacity)))
+        .find(|(_, capacity)| *capacity < 0);
+    if let Some((what, capacity)) = invalid {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": format!("{} has an invalid capacity of {}", what, capacity)
+        });
+    }
+
+    let default_capacity = match options.default_capacity {
+        Some(capacity) => Some(capacity),
+        None => match db.collection::<Document>("dorms").find_one(doc! { "_id": dorm_id }, None).await {
+            Ok(dorm) => dorm.and_then(|dorm| dorm.get_i32("default_capacity").ok()),
+            Err(e) => return internal_error(e),
+        },
+    };
+
+    let now = DateTime::now();
+    let mut batch = ImportBatch {
+        id: None,
+        name: options.batch_name.clone().unwrap_or_else(|| {
+            format!("Import {}", now.try_to_rfc3339_string().unwrap_or_default())
+        }),
+        dorm_id,
+        school_id,
+        status: BatchStatus::Planned,
+        created_by: principal.email.clone(),
+        created_at: now,
+        room_data: roster.room_data,
+        capacities: roster.capacities,
+        default_capacity,
+        row_errors: roster.row_errors,
+        source_text: roster.source_text,
+        removals: roster.removals,
+        email_pattern: options.email_pattern.clone(),
+        delivery: options.delivery,
+        policy: options.policy,
+        plan: Vec::new(),
+        committed_at: None,
+        rolled_back_at: None,
+        credential_sheet_id: None,
+        previous_rooms: Vec::new(),
+        previous_accounts: Vec::new(),
+    };
+    batch.plan = match plan(db, &batch).await {
+        Ok(planned) => planned,
+        Err(e) => return internal_error(e),
+    };
+    match batches(db).insert_one(&batch, None).await {
+        Ok(result) => batch.id = result.inserted_id.as_object_id(),
+        Err(e) => return internal_error(e),
+    }
+    let batch_id = batch.id.expect("Inserted batches have an ID");
+
+    if options.dry_run {
+        audit::record(
+            db,
+            AuditEntry::new("import_batch_planned")
+                .actor(&principal.email)
+                .school(school_id)
+                .target(&batch_id.to_hex())
+                .request(req)
+                .details(doc! {
+                    "dorm_id": dorm_id,
+                    "name": &batch.name,
+                    "policy": to_bson(&batch.policy).expect("Failed to serialize policy"),
+                }),
+        )
+        .await;
+        return HttpResponse::Ok().json(batch_view(&batch));
+    }
+    commit(req, db, jobs, mailer, principal, batch_id).await
+}
+
+#[post("/admin/import-rooms")]
+async fn import_rooms(
+    http_req: HttpRequest,
+    req: web::Json<RoomImportRequest>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    principal: Principal,
+) -> impl Responder {
+    // Parse the dorm_id
+    let dorm_id = match ObjectId::parse_str(&req.dorm_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid dorm ID format"
+        }),
+    };
+
+    let roster = match read_room_data(dorm_id, &req.room_data) {
+        Ok(roster) => roster,
+        Err(error) => return HttpResponse::BadRequest().json(doc! { "error": error }),
+    };
+    start(&http_req, &db, &jobs, mailer.into_inner(), &principal, roster, &req.options).await
+}
+
+// Looks up a batch the caller may import into
+async fn find_batch(
+    db: &Database,
+    principal: &Principal,
+    batch_id: &str,
+) -> Result<ImportBatch, HttpResponse> {
+    let oid = ObjectId::parse_str(batch_id).map_err(|_| {
+        HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid batch ID"
+        })
+    })?;
+    let batch = match batches(db).find_one(doc! { "_id": oid }, None).await {
+        Ok(Some(batch)) => batch,
+        Ok(None) => return Err(HttpResponse::NotFound().json(doc! {
+            "error": "Import batch not found"
+        })),
+        Err(e) => return Err(internal_error(e)),
+    };
+    let scope = Scope::Dorm {
+        school_id: batch.school_id,
+        dorm_id: batch.dorm_id,
+    };
+    authorize(principal, Permission::ImportRooms, &scope)?;
+    Ok(batch)
+}
+
+#[post("/admin/import-batches/{batch_id}/commit")]
+async fn commit_batch(
+    req: HttpRequest,
+    batch_id: web::Path<String>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    principal: Principal,
+) -> impl Responder {
+    let batch = match find_batch(&db, &principal, &batch_id).await {
+        Ok(batch) => batch,
+        Err(response) => return response,
+    };
+    let batch_id = batch.id.expect("Stored batches have an ID");
+    commit(&req, &db, &jobs, mailer.into_inner(), &principal, batch_id).await
+}
+
+#[post("/admin/import-batches/{batch_id}/rollback")]
+async fn rollback_batch(
+    req: HttpRequest,
+    batch_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let batch = match find_batch(&db, &principal, &batch_id).await {
+        Ok(batch) => batch,
+        Err(response) => return response,
+    };
+    let batch_id = batch.id.expect("Stored batches have an ID");
+
+    let claimed = batches(&db)
+        .update_one(
+            doc! { "_id": batch_id, "status": "committed" },
+            doc! { "$set": { "status": "rolling_back" } },
+            None,
+        )
+        .await;
+    match claimed {
+        Ok(result) if result.modified_count == 0 => return HttpResponse::Conflict().json(doc! {
+            "error": "Only committed batches can be rolled back"
+        }),
+        Ok(_) => {}
+        Err(e) => return internal_error(e),
+    }
+    let reopen = || async {
+        let _ = batches(&db)
+            .update_one(
+                doc! { "_id": batch_id },
+                doc! { "$set": { "status": "committed" } },
+                None,
+            )
+            .await;
+    };
+
+    match changed_since_commit(&db, &batch).await {
+        Ok(changed) if !changed.is_empty() => {
+            reopen().await;
+            return HttpResponse::Conflict().json(doc! {
+                "error": "Rooms this batch changed have changed again since; rolling it back would undo that",
+                "rooms": changed,
+            });
+        }
+        Ok(_) => {}
+        Err(e) => {
+            reopen().await;
+            return internal_error(e);
+        }
+    }
+
+    match undo(&db, batch_id).await {
+        Ok(removed) => {
+            let result = batches(&db)
+                .update_one(
+                    doc! { "_id": batch_id },
+                    doc! { "$set": { "status": "rolled_back", "rolled_back_at": DateTime::now() } },
+                    None,
+                )
+                .await;
+            if let Err(e) = result {
+                println!("Failed to update import batch {}: {:?}", batch_id, e);
+            }
+            audit::record(
+                &db,
+                AuditEntry::new("import_batch_rolled_back")
+                    .actor(&principal.email)
+                    .school(batch.school_id)
+                    .target(&batch_id.to_hex())
+                    .request(&req)
+                    .details(removed.clone()),
+            )
+            .await;
+            let mut response = doc! { "message": "Import batch rolled back" };
+            response.extend(removed);
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) => {
+            // Left as rolling_back; running the rollback again is safe, so
+            // put it back to let the admin retry
+            println!("Failed to roll back import batch {}: {:?}", batch_id, e);
+            reopen().await;
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to roll back import batch"
+            })
+        }
+    }
+}
+
+#[get("/admin/import-batches/{batch_id}")]
+async fn get_batch(
+    batch_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    match find_batch(&db, &principal, &batch_id).await {
+        Ok(batch) => HttpResponse::Ok().json(batch_view(&batch)),
+        Err(response) => response,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchListQuery {
+    dorm_id: String,
+}
+
+// A dorm's batches, newest first, without their per-row reports
+#[get("/admin/import-batches")]
+async fn list_batches(
+    query: web::Query<BatchListQuery>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let dorm_id = match ObjectId::parse_str(&query.dorm_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid dorm ID format"
+        }),
+    };
+    match roles::dorm_scope(&db, dorm_id).await {
+        Ok(Some(scope)) => {
+            if let Err(denied) = authorize(&principal, Permission::ImportRooms, &scope) {
+                return denied;
+            }
+        }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Dorm not found"
+        }),
+        Err(e) => return internal_error(e),
+    }
+
+    let page = match Page::new(&page, &["created_at", "status"], "-created_at") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    let collection = db.collection::<Document>("import_batches");
+    let listed = page.respond(&collection, doc! { "dorm_id": dorm_id }, |batch: ImportBatch| {
+        let mut view = batch_view(&batch);
+        view.remove("report");
+        view
+    });
+    match listed.await {
+        Ok(response) => response,
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn student(name: &str, id: i32, email: Option<&str>) -> StudentData {
+        StudentData {
+            name: name.to_string(),
+            id,
+            email: email.map(str::to_string),
+        }
+    }
+
+    fn existing(email: &str, student_id: Option<i32>, room: Option<&str>) -> ExistingStudent {
+        ExistingStudent {
+            id: ObjectId::new(),
+            email: email.to_string(),
+            name: Some("Ada".to_string()),
+            student_id,
+            assigned_room: room.map(str::to_string),
+            is_staff: false,
+        }
+    }
+
+    fn occupant(email: &str) -> Student {
+        Student {
+            id: None,
+            name: email.to_string(),
+            display_name: None,
+            student_id: None,
+            assigned_at: None,
+        }
+    }
+
+    #[test]
+    fn rooms_are_compared_with_what_the_commit_left() {
+        let planned_id = ObjectId::new();
+        let plan = vec![PlannedRoom {
+            number: "101".to_string(),
+            status: RowStatus::Updated,
+            room_id: Some(planned_id),
+            capacity: 3,
+            roster: vec!["grace@x.edu".to_string(), "ada@x.edu".to_string()],
+            removed: Vec::new(),
+            students: vec![PlannedStudent {
+                name: "Ada".to_string(),
+                student_id: 1,
+                email: Some("ada@x.edu".to_string()),
+                status: RowStatus::Updated,
+                account_id: Some(ObjectId::new()),
+                message: None,
+            }],
+            warnings: Vec::new(),
+        }];
+
+        let planned = RoomSnapshot {
+            room_id: planned_id,
+            capacity: 2,
+            current_students: vec![occupant("grace@x.edu")],
+        };
+        assert_eq!(after_commit(&plan, &planned), (3, vec!["ada@x.edu".to_string(), "grace@x.edu".to_string()]));
+
+        // Ada's old room only lost Ada
+        let old_room = RoomSnapshot {
+            room_id: ObjectId::new(),
+            capacity: 2,
+            current_students: vec![occupant("ada@x.edu"), occupant("alan@x.edu")],
+        };
+        assert_eq!(after_commit(&plan, &old_room), (2, vec!["alan@x.edu".to_string()]));
+    }
+
+    #[test]
+    fn plan_reports_failures_without_dropping_rows() {
+        let room_data = BTreeMap::from([
+            ("101".to_string(), vec![student("Ada", 1, None), student("Grace", 2, Some("taken@x.edu"))]),
+            ("102".to_string(), vec![student("Alan", 3, Some("s1@x.edu")), student("Ada", 1, None)]),
+        ]);
+        let students = [existing("taken@x.edu", Some(9), None)];
+
+        let plan = plan_rows(&room_data, &BTreeMap::new(), None, Some("s{id}@x.edu"), MergePolicy::Skip, &HashMap::new(), &students);
+
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].status, RowStatus::Created);
+        assert_eq!(plan[0].capacity, 2);
+        assert_eq!(plan[0].roster, vec!["s1@x.edu"]);
+        assert_eq!(plan[0].students[0].status, RowStatus::Created);
+        assert!(plan[0].students[1].message.as_deref().unwrap().contains("belongs to student 9"));
+        // Rendered from the pattern in 101, then given explicitly in 102
+        assert!(plan[1].students[0].message.as_deref().unwrap().contains("more than once"));
+        assert!(plan[1].students[1].message.as_deref().unwrap().contains("Student id 1"));
+        assert_eq!(failures(&plan).len(), 3);
+        assert_eq!(
+            report(&plan).1,
+            doc! { "created": 3_i64, "updated": 0_i64, "unchanged": 0_i64, "failed": 3_i64, "warnings": 2_i64 }
+        );
+    }
+
+    #[test]
+    fn reimporting_the_same_data_changes_nothing() {
+        let room_data = BTreeMap::from([("101".to_string(), vec![student("Ada", 1, Some("ada@x.edu"))])]);
+        let rooms = HashMap::from([(
+            "101".to_string(),
+            ExistingRoom { id: ObjectId::new(), capacity: 1, occupants: vec!["ada@x.edu".to_string()] },
+        )]);
+        let students = [existing("ada@x.edu", Some(1), Some("101"))];
+
+        for policy in [MergePolicy::Skip, MergePolicy::Merge, MergePolicy::Replace] {
+            let plan = plan_rows(&room_data, &BTreeMap::new(), None, None, policy, &rooms, &students);
+            assert_eq!(plan[0].status, RowStatus::Unchanged, "{:?}", policy);
+            assert_eq!(plan[0].students[0].status, RowStatus::Unchanged, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn policies_decide_what_happens_to_existing_rosters() {
+        // Ada moves from 102 into 101, where Bob already lives
+        let room_data = BTreeMap::from([("101".to_string(), vec![student("Ada", 1, Some("ada@x.edu"))])]);
+        let rooms = HashMap::from([(
+            "101".to_string(),
+            ExistingRoom { id: ObjectId::new(), capacity: 1, occupants: vec!["bob@x.edu".to_string()] },
+        )]);
+        let students = [existing("ada@x.edu", Some(1), Some("102"))];
+
+        let skip = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Skip, &rooms, &students);
+        assert_eq!(skip[0].students[0].status, RowStatus::Unchanged);
+        assert_eq!(skip[0].status, RowStatus::Unchanged);
+
+        let merge = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Merge, &rooms, &students);
+        assert_eq!(merge[0].students[0].status, RowStatus::Updated);
+        assert_eq!(merge[0].students[0].message.as_deref(), Some("Moves from room 102"));
+        assert_eq!(merge[0].roster, vec!["bob@x.edu", "ada@x.edu"]);
+        assert_eq!(merge[0].capacity, 1);
+        assert_eq!(merge[0].warnings, vec!["2 students but a capacity of 1"]);
+        assert_eq!(merge[0].status, RowStatus::Updated);
+
+        let replace = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Replace, &rooms, &students);
+        assert_eq!(replace[0].roster, vec!["ada@x.edu"]);
+        assert_eq!(replace[0].removed, vec!["bob@x.edu"]);
+        assert_eq!(replace[0].capacity, 1);
+    }
+
+    #[test]
+    fn merging_records_real_names_on_existing_students() {
+        let room_data = BTreeMap::from([("101".to_string(), vec![student("Ada Lovelace", 1, Some("ada@x.edu"))])]);
+        let rooms = HashMap::from([(
+            "101".to_string(),
+            ExistingRoom { id: ObjectId::new(), capacity: 1, occupants: vec!["ada@x.edu".to_string()] },
+        )]);
+        let students = [existing("ada@x.edu", None, Some("101"))];
+
+        let plan = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Merge, &rooms, &students);
+        assert_eq!(plan[0].students[0].status, RowStatus::Updated);
+        assert_eq!(plan[0].students[0].message.as_deref(), Some("Records the student's name and id"));
+        // The room is rewritten so its occupant entry carries the name too
+        assert_eq!(plan[0].status, RowStatus::Updated);
+    }
+
+    #[test]
+    fn students_match_by_id_before_email() {
+        let room_data = BTreeMap::from([("101".to_string(), vec![student("Ada", 1, Some("new@x.edu"))])]);
+        let students = [existing("ada@x.edu", Some(1), Some("101"))];
+
+        let plan = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Merge, &HashMap::new(), &students);
+        assert_eq!(plan[0].students[0].email.as_deref(), Some("ada@x.edu"));
+        assert_eq!(plan[0].students[0].account_id, Some(students[0].id));
+    }
+
+    #[test]
+    fn capacity_is_given_or_kept_and_the_roster_fallback_warns() {
+        let room_data = BTreeMap::from([
+            ("101".to_string(), vec![student("Ada", 1, Some("ada@x.edu"))]),
+            ("102".to_string(), vec![student("Grace", 2, Some("grace@x.edu"))]),
+            ("103".to_string(), vec![student("Alan", 3, Some("alan@x.edu")), student("Edsger", 4, Some("e@x.edu"))]),
+            ("201".to_string(), vec![]),
+        ]);
+        let capacities = BTreeMap::from([("101".to_string(), 4), ("103".to_string(), 1)]);
+        let rooms = HashMap::from([(
+            "201".to_string(),
+            ExistingRoom { id: ObjectId::new(), capacity: 3, occupants: vec![] },
+        )]);
+
+        let plan = plan_rows(&room_data, &capacities, Some(2), None, MergePolicy::Merge, &rooms, &[]);
+        let capacity: Vec<i32> = plan.iter().map(|room| room.capacity).collect();
+        assert_eq!(capacity, vec![4, 2, 1, 3]);
+        assert!(plan[0].warnings.is_empty());
+        assert_eq!(plan[2].warnings, vec!["2 students but a capacity of 1"]);
+        // The dorm's default doesn't shrink an existing room
+        assert_eq!(plan[3].status, RowStatus::Unchanged);
+
+        // With no capacity anywhere, the room holds its roster and says so
+        let plan = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Merge, &HashMap::new(), &[]);
+        assert_eq!(plan[2].capacity, 2);
+        assert_eq!(plan[2].warnings, vec!["No capacity given; the room holds only the students listed"]);
+    }
+
+    #[test]
+    fn json_rooms_may_carry_a_capacity() {
+        let rooms: BTreeMap<String, RoomEntry> = serde_json::from_value(serde_json::json!({
+            "101": [{ "name": "Ada", "id": 1 }],
+            "102": { "capacity": 4, "students": [{ "name": "Grace", "id": 2 }] },
+            "103": { "capacity": 2 },
+        }))
+        .unwrap();
+
+        assert!(matches!(&rooms["101"], RoomEntry::Students(students) if students.len() == 1));
+        assert!(matches!(&rooms["102"], RoomEntry::Room { capacity: Some(4), students } if students.len() == 1));
+        assert!(matches!(&rooms["103"], RoomEntry::Room { capacity: Some(2), students } if students.is_empty()));
+    }
+
+    #[test]
+    fn students_need_an_email_or_pattern() {
+        let room_data = BTreeMap::from([("101".to_string(), vec![student("Ada", 1, None)])]);
+        let plan = plan_rows(&room_data, &BTreeMap::new(), None, None, MergePolicy::Skip, &HashMap::new(), &[]);
+        assert_eq!(
+            plan[0].students[0].message.as_deref(),
+            Some("No email and no email pattern")
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::task::TaskTracker;
+
+// Tracks work that must run to completion even if the request that started it
+// goes away (client disconnect, worker shutdown). Tasks are spawned onto the
+// main runtime rather than the worker's, so they outlive the HTTP workers and
+// can be flushed after the server stops.
+#[derive(Clone)]
+pub struct BackgroundJobs {
+    tracker: TaskTracker,
+    handle: Handle,
+}
+
+impl BackgroundJobs {
+    // Must be called from the main runtime (the one that outlives the server)
+    pub fn new() -> Self {
+        BackgroundJobs {
+            tracker: TaskTracker::new(),
+            handle: Handle::current(),
+        }
+    }
+
+    // Fire-and-forget; the task is still flushed on shutdown
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.spawn(self.tracker.track_future(task))
+    }
+
+    // Runs a multi-step write to completion and waits for its result. Dropping
+    // the returned future does not cancel the work.
+    pub async fn run<F>(&self, task: F) -> Result<F::Output, JoinError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn(task).await
+    }
+
+    // Waits for pending jobs once the server has stopped. Returns false if
+    // the timeout elapsed first.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.tracker.close();
+        println!("Flushing {} pending background jobs...", self.tracker.len());
+        tokio::time::timeout(timeout, self.tracker.wait()).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn job(done: &Arc<AtomicBool>, after: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let done = done.clone();
+        async move {
+            tokio::time::sleep(after).await;
+            done.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_pending_jobs() {
+        let jobs = BackgroundJobs::new();
+        let done = Arc::new(AtomicBool::new(false));
+        jobs.spawn(job(&done, Duration::from_millis(20)));
+
+        assert!(jobs.flush(Duration::from_secs(5)).await);
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn flush_gives_up_after_the_timeout() {
+        let jobs = BackgroundJobs::new();
+        let done = Arc::new(AtomicBool::new(false));
+        jobs.spawn(job(&done, Duration::from_secs(60)));
+
+        assert!(!jobs.flush(Duration::from_millis(20)).await);
+        assert!(!done.load(Ordering::SeqCst));
+    }
+
+    // A request dropped mid-write still gets its write finished
+    #[tokio::test]
+    async fn run_outlives_the_caller() {
+        let jobs = BackgroundJobs::new();
+        let done = Arc::new(AtomicBool::new(false));
+        let abandoned = tokio::time::timeout(Duration::from_millis(1), jobs.run(job(&done, Duration::from_millis(20))));
+        assert!(abandoned.await.is_err());
+
+        assert!(jobs.flush(Duration::from_secs(5)).await);
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
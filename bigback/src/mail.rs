@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::jobs::BackgroundJobs;
+
+#[derive(Debug, Clone)]
+pub struct MailMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub type MailError = Box<dyn Error + Send + Sync>;
+
+// Anything that can deliver a message. Sends happen on background jobs, so
+// implementations may block.
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError>;
+}
+
+// Prints messages to stdout; the default for local development
+pub struct ConsoleMailer;
+
+impl Mailer for ConsoleMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError> {
+        println!(
+            "--- mail to {} ---\nSubject: {}\n\n{}\n--- end of mail ---",
+            message.to, message.subject, message.body
+        );
+        Ok(())
+    }
+}
+
+// Appends messages to a file, for tests and staging environments that need
+// to read what was sent
+pub struct FileMailer {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileMailer {
+    pub fn new(path: PathBuf) -> Self {
+        FileMailer {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &MailMessage) -> Result<(), MailError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(
+            file,
+            "To: {}\nSubject: {}\n\n{}\n",
+            message.to, message.subject, message.body
+        )?;
+        Ok(())
+    }
+}
+
+// MAIL_TRANSPORT=file writes to MAIL_FILE (default ./mail.log); anything
+// else prints to the console
+pub fn mailer_from_env() -> Arc<dyn Mailer> {
+    match std::env::var("MAIL_TRANSPORT").as_deref() {
+        Ok("file") => {
+            let path = std::env::var("MAIL_FILE").unwrap_or_else(|_| "mail.log".to_string());
+            println!("Writing outgoing mail to {}", path);
+            Arc::new(FileMailer::new(PathBuf::from(path)))
+        }
+        _ => Arc::new(ConsoleMailer),
+    }
+}
+
+// Sends on a background job so the request doesn't wait for delivery, and
+// pending mail is still flushed on shutdown
+pub fn queue(jobs: &BackgroundJobs, mailer: Arc<dyn Mailer>, message: MailMessage) {
+    jobs.spawn(async move {
+        let to = message.to.clone();
+        match tokio::task::spawn_blocking(move || mailer.send(&message)).await {
+            Ok(Ok(())) => println!("Sent mail to {}", to),
+            Ok(Err(e)) => println!("Failed to send mail to {}: {:?}", to, e),
+            Err(e) => println!("Mail job for {} panicked: {:?}", to, e),
+        }
+    });
+}
+
+// Base URL used to build links in outgoing mail
+pub fn app_base_url() -> String {
+    std::env::var("APP_BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
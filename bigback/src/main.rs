@@ -1,37 +1,95 @@
 use actix_cors::Cors;
 use actix_web::{
-    get, post, web, App, HttpResponse, HttpServer, Responder,
-    middleware::Logger,
+    get, post, put, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    middleware::{from_fn, Logger},
 };
-use futures::StreamExt;
 use mongodb::{
-    bson::{doc, oid::ObjectId, to_bson, Bson},
+    bson::{doc, oid::ObjectId, to_bson, DateTime, Document},
     Client, Database,
 };
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    id: Option<ObjectId>,
-    email: String,
-    password: String,
-    assigned_room: Option<String>,
-}
+mod accounts;
+mod api_keys;
+mod audit;
+mod auth;
+mod credentials;
+mod duplicates;
+mod export;
+mod imports;
+mod jobs;
+mod mail;
+mod ocr;
+mod oidc;
+mod pagination;
+mod passwords;
+mod rate_limit;
+mod roles;
+mod room_sheets;
+mod roster_diff;
+mod scim;
+mod sessions;
+mod spreadsheets;
+mod streaming;
+mod tls;
+mod tokens;
+mod two_factor;
+mod uploads;
+
+use accounts::Account;
+use audit::AuditEntry;
+use jobs::BackgroundJobs;
+use mail::Mailer;
+use pagination::{Page, PageQuery};
+use rate_limit::{LoginGuard, LoginPolicy};
+use roles::{authorize, Permission, Principal, Role, RoleGrant, Scope};
+use tls::{ReloadingCertResolver, TlsPaths};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Dorm {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     id: Option<ObjectId>,
     name: String,
+    // Missing on dorms created before dorms were tied to a school
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    school_id: Option<ObjectId>,
+    // Capacity of rooms imported without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_capacity: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]  // Added Clone
 struct Student {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     id: Option<ObjectId>,
+    // The student's account email, which is how occupants are matched to
+    // accounts
     name: String,
+    // The student's real name and the school's id for them, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    student_id: Option<i32>,
+    // When the student moved in; unknown for assignments made before this
+    // was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assigned_at: Option<DateTime>,
+}
+
+impl Student {
+    // A student moving in now
+    fn of(account: &Account) -> Self {
+        Student {
+            id: None,
+            name: account.email.clone(),
+            display_name: account.name.clone(),
+            student_id: account.student_id,
+            assigned_at: Some(DateTime::now()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,82 +100,148 @@ struct Room {
     number: String,
     capacity: i32,
     current_students: Vec<Student>,
+    // The import batch that created the room, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    import_batch: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    floor: Option<i32>,
+    // Features students look for, e.g. "accessible" or "ensuite"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attributes: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct LoginCredentials {
-    email: String,
-    password: String,
+impl Room {
+    // The floor a room number implies, by the usual numbering: "513" and
+    // "0513" are on floor 5, "1204" on floor 12. Numbers like "B2" don't say.
+    fn floor_of(number: &str) -> Option<i32> {
+        let number = number.trim();
+        if number.len() < 3 || !number.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        number.parse::<i32>().ok().map(|number| number / 100)
+    }
+
+    // A filter for the rooms in a school's dorms. Emails are only unique
+    // within a school, so anything finding occupants by email has to stay
+    // inside one.
+    async fn in_school(db: &Database, school_id: Option<ObjectId>) -> Result<Document, mongodb::error::Error> {
+        let dorm_ids = db
+            .collection::<Document>("dorms")
+            .distinct("_id", doc! { "school_id": school_id }, None)
+            .await?;
+        Ok(doc! { "dorm_id": { "$in": dorm_ids } })
+    }
 }
+
 // Database connection helper
-async fn get_db(mongo_uri: String) -> Result<Database, Box<dyn Error>> {
+async fn get_client(mongo_uri: String) -> Result<Client, Box<dyn Error>> {
     let client = Client::with_uri_str(&mongo_uri).await?;
-    Ok(client.database("dorm_management"))
+    Ok(client)
+}
+
+#[derive(Debug, Deserialize)]
+struct DormListQuery {
+    school_id: Option<String>,
+    // Part of the name, any case
+    q: Option<String>,
 }
 
-// Route handlers
-async fn login(
-    credentials: web::Json<LoginCredentials>,
+#[get("/dorms")]
+async fn get_dorms(
+    query: web::Query<DormListQuery>,
+    page: web::Query<PageQuery>,
     db: web::Data<Database>,
+    principal: Principal,
 ) -> impl Responder {
-    println!("Login attempt with email: {}", credentials.email);
-    
-    let collection = db.collection::<User>("users");
-    
-    let query = doc! {
-        "email": &credentials.email,
-        "password": &credentials.password
+    println!("Fetching all dorms");
+    if let Err(denied) = authorize(&principal, Permission::ViewDorms, &Scope::Own) {
+        return denied;
+    }
+    let page = match Page::new(&page, &["name", "_id"], "name") {
+        Ok(page) => page,
+        Err(response) => return response,
     };
-    
-    println!("Executing query: {:?}", query);
-    
-    match collection.find_one(query, None).await {
-        Ok(Some(user)) => {
-            println!("User found, login successful");
-            HttpResponse::Ok().json(user)
-        },
-        Ok(None) => {
-            println!("No user found with provided credentials");
-            HttpResponse::Unauthorized().json(doc! {
-                "error": "Invalid credentials"
-            })
-        },
-        Err(e) => {
-            println!("Database error: {:?}", e);
-            HttpResponse::InternalServerError().json(doc! {
-                "error": "Internal server error"
-            })
+
+    // Only the dorms the caller can see
+    let mut filter = principal.dorm_filter(Permission::ViewDorms).unwrap_or_default();
+    if let Some(school_id) = &query.school_id {
+        match ObjectId::parse_str(school_id) {
+            Ok(oid) => filter = doc! { "$and": [filter, { "school_id": oid }] },
+            Err(_) => return HttpResponse::BadRequest().json(doc! {
+                "error": "Invalid school ID"
+            }),
         }
     }
-}
+    if let Some(q) = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        filter = doc! { "$and": [filter, { "name": { "$regex": escape_regex(q), "$options": "i" } }] };
+    }
 
-#[get("/dorms")]
-async fn get_dorms(db: web::Data<Database>) -> impl Responder {
-    println!("Fetching all dorms");
-    let collection = db.collection::<Dorm>("dorms");
-    
-    match collection.find(None, None).await {
-        Ok(cursor) => {
-            let dorms: Vec<_> = cursor
-                .collect::<Vec<Result<_, _>>>()
-                .await
-                .into_iter()
-                .filter_map(Result::ok)
-                .collect();
-            println!("Found {} dorms", dorms.len());
-            HttpResponse::Ok().json(dorms)
-        }
+    match page.respond(&db.collection::<Document>("dorms"), filter, |dorm: Dorm| dorm).await {
+        Ok(response) => response,
         Err(e) => {
             println!("Error fetching dorms: {:?}", e);
             HttpResponse::InternalServerError().finish()
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+struct RoomListQuery {
+    // true for rooms with a free bed, false for full ones
+    has_vacancy: Option<bool>,
+    min_capacity: Option<i32>,
+    max_capacity: Option<i32>,
+    floor: Option<i32>,
+    // Comma-separated; rooms must have all of them
+    attributes: Option<String>,
+}
+
+impl RoomListQuery {
+    fn filter(&self, dorm_id: ObjectId) -> Document {
+        let mut filter = doc! { "dorm_id": dorm_id };
+        if let Some(has_vacancy) = self.has_vacancy {
+            let occupied = doc! { "$size": "$current_students" };
+            let vacancy = match has_vacancy {
+                true => doc! { "$lt": [occupied, "$capacity"] },
+                false => doc! { "$gte": [occupied, "$capacity"] },
+            };
+            filter.insert("$expr", vacancy);
+        }
+        let mut capacity = Document::new();
+        if let Some(min) = self.min_capacity {
+            capacity.insert("$gte", min);
+        }
+        if let Some(max) = self.max_capacity {
+            capacity.insert("$lte", max);
+        }
+        if !capacity.is_empty() {
+            filter.insert("capacity", capacity);
+        }
+        if let Some(floor) = self.floor {
+            filter.insert("floor", floor);
+        }
+        if let Some(attributes) = &self.attributes {
+            let wanted: Vec<String> = attributes.split(',').map(str::to_string).collect();
+            let wanted = normalize_attributes(&wanted);
+            if !wanted.is_empty() {
+                filter.insert("attributes", doc! { "$all": wanted });
+            }
+        }
+        filter
+    }
+}
+
 #[get("/dorms/{dorm_id}/rooms")]
-async fn get_rooms(db: web::Data<Database>, dorm_id: web::Path<String>) -> impl Responder {
+async fn get_rooms(
+    db: web::Data<Database>,
+    principal: Principal,
+    dorm_id: web::Path<String>,
+    query: web::Query<RoomListQuery>,
+    page: web::Query<PageQuery>,
+) -> impl Responder {
     println!("Received request for dorm_id: {}", dorm_id);
     
-    let collection = db.collection::<Room>("rooms");
+    let collection = db.collection::<Document>("rooms");
     
     let oid = match ObjectId::parse_str(dorm_id.as_str()) {
         Ok(oid) => oid,
@@ -129,19 +253,31 @@ async fn get_rooms(db: web::Data<Database>, dorm_id: web::Path<String>) -> impl
         }
     };
     
+    match roles::dorm_scope(&db, oid).await {
+        Ok(Some(scope)) => {
+            if let Err(denied) = authorize(&principal, Permission::ViewDorms, &scope) {
+                return denied;
+            }
+        }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Dorm not found"
+        }),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to fetch rooms"
+            });
+        }
+    }
+    let page = match Page::new(&page, &["number", "capacity", "floor", "_id"], "number") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
     println!("Looking for rooms with dorm_id: {}", oid);
     
-    match collection.find(doc! { "dorm_id": oid }, None).await {
-        Ok(cursor) => {
-            let rooms: Vec<_> = cursor
-                .collect::<Vec<Result<_, _>>>()
-                .await
-                .into_iter()
-                .filter_map(Result::ok)
-                .collect();
-            println!("Found {} rooms", rooms.len());
-            HttpResponse::Ok().json(rooms)
-        }
+    match page.respond(&collection, query.filter(oid), |room: Room| room).await {
+        Ok(response) => response,
         Err(e) => {
             println!("Error fetching rooms: {:?}", e);
             HttpResponse::InternalServerError().json(doc! {
@@ -201,35 +337,40 @@ async fn get_rooms(db: web::Data<Database>, dorm_id: web::Path<String>) -> impl
 //         }
 //     }
 // }
-#[get("/user")]
-async fn get_user(db: web::Data<Database>) -> impl Responder {
-    println!("Fetching user info");
-    let collection = db.collection::<User>("users");
-    
-    match collection.find_one(None, None).await {
-        Ok(Some(user)) => {
-            println!("User found");
-            HttpResponse::Ok().json(user)
-        }
-        Ok(None) => {
-            println!("No user found");
-            HttpResponse::NotFound().finish()
-        }
+// The student account behind the caller's session
+async fn current_student(
+    accounts_collection: &mongodb::Collection<Account>,
+    principal: &Principal,
+) -> Result<Account, HttpResponse> {
+    let auth = principal.session()?;
+    match accounts_collection
+        .find_one(doc! { "_id": auth.account_id }, None)
+        .await
+    {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(HttpResponse::NotFound().json(doc! {
+            "error": "User not found"
+        })),
         Err(e) => {
-            println!("Error fetching user: {:?}", e);
-            HttpResponse::InternalServerError().finish()
+            println!("Error finding user: {:?}", e);
+            Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }))
         }
     }
 }
 #[post("/rooms/{room_id}/assign")]
 async fn assign_room(
+    req: HttpRequest,
     db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    principal: Principal,
     room_id: web::Path<String>,
 ) -> impl Responder {
     println!("Assigning room with ID: {}", room_id);
     
     let rooms_collection = db.collection::<Room>("rooms");
-    let users_collection = db.collection::<User>("users");
+    let accounts_collection = accounts::collection(&db);
     
     let oid = match ObjectId::parse_str(room_id.as_str()) {
         Ok(oid) => oid,
@@ -241,24 +382,35 @@ async fn assign_room(
         }
     };
     
-    // Get current user
-    let current_user = match users_collection.find_one(doc! {}, None).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return HttpResponse::NotFound().json(doc! {
-                "error": "User not found"
-            });
+    let school_id = match roles::room_scope(&db, oid).await {
+        Ok(Some(scope)) => {
+            if let Err(denied) = authorize(&principal, Permission::ChooseOwnRoom, &scope) {
+                return denied;
+            }
+            match scope {
+                Scope::Dorm { school_id, .. } => school_id,
+                _ => None,
+            }
         }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Room not found"
+        }),
         Err(e) => {
-            println!("Error finding user: {:?}", e);
+            println!("Database error when finding room: {:?}", e);
             return HttpResponse::InternalServerError().json(doc! {
                 "error": "Internal server error"
             });
         }
     };
 
+    // Get current user
+    let current_user = match current_student(&accounts_collection, &principal).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
     // Get target room
-    let mut target_room = match rooms_collection.find_one(doc! { "_id": oid }, None).await {
+    let target_room = match rooms_collection.find_one(doc! { "_id": oid }, None).await {
         Ok(Some(room)) => room,
         Ok(None) => {
             return HttpResponse::NotFound().json(doc! {
@@ -280,14 +432,72 @@ async fn assign_room(
         });
     }
 
-    // Remove user from ALL rooms (not just their currently assigned room)
+    let before = doc! { "assigned_room": &current_user.assigned_room };
+    let after = doc! { "assigned_room": &target_room.number };
+    let student_id = current_user.id.expect("Stored accounts have an ID");
+
+    // The pull/set/set sequence runs as a background job so a dropped
+    // connection or a redeploy can't leave the student out of every room
+    match jobs.run(move_student(db.get_ref().clone(), current_user, oid, target_room)).await {
+        Ok(Ok(())) => {
+            println!("Successfully assigned room");
+            audit::record(
+                &db,
+                AuditEntry::new("room_assigned")
+                    .actor(&principal.email)
+                    .school(school_id)
+                    .target(&student_id.to_hex())
+                    .request(&req)
+                    .details(doc! { "room_id": oid })
+                    .diff(Some(&before), Some(&after)),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Room assigned successfully"
+            })
+        },
+        Ok(Err(error)) => HttpResponse::InternalServerError().json(doc! {
+            "error": error
+        }),
+        Err(e) => {
+            println!("Room assignment job failed: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to assign room"
+            })
+        }
+    }
+}
+
+// Moves a student out of every room and into the target room, returning the
+// error message for whichever step failed
+async fn move_student(
+    db: Database,
+    student: Account,
+    room_oid: ObjectId,
+    mut target_room: Room,
+) -> Result<(), &'static str> {
+    let rooms_collection = db.collection::<Room>("rooms");
+    let accounts_collection = accounts::collection(&db);
+    let occupant = Student::of(&student);
+    let email = student.email;
+
+    // Remove user from every room of their school (not just their currently
+    // assigned room)
+    let mut filter = match Room::in_school(&db, student.school_id).await {
+        Ok(filter) => filter,
+        Err(e) => {
+            println!("Error finding the school's dorms: {:?}", e);
+            return Err("Failed to update rooms");
+        }
+    };
+    filter.insert("current_students.name", &email);
     match rooms_collection
         .update_many(
-            doc! {},  // Empty filter to match all rooms
+            filter,
             doc! { 
                 "$pull": { 
                     "current_students": { 
-                        "name": &current_user.email 
+                        "name": &email 
                     } 
                 } 
             },
@@ -298,17 +508,12 @@ async fn assign_room(
         Ok(_) => println!("Removed user from all rooms successfully"),
         Err(e) => {
             println!("Error removing user from rooms: {:?}", e);
-            return HttpResponse::InternalServerError().json(doc! {
-                "error": "Failed to update rooms"
-            });
+            return Err("Failed to update rooms");
         }
     }
 
     // Add user to new room
-    target_room.current_students.push(Student {
-        id: None,
-        name: current_user.email.clone(),
-    });
+    target_room.current_students.push(occupant);
 
     // Convert target room students to BSON and update
     let students_bson = to_bson(&target_room.current_students)
@@ -316,7 +521,7 @@ async fn assign_room(
 
     match rooms_collection
         .update_one(
-            doc! { "_id": oid },
+            doc! { "_id": room_oid },
             doc! { "$set": { "current_students": students_bson } },
             None,
         )
@@ -325,32 +530,23 @@ async fn assign_room(
         Ok(_) => println!("Updated target room successfully"),
         Err(e) => {
             println!("Error updating target room: {:?}", e);
-            return HttpResponse::InternalServerError().json(doc! {
-                "error": "Failed to update target room"
-            });
+            return Err("Failed to update target room");
         }
     }
 
     // Update user's assigned room
-    match users_collection
+    match accounts_collection
         .update_one(
-            doc! { "email": &current_user.email },
+            doc! { "_id": student.id },
             doc! { "$set": { "assigned_room": &target_room.number } },
             None,
         )
         .await
     {
-        Ok(_) => {
-            println!("Successfully assigned room");
-            HttpResponse::Ok().json(doc! {
-                "message": "Room assigned successfully"
-            })
-        },
+        Ok(_) => Ok(()),
         Err(e) => {
             println!("Error assigning room: {:?}", e);
-            HttpResponse::InternalServerError().json(doc! {
-                "error": "Failed to assign room"
-            })
+            Err("Failed to assign room")
         }
     }
 }
@@ -360,28 +556,81 @@ struct School {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     id: Option<ObjectId>,
     name: String,
-   
+    // Staff of the school can't log in without TOTP set up
+    #[serde(default)]
+    require_admin_2fa: bool,
+    // Identity provider for single sign-on, if the school has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    oidc: Option<oidc::OidcConfig>,
 }
 
 #[derive(Debug, Deserialize)]
-struct AdminLoginCredentials {
-    email: String,
-    password: String,
-    school_id: String,
+struct SchoolSettingsRequest {
+    require_admin_2fa: bool,
 }
-#[derive(Debug, Serialize, Deserialize)]
-struct AdminCredential {
-    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    id: Option<ObjectId>,
-    email: String,
-    password: String,
-    school_id: ObjectId,  // Store as ObjectId instead of String
+
+#[put("/admin/schools/{school_id}/settings")]
+async fn update_school_settings(
+    http_req: HttpRequest,
+    school_id: web::Path<String>,
+    req: web::Json<SchoolSettingsRequest>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match ObjectId::parse_str(school_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageSchoolSettings, &Scope::School(school_oid)) {
+        return denied;
+    }
+
+    // Returns the school as it was, for the audit log
+    match db.collection::<School>("schools")
+        .find_one_and_update(
+            doc! { "_id": school_oid },
+            doc! { "$set": { "require_admin_2fa": req.require_admin_2fa } },
+            None,
+        )
+        .await
+    {
+        Ok(None) => HttpResponse::NotFound().json(doc! {
+            "error": "School not found"
+        }),
+        Ok(Some(school)) => {
+            audit::record(
+                &db,
+                AuditEntry::new("school_settings_changed")
+                    .actor(&principal.email)
+                    .school(Some(school_oid))
+                    .target(&school_oid.to_hex())
+                    .request(&http_req)
+                    .diff(
+                        Some(&doc! { "require_admin_2fa": school.require_admin_2fa }),
+                        Some(&doc! { "require_admin_2fa": req.require_admin_2fa }),
+                    ),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "School settings updated"
+            })
+        }
+        Err(e) => {
+            println!("Failed to update school settings: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to update school settings"
+            })
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct CreateDormRequest {
     name: String,
     school_id: String,
+    default_capacity: Option<i32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -389,6 +638,10 @@ struct CreateRoomRequest {
     dorm_id: String,
     number: String,
     capacity: i32,
+    // Taken from the room number when not given
+    floor: Option<i32>,
+    #[serde(default)]
+    attributes: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -396,10 +649,12 @@ struct CreateStudentRequest {
     email: String,
     password: String,
     school_id: String,
+    name: Option<String>,
+    student_id: Option<i32>,
 }
 // Replace the existing initialize_test_admin function with this one
 async fn initialize_test_admin(db: &Database) -> Result<ObjectId, Box<dyn Error>> {
-    let admin_collection = db.collection::<AdminCredential>("admin_credentials");
+    let admin_collection = accounts::collection(db);
     
     // Check if test admin exists
     if let Ok(Some(admin)) = admin_collection
@@ -423,11 +678,13 @@ async fn initialize_test_admin(db: &Database) -> Result<ObjectId, Box<dyn Error>
     };
 
     // Create test admin credentials
-    let test_admin = AdminCredential {
-        id: None,
-        email: "1".to_string(),
-        password: "1".to_string(),
-        school_id,
+    let test_admin = Account {
+        roles: vec![RoleGrant {
+            role: Role::SchoolAdmin,
+            school_id: Some(school_id),
+            dorm_id: None,
+        }],
+        ..Account::student("1".to_string(), passwords::hash_password("1"), Some(school_id))
     };
  
     match admin_collection.insert_one(test_admin, None).await {
@@ -442,133 +699,112 @@ async fn initialize_test_admin(db: &Database) -> Result<ObjectId, Box<dyn Error>
     }
 }
 
-// Update the admin_login handler to use the new AdminCredential struct
-#[post("/admin/login")]
-async fn admin_login(
-    credentials: web::Json<AdminLoginCredentials>,
+#[post("/rooms/unassign")]
+async fn unassign_room(
+    req: HttpRequest,
     db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    principal: Principal,
 ) -> impl Responder {
-    let admin_collection = db.collection::<AdminCredential>("admin_credentials");
-    let schools_collection = db.collection::<School>("schools");
+    println!("Unassigning room");
+    if let Err(denied) = authorize(&principal, Permission::ChooseOwnRoom, &Scope::Own) {
+        return denied;
+    }
+    let accounts_collection = accounts::collection(&db);
     
-    let school_oid = match ObjectId::parse_str(&credentials.school_id) {
-        Ok(oid) => oid,
-        Err(_) => return HttpResponse::BadRequest().json(doc! {
-            "error": "Invalid school ID"
-        }),
+    // Get current user
+    let current_user = match current_student(&accounts_collection, &principal).await {
+        Ok(user) => user,
+        Err(response) => return response,
     };
 
-    // First check if the admin credentials exist
-    match admin_collection
-        .find_one(doc! { 
-            "email": &credentials.email,
-            "password": &credentials.password,  // In production, use proper password verification
-            "school_id": school_oid
-        }, None)
-        .await
-    {
-        Ok(Some(_)) => {
-            // If admin exists, get school info
-            match schools_collection.find_one(doc! { "_id": school_oid }, None).await {
-                Ok(Some(school)) => {
-                    HttpResponse::Ok().json(doc! {
-                        "message": "Login successful",
-                        "school": {
-                            "id": school.id,
-                            "name": school.name
-                        }
-                    })
-                },
-                Ok(None) => HttpResponse::NotFound().json(doc! {
-                    "error": "School not found"
-                }),
-                Err(_) => HttpResponse::InternalServerError().finish(),
-            }
+    let before = doc! { "assigned_room": &current_user.assigned_room };
+    let school_id = current_user.school_id;
+    let student_id = current_user.id.expect("Stored accounts have an ID");
+
+    match jobs.run(remove_student(db.get_ref().clone(), current_user)).await {
+        Ok(Ok(())) => {
+            println!("Successfully unassigned room");
+            audit::record(
+                &db,
+                AuditEntry::new("room_unassigned")
+                    .actor(&principal.email)
+                    .school(school_id)
+                    .target(&student_id.to_hex())
+                    .request(&req)
+                    .diff(Some(&before), Some(&doc! { "assigned_room": null })),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Room unassigned successfully"
+            })
         },
-        Ok(None) => HttpResponse::Unauthorized().json(doc! {
-            "error": "Invalid credentials"
+        Ok(Err(error)) => HttpResponse::InternalServerError().json(doc! {
+            "error": error
         }),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(e) => {
+            println!("Room unassignment job failed: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to unassign room"
+            })
+        }
     }
 }
-#[post("/rooms/unassign")]
-async fn unassign_room(db: web::Data<Database>) -> impl Responder {
-    println!("Unassigning room");
+
+// Takes a student out of their assigned room and clears the assignment,
+// returning the error message for whichever step failed
+async fn remove_student(db: Database, current_user: Account) -> Result<(), &'static str> {
     let rooms_collection = db.collection::<Room>("rooms");
-    let users_collection = db.collection::<User>("users");
-    
-    // Get current user
-    let current_user = match users_collection.find_one(doc! {}, None).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return HttpResponse::NotFound().json(doc! {
-                "error": "User not found"
-            });
-        }
-        Err(e) => {
-            println!("Error finding user: {:?}", e);
-            return HttpResponse::InternalServerError().json(doc! {
-                "error": "Internal server error"
-            });
-        }
-    };
+    let accounts_collection = accounts::collection(&db);
 
-    // If user is assigned to a room, remove them from it
+    // If user is assigned to a room, remove them from it. Room numbers
+    // repeat across dorms and emails across schools, so only the school's
+    // room with both is touched.
     if let Some(room_number) = &current_user.assigned_room {
-        if let Ok(Some(mut room)) = rooms_collection
-            .find_one(doc! { "number": room_number }, None)
-            .await 
-        {
-            // Remove user from room
-            room.current_students.retain(|student| student.name != current_user.email);
-            
-            // Convert current_students to BSON and update
-            let students_bson = to_bson(&room.current_students)
-                .expect("Failed to serialize students");
-            
-            if let Err(e) = rooms_collection
-                .update_one(
-                    doc! { "number": room_number },
-                    doc! { "$set": { "current_students": students_bson } },
-                    None,
-                )
-                .await
-            {
-                println!("Error updating room: {:?}", e);
-                return HttpResponse::InternalServerError().json(doc! {
-                    "error": "Failed to update room"
-                });
+        let mut filter = match Room::in_school(&db, current_user.school_id).await {
+            Ok(filter) => filter,
+            Err(e) => {
+                println!("Error finding the school's dorms: {:?}", e);
+                return Err("Failed to update room");
             }
+        };
+        filter.insert("number", room_number);
+        filter.insert("current_students.name", &current_user.email);
+        if let Err(e) = rooms_collection
+            .update_many(
+                filter,
+                doc! { "$pull": { "current_students": { "name": &current_user.email } } },
+                None,
+            )
+            .await
+        {
+            println!("Error updating room: {:?}", e);
+            return Err("Failed to update room");
         }
     }
 
     // Update user's assigned room to null
-    match users_collection
+    match accounts_collection
         .update_one(
-            doc! { "email": &current_user.email },
+            doc! { "_id": current_user.id },
             doc! { "$set": { "assigned_room": null } },
             None,
         )
         .await
     {
-        Ok(_) => {
-            println!("Successfully unassigned room");
-            HttpResponse::Ok().json(doc! {
-                "message": "Room unassigned successfully"
-            })
-        },
+        Ok(_) => Ok(()),
         Err(e) => {
             println!("Error unassigning room: {:?}", e);
-            HttpResponse::InternalServerError().json(doc! {
-                "error": "Failed to unassign room"
-            })
+            Err("Failed to unassign room")
         }
     }
 }
 #[post("/admin/dorms")]
 async fn create_dorm(
+    http_req: HttpRequest,
     req: web::Json<CreateDormRequest>,
     db: web::Data<Database>,
+    principal: Principal,
 ) -> impl Responder {
     let dorms_collection = db.collection::<Dorm>("dorms");
     
@@ -578,17 +814,44 @@ async fn create_dorm(
             "error": "Invalid school ID"
         }),
     };
+    if let Err(denied) = authorize(&principal, Permission::ManageDorms, &Scope::School(school_id)) {
+        return denied;
+    }
+
+    if req.default_capacity.is_some_and(|capacity| capacity < 0) {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "Capacity can't be negative"
+        });
+    }
 
     let new_dorm = Dorm {
         id: None,
         name: req.name.clone(),
+        school_id: Some(school_id),
+        default_capacity: req.default_capacity,
     };
 
     match dorms_collection.insert_one(new_dorm, None).await {
-        Ok(result) => HttpResponse::Ok().json(doc! {
-            "id": result.inserted_id,
-            "message": "Dorm created successfully"
-        }),
+        Ok(result) => {
+            audit::record(
+                &db,
+                AuditEntry::new("dorm_created")
+                    .actor(&principal.email)
+                    .school(Some(school_id))
+                    .target(&result.inserted_id.as_object_id().map(|id| id.to_hex()).unwrap_or_default())
+                    .request(&http_req)
+                    .diff(None, Some(&doc! {
+                        "name": &req.name,
+                        "school_id": school_id,
+                        "default_capacity": req.default_capacity,
+                    })),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "id": result.inserted_id,
+                "message": "Dorm created successfully"
+            })
+        },
         Err(e) => {
             println!("Failed to create dorm: {:?}", e);
             HttpResponse::InternalServerError().json(doc! {
@@ -597,10 +860,24 @@ async fn create_dorm(
         },
     }
 }
+// Lowercase and without repeats, so filters match however they were typed
+fn normalize_attributes(attributes: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = attributes
+        .iter()
+        .map(|attribute| attribute.trim().to_lowercase())
+        .filter(|attribute| !attribute.is_empty())
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
+}
+
 #[post("/admin/rooms")]
 async fn create_room(
+    http_req: HttpRequest,
     req: web::Json<CreateRoomRequest>,
     db: web::Data<Database>,
+    principal: Principal,
 ) -> impl Responder {
     let rooms_collection = db.collection::<Room>("rooms");
     
@@ -613,12 +890,29 @@ async fn create_room(
     };
 
     // Verify that the dorm exists
-    let dorms_collection = db.collection::<Dorm>("dorms");
-    if let Ok(None) = dorms_collection.find_one(doc! { "_id": &dorm_id }, None).await {
-        return HttpResponse::NotFound().json(doc! {
+    let school_id = match roles::dorm_scope(&db, dorm_id).await {
+        Ok(Some(scope)) => {
+            if let Err(denied) = authorize(&principal, Permission::ManageRooms, &scope) {
+                return denied;
+            }
+            match scope {
+                Scope::Dorm { school_id, .. } => school_id,
+                _ => None,
+            }
+        }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
             "error": "Dorm not found"
-        });
-    }
+        }),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to create room"
+            });
+        }
+    };
+
+    let floor = req.floor.or_else(|| Room::floor_of(&req.number));
+    let attributes = normalize_attributes(&req.attributes);
 
     // Create the new room with proper initialization
     let new_room = Room {
@@ -627,13 +921,32 @@ async fn create_room(
         number: req.number.clone(),
         capacity: req.capacity,
         current_students: Vec::new(),
+        import_batch: None,
+        floor,
+        attributes: attributes.clone(),
     };
 
     match rooms_collection.insert_one(new_room, None).await {
         Ok(result) => {
             let room_id = result.inserted_id.as_object_id()
                 .expect("MongoDB should have generated an ObjectId");
-                
+
+            audit::record(
+                &db,
+                AuditEntry::new("room_created")
+                    .actor(&principal.email)
+                    .school(school_id)
+                    .target(&room_id.to_hex())
+                    .request(&http_req)
+                    .diff(None, Some(&doc! {
+                        "dorm_id": dorm_id,
+                        "number": &req.number,
+                        "capacity": req.capacity,
+                        "floor": floor,
+                        "attributes": &attributes,
+                    })),
+            )
+            .await;
             HttpResponse::Ok().json(doc! {
                 "id": room_id,
                 "message": "Room created successfully"
@@ -650,10 +963,12 @@ async fn create_room(
 
 #[post("/admin/students")]
 async fn create_student(
+    http_req: HttpRequest,
     req: web::Json<CreateStudentRequest>,
     db: web::Data<Database>,
+    principal: Principal,
 ) -> impl Responder {
-    let users_collection = db.collection::<User>("users");
+    let accounts_collection = accounts::collection(&db);
     
     let school_id = match ObjectId::parse_str(&req.school_id) {
         Ok(oid) => oid,
@@ -661,10 +976,17 @@ async fn create_student(
             "error": "Invalid school ID"
         }),
     };
+    if let Err(denied) = authorize(&principal, Permission::CreateStudents, &Scope::School(school_id)) {
+        return denied;
+    }
 
-    // Check if user already exists
-    if let Ok(Some(_)) = users_collection
-        .find_one(doc! { "email": &req.email }, None)
+    if let Err(error) = passwords::check_new_password(&req.password) {
+        return HttpResponse::BadRequest().json(doc! { "error": error });
+    }
+
+    // Emails only have to be unique within a school
+    if let Ok(Some(_)) = accounts_collection
+        .find_one(doc! { "email": &req.email, "school_id": school_id }, None)
         .await 
     {
         return HttpResponse::BadRequest().json(doc! {
@@ -672,18 +994,49 @@ async fn create_student(
         });
     }
 
-    let new_user = User {
-        id: None,
-        email: req.email.clone(),
-        password: req.password.clone(), // In production, hash this password
-        assigned_room: None,
+    if let Some(student_id) = req.student_id {
+        if let Ok(Some(_)) = accounts_collection
+            .find_one(doc! { "student_id": student_id, "school_id": school_id }, None)
+            .await
+        {
+            return HttpResponse::BadRequest().json(doc! {
+                "error": "Student with this student ID already exists"
+            });
+        }
+    }
+
+    let new_user = Account {
+        name: req.name.clone(),
+        student_id: req.student_id,
+        ..Account::student(
+            req.email.clone(),
+            passwords::hash_password(&req.password),
+            Some(school_id),
+        )
     };
 
-    match users_collection.insert_one(new_user, None).await {
-        Ok(result) => HttpResponse::Ok().json(doc! {
-            "id": result.inserted_id,
-            "message": "Student created successfully"
-        }),
+    match accounts_collection.insert_one(new_user, None).await {
+        Ok(result) => {
+            audit::record(
+                &db,
+                AuditEntry::new("student_created")
+                    .actor(&principal.email)
+                    .school(Some(school_id))
+                    .target(&result.inserted_id.as_object_id().map(|id| id.to_hex()).unwrap_or_default())
+                    .request(&http_req)
+                    .diff(None, Some(&doc! {
+                        "email": &req.email,
+                        "school_id": school_id,
+                        "name": &req.name,
+                        "student_id": req.student_id,
+                    })),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "id": result.inserted_id,
+                "message": "Student created successfully"
+            })
+        },
         Err(e) => {
             println!("Failed to create student: {:?}", e);
             HttpResponse::InternalServerError().json(doc! {
@@ -693,6 +1046,80 @@ async fn create_student(
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct StudentSearchQuery {
+    school_id: String,
+    // Matches student ids exactly, and names and emails by substring
+    q: Option<String>,
+}
+
+// Escapes text for use inside a MongoDB regular expression
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn student_search_filter(school_id: ObjectId, q: Option<&str>) -> Document {
+    let mut filter = doc! { "school_id": school_id, "roles.role": "student" };
+    let Some(q) = q.map(str::trim).filter(|q| !q.is_empty()) else {
+        return filter;
+    };
+    let pattern = doc! { "$regex": escape_regex(q), "$options": "i" };
+    let mut any = vec![doc! { "name": pattern.clone() }, doc! { "email": pattern }];
+    if let Ok(student_id) = q.parse::<i32>() {
+        any.push(doc! { "student_id": student_id });
+    }
+    filter.insert("$or", any);
+    filter
+}
+
+// Finds a school's students by name, email or student id
+#[get("/admin/students")]
+async fn search_students(
+    query: web::Query<StudentSearchQuery>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_id = match ObjectId::parse_str(&query.school_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageStudents, &Scope::School(school_id)) {
+        return denied;
+    }
+
+    let page = match Page::new(&page, &["name", "email", "student_id"], "name") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    let filter = student_search_filter(school_id, query.q.as_deref());
+    let collection = db.collection::<Document>("accounts");
+    let students = page.respond(&collection, filter, |account: Account| doc! {
+        "_id": account.id,
+        "email": account.email,
+        "name": account.name,
+        "student_id": account.student_id,
+        "assigned_room": account.assigned_room,
+        "deactivated": account.deactivated_at.is_some(),
+    });
+    match students.await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error searching students: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 // Add this helper function to initialize a test school if it doesn't exist
 async fn initialize_test_school(db: &Database) -> Result<ObjectId, Box<dyn Error>> {
     let schools_collection = db.collection::<School>("schools");
@@ -710,7 +1137,8 @@ async fn initialize_test_school(db: &Database) -> Result<ObjectId, Box<dyn Error
     let test_school = School {
         id: None,
         name: "Test School".to_string(),
-        
+        require_admin_2fa: false,
+        oidc: None,
     };
  
     match schools_collection.insert_one(test_school, None).await {
@@ -724,110 +1152,6 @@ async fn initialize_test_school(db: &Database) -> Result<ObjectId, Box<dyn Error
         },
     }
 }
-// Add this new struct at the top with your other structs
-#[derive(Debug, Deserialize)]
-struct RoomImportRequest {
-    dorm_id: String,
-    room_data: serde_json::Value,  // Raw JSON data
-}
-
-#[derive(Debug, Deserialize)]
-struct StudentData {
-    name: String,
-    id: i32,
-}
-
-// Add this new route handler
-#[post("/admin/import-rooms")]
-async fn import_rooms(
-    req: web::Json<RoomImportRequest>,
-    db: web::Data<Database>,
-) -> impl Responder {
-    let rooms_collection = db.collection::<Room>("rooms");
-    let users_collection = db.collection::<User>("users");
-    
-    // Parse the dorm_id
-    let dorm_id = match ObjectId::parse_str(&req.dorm_id) {
-        Ok(oid) => oid,
-        Err(_) => return HttpResponse::BadRequest().json(doc! {
-            "error": "Invalid dorm ID format"
-        }),
-    };
-
-    // Parse the room_data JSON
-    let room_data: std::collections::HashMap<String, Vec<StudentData>> = 
-        match serde_json::from_value(req.room_data.clone()) {
-            Ok(data) => data,
-            Err(e) => return HttpResponse::BadRequest().json(doc! {
-                "error": format!("Invalid JSON format: {}", e)
-            }),
-        };
-
-    let mut created_rooms = 0;
-    let mut created_students = 0;
-
-    for (room_number, students) in room_data {
-        // Create the room
-        let new_room = Room {
-            id: None,
-            dorm_id,
-            number: room_number.clone(),
-            capacity: students.len() as i32,
-            current_students: vec![],  // Will be populated after creating students
-        };
-
-        let room_result = match rooms_collection.insert_one(new_room, None).await {
-            Ok(result) => result,
-            Err(e) => {
-                println!("Failed to create room {}: {:?}", room_number, e);
-                continue;
-            }
-        };
-
-        created_rooms += 1;
-
-        // Create students and add them to the room
-        let mut room_students = Vec::new();
-        for student in students {
-            // Create user account for the student
-            let student_email = format!("student{}@example.com", student.id);
-            let new_user = User {
-                id: None,
-                email: student_email.clone(),
-                password: format!("pass{}", student.id),  // Simple password generation
-                assigned_room: Some(room_number.clone()),
-            };
-
-            match users_collection.insert_one(new_user, None).await {
-                Ok(_) => {
-                    created_students += 1;
-                    room_students.push(Student {
-                        id: None,
-                        name: student_email,
-                    });
-                }
-                Err(e) => println!("Failed to create student {}: {:?}", student.id, e),
-            }
-        }
-
-        // Update room with student list
-        let student_bson = to_bson(&room_students).expect("Failed to serialize students");
-        let _ = rooms_collection
-            .update_one(
-                doc! { "_id": room_result.inserted_id },
-                doc! { "$set": { "current_students": student_bson } },
-                None,
-            )
-            .await;
-    }
-
-    HttpResponse::Ok().json(doc! {
-        "message": "Import completed successfully",
-        "rooms_created": created_rooms,
-        "students_created": created_students,
-    })
-}
-
 // Add the new route to your main function's App builder
 
 // Update your main function to initialize the test school
@@ -839,13 +1163,43 @@ async fn main() -> std::io::Result<()> {
     let mongo_uri = std::env::var("MONGODB_URI")
         .unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
 
+    // How long to wait for in-flight requests, then for background jobs,
+    // before giving up on a graceful shutdown
+    let shutdown_timeout = std::env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(30);
+
+    // How often to look for duplicate student accounts
+    let duplicate_scan_interval = std::env::var("DUPLICATE_SCAN_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(24 * 60 * 60);
+
+    // How often to check the certificate files for changes when serving TLS
+    let tls_reload_interval = std::env::var("TLS_RELOAD_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .unwrap_or(10);
+
     println!("Connecting to MongoDB...");
     
-    let db = web::Data::new(
-        get_db(mongo_uri)
-            .await
-            .expect("Failed to connect to MongoDB"),
-    );
+    let client = get_client(mongo_uri)
+        .await
+        .expect("Failed to connect to MongoDB");
+    let db = web::Data::new(client.database("dorm_management"));
+    let jobs = web::Data::new(BackgroundJobs::new());
+    let login_guard = web::Data::new(LoginGuard::new(LoginPolicy::from_env()));
+    let mailer: web::Data<dyn Mailer> = web::Data::from(mail::mailer_from_env());
+    let ocr_provider: web::Data<dyn ocr::OcrProvider> = web::Data::from(ocr::ocr_from_env());
+    let oidc_client = web::Data::new(oidc::OidcClient::new());
+
+    if let Err(e) = accounts::migrate_accounts(&db).await {
+        println!("Error migrating accounts: {:?}", e);
+    }
+    if let Err(e) = LoginGuard::create_index(&db).await {
+        println!("Error creating login lockout index: {:?}", e);
+    }
 
     // Initialize test data and school
     //initialize_test_data(&db).await;
@@ -857,9 +1211,13 @@ async fn main() -> std::io::Result<()> {
         Ok(admin_id) => println!("Test admin ID: {}", admin_id),
         Err(e) => println!("Error initializing test school: {:?}", e),
     }
+    duplicates::spawn_scanner(db.get_ref().clone(), Duration::from_secs(duplicate_scan_interval));
     println!("Starting HTTP server...");
 
-    HttpServer::new(move || {
+    // Actix stops accepting connections on SIGTERM/SIGINT and drains the
+    // workers for up to `shutdown_timeout` seconds before returning here
+    let app_jobs = jobs.clone();
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -867,25 +1225,115 @@ async fn main() -> std::io::Result<()> {
             .supports_credentials();
 
         App::new()
+            .wrap(from_fn(audit::tag_request))
             .wrap(Logger::default())
             .wrap(cors)
             .app_data(db.clone())
+            .app_data(app_jobs.clone())
+            .app_data(login_guard.clone())
+            .app_data(mailer.clone())
+            .app_data(ocr_provider.clone())
+            .app_data(oidc_client.clone())
             .service(
                 web::scope("/api")
-                    .route("/login", web::post().to(login))
+                    .service(accounts::login)
                     .service(get_dorms)
                     .service(get_rooms)
-                    .service(get_user)
+                    .service(accounts::get_user)
                     .service(assign_room)
                     .service(unassign_room)
-                    .service(admin_login)
+                    .service(accounts::admin_login)
+                    .service(accounts::login_second_factor)
                     .service(create_dorm)
                     .service(create_room)
                     .service(create_student)
-                    .service(import_rooms),
+                    .service(search_students)
+                    .service(duplicates::start_scan)
+                    .service(duplicates::list_duplicates)
+                    .service(duplicates::dismiss_duplicate)
+                    .service(duplicates::merge_accounts)
+                    .service(imports::import_rooms)
+                    .service(spreadsheets::import_spreadsheet)
+                    .service(room_sheets::import_ocr_text)
+                    .service(room_sheets::import_images)
+                    .service(imports::list_batches)
+                    .service(imports::get_batch)
+                    .service(imports::commit_batch)
+                    .service(imports::rollback_batch)
+                    .service(roster_diff::roster_diff)
+                    .service(roster_diff::apply_roster_diff)
+                    .service(export::export_rosters)
+                    .service(rate_limit::list_lockouts)
+                    .service(rate_limit::clear_lockout)
+                    .service(credentials::download_credential_sheet)
+                    .service(credentials::accept_invite)
+                    .service(passwords::change_password)
+                    .service(passwords::forgot_password)
+                    .service(passwords::reset_password)
+                    .service(roles::set_roles)
+                    .service(update_school_settings)
+                    .service(two_factor::setup)
+                    .service(two_factor::confirm)
+                    .service(two_factor::regenerate_recovery_codes)
+                    .service(two_factor::disable)
+                    .service(oidc::start_login)
+                    .service(oidc::callback)
+                    .service(oidc::update_sso_settings)
+                    .service(sessions::list_sessions)
+                    .service(sessions::revoke_session)
+                    .service(sessions::revoke_other_sessions)
+                    .service(sessions::revoke_account_sessions)
+                    .service(api_keys::create_key)
+                    .service(api_keys::list_keys)
+                    .service(api_keys::revoke_key)
+                    .service(audit::query_log)
+                    .service(scim::create_token)
+                    .service(scim::list_tokens)
+                    .service(scim::revoke_token)
+                    .service(
+                        web::scope("/scim/v2")
+                            .app_data(scim::json_config())
+                            .service(scim::list_users)
+                            .service(scim::get_user)
+                            .service(scim::create_user)
+                            .service(scim::replace_user)
+                            .service(scim::update_user)
+                            .service(scim::delete_user)
+                            .service(scim::list_groups)
+                            .service(scim::get_group)
+                            .service(scim::create_group)
+                            .service(scim::replace_group)
+                            .service(scim::update_group)
+                            .service(scim::delete_group)
+                            .service(scim::service_provider_config),
+                    ),
             )
     })
-    .bind("127.0.0.1:3000")?
-    .run()
-    .await
-}
\ No newline at end of file
+    .shutdown_timeout(shutdown_timeout);
+
+    let server = match TlsPaths::from_env() {
+        Some(paths) => {
+            let resolver = Arc::new(
+                ReloadingCertResolver::new(paths).expect("Failed to load TLS certificate"),
+            );
+            tls::spawn_reloader(resolver.clone(), Duration::from_secs(tls_reload_interval));
+            println!("Serving HTTPS (HTTP/2 and HTTP/1.1)");
+            server.bind_rustls_0_23("127.0.0.1:3000", tls::server_config(resolver))?
+        }
+        None => server.bind("127.0.0.1:3000")?,
+    };
+
+    server.run().await?;
+
+    println!("HTTP server stopped");
+    if !jobs.flush(Duration::from_secs(shutdown_timeout)).await {
+        println!("Timed out waiting for background jobs");
+    }
+
+    // The server's app data has been dropped by now, so nothing else holds
+    // the client and shutdown can complete
+    client.shutdown().await;
+    println!("MongoDB client closed");
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+// Text recognition for photos of room sheets. Which engine does the work is
+// configured per deployment; the room sheet parser only sees the text.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+pub type OcrError = Box<dyn Error + Send + Sync>;
+
+// Anything that can read the text in an image. Recognition runs on a
+// blocking thread, so implementations may block.
+pub trait OcrProvider: Send + Sync {
+    fn recognize(&self, image: &[u8]) -> Result<String, OcrError>;
+}
+
+// Runs Tesseract on this machine, so photos never leave the server
+pub struct TesseractOcr {
+    binary: PathBuf,
+    language: String,
+}
+
+impl TesseractOcr {
+    pub fn new(binary: PathBuf, language: String) -> Self {
+        TesseractOcr { binary, language }
+    }
+}
+
+impl OcrProvider for TesseractOcr {
+    fn recognize(&self, image: &[u8]) -> Result<String, OcrError> {
+        let mut child = Command::new(&self.binary)
+            .args(["stdin", "stdout", "-l", &self.language])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run {}: {}", self.binary.display(), e))?;
+        // Tesseract reads the whole image before writing anything, so this
+        // can't fill the output pipe and stall
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(image)?;
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("Tesseract failed ({}): {}", output.status, stderr.trim()).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+// Answers with prepared text for known images, keyed by the SHA-256 of the
+// image. For tests, and for demos without an OCR engine installed.
+pub struct FixtureOcr {
+    texts: HashMap<String, String>,
+}
+
+impl FixtureOcr {
+    pub fn new(fixtures: &[(&[u8], &str)]) -> Self {
+        FixtureOcr {
+            texts: fixtures
+                .iter()
+                .map(|(image, text)| (image_hash(image), text.to_string()))
+                .collect(),
+        }
+    }
+
+    // Every `<sha256 of the image>.txt` in the directory
+    pub fn from_dir(dir: &Path) -> std::io::Result<Self> {
+        let mut texts = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "txt") {
+                if let Some(hash) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    texts.insert(hash.to_ascii_lowercase(), std::fs::read_to_string(&path)?);
+                }
+            }
+        }
+        Ok(FixtureOcr { texts })
+    }
+}
+
+impl OcrProvider for FixtureOcr {
+    fn recognize(&self, image: &[u8]) -> Result<String, OcrError> {
+        let hash = image_hash(image);
+        self.texts
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| format!("No OCR fixture for image {}", hash).into())
+    }
+}
+
+fn image_hash(image: &[u8]) -> String {
+    hex::encode(Sha256::digest(image))
+}
+
+// OCR_PROVIDER=fixtures reads fixtures from OCR_FIXTURES (default
+// ./ocr-fixtures); anything else runs Tesseract (OCR_TESSERACT, default
+// `tesseract` on the PATH) in OCR_LANGUAGE (default eng)
+pub fn ocr_from_env() -> Arc<dyn OcrProvider> {
+    match std::env::var("OCR_PROVIDER").as_deref() {
+        Ok("fixtures") => {
+            let dir = std::env::var("OCR_FIXTURES").unwrap_or_else(|_| "ocr-fixtures".to_string());
+            println!("Reading OCR results from fixtures in {}", dir);
+            match FixtureOcr::from_dir(Path::new(&dir)) {
+                Ok(fixtures) => Arc::new(fixtures),
+                Err(e) => {
+                    println!("Failed to read OCR fixtures from {}: {:?}", dir, e);
+                    Arc::new(FixtureOcr::new(&[]))
+                }
+            }
+        }
+        _ => {
+            let binary = std::env::var("OCR_TESSERACT").unwrap_or_else(|_| "tesseract".to_string());
+            let language = std::env::var("OCR_LANGUAGE").unwrap_or_else(|_| "eng".to_string());
+            Arc::new(TesseractOcr::new(PathBuf::from(binary), language))
+        }
+    }
+}
+
+// Recognises images on a blocking thread
+pub async fn recognize(provider: Arc<dyn OcrProvider>, image: Vec<u8>) -> Result<String, OcrError> {
+    tokio::task::spawn_blocking(move || provider.recognize(&image))
+        .await
+        .map_err(|e| format!("OCR job panicked: {:?}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_answer_for_known_images_only() {
+        let ocr = FixtureOcr::new(&[(b"photo of 513", "Room-513\n1. Rezwan (170)\n")]);
+
+        assert_eq!(ocr.recognize(b"photo of 513").unwrap(), "Room-513\n1. Rezwan (170)\n");
+        assert!(ocr
+            .recognize(b"another photo")
+            .unwrap_err()
+            .to_string()
+            .starts_with("No OCR fixture for image "));
+    }
+
+    #[test]
+    fn fixtures_are_read_from_a_directory() {
+        let dir = std::env::temp_dir().join(format!("ocr-fixtures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("{}.txt", image_hash(b"image"))), "Room-101\n").unwrap();
+        std::fs::write(dir.join("notes.md"), "not a fixture").unwrap();
+
+        let ocr = FixtureOcr::from_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(ocr.texts.len(), 1);
+        assert_eq!(ocr.recognize(b"image").unwrap(), "Room-101\n");
+    }
+}
@@ -0,0 +1,834 @@
+use std::time::Duration;
+
+use actix_web::{cookie::Cookie, get, put, web, HttpRequest, HttpResponse, Responder};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, DateTime},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::accounts::{self, Account};
+use crate::audit::{self, AuditEntry};
+use crate::auth;
+use crate::mail;
+use crate::passwords;
+use crate::rate_limit::client_ip;
+use crate::roles::{authorize, Permission, Principal, Scope};
+use crate::tokens;
+use crate::two_factor;
+use crate::School;
+
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+// Ties a login to the browser that started it, holding the state's hash
+const STATE_COOKIE: &str = "sso_state";
+
+// A school's identity provider, set through PUT /admin/schools/{id}/sso
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    // Discovery is done from `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub claims: ClaimMapping,
+    // Email domains allowed to sign in, e.g. ["students.example.edu"]; empty
+    // allows any
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+}
+
+// Which ID token claims hold what we need, for providers that don't use the
+// standard names
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClaimMapping {
+    #[serde(default = "default_email_claim")]
+    pub email: String,
+    // Boolean claim that must be true; null skips the check
+    #[serde(default = "default_email_verified_claim")]
+    pub email_verified: Option<String>,
+}
+
+fn default_email_claim() -> String {
+    "email".to_string()
+}
+
+fn default_email_verified_claim() -> Option<String> {
+    Some("email_verified".to_string())
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        ClaimMapping {
+            email: default_email_claim(),
+            email_verified: default_email_verified_claim(),
+        }
+    }
+}
+
+// The parts of the provider's discovery document we use
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Talks to identity providers. Shared through app data so connections are
+// reused.
+pub struct OidcClient {
+    http: reqwest::Client,
+}
+
+impl OidcClient {
+    pub fn new() -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to build HTTP client");
+        OidcClient { http }
+    }
+
+    async fn discover(&self, issuer: &str) -> Result<ProviderMetadata, String> {
+        let issuer = issuer.trim_end_matches('/');
+        let url = format!("{}/.well-known/openid-configuration", issuer);
+        let metadata: ProviderMetadata = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to fetch {}: {}", url, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid discovery document at {}: {}", url, e))?;
+
+        // Per the spec the document has to name the issuer we asked
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(format!(
+                "Discovery document is for issuer {}, not {}",
+                metadata.issuer, issuer
+            ));
+        }
+        Ok(metadata)
+    }
+
+    async fn exchange_code(
+        &self,
+        metadata: &ProviderMetadata,
+        config: &OidcConfig,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<String, String> {
+        let redirect_uri = redirect_uri();
+        let response: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("client_id", config.client_id.as_str()),
+                ("client_secret", config.client_secret.as_str()),
+                ("code_verifier", code_verifier),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Token request failed: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+        Ok(response.id_token)
+    }
+
+    async fn fetch_jwks(&self, metadata: &ProviderMetadata) -> Result<JwkSet, String> {
+        self.http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to fetch signing keys: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid signing keys: {}", e))
+    }
+}
+
+// Where providers send the browser back to; has to be registered with each
+// provider
+fn redirect_uri() -> String {
+    std::env::var("SSO_REDIRECT_URI")
+        .unwrap_or_else(|_| "http://localhost:3000/api/sso/callback".to_string())
+}
+
+// Lax, so it comes back with the identity provider's redirect but not with
+// requests other sites make in the background
+fn state_cookie(state: &str) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, tokens::hash_token(state))
+        .path("/")
+        .http_only(true)
+        .secure(redirect_uri().starts_with("https://"))
+        .same_site(actix_web::cookie::SameSite::Lax)
+        .max_age(actix_web::cookie::time::Duration::seconds(PENDING_LOGIN_TTL.as_secs() as i64))
+        .finish()
+}
+
+// Whether the callback's state is the one this browser was given. Without
+// this, someone could start a login and have a victim finish it, signing the
+// victim in as them.
+fn state_matches(cookie: Option<&str>, state: &str) -> bool {
+    cookie.is_some_and(|hash| hash == tokens::hash_token(state))
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()))
+}
+
+fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+// Checks the ID token's signature, issuer, audience, expiry and nonce, and
+// returns its claims. HMAC-signed tokens are verified with the client secret,
+// anything else with the provider's published keys.
+fn verify_id_token(
+    id_token: &str,
+    config: &OidcConfig,
+    jwks: Option<&JwkSet>,
+    nonce: &str,
+) -> Result<Map<String, Value>, String> {
+    let header = jsonwebtoken::decode_header(id_token).map_err(|e| format!("Invalid ID token: {}", e))?;
+
+    let key = if is_hmac(header.alg) {
+        DecodingKey::from_secret(config.client_secret.as_bytes())
+    } else {
+        let jwks = jwks.ok_or("Provider signing keys are required")?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or("ID token is signed with an unknown key")?;
+        DecodingKey::from_jwk(jwk).map_err(|e| format!("Unusable signing key: {}", e))?
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[config.issuer.trim_end_matches('/'), config.issuer.as_str()]);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(id_token, &key, &validation)
+        .map_err(|e| format!("ID token rejected: {}", e))?
+        .claims;
+
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        return Err("ID token nonce doesn't match this login".to_string());
+    }
+    Ok(claims)
+}
+
+// The identity's `sub`, which accounts are linked by. Without one, every
+// such identity would link to the same account.
+fn subject(claims: &Map<String, Value>) -> Option<&str> {
+    claims.get("sub").and_then(Value::as_str).filter(|subject| !subject.trim().is_empty())
+}
+
+// The verified email from the claims, following the school's mapping
+fn mapped_email(claims: &Map<String, Value>, config: &OidcConfig) -> Result<String, String> {
+    let email = claims
+        .get(&config.claims.email)
+        .and_then(Value::as_str)
+        .map(|email| email.trim().to_lowercase())
+        .filter(|email| email.contains('@'))
+        .ok_or_else(|| format!("ID token has no email in the {} claim", config.claims.email))?;
+
+    if let Some(verified_claim) = &config.claims.email_verified {
+        // A provider that doesn't say the email is verified hasn't verified it
+        if claims.get(verified_claim).and_then(Value::as_bool) != Some(true) {
+            return Err("Email address is not verified by the identity provider".to_string());
+        }
+    }
+
+    let domain = email.rsplit('@').next().unwrap_or_default();
+    if !config.allowed_domains.is_empty()
+        && !config
+            .allowed_domains
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+    {
+        return Err(format!("Email domain {} is not allowed for this school", domain));
+    }
+    Ok(email)
+}
+
+// State for a login between the redirect to the provider and the callback.
+// Only a hash of `state` is stored, like other tokens.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    state_hash: String,
+    nonce: String,
+    code_verifier: String,
+    school_id: ObjectId,
+    created_at: DateTime,
+    expires_at: DateTime,
+}
+
+async fn school_with_sso(db: &Database, school_id: ObjectId) -> Result<Option<(School, OidcConfig)>, mongodb::error::Error> {
+    let school = db
+        .collection::<School>("schools")
+        .find_one(doc! { "_id": school_id }, None)
+        .await?;
+    Ok(school.and_then(|school| {
+        let config = school.oidc.clone()?;
+        Some((school, config))
+    }))
+}
+
+// Starts SSO: redirects the browser to the school's identity provider
+#[get("/sso/{school_id}/login")]
+async fn start_login(
+    school_id: web::Path<String>,
+    db: web::Data<Database>,
+    client: web::Data<OidcClient>,
+) -> impl Responder {
+    let school_oid = match ObjectId::parse_str(school_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+    let config = match school_with_sso(&db, school_oid).await {
+        Ok(Some((_, config))) => config,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Single sign-on is not set up for this school"
+        }),
+        Err(e) => {
+            println!("Error finding school: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    let metadata = match client.discover(&config.issuer).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            println!("SSO discovery failed for school {}: {}", school_oid, e);
+            return HttpResponse::BadGateway().json(doc! {
+                "error": "The school's identity provider is unavailable"
+            });
+        }
+    };
+
+    let state = tokens::generate_token();
+    let nonce = tokens::generate_token();
+    let code_verifier = tokens::generate_token();
+    let now = DateTime::now();
+    let pending = PendingLogin {
+        id: None,
+        state_hash: tokens::hash_token(&state),
+        nonce: nonce.clone(),
+        code_verifier: code_verifier.clone(),
+        school_id: school_oid,
+        created_at: now,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + PENDING_LOGIN_TTL.as_millis() as i64),
+    };
+    let pending_logins = db.collection::<PendingLogin>("sso_logins");
+    // Abandoned logins would otherwise pile up
+    let _ = pending_logins
+        .delete_many(doc! { "expires_at": { "$lte": now } }, None)
+        .await;
+    if let Err(e) = pending_logins.insert_one(pending, None).await {
+        println!("Error saving SSO login: {:?}", e);
+        return HttpResponse::InternalServerError().json(doc! {
+            "error": "Internal server error"
+        });
+    }
+
+    let mut location = match reqwest::Url::parse(&metadata.authorization_endpoint) {
+        Ok(url) => url,
+        Err(e) => {
+            println!("Invalid authorization endpoint for school {}: {}", school_oid, e);
+            return HttpResponse::BadGateway().json(doc! {
+                "error": "The school's identity provider is misconfigured"
+            });
+        }
+    };
+    location
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &redirect_uri())
+        .append_pair("scope", "openid email profile")
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &pkce_challenge(&code_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    HttpResponse::Found()
+        .insert_header(("Location", location.as_str()))
+        .cookie(state_cookie(&state))
+        .finish()
+}
+
+// Sends the browser back to the frontend. Results go in the fragment so they
+// never reach server logs.
+fn back_to_app(fragment: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header(("Location", format!("{}/sso#{}", mail::app_base_url(), fragment)))
+        .finish()
+}
+
+fn sso_error(message: &str) -> HttpResponse {
+    back_to_app(&format!("error={}", urlencoding::encode(message)))
+}
+
+// Whether a first sign-in may take over an existing account with the same
+// email. Staff accounts carry more than a student's access, so an identity
+// provider vouching for the email isn't enough for them.
+fn linkable_by_email(account: &Account) -> Result<(), &'static str> {
+    if !account.is_active() {
+        return Err("This account has been deactivated");
+    }
+    if account.sso_subject.is_some() {
+        return Err("This account is already linked to a different sign-in");
+    }
+    if account.is_staff() {
+        return Err("Staff accounts can't be linked to single sign-on by email");
+    }
+    Ok(())
+}
+
+// Finds the account for an identity, linking by email on first sign-in and
+// creating a student account if the school has none for that email
+async fn link_account(
+    db: &Database,
+    school_id: ObjectId,
+    subject: &str,
+    email: &str,
+    ip: Option<&str>,
+) -> Result<Result<Account, &'static str>, mongodb::error::Error> {
+    let accounts_collection = accounts::collection(db);
+
+    if let Some(account) = accounts_collection
+        .find_one(doc! { "school_id": school_id, "sso_subject": subject }, None)
+        .await?
+    {
+        let account = accounts::follow_merge(db, account).await?;
+        if !account.is_active() {
+            return Ok(Err("This account has been deactivated"));
+        }
+        return Ok(Ok(account));
+    }
+
+    if let Some(account) = accounts_collection
+        .find_one(doc! { "school_id": school_id, "email": email }, None)
+        .await?
+    {
+        // Someone merged as a duplicate signs in to the account they were
+        // merged into
+        let mut account = accounts::follow_merge(db, account).await?;
+        if let Err(message) = linkable_by_email(&account) {
+            return Ok(Err(message));
+        }
+        accounts_collection
+            .update_one(
+                doc! { "_id": account.id },
+                doc! { "$set": { "sso_subject": subject } },
+                None,
+            )
+            .await?;
+        account.sso_subject = Some(subject.to_string());
+
+        audit::record(
+            db,
+            AuditEntry::new("sso_account_linked")
+                .actor(email)
+                .school(Some(school_id))
+                .target(&account.id.expect("Stored accounts have an ID").to_hex())
+                .ip(ip),
+        )
+        .await;
+        return Ok(Ok(account));
+    }
+
+    // Nobody knows this password; the account is only reachable through SSO
+    // unless someone resets it
+    let password = passwords::generate_password(32);
+    let mut account = Account {
+        sso_subject: Some(subject.to_string()),
+        ..Account::student(email.to_string(), passwords::hash_password(&password), Some(school_id))
+    };
+    let result = accounts_collection.insert_one(&account, None).await?;
+    account.id = result.inserted_id.as_object_id();
+
+    audit::record(
+        db,
+        AuditEntry::new("sso_account_created")
+            .actor(email)
+            .school(Some(school_id))
+            .target(&account.id.expect("Inserted accounts have an ID").to_hex())
+            .ip(ip),
+    )
+    .await;
+    Ok(Ok(account))
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+// Where the identity provider sends the browser back to. Ends with a
+// redirect to the frontend carrying either a session token, a 2FA challenge
+// or an error.
+#[get("/sso/callback")]
+async fn callback(
+    req: HttpRequest,
+    query: web::Query<CallbackQuery>,
+    db: web::Data<Database>,
+    client: web::Data<OidcClient>,
+) -> impl Responder {
+    if let Some(error) = &query.error {
+        println!("Identity provider returned an error: {}", error);
+        return sso_error("Sign-in was cancelled or refused");
+    }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return sso_error("Sign-in response is incomplete");
+    };
+    let cookie = req.cookie(STATE_COOKIE);
+    if !state_matches(cookie.as_ref().map(Cookie::value), state) {
+        return sso_error("Sign-in was started in another browser, please try again");
+    }
+
+    // Deleting it makes the state single use
+    let pending = match db
+        .collection::<PendingLogin>("sso_logins")
+        .find_one_and_delete(
+            doc! {
+                "state_hash": tokens::hash_token(state),
+                "expires_at": { "$gt": DateTime::now() },
+            },
+            None,
+        )
+        .await
+    {
+        Ok(Some(pending)) => pending,
+        Ok(None) => return sso_error("Sign-in expired, please try again"),
+        Err(e) => {
+            println!("Error finding SSO login: {:?}", e);
+            return sso_error("Something went wrong, please try again");
+        }
+    };
+
+    let config = match school_with_sso(&db, pending.school_id).await {
+        Ok(Some((_, config))) => config,
+        Ok(None) => return sso_error("Single sign-on is not set up for this school"),
+        Err(e) => {
+            println!("Error finding school: {:?}", e);
+            return sso_error("Something went wrong, please try again");
+        }
+    };
+
+    let claims = async {
+        let metadata = client.discover(&config.issuer).await?;
+        let id_token = client
+            .exchange_code(&metadata, &config, code, &pending.code_verifier)
+            .await?;
+        let header = jsonwebtoken::decode_header(&id_token).map_err(|e| format!("Invalid ID token: {}", e))?;
+        let jwks = if is_hmac(header.alg) {
+            None
+        } else {
+            Some(client.fetch_jwks(&metadata).await?)
+        };
+        verify_id_token(&id_token, &config, jwks.as_ref(), &pending.nonce)
+    }
+    .await;
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => {
+            println!("SSO login failed for school {}: {}", pending.school_id, e);
+            return sso_error("Sign-in could not be verified");
+        }
+    };
+
+    let email = match mapped_email(&claims, &config) {
+        Ok(email) => email,
+        Err(e) => {
+            println!("SSO login refused for school {}: {}", pending.school_id, e);
+            return sso_error(&e);
+        }
+    };
+    let Some(subject) = subject(&claims) else {
+        println!("SSO login refused for school {}: ID token has no subject", pending.school_id);
+        return sso_error("Sign-in could not be verified");
+    };
+
+    let ip = client_ip(&req);
+    let account = match link_account(&db, pending.school_id, subject, &email, ip.as_deref()).await {
+        Ok(Ok(account)) => account,
+        Ok(Err(refused)) => return sso_error(refused),
+        Err(e) => {
+            println!("Error linking SSO account: {:?}", e);
+            return sso_error("Something went wrong, please try again");
+        }
+    };
+    let account_id = account.id.expect("Stored accounts have an ID");
+
+    // The identity provider replaces the password, not the second factor
+    if account.has_two_factor() {
+        return match tokens::issue(&db, "login_2fa", account_id, two_factor::CHALLENGE_TTL).await {
+            Ok(challenge) => back_to_app(&format!("challenge={}", challenge)),
+            Err(e) => {
+                println!("Error issuing 2FA challenge: {:?}", e);
+                sso_error("Something went wrong, please try again")
+            }
+        };
+    }
+    match two_factor::school_requires_2fa(&db, account.school_id).await {
+        Ok(true) if account.is_staff() => {
+            return sso_error("Your school requires two-factor authentication; log in with your password to set it up");
+        }
+        Ok(_) => {}
+        Err(e) => {
+            println!("Error finding school: {:?}", e);
+            return sso_error("Something went wrong, please try again");
+        }
+    }
+
+    match auth::create_session(&db, account_id, &req).await {
+        Ok(token) => back_to_app(&format!("token={}", token)),
+        Err(e) => {
+            println!("Error creating session: {:?}", e);
+            sso_error("Something went wrong, please try again")
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SsoSettingsRequest {
+    // Null turns SSO off
+    oidc: Option<OidcConfig>,
+}
+
+// Sets or removes a school's identity provider. The provider's discovery
+// document is fetched first so a typo in the issuer fails here rather than
+// on the first student's login.
+#[put("/admin/schools/{school_id}/sso")]
+async fn update_sso_settings(
+    http_req: HttpRequest,
+    school_id: web::Path<String>,
+    req: web::Json<SsoSettingsRequest>,
+    db: web::Data<Database>,
+    client: web::Data<OidcClient>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match ObjectId::parse_str(school_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageSchoolSettings, &Scope::School(school_oid)) {
+        return denied;
+    }
+
+    if let Some(config) = &req.oidc {
+        if let Err(e) = client.discover(&config.issuer).await {
+            return HttpResponse::BadRequest().json(doc! {
+                "error": format!("Couldn't reach the identity provider: {}", e)
+            });
+        }
+    }
+
+    let oidc = to_bson(&req.oidc).expect("Failed to serialize SSO settings");
+    match db
+        .collection::<School>("schools")
+        .update_one(doc! { "_id": school_oid }, doc! { "$set": { "oidc": oidc } }, None)
+        .await
+    {
+        Ok(result) if result.matched_count == 0 => HttpResponse::NotFound().json(doc! {
+            "error": "School not found"
+        }),
+        Ok(_) => {
+            // Never log the client secret
+            let details = match &req.oidc {
+                Some(config) => doc! { "issuer": &config.issuer, "client_id": &config.client_id },
+                None => doc! { "disabled": true },
+            };
+            audit::record(
+                &db,
+                AuditEntry::new("sso_settings_changed")
+                    .actor(&principal.email)
+                    .school(Some(school_oid))
+                    .target(&school_oid.to_hex())
+                    .request(&http_req)
+                    .details(details),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Single sign-on settings updated"
+            })
+        }
+        Err(e) => {
+            println!("Failed to update SSO settings: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to update single sign-on settings"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use crate::roles::{Role, RoleGrant};
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            issuer: "http://127.0.0.1:9000".to_string(),
+            client_id: "dorms".to_string(),
+            client_secret: "mock-secret".to_string(),
+            claims: ClaimMapping::default(),
+            allowed_domains: vec![],
+        }
+    }
+
+    fn sign(claims: Value, secret: &str) -> String {
+        jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn claims(nonce: &str) -> Value {
+        json!({
+            "iss": "http://127.0.0.1:9000",
+            "aud": "dorms",
+            "sub": "mock|ada",
+            "exp": jsonwebtoken::get_current_timestamp() + 300,
+            "nonce": nonce,
+            "email": "Ada@Example.edu",
+            "email_verified": true,
+        })
+    }
+
+    #[test]
+    fn accepts_a_token_signed_with_the_client_secret() {
+        let token = sign(claims("n1"), "mock-secret");
+        let claims = verify_id_token(&token, &config(), None, "n1").unwrap();
+        assert_eq!(mapped_email(&claims, &config()).unwrap(), "ada@example.edu");
+    }
+
+    #[test]
+    fn rejects_tokens_not_meant_for_this_login() {
+        let config = config();
+        let wrong_secret = sign(claims("n1"), "other-secret");
+        assert!(verify_id_token(&wrong_secret, &config, None, "n1").is_err());
+
+        let token = sign(claims("n1"), "mock-secret");
+        assert!(verify_id_token(&token, &config, None, "n2").is_err());
+
+        let mut other_audience = claims("n1");
+        other_audience["aud"] = json!("someone-else");
+        assert!(verify_id_token(&sign(other_audience, "mock-secret"), &config, None, "n1").is_err());
+
+        let mut other_issuer = claims("n1");
+        other_issuer["iss"] = json!("http://evil.example");
+        assert!(verify_id_token(&sign(other_issuer, "mock-secret"), &config, None, "n1").is_err());
+
+        let mut expired = claims("n1");
+        expired["exp"] = json!(jsonwebtoken::get_current_timestamp() - 3600);
+        assert!(verify_id_token(&sign(expired, "mock-secret"), &config, None, "n1").is_err());
+    }
+
+    #[test]
+    fn the_state_must_come_from_this_browser() {
+        let cookie = state_cookie("s1");
+        assert!(cookie.http_only().unwrap_or(false));
+        assert_eq!(cookie.same_site(), Some(actix_web::cookie::SameSite::Lax));
+        assert!(state_matches(Some(cookie.value()), "s1"));
+        assert!(!state_matches(Some(cookie.value()), "s2"));
+        assert!(!state_matches(None, "s1"));
+    }
+
+    #[test]
+    fn identities_need_a_subject() {
+        let Value::Object(mut claims) = claims("n1") else { unreachable!() };
+        assert_eq!(subject(&claims), Some("mock|ada"));
+        claims.insert("sub".to_string(), json!(""));
+        assert_eq!(subject(&claims), None);
+        claims.insert("sub".to_string(), json!(42));
+        assert_eq!(subject(&claims), None);
+        claims.remove("sub");
+        assert_eq!(subject(&claims), None);
+    }
+
+    #[test]
+    fn maps_claims_and_checks_domains() {
+        let mut config = config();
+        config.claims.email = "upn".to_string();
+        config.allowed_domains = vec!["students.example.edu".to_string()];
+
+        let mut claims = Map::new();
+        claims.insert("upn".to_string(), json!("s123@students.example.edu"));
+        assert!(mapped_email(&claims, &config).is_err());
+
+        claims.insert("email_verified".to_string(), json!(true));
+        assert_eq!(mapped_email(&claims, &config).unwrap(), "s123@students.example.edu");
+
+        claims.insert("upn".to_string(), json!("s123@gmail.com"));
+        assert!(mapped_email(&claims, &config).is_err());
+
+        claims.insert("upn".to_string(), json!("s123@students.example.edu"));
+        claims.insert("email_verified".to_string(), json!(false));
+        assert!(mapped_email(&claims, &config).is_err());
+
+        config.claims.email_verified = None;
+        assert!(mapped_email(&claims, &config).is_ok());
+    }
+
+    #[test]
+    fn only_unlinked_student_accounts_are_linked_by_email() {
+        let mut account = Account::student("ada@example.edu".to_string(), String::new(), None);
+        assert!(linkable_by_email(&account).is_ok());
+
+        account.roles.push(RoleGrant {
+            role: Role::ResidentAssistant,
+            school_id: None,
+            dorm_id: None,
+        });
+        assert!(linkable_by_email(&account).is_err());
+
+        account.roles.truncate(1);
+        account.sso_subject = Some("mock|someone-else".to_string());
+        assert!(linkable_by_email(&account).is_err());
+    }
+
+    // S256: unpadded base64url of the verifier's SHA-256
+    #[test]
+    fn pkce_challenge_is_s256() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r7TA3ElX-jsTHs"),
+            "xIbJcaN21nbfBmFjqd2Bd2OCMF3DRWU057SpBi473V8"
+        );
+    }
+}
@@ -0,0 +1,343 @@
+// Cursor pagination for list endpoints. A list is sorted by one of the
+// endpoint's sort fields and then by _id, and the cursor records where the
+// last page ended, so pages don't shift when documents are added. Every page
+// carries the total matching the filters, and any documents that couldn't be
+// read are listed rather than quietly left out.
+//
+// With `stream` the whole list is streamed instead, in the same order (see
+// streaming.rs).
+use actix_web::HttpResponse;
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, from_document, oid::ObjectId, Bson, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::streaming::{self, Format};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+// The query parameters every list endpoint takes
+#[derive(Debug, Default, Deserialize)]
+pub struct PageQuery {
+    // `next` from the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    // One of the endpoint's sort fields, with a leading "-" for descending,
+    // e.g. "-capacity"
+    pub sort: Option<String>,
+    // "json" or "ndjson" to stream everything from the cursor on, ignoring
+    // `limit`
+    pub stream: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    pub field: &'static str,
+    pub descending: bool,
+}
+
+impl Sort {
+    pub fn parse(value: &str, allowed: &[&'static str]) -> Result<Sort, String> {
+        let (name, descending) = match value.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (value, false),
+        };
+        match allowed.iter().find(|field| **field == name) {
+            Some(field) => Ok(Sort { field, descending }),
+            None => Err(format!("Can't sort by '{}'; use one of {}", name, allowed.join(", "))),
+        }
+    }
+
+    fn direction(&self) -> i32 {
+        if self.descending {
+            -1
+        } else {
+            1
+        }
+    }
+}
+
+// Where the previous page ended: the last document's sort value and ID
+#[derive(Debug, Clone, PartialEq)]
+struct Position {
+    value: Bson,
+    id: ObjectId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    pub sort: Sort,
+    pub limit: i64,
+    pub stream: Option<Format>,
+    after: Option<Position>,
+}
+
+impl Page {
+    // `sorts` are the fields the endpoint can sort by; `default_sort` is
+    // used when the query doesn't pick one
+    #[allow(clippy::result_large_err)]
+    pub fn new(query: &PageQuery, sorts: &[&'static str], default_sort: &str) -> Result<Page, HttpResponse> {
+        let bad_request = |error: String| HttpResponse::BadRequest().json(doc! { "error": error });
+        let sort = Sort::parse(query.sort.as_deref().unwrap_or(default_sort), sorts).map_err(bad_request)?;
+        let after = match &query.cursor {
+            Some(cursor) => Some(decode(cursor, &sort).ok_or_else(|| bad_request("Invalid cursor".to_string()))?),
+            None => None,
+        };
+        let stream = match &query.stream {
+            Some(format) => Some(
+                Format::parse(format).ok_or_else(|| bad_request("Stream must be json or ndjson".to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(Page {
+            sort,
+            limit: query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+            stream,
+            after,
+        })
+    }
+
+    // The filter for this page: `filter`, from where the last page ended.
+    // Documents without the sort field sort before everything else, and a
+    // range query never matches them, so they're matched explicitly.
+    pub fn filter(&self, filter: Document) -> Document {
+        let Some(Position { value, id }) = &self.after else {
+            return filter;
+        };
+        let field = self.sort.field;
+        let beyond = if self.sort.descending { "$lt" } else { "$gt" };
+        let position = if field == "_id" {
+            doc! { "_id": { beyond: id } }
+        } else {
+            let mut any = vec![doc! { field: value.clone(), "_id": { beyond: id } }];
+            match (value, self.sort.descending) {
+                (Bson::Null, false) => any.push(doc! { field: { "$ne": null } }),
+                (Bson::Null, true) => {}
+                (_, false) => any.push(doc! { field: { "$gt": value.clone() } }),
+                (_, true) => {
+                    any.push(doc! { field: { "$lt": value.clone() } });
+                    any.push(doc! { field: null });
+                }
+            }
+            doc! { "$or": any }
+        };
+        if filter.is_empty() {
+            return position;
+        }
+        doc! { "$and": [filter, position] }
+    }
+
+    fn order(&self) -> Document {
+        let mut sort = doc! { self.sort.field: self.sort.direction() };
+        if self.sort.field != "_id" {
+            sort.insert("_id", self.sort.direction());
+        }
+        sort
+    }
+
+    // One more than the page holds, to tell whether there's another page
+    pub fn options(&self) -> FindOptions {
+        FindOptions::builder().sort(self.order()).limit(self.limit + 1).build()
+    }
+
+    // The cursor for the page that starts after `last`
+    fn next(&self, last: &Document) -> Option<String> {
+        let id = last.get_object_id("_id").ok()?;
+        let value = last.get(self.sort.field).cloned().unwrap_or(Bson::Null);
+        let cursor = doc! { "f": self.sort.field, "v": value, "id": id };
+        Some(hex::encode(mongodb::bson::to_vec(&cursor).ok()?))
+    }
+
+    // Reads this page of `collection`, counting everything that matches
+    // `filter`
+    pub async fn fetch<T: DeserializeOwned>(
+        &self,
+        collection: &Collection<Document>,
+        filter: Document,
+    ) -> Result<Listing<T>, mongodb::error::Error> {
+        let total = collection.count_documents(filter.clone(), None).await?;
+        let mut cursor = collection.find(self.filter(filter), self.options()).await?;
+        let mut listing = Listing {
+            items: Vec::new(),
+            total,
+            next: None,
+            invalid: Vec::new(),
+        };
+        let mut read = 0;
+        let mut last = None;
+        while let Some(document) = cursor.next().await {
+            let document = document?;
+            read += 1;
+            if read > self.limit {
+                listing.next = last.as_ref().and_then(|last| self.next(last));
+                break;
+            }
+            listing.read(&document);
+            last = Some(document);
+        }
+        Ok(listing)
+    }
+
+    // The response for this page, or the stream when one was asked for, with
+    // each item shown as `view` shows it
+    pub async fn respond<T, U>(
+        &self,
+        collection: &Collection<Document>,
+        filter: Document,
+        view: impl FnMut(T) -> U + Send + 'static,
+    ) -> Result<HttpResponse, mongodb::error::Error>
+    where
+        T: DeserializeOwned + 'static,
+        U: Serialize + 'static,
+    {
+        let Some(format) = self.stream else {
+            return Ok(HttpResponse::Ok().json(self.fetch::<T>(collection, filter).await?.map(view)));
+        };
+        let options = FindOptions::builder().sort(self.order()).build();
+        let cursor = collection.find(self.filter(filter), options).await?;
+        Ok(streaming::respond(format, |mut sender| async move {
+            sender.documents(cursor, view).await
+        }))
+    }
+}
+
+fn decode(cursor: &str, sort: &Sort) -> Option<Position> {
+    let bytes = hex::decode(cursor).ok()?;
+    let cursor = Document::from_reader(bytes.as_slice()).ok()?;
+    // A cursor only makes sense with the sort it came from
+    if cursor.get_str("f").ok()? != sort.field {
+        return None;
+    }
+    Some(Position {
+        value: cursor.get("v")?.clone(),
+        id: cursor.get_object_id("id").ok()?,
+    })
+}
+
+// A document of the list that couldn't be read
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InvalidDocument {
+    pub id: Option<String>,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Listing<T> {
+    pub items: Vec<T>,
+    // Everything matching the filters, across all pages
+    pub total: u64,
+    // The cursor for the next page, if there is one
+    pub next: Option<String>,
+    pub invalid: Vec<InvalidDocument>,
+}
+
+// Reads a document of a list, or says why it couldn't be read
+pub fn read<T: DeserializeOwned>(document: &Document) -> Result<T, InvalidDocument> {
+    from_document::<T>(document.clone()).map_err(|e| {
+        let id = document.get("_id").map(|id| match id {
+            Bson::ObjectId(oid) => oid.to_hex(),
+            other => other.to_string(),
+        });
+        println!("Unreadable document {:?}: {}", id, e);
+        InvalidDocument { id, error: e.to_string() }
+    })
+}
+
+impl<T: DeserializeOwned> Listing<T> {
+    // Adds a document to the page, or to `invalid` if it isn't a T
+    fn read(&mut self, document: &Document) {
+        match read::<T>(document) {
+            Ok(item) => self.items.push(item),
+            Err(invalid) => self.invalid.push(invalid),
+        }
+    }
+}
+
+impl<T> Listing<T> {
+    // The same page with each item shown differently, e.g. without secrets
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Listing<U> {
+        Listing {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            next: self.next,
+            invalid: self.invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(sort: &str, cursor: Option<String>) -> Page {
+        let query = PageQuery {
+            cursor,
+            limit: Some(2),
+            sort: Some(sort.to_string()),
+            stream: None,
+        };
+        Page::new(&query, &["number", "capacity"], "number").unwrap()
+    }
+
+    #[test]
+    fn sorts_are_limited_to_the_endpoints_fields() {
+        assert_eq!(
+            Sort::parse("-capacity", &["number", "capacity"]),
+            Ok(Sort { field: "capacity", descending: true })
+        );
+        assert!(Sort::parse("password", &["number"]).is_err());
+    }
+
+    #[test]
+    fn cursors_continue_after_the_last_document() {
+        let id = ObjectId::new();
+        let first = page("-capacity", None);
+        let cursor = first.next(&doc! { "_id": id, "capacity": 4 }).unwrap();
+
+        let second = page("-capacity", Some(cursor.clone()));
+        assert_eq!(
+            second.filter(doc! { "dorm_id": 1 }),
+            doc! { "$and": [
+                { "dorm_id": 1 },
+                { "$or": [
+                    { "capacity": 4, "_id": { "$lt": id } },
+                    { "capacity": { "$lt": 4 } },
+                    { "capacity": null },
+                ] },
+            ] }
+        );
+
+        // Not with another sort
+        let query = PageQuery {
+            cursor: Some(cursor),
+            ..PageQuery::default()
+        };
+        assert!(Page::new(&query, &["number", "capacity"], "number").is_err());
+    }
+
+    #[test]
+    fn unreadable_documents_are_reported() {
+        #[derive(Deserialize)]
+        struct Named {
+            name: String,
+        }
+        let mut listing: Listing<Named> = Listing {
+            items: Vec::new(),
+            total: 2,
+            next: None,
+            invalid: Vec::new(),
+        };
+        let id = ObjectId::new();
+        listing.read(&doc! { "_id": ObjectId::new(), "name": "North" });
+        listing.read(&doc! { "_id": id, "name": 5 });
+
+        assert_eq!(listing.items.len(), 1);
+        assert_eq!(listing.items[0].name, "North");
+        assert_eq!(listing.invalid.len(), 1);
+        assert_eq!(listing.invalid[0].id, Some(id.to_hex()));
+    }
+}
@@ -0,0 +1,429 @@
+use std::time::Duration;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection, Database,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+
+use crate::accounts::{self, Account};
+use crate::audit::{self, AuditEntry};
+use crate::auth::{self, Authenticated};
+use crate::jobs::BackgroundJobs;
+use crate::mail::{self, MailMessage, Mailer};
+use crate::rate_limit::{client_ip, LoginGuard};
+use crate::tokens;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const RESET_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 hashing with default params cannot fail")
+        .to_string()
+}
+
+pub enum Verified {
+    No,
+    Yes,
+    // Matched a plaintext password from before hashing was introduced; the
+    // caller should store a hash in its place
+    YesNeedsRehash,
+}
+
+pub fn verify_password(password: &str, stored: &str) -> Verified {
+    match PasswordHash::new(stored) {
+        Ok(hash) => match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Verified::Yes,
+            Err(_) => Verified::No,
+        },
+        Err(_) if !stored.is_empty() && stored == password => Verified::YesNeedsRehash,
+        Err(_) => Verified::No,
+    }
+}
+
+// Checks a password against an account's stored one, replacing a legacy
+// plaintext password with its hash when it matches
+pub async fn verify_and_upgrade<T>(
+    collection: &Collection<T>,
+    account_id: Option<ObjectId>,
+    password: &str,
+    stored: &str,
+) -> bool {
+    match verify_password(password, stored) {
+        Verified::No => false,
+        Verified::Yes => true,
+        Verified::YesNeedsRehash => {
+            if let Some(id) = account_id {
+                if let Err(e) = collection
+                    .update_one(
+                        doc! { "_id": id },
+                        doc! { "$set": { "password": hash_password(password) } },
+                        None,
+                    )
+                    .await
+                {
+                    println!("Failed to upgrade password hash: {:?}", e);
+                }
+            }
+            true
+        }
+    }
+}
+
+// Random alphanumeric password for generated accounts
+pub fn generate_password(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+pub fn check_new_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+pub async fn set_password(
+    db: &Database,
+    account_id: ObjectId,
+    new_password: &str,
+) -> Result<(), mongodb::error::Error> {
+    accounts::collection(db)
+        .update_one(
+            doc! { "_id": account_id },
+            doc! { "$set": {
+                "password": hash_password(new_password),
+                "must_change_password": false,
+            } },
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangePasswordRequest {
+    // Only used without a session: students whose generated password must be
+    // changed can't log in yet, so they identify themselves by email
+    email: Option<String>,
+    school_id: Option<String>,
+    current_password: String,
+    new_password: String,
+}
+
+// Only a generated password that blocks the first login can be changed
+// without a session. Everyone else logs in first, with their second factor
+// if they have one.
+fn changeable_without_session(account: &Account) -> bool {
+    account.must_change_password && !account.has_two_factor()
+}
+
+#[post("/password/change")]
+async fn change_password(
+    http_req: HttpRequest,
+    auth: Option<Authenticated>,
+    req: web::Json<ChangePasswordRequest>,
+    db: web::Data<Database>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    let ip = client_ip(&http_req);
+    let collection = accounts::collection(&db);
+    let account = match (&auth, &req.email) {
+        (Some(auth), _) => {
+            let filter = doc! { "_id": auth.account_id, "deactivated_at": null };
+            let account = match collection.find_one(filter, None).await {
+                Ok(Some(account)) => account,
+                Ok(None) => return HttpResponse::Unauthorized().json(doc! {
+                    "error": "Invalid credentials"
+                }),
+                Err(e) => {
+                    println!("Error finding account: {:?}", e);
+                    return HttpResponse::InternalServerError().json(doc! {
+                        "error": "Internal server error"
+                    });
+                }
+            };
+
+            // Checking the current password is as brute-forceable as a login
+            let login_accounts = [account.login_account()];
+            if let Some(blocked) = guard.check(&db, &login_accounts, ip.as_deref()).await.blocked_response() {
+                return blocked;
+            }
+            if !verify_and_upgrade(&collection, account.id, &req.current_password, &account.password).await {
+                guard.record_failure(&db, &login_accounts, ip.as_deref()).await;
+                return HttpResponse::Unauthorized().json(doc! {
+                    "error": "Invalid credentials"
+                });
+            }
+            // With 2FA the password alone isn't a login, and mustn't clear the
+            // failures counted against TOTP codes
+            if !account.has_two_factor() {
+                guard.record_success(&db, &account.login_account()).await;
+            }
+            account
+        }
+        (None, Some(email)) => {
+            let school_id = match req.school_id.as_deref().map(ObjectId::parse_str) {
+                Some(Ok(oid)) => Some(oid),
+                Some(Err(_)) => return HttpResponse::BadRequest().json(doc! {
+                    "error": "Invalid school ID"
+                }),
+                None => None,
+            };
+            let candidates = match accounts::login_candidates(&db, email, school_id).await {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    println!("Error finding account: {:?}", e);
+                    return HttpResponse::InternalServerError().json(doc! {
+                        "error": "Internal server error"
+                    });
+                }
+            };
+
+            // Limited like a login, unknown emails included
+            let login_accounts = accounts::lockout_accounts(email, school_id, &candidates);
+            if let Some(blocked) = guard.check(&db, &login_accounts, ip.as_deref()).await.blocked_response() {
+                return blocked;
+            }
+            let Some(account) = accounts::authenticate(&db, candidates, &req.current_password).await else {
+                guard.record_failure(&db, &login_accounts, ip.as_deref()).await;
+                return HttpResponse::Unauthorized().json(doc! {
+                    "error": "Invalid credentials"
+                });
+            };
+            if !changeable_without_session(&account) {
+                return HttpResponse::Forbidden().json(doc! {
+                    "error": "Log in to change your password"
+                });
+            }
+            guard.record_success(&db, &account.login_account()).await;
+            account
+        }
+        (None, None) => return HttpResponse::Unauthorized().json(doc! {
+            "error": "Not authenticated"
+        }),
+    };
+
+    if let Err(error) = check_new_password(&req.new_password) {
+        return HttpResponse::BadRequest().json(doc! { "error": error });
+    }
+    if req.new_password == req.current_password {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "New password must be different from the current one"
+        });
+    }
+
+    let account_id = account.id.expect("Stored accounts have an ID");
+    match set_password(&db, account_id, &req.new_password).await {
+        Ok(()) => {
+            // Anyone else holding a session for this account is logged out
+            let current_session = auth.as_ref().map(|auth| auth.session_id);
+            if let Err(e) =
+                auth::revoke_all_sessions(&db, account_id, current_session).await
+            {
+                println!("Error revoking sessions after password change: {:?}", e);
+            }
+
+            audit::record(
+                &db,
+                AuditEntry::new("password_changed")
+                    .actor(&account.email)
+                    .school(account.school_id)
+                    .target(&account_id.to_hex())
+                    .ip(ip.as_deref()),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Password changed successfully"
+            })
+        }
+        Err(e) => {
+            println!("Error changing password: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to change password"
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ForgotPasswordRequest {
+    email: String,
+    // Only needed when the email has accounts in more than one school
+    school_id: Option<String>,
+}
+
+// Always answers the same way, so it can't be used to find out which emails
+// have accounts
+#[post("/password/forgot")]
+async fn forgot_password(
+    http_req: HttpRequest,
+    req: web::Json<ForgotPasswordRequest>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    let school_id = match req.school_id.as_deref().map(ObjectId::parse_str) {
+        Some(Ok(oid)) => Some(oid),
+        Some(Err(_)) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+        None => None,
+    };
+
+    let candidates = match accounts::login_candidates(&db, &req.email, school_id).await {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            println!("Error finding account: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    // Counted like a login so the endpoint can't be used to flood inboxes,
+    // and against unknown emails too, so being limited doesn't give away
+    // that an account exists
+    let ip = client_ip(&http_req);
+    let login_accounts = accounts::lockout_accounts(&req.email, school_id, &candidates);
+    if let Some(blocked) = guard.check(&db, &login_accounts, ip.as_deref()).await.blocked_response() {
+        return blocked;
+    }
+
+    // Deactivated accounts aren't candidates. An email with accounts in
+    // several schools needs the school, or it's not clear whose password to
+    // reset.
+    match candidates.as_slice() {
+        [account] => {
+            let account_id = account.id.expect("Stored accounts have an ID");
+            match tokens::issue(&db, "password_reset", account_id, RESET_TTL).await {
+                Ok(token) => {
+                    mail::queue(&jobs, mailer.into_inner(), reset_message(&account.email, &token));
+                    audit::record(
+                        &db,
+                        AuditEntry::new("password_reset_requested")
+                            .actor(&account.email)
+                            .school(account.school_id)
+                            .target(&account_id.to_hex())
+                            .ip(ip.as_deref()),
+                    )
+                    .await;
+                }
+                Err(e) => println!("Failed to issue reset token: {:?}", e),
+            }
+        }
+        [] => println!("Password reset requested for unknown email {}", req.email),
+        _ => println!("Password reset requested for {} without saying which school", req.email),
+    }
+
+    HttpResponse::Ok().json(doc! {
+        "message": "If that account exists, a reset link has been sent"
+    })
+}
+
+fn reset_message(email: &str, token: &str) -> MailMessage {
+    MailMessage {
+        to: email.to_string(),
+        subject: "Reset your dorm account password".to_string(),
+        body: format!(
+            "Someone asked to reset the password for this account.\n\n\
+             Choose a new password here within the next hour:\n{}/reset-password?token={}\n\n\
+             If this wasn't you, you can ignore this message.\n",
+            mail::app_base_url(),
+            token
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResetPasswordRequest {
+    token: String,
+    new_password: String,
+}
+
+// Sets a new password from a reset token and logs the account out everywhere
+#[post("/password/reset")]
+async fn reset_password(
+    http_req: HttpRequest,
+    req: web::Json<ResetPasswordRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(error) = check_new_password(&req.new_password) {
+        return HttpResponse::BadRequest().json(doc! { "error": error });
+    }
+
+    let token = match tokens::consume(&db, "password_reset", &req.token).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Reset link is invalid or has expired"
+        }),
+        Err(e) => {
+            println!("Error redeeming reset token: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    if let Err(e) = set_password(&db, token.account_id, &req.new_password).await {
+        println!("Error resetting password: {:?}", e);
+        return HttpResponse::InternalServerError().json(doc! {
+            "error": "Failed to reset password"
+        });
+    }
+    if let Err(e) = auth::revoke_all_sessions(&db, token.account_id, None).await {
+        println!("Error revoking sessions after reset: {:?}", e);
+    }
+
+    audit::record(
+        &db,
+        AuditEntry::new("password_reset_completed")
+            .target(&token.account_id.to_hex())
+            .request(&http_req),
+    )
+    .await;
+
+    HttpResponse::Ok().json(doc! {
+        "message": "Password reset, you can now log in"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::two_factor::TwoFactor;
+    use mongodb::bson::DateTime;
+
+    #[test]
+    fn only_generated_passwords_change_without_a_session() {
+        let mut account = Account::student("ada@x.edu".to_string(), String::new(), None);
+        assert!(!changeable_without_session(&account));
+
+        account.must_change_password = true;
+        assert!(changeable_without_session(&account));
+
+        account.two_factor = Some(TwoFactor {
+            secret: String::new(),
+            confirmed_at: Some(DateTime::now()),
+            last_used_step: None,
+            recovery_codes: Vec::new(),
+        });
+        assert!(!changeable_without_session(&account));
+    }
+}
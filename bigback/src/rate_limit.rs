@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{delete, get, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    IndexModel,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuditEntry};
+use crate::pagination::{Page, PageQuery};
+use crate::roles::{authorize, Permission, Principal, Scope};
+
+// Limits for the login endpoints, read from LOGIN_* environment variables
+#[derive(Debug, Clone)]
+pub struct LoginPolicy {
+    // Attempts allowed from one IP per window, successful or not
+    pub max_attempts_per_ip: u32,
+    pub ip_window: Duration,
+    // Consecutive failures before an account is locked
+    pub max_failures: i32,
+    // How long a lockout lasts; also how long failures are remembered
+    pub lockout: Duration,
+}
+
+impl LoginPolicy {
+    pub fn from_env() -> Self {
+        fn var(name: &str, default: u64) -> u64 {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        LoginPolicy {
+            max_attempts_per_ip: var("LOGIN_MAX_ATTEMPTS_PER_IP", 20) as u32,
+            ip_window: Duration::from_secs(var("LOGIN_IP_WINDOW_SECS", 60)),
+            max_failures: var("LOGIN_MAX_FAILURES", 5) as i32,
+            lockout: Duration::from_secs(var("LOGIN_LOCKOUT_SECS", 900)),
+        }
+    }
+}
+
+// Failure count and lockout state for one account, kept in Mongo so that an
+// admin can see and clear it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginLockout {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub account: String,
+    pub email: String,
+    pub school_id: Option<ObjectId>,
+    pub failures: i32,
+    pub first_failure_at: DateTime,
+    pub locked_until: Option<DateTime>,
+}
+
+// The account a login attempt is for. Emails are only unique within a
+// school, so the key is the account's school and email; accounts from
+// before schools have only the email.
+pub struct LoginAccount<'a> {
+    pub email: &'a str,
+    pub school_id: Option<ObjectId>,
+}
+
+impl LoginAccount<'_> {
+    fn key(&self) -> String {
+        match self.school_id {
+            Some(school_id) => format!("{}:{}", school_id, self.email),
+            None => self.email.to_string(),
+        }
+    }
+}
+
+pub enum LoginCheck {
+    Allowed,
+    IpLimited,
+    Locked(DateTime),
+}
+
+pub struct LoginGuard {
+    policy: LoginPolicy,
+    // Per-IP attempt counts for the current window. In-memory only; a restart
+    // resets them, which is fine for a short window.
+    attempts: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl LoginGuard {
+    pub fn new(policy: LoginPolicy) -> Self {
+        LoginGuard {
+            policy,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn allow_ip(&self, ip: &str) -> bool {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+
+        // Drop expired windows now and then so the map can't grow unbounded
+        if attempts.len() > 10_000 {
+            attempts.retain(|_, (started, _)| now.duration_since(*started) < self.policy.ip_window);
+        }
+
+        let entry = attempts.entry(ip.to_string()).or_insert((now, 0));
+        if now.duration_since(entry.0) >= self.policy.ip_window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        entry.1 <= self.policy.max_attempts_per_ip
+    }
+
+    // Counts the attempt against the IP and checks the lockouts of the
+    // accounts it could be for. Blocked attempts are written to the audit
+    // log.
+    pub async fn check(
+        &self,
+        db: &Database,
+        accounts: &[LoginAccount<'_>],
+        ip: Option<&str>,
+    ) -> LoginCheck {
+        if !self.allow_ip(ip.unwrap_or("unknown")) {
+            println!("Login rate limit hit for IP {:?}", ip);
+            if let Some(account) = accounts.first() {
+                audit::record(
+                    db,
+                    AuditEntry::new("login_rate_limited")
+                        .actor(account.email)
+                        .school(account.school_id)
+                        .target(&account.key())
+                        .ip(ip),
+                )
+                .await;
+            }
+            return LoginCheck::IpLimited;
+        }
+
+        let lockouts = db.collection::<LoginLockout>("login_lockouts");
+        let keys: Vec<String> = accounts.iter().map(LoginAccount::key).collect();
+        let locked = doc! { "account": { "$in": keys }, "locked_until": { "$gt": DateTime::now() } };
+        match lockouts.find_one(locked, None).await {
+            Ok(Some(LoginLockout { account, email, school_id, locked_until: Some(until), .. })) => {
+                audit::record(
+                    db,
+                    AuditEntry::new("login_blocked_locked")
+                        .actor(&email)
+                        .school(school_id)
+                        .target(&account)
+                        .ip(ip),
+                )
+                .await;
+                LoginCheck::Locked(until)
+            }
+            Ok(_) => LoginCheck::Allowed,
+            Err(e) => {
+                // Don't lock everyone out because the lockout lookup failed
+                println!("Error checking login lockout: {:?}", e);
+                LoginCheck::Allowed
+            }
+        }
+    }
+
+    // Records a failed attempt against each account it could have been
+    // for, locking those that reach the failure limit
+    pub async fn record_failure(
+        &self,
+        db: &Database,
+        accounts: &[LoginAccount<'_>],
+        ip: Option<&str>,
+    ) {
+        for account in accounts {
+            self.record_one_failure(db, account, ip).await;
+        }
+    }
+
+    async fn record_one_failure(
+        &self,
+        db: &Database,
+        account: &LoginAccount<'_>,
+        ip: Option<&str>,
+    ) {
+        let lockouts = db.collection::<LoginLockout>("login_lockouts");
+        let key = account.key();
+        let now = DateTime::now();
+
+        audit::record(
+            db,
+            AuditEntry::new("login_failed")
+                .actor(account.email)
+                .school(account.school_id)
+                .target(&key)
+                .ip(ip),
+        )
+        .await;
+
+        // Start a fresh count if the previous failures are old, or the
+        // previous lockout has run out
+        let reset = self.stale(&key, now);
+        let fresh = doc! { "$set": { "failures": 0, "first_failure_at": now, "locked_until": null } };
+        if let Err(e) = lockouts.update_one(reset, fresh, None).await {
+            println!("Error resetting old login failures: {:?}", e);
+            return;
+        }
+
+        // Counted in one step, so concurrent failures can't overwrite each
+        // other's count
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let update = doc! {
+            "$inc": { "failures": 1 },
+            "$set": { "email": account.email, "school_id": account.school_id },
+            "$setOnInsert": { "first_failure_at": now, "locked_until": null },
+        };
+        let lockout = match lockouts.find_one_and_update(doc! { "account": &key }, update, options).await {
+            Ok(Some(lockout)) => lockout,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Error recording login failure: {:?}", e);
+                return;
+            }
+        };
+        let Some(locked_until) = self.lock_until(&lockout, now) else {
+            return;
+        };
+        let failures = lockout.failures;
+        // Only the failure that reaches the limit sets the lockout, and only
+        // the request that actually set it logs it
+        let result = lockouts
+            .update_one(
+                doc! { "account": &key, "locked_until": null },
+                doc! { "$set": { "locked_until": locked_until } },
+                None,
+            )
+            .await;
+        match result {
+            Ok(result) if result.modified_count == 1 => {}
+            Ok(_) => return,
+            Err(e) => {
+                println!("Error locking account: {:?}", e);
+                return;
+            }
+        }
+
+        println!("Locking account {} after {} failures", key, failures);
+        audit::record(
+            db,
+            AuditEntry::new("account_locked")
+                .actor(account.email)
+                .school(account.school_id)
+                .target(&key)
+                .ip(ip)
+                .details(doc! { "failures": failures, "locked_until": locked_until }),
+        )
+        .await;
+    }
+
+    // When the account should be locked until, given its failures so far.
+    // Failures past the limit don't extend a lockout that's already set.
+    fn lock_until(&self, lockout: &LoginLockout, now: DateTime) -> Option<DateTime> {
+        if lockout.failures < self.policy.max_failures || lockout.locked_until.is_some() {
+            return None;
+        }
+        Some(DateTime::from_millis(now.timestamp_millis() + self.policy.lockout.as_millis() as i64))
+    }
+
+    // Matches the account's record if its failures are too old to count or
+    // its lockout has run out
+    fn stale(&self, key: &str, now: DateTime) -> Document {
+        let window_start = now.timestamp_millis() - self.policy.lockout.as_millis() as i64;
+        doc! {
+            "account": key,
+            "$or": [
+                { "first_failure_at": { "$lt": DateTime::from_millis(window_start) } },
+                { "locked_until": { "$lte": now } },
+            ],
+        }
+    }
+
+    // Makes sure concurrent first failures can't create two records for
+    // one account
+    pub async fn create_index(db: &Database) -> Result<(), mongodb::error::Error> {
+        let index = IndexModel::builder()
+            .keys(doc! { "account": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        db.collection::<LoginLockout>("login_lockouts").create_index(index, None).await?;
+        Ok(())
+    }
+
+    pub async fn record_success(&self, db: &Database, account: &LoginAccount<'_>) {
+        let lockouts = db.collection::<LoginLockout>("login_lockouts");
+        if let Err(e) = lockouts.delete_one(doc! { "account": account.key() }, None).await {
+            println!("Error resetting login failures: {:?}", e);
+        }
+    }
+}
+
+impl LoginCheck {
+    // The response to send instead of checking credentials, if any
+    pub fn blocked_response(self) -> Option<HttpResponse> {
+        match self {
+            LoginCheck::Allowed => None,
+            LoginCheck::IpLimited => Some(
+                HttpResponse::TooManyRequests()
+                    .insert_header(("Retry-After", "60"))
+                    .json(doc! {
+                        "error": "Too many login attempts, try again later"
+                    }),
+            ),
+            LoginCheck::Locked(until) => {
+                let retry_after =
+                    ((until.timestamp_millis() - DateTime::now().timestamp_millis()) / 1000).max(1);
+                Some(
+                    HttpResponse::TooManyRequests()
+                        .insert_header(("Retry-After", retry_after.to_string()))
+                        .json(doc! {
+                            "error": "Account temporarily locked",
+                            "locked_until": until,
+                        }),
+                )
+            }
+        }
+    }
+}
+
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.connection_info().peer_addr().map(str::to_string)
+}
+
+#[derive(Debug, Deserialize)]
+struct LockoutQuery {
+    school_id: Option<String>,
+}
+
+// Accounts that are currently locked out
+#[get("/admin/lockouts")]
+async fn list_lockouts(
+    query: web::Query<LockoutQuery>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let collection = db.collection::<Document>("login_lockouts");
+
+    let mut filter = doc! { "locked_until": { "$gt": DateTime::now() } };
+    match &query.school_id {
+        Some(school_id) => match ObjectId::parse_str(school_id) {
+            Ok(oid) => {
+                if let Err(denied) = authorize(&principal, Permission::ViewLockouts, &Scope::School(oid)) {
+                    return denied;
+                }
+                filter.insert("school_id", oid);
+            }
+            Err(_) => return HttpResponse::BadRequest().json(doc! {
+                "error": "Invalid school ID"
+            }),
+        },
+        // Every school the caller can see
+        None => {
+            if let Err(denied) = authorize(&principal, Permission::ViewLockouts, &Scope::Own) {
+                return denied;
+            }
+            if let Some(schools) = principal.schools_with(Permission::ViewLockouts) {
+                filter.insert("school_id", doc! { "$in": schools });
+            }
+        }
+    }
+
+    let page = match Page::new(&page, &["locked_until", "email"], "-locked_until") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    match page.respond(&collection, filter, |lockout: LoginLockout| lockout).await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error fetching lockouts: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to fetch lockouts"
+            })
+        }
+    }
+}
+
+#[delete("/admin/lockouts/{lockout_id}")]
+async fn clear_lockout(
+    req: HttpRequest,
+    lockout_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let collection = db.collection::<LoginLockout>("login_lockouts");
+
+    let oid = match ObjectId::parse_str(lockout_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid lockout ID"
+        }),
+    };
+
+    match collection.find_one(doc! { "_id": oid }, None).await {
+        Ok(Some(lockout)) => {
+            let scope = Scope::of_school(lockout.school_id);
+            if let Err(denied) = authorize(&principal, Permission::ClearLockouts, &scope) {
+                return denied;
+            }
+        }
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Lockout not found"
+        }),
+        Err(e) => {
+            println!("Error finding lockout: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to clear lockout"
+            });
+        }
+    }
+
+    match collection.find_one_and_delete(doc! { "_id": oid }, None).await {
+        Ok(Some(lockout)) => {
+            audit::record(
+                &db,
+                AuditEntry::new("lockout_cleared")
+                    .actor(&principal.email)
+                    .school(lockout.school_id)
+                    .target(&lockout.account)
+                    .request(&req)
+                    .details(doc! { "failures": lockout.failures }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Lockout cleared"
+            })
+        }
+        Ok(None) => HttpResponse::NotFound().json(doc! {
+            "error": "Lockout not found"
+        }),
+        Err(e) => {
+            println!("Error clearing lockout: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to clear lockout"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard_with(ip_window: Duration) -> LoginGuard {
+        LoginGuard::new(LoginPolicy {
+            max_attempts_per_ip: 2,
+            ip_window,
+            max_failures: 3,
+            lockout: Duration::from_secs(900),
+        })
+    }
+
+    const SCHOOL: &str = "65a1f0c2e4b0a1b2c3d4e5f6";
+
+    fn lockout(failures: i32, locked_until: Option<DateTime>) -> LoginLockout {
+        LoginLockout {
+            id: None,
+            account: format!("{}:ada@x.edu", SCHOOL),
+            email: "ada@x.edu".to_string(),
+            school_id: Some(ObjectId::parse_str(SCHOOL).unwrap()),
+            failures,
+            first_failure_at: DateTime::from_millis(0),
+            locked_until,
+        }
+    }
+
+    #[test]
+    fn ips_get_a_number_of_attempts_per_window() {
+        let guard = guard_with(Duration::from_secs(60));
+        assert!(guard.allow_ip("10.0.0.1"));
+        assert!(guard.allow_ip("10.0.0.1"));
+        assert!(!guard.allow_ip("10.0.0.1"));
+        // Counted per IP
+        assert!(guard.allow_ip("10.0.0.2"));
+
+        // A new window starts the count again
+        let unwindowed = guard_with(Duration::ZERO);
+        for _ in 0..5 {
+            assert!(unwindowed.allow_ip("10.0.0.1"));
+        }
+    }
+
+    #[test]
+    fn the_failure_reaching_the_limit_locks_the_account() {
+        let guard = guard_with(Duration::from_secs(60));
+        let now = DateTime::from_millis(1_000_000);
+
+        assert_eq!(guard.lock_until(&lockout(2, None), now), None);
+        assert_eq!(
+            guard.lock_until(&lockout(3, None), now),
+            Some(DateTime::from_millis(1_000_000 + 900_000))
+        );
+        // Later failures don't push an existing lockout back
+        let locked = Some(DateTime::from_millis(1_500_000));
+        assert_eq!(guard.lock_until(&lockout(4, locked), now), None);
+    }
+
+    #[test]
+    fn old_failures_and_finished_lockouts_are_stale() {
+        let guard = guard_with(Duration::from_secs(60));
+        let now = DateTime::from_millis(1_000_000);
+
+        let key = format!("{}:ada@x.edu", SCHOOL);
+        assert_eq!(
+            guard.stale(&key, now),
+            doc! {
+                "account": &key,
+                "$or": [
+                    { "first_failure_at": { "$lt": DateTime::from_millis(100_000) } },
+                    { "locked_until": { "$lte": now } },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn lockouts_are_keyed_by_school_and_email() {
+        let school_id = Some(ObjectId::parse_str(SCHOOL).unwrap());
+        let account = LoginAccount { email: "ada@x.edu", school_id };
+        assert_eq!(account.key(), lockout(0, None).account);
+        // Accounts from before schools
+        assert_eq!(LoginAccount { email: "ada@x.edu", school_id: None }.key(), "ada@x.edu");
+    }
+}
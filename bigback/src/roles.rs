@@ -0,0 +1,764 @@
+use actix_web::{
+    dev::Payload, put, web, FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::future::LocalBoxFuture;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, Document},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts;
+use crate::api_keys::{self, ApiScope};
+use crate::audit::{self, AuditEntry};
+use crate::auth::{self, Authenticated};
+use crate::{Dorm, Room};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Student,
+    // Scoped to a single dorm
+    ResidentAssistant,
+    // Read-only access to a school
+    Auditor,
+    Maintenance,
+    SchoolAdmin,
+    // Every school
+    SuperAdmin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ViewDorms,
+    ViewOwnAccount,
+    ChooseOwnRoom,
+    ManageDorms,
+    ManageRooms,
+    // Downloading rosters, which changes nothing
+    ExportRosters,
+    ManageStudents,
+    // Only adding students, as imports do; finding, merging and dismissing
+    // duplicates needs ManageStudents
+    CreateStudents,
+    ImportRooms,
+    ViewLockouts,
+    ClearLockouts,
+    ManageRoles,
+    ManageSchoolSettings,
+    RevokeSessions,
+    ManageApiKeys,
+    ViewAuditLog,
+}
+
+impl Role {
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Student => &[ViewDorms, ViewOwnAccount, ChooseOwnRoom],
+            Role::ResidentAssistant => &[ViewDorms, ViewOwnAccount, ManageRooms, ExportRosters],
+            Role::Auditor => &[ViewDorms, ViewOwnAccount, ExportRosters, ViewLockouts, ViewAuditLog],
+            Role::Maintenance => &[ViewDorms, ViewOwnAccount],
+            Role::SchoolAdmin | Role::SuperAdmin => &[
+                ViewDorms,
+                ViewOwnAccount,
+                ManageDorms,
+                ManageRooms,
+                ExportRosters,
+                ManageStudents,
+                CreateStudents,
+                ImportRooms,
+                ViewLockouts,
+                ClearLockouts,
+                ManageRoles,
+                ManageSchoolSettings,
+                RevokeSessions,
+                ManageApiKeys,
+                ViewAuditLog,
+            ],
+        }
+    }
+}
+
+// A role held within a school, or within one dorm of it. Grants without a
+// school predate schools on student accounts and apply everywhere.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoleGrant {
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub school_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dorm_id: Option<ObjectId>,
+}
+
+// What a request acts on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    // The caller's own account
+    Own,
+    School(ObjectId),
+    // Not tied to any one school, e.g. records that predate schools on
+    // accounts; only unscoped grants reach it
+    Global,
+    // Dorms created before dorms recorded their school have no school_id
+    Dorm {
+        school_id: Option<ObjectId>,
+        dorm_id: ObjectId,
+    },
+}
+
+impl RoleGrant {
+    fn covers(&self, scope: &Scope) -> bool {
+        if self.role == Role::SuperAdmin {
+            return true;
+        }
+        match *scope {
+            Scope::Own => true,
+            Scope::School(school_id) => {
+                self.dorm_id.is_none() && self.school_id.is_none_or(|own| own == school_id)
+            }
+            Scope::Global => self.dorm_id.is_none() && self.school_id.is_none(),
+            Scope::Dorm { school_id, dorm_id } => match (self.dorm_id, self.school_id) {
+                (Some(own_dorm), _) => own_dorm == dorm_id,
+                (None, Some(own_school)) => school_id == Some(own_school),
+                (None, None) => true,
+            },
+        }
+    }
+}
+
+pub fn allows(grants: &[RoleGrant], permission: Permission, scope: &Scope) -> bool {
+    grants
+        .iter()
+        .any(|grant| grant.role.permissions().contains(&permission) && grant.covers(scope))
+}
+
+// Who is making the request: someone logged in, or an API key
+#[derive(Debug, Clone)]
+pub enum Identity {
+    Session(Authenticated),
+    ApiKey(ObjectId),
+}
+
+// The caller together with their role grants. API keys act as a school
+// admin of their school, cut down to the key's scopes.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub identity: Identity,
+    pub email: String,
+    pub grants: Vec<RoleGrant>,
+    pub api_scopes: Option<Vec<ApiScope>>,
+}
+
+impl Principal {
+    pub fn may(&self, permission: Permission, scope: &Scope) -> bool {
+        let key_allows = self
+            .api_scopes
+            .as_deref()
+            .is_none_or(|scopes| api_keys::scopes_allow(scopes, permission));
+        key_allows && allows(&self.grants, permission, scope)
+    }
+
+    fn from_api_key(key: api_keys::ApiKey) -> Principal {
+        Principal {
+            identity: Identity::ApiKey(key.id.expect("Stored API keys have an ID")),
+            email: format!("api-key:{}", key.name),
+            grants: vec![RoleGrant {
+                role: Role::SchoolAdmin,
+                school_id: Some(key.school_id),
+                dorm_id: None,
+            }],
+            api_scopes: Some(key.scopes),
+        }
+    }
+
+    // The account or API key behind the request, for logs and for records
+    // only their creator may use later
+    pub fn id(&self) -> ObjectId {
+        match &self.identity {
+            Identity::Session(auth) => auth.account_id,
+            Identity::ApiKey(key_id) => *key_id,
+        }
+    }
+
+    // The caller's session, for endpoints about their own account
+    #[allow(clippy::result_large_err)]
+    pub fn session(&self) -> Result<&Authenticated, HttpResponse> {
+        match &self.identity {
+            Identity::Session(auth) => Ok(auth),
+            Identity::ApiKey(_) => Err(HttpResponse::Forbidden().json(doc! {
+                "error": "API keys can't do that"
+            })),
+        }
+    }
+
+    pub fn is_super_admin(&self) -> bool {
+        self.grants.iter().any(|grant| grant.role == Role::SuperAdmin)
+    }
+
+    // Schools in which the caller holds `permission`; None means every
+    // school. Used to filter list endpoints.
+    pub fn schools_with(&self, permission: Permission) -> Option<Vec<ObjectId>> {
+        let mut schools = Vec::new();
+        if let Some(scopes) = &self.api_scopes {
+            if !api_keys::scopes_allow(scopes, permission) {
+                return Some(schools);
+            }
+        }
+        for grant in &self.grants {
+            if !grant.role.permissions().contains(&permission) {
+                continue;
+            }
+            match grant.school_id {
+                Some(school_id) if grant.role != Role::SuperAdmin => schools.push(school_id),
+                _ => return None,
+            }
+        }
+        Some(schools)
+    }
+
+    // A filter for the dorms in which the caller holds `permission`, the
+    // same ones `may` allows with `Scope::of_dorm`; None means every dorm
+    pub fn dorm_filter(&self, permission: Permission) -> Option<Document> {
+        let nothing = doc! { "_id": { "$in": [] } };
+        if let Some(scopes) = &self.api_scopes {
+            if !api_keys::scopes_allow(scopes, permission) {
+                return Some(nothing);
+            }
+        }
+        let mut any = Vec::new();
+        for grant in &self.grants {
+            if !grant.role.permissions().contains(&permission) {
+                continue;
+            }
+            match (grant.role, grant.dorm_id, grant.school_id) {
+                (Role::SuperAdmin, _, _) | (_, None, None) => return None,
+                (_, Some(dorm_id), _) => any.push(doc! { "_id": dorm_id }),
+                (_, None, Some(school_id)) => any.push(doc! { "school_id": school_id }),
+            }
+        }
+        match any.is_empty() {
+            true => Some(nothing),
+            false => Some(doc! { "$or": any }),
+        }
+    }
+}
+
+// The one check every handler goes through. Returns the response to send
+// when the caller isn't allowed.
+#[allow(clippy::result_large_err)]
+pub fn authorize(
+    principal: &Principal,
+    permission: Permission,
+    scope: &Scope,
+) -> Result<(), HttpResponse> {
+    if principal.may(permission, scope) {
+        return Ok(());
+    }
+    println!(
+        "Denied {:?} on {:?} to {} ({})",
+        permission, scope, principal.email, principal.id()
+    );
+    Err(HttpResponse::Forbidden().json(doc! {
+        "error": "You don't have permission to do that"
+    }))
+}
+
+async fn load_grants(
+    db: &Database,
+    account_id: ObjectId,
+) -> Result<Option<(String, Vec<RoleGrant>)>, mongodb::error::Error> {
+    let account = accounts::collection(db)
+        .find_one(doc! { "_id": account_id, "deactivated_at": null }, None)
+        .await?;
+    Ok(account.map(|account| {
+        let grants = account.grants();
+        (account.email, grants)
+    }))
+}
+
+impl FromRequest for Principal {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        // Tests stand in a caller without a database behind them
+        #[cfg(test)]
+        {
+            use actix_web::HttpMessage;
+            if let Some(principal) = req.extensions().get::<Principal>().cloned() {
+                return Box::pin(async move { Ok(principal) });
+            }
+        }
+        let db = req.app_data::<web::Data<Database>>().cloned();
+        let api_key = auth::bearer_token(req).filter(|token| token.starts_with(api_keys::KEY_PREFIX));
+        let auth = Authenticated::from_request(req, payload);
+
+        Box::pin(async move {
+            let db = db.ok_or_else(|| {
+                actix_web::error::ErrorInternalServerError("Database not configured")
+            })?;
+            if let Some(key) = api_key {
+                return match api_keys::authenticate(&db, &key).await {
+                    Ok(Some(key)) => Ok(Principal::from_api_key(key)),
+                    Ok(None) => Err(actix_web::error::ErrorUnauthorized("Invalid or expired API key")),
+                    Err(e) => {
+                        println!("Error looking up API key: {:?}", e);
+                        Err(actix_web::error::ErrorInternalServerError("Internal server error"))
+                    }
+                };
+            }
+            let auth = auth.await?;
+
+            match load_grants(&db, auth.account_id).await {
+                Ok(Some((email, grants))) => Ok(Principal {
+                    identity: Identity::Session(auth),
+                    email,
+                    grants,
+                    api_scopes: None,
+                }),
+                Ok(None) => Err(actix_web::error::ErrorUnauthorized("Account no longer exists")),
+                Err(e) => {
+                    println!("Error loading roles: {:?}", e);
+                    Err(actix_web::error::ErrorInternalServerError("Internal server error"))
+                }
+            }
+        })
+    }
+}
+
+impl Scope {
+    pub fn of_dorm(dorm: &Dorm) -> Scope {
+        Scope::Dorm {
+            school_id: dorm.school_id,
+            dorm_id: dorm.id.expect("Stored dorms have an ID"),
+        }
+    }
+
+    pub fn of_school(school_id: Option<ObjectId>) -> Scope {
+        school_id.map_or(Scope::Global, Scope::School)
+    }
+}
+
+// Scope of a dorm, or None if there is no such dorm
+pub async fn dorm_scope(
+    db: &Database,
+    dorm_id: ObjectId,
+) -> Result<Option<Scope>, mongodb::error::Error> {
+    let dorm = db
+        .collection::<Dorm>("dorms")
+        .find_one(doc! { "_id": dorm_id }, None)
+        .await?;
+    Ok(dorm.as_ref().map(Scope::of_dorm))
+}
+
+// Scope of the dorm a room belongs to, or None if there is no such room
+pub async fn room_scope(
+    db: &Database,
+    room_id: ObjectId,
+) -> Result<Option<Scope>, mongodb::error::Error> {
+    let room = db
+        .collection::<Room>("rooms")
+        .find_one(doc! { "_id": room_id }, None)
+        .await?;
+    match room {
+        Some(room) => Ok(dorm_scope(db, room.dorm_id).await?.or(Some(Scope::Dorm {
+            school_id: None,
+            dorm_id: room.dorm_id,
+        }))),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SetRolesRequest {
+    account_id: String,
+    roles: Vec<RoleGrant>,
+}
+
+fn grant_scope(grant: &RoleGrant) -> Option<Scope> {
+    match (grant.school_id, grant.dorm_id) {
+        (school_id, Some(dorm_id)) => Some(Scope::Dorm { school_id, dorm_id }),
+        (Some(school_id), None) => Some(Scope::School(school_id)),
+        (None, None) => None,
+    }
+}
+
+// Whether a grant may be given to an account of this school. Super admins
+// span every school; accounts that predate schools take any grant.
+fn within_school(grant: &RoleGrant, school_id: Option<ObjectId>) -> bool {
+    grant.role == Role::SuperAdmin || school_id.is_none() || grant.school_id == school_id
+}
+
+// Replaces an account's roles. The caller has to be able to manage roles
+// everywhere the account's old and new grants apply; only super admins can
+// hand out unscoped grants.
+#[put("/admin/roles")]
+async fn set_roles(
+    req: HttpRequest,
+    principal: Principal,
+    body: web::Json<SetRolesRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let account_oid = match ObjectId::parse_str(&body.account_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid account ID"
+        }),
+    };
+    let account = match accounts::collection(&db)
+        .find_one(doc! { "_id": account_oid, "deactivated_at": null }, None)
+        .await
+    {
+        Ok(Some(account)) => account,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Account not found"
+        }),
+        Err(e) => {
+            println!("Error loading roles: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+    let current = account.grants();
+
+    if !body.roles.iter().all(|grant| within_school(grant, account.school_id)) {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "Roles must be within the account's school"
+        });
+    }
+    for grant in &body.roles {
+        let Some(dorm_id) = grant.dorm_id else { continue };
+        let dorms = db.collection::<Document>("dorms");
+        match dorms.count_documents(doc! { "_id": dorm_id, "school_id": grant.school_id }, None).await {
+            Ok(0) => return HttpResponse::BadRequest().json(doc! {
+                "error": "Dorm not found in the account's school"
+            }),
+            Ok(_) => {}
+            Err(e) => {
+                println!("Error finding dorm: {:?}", e);
+                return HttpResponse::InternalServerError().json(doc! {
+                    "error": "Internal server error"
+                });
+            }
+        }
+    }
+
+    for grant in current.iter().chain(body.roles.iter()) {
+        let allowed = match grant_scope(grant) {
+            _ if grant.role == Role::SuperAdmin => principal.is_super_admin(),
+            Some(scope) => principal.may(Permission::ManageRoles, &scope),
+            None => principal.is_super_admin(),
+        };
+        if !allowed {
+            return HttpResponse::Forbidden().json(doc! {
+                "error": "You can't manage one or more of these roles"
+            });
+        }
+    }
+
+    let roles_bson = to_bson(&body.roles).expect("Failed to serialize roles");
+    match accounts::collection(&db)
+        .update_one(
+            doc! { "_id": account_oid },
+            doc! { "$set": { "roles": &roles_bson } },
+            None,
+        )
+        .await
+    {
+        Ok(_) => {
+            audit::record(
+                &db,
+                AuditEntry::new("roles_changed")
+                    .actor(&principal.email)
+                    .target(&account_oid.to_hex())
+                    .request(&req)
+                    .details(doc! { "email": &account.email })
+                    .diff(
+                        Some(&doc! { "roles": to_bson(&current).expect("Failed to serialize roles") }),
+                        Some(&doc! { "roles": roles_bson }),
+                    ),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Roles updated"
+            })
+        }
+        Err(e) => {
+            println!("Error updating roles: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to update roles"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::dev::Service;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpMessage};
+    use serde_json::{json, Value};
+
+    const ROLES: &[Role] = &[
+        Role::Student,
+        Role::ResidentAssistant,
+        Role::Auditor,
+        Role::Maintenance,
+        Role::SchoolAdmin,
+        Role::SuperAdmin,
+    ];
+    const ADMINS: &[Role] = &[Role::SchoolAdmin, Role::SuperAdmin];
+
+    fn grant(role: Role, school: ObjectId, dorm: ObjectId) -> RoleGrant {
+        RoleGrant {
+            role,
+            school_id: if role == Role::SuperAdmin { None } else { Some(school) },
+            dorm_id: if role == Role::ResidentAssistant { Some(dorm) } else { None },
+        }
+    }
+
+    fn person(grants: Vec<RoleGrant>) -> Principal {
+        Principal {
+            identity: Identity::Session(Authenticated {
+                session_id: ObjectId::new(),
+                account_id: ObjectId::new(),
+            }),
+            email: "someone@x.edu".to_string(),
+            grants,
+            api_scopes: None,
+        }
+    }
+
+    // Requests to handlers that authorize against the school in the request,
+    // with the roles in that school that may make them
+    fn school_requests(school: ObjectId) -> Vec<(Method, String, Option<Value>, &'static [Role])> {
+        vec![
+            (
+                Method::GET,
+                format!("/admin/export?school_id={}&format=csv", school),
+                None,
+                &[Role::Auditor, Role::SchoolAdmin, Role::SuperAdmin],
+            ),
+            (
+                Method::GET,
+                format!("/admin/lockouts?school_id={}", school),
+                None,
+                &[Role::Auditor, Role::SchoolAdmin, Role::SuperAdmin],
+            ),
+            (
+                Method::GET,
+                format!("/admin/audit-log?school_id={}", school),
+                None,
+                &[Role::Auditor, Role::SchoolAdmin, Role::SuperAdmin],
+            ),
+            (
+                Method::POST,
+                "/admin/dorms".to_string(),
+                Some(json!({ "name": "North", "school_id": school.to_hex() })),
+                ADMINS,
+            ),
+            (Method::GET, format!("/admin/students?school_id={}", school), None, ADMINS),
+            (Method::GET, format!("/admin/schools/{}/duplicates", school), None, ADMINS),
+            (Method::GET, format!("/admin/schools/{}/api-keys", school), None, ADMINS),
+            (
+                Method::PUT,
+                format!("/admin/schools/{}/settings", school),
+                Some(json!({ "require_admin_2fa": true })),
+                ADMINS,
+            ),
+        ]
+    }
+
+    // Calls the real handler as `principal`. Nothing listens on the
+    // database, so whatever gets past authorization fails on it instead of
+    // answering 403.
+    async fn call(principal: &Principal, method: &Method, uri: &str, body: Option<&Value>) -> StatusCode {
+        let db = mongodb::Client::with_uri_str("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=100")
+            .await
+            .unwrap()
+            .database("test");
+        let principal = principal.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(db))
+                .wrap_fn(move |req, srv| {
+                    req.extensions_mut().insert(principal.clone());
+                    srv.call(req)
+                })
+                .service(crate::export::export_rosters)
+                .service(crate::rate_limit::list_lockouts)
+                .service(crate::audit::query_log)
+                .service(crate::create_dorm)
+                .service(crate::search_students)
+                .service(crate::duplicates::list_duplicates)
+                .service(crate::api_keys::list_keys)
+                .service(crate::update_school_settings)
+                .service(crate::sessions::list_sessions),
+        )
+        .await;
+        let mut req = TestRequest::default().method(method.clone()).uri(uri);
+        if let Some(body) = body {
+            req = req.set_json(body);
+        }
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    #[actix_web::test]
+    async fn handlers_let_in_the_roles_that_hold_their_permission() {
+        let school = ObjectId::new();
+        let dorm = ObjectId::new();
+        let other_school = ObjectId::new();
+
+        for &role in ROLES {
+            let caller = person(vec![grant(role, school, dorm)]);
+            for (method, uri, body, allowed) in school_requests(school) {
+                let status = call(&caller, &method, &uri, body.as_ref()).await;
+                assert_eq!(status != StatusCode::FORBIDDEN, allowed.contains(&role), "{:?} on {} {}", role, method, uri);
+            }
+            for (method, uri, body, allowed) in school_requests(other_school) {
+                let status = call(&caller, &method, &uri, body.as_ref()).await;
+                assert_eq!(
+                    status != StatusCode::FORBIDDEN,
+                    role == Role::SuperAdmin && allowed.contains(&role),
+                    "{:?} on {} {} in another school",
+                    role,
+                    method,
+                    uri
+                );
+            }
+            assert_ne!(call(&caller, &Method::GET, "/sessions", None).await, StatusCode::FORBIDDEN, "{:?}", role);
+        }
+    }
+
+    #[test]
+    fn dorm_grants_stay_in_their_dorm() {
+        let school = ObjectId::new();
+        let grants = [grant(Role::ResidentAssistant, school, ObjectId::new())];
+        let other_dorm = Scope::Dorm {
+            school_id: Some(school),
+            dorm_id: ObjectId::new(),
+        };
+        assert!(!allows(&grants, Permission::ManageRooms, &other_dorm));
+    }
+
+    #[test]
+    fn grants_stay_within_the_account_school() {
+        let school = ObjectId::new();
+        let dorm = ObjectId::new();
+        let other_school = ObjectId::new();
+
+        for &role in ROLES {
+            let granted = grant(role, school, dorm);
+            assert!(within_school(&granted, Some(school)));
+            assert!(within_school(&granted, None));
+            assert_eq!(within_school(&granted, Some(other_school)), role == Role::SuperAdmin, "{:?}", role);
+        }
+        let unscoped = RoleGrant {
+            role: Role::SchoolAdmin,
+            school_id: None,
+            dorm_id: None,
+        };
+        assert!(!within_school(&unscoped, Some(school)));
+    }
+
+    #[test]
+    fn legacy_dorms_are_only_visible_to_unscoped_grants() {
+        let legacy = Scope::Dorm {
+            school_id: None,
+            dorm_id: ObjectId::new(),
+        };
+        let school_admin = [RoleGrant {
+            role: Role::SchoolAdmin,
+            school_id: Some(ObjectId::new()),
+            dorm_id: None,
+        }];
+        let legacy_student = [RoleGrant {
+            role: Role::Student,
+            school_id: None,
+            dorm_id: None,
+        }];
+
+        assert!(!allows(&school_admin, Permission::ManageRooms, &legacy));
+        assert!(allows(&legacy_student, Permission::ChooseOwnRoom, &legacy));
+    }
+
+    fn api_key(school: ObjectId, scopes: &[ApiScope]) -> Principal {
+        Principal::from_api_key(api_keys::ApiKey {
+            id: Some(ObjectId::new()),
+            school_id: school,
+            name: "registrar".to_string(),
+            prefix: "dk_00000000".to_string(),
+            key_hash: String::new(),
+            scopes: scopes.to_vec(),
+            created_by: ObjectId::new(),
+            created_at: mongodb::bson::DateTime::now(),
+            expires_at: mongodb::bson::DateTime::now(),
+            last_used_at: None,
+            revoked_at: None,
+        })
+    }
+
+    #[actix_web::test]
+    async fn api_keys_are_limited_to_their_scopes_and_school() {
+        let school = ObjectId::new();
+        let other_school = ObjectId::new();
+
+        let scoped = [
+            (ApiScope::ReadOnly, &["/admin/export", "/admin/lockouts"][..]),
+            (ApiScope::Import, &[][..]),
+            (ApiScope::Assignments, &["/admin/export"][..]),
+        ];
+        for (api_scope, reachable) in scoped {
+            let key = api_key(school, &[api_scope]);
+            for (method, uri, body, _) in school_requests(school) {
+                let status = call(&key, &method, &uri, body.as_ref()).await;
+                let path = uri.split('?').next().unwrap();
+                assert_eq!(status != StatusCode::FORBIDDEN, reachable.contains(&path), "{:?} key on {} {}", api_scope, method, uri);
+            }
+            for (method, uri, body, _) in school_requests(other_school) {
+                assert_eq!(call(&key, &method, &uri, body.as_ref()).await, StatusCode::FORBIDDEN);
+            }
+            assert_eq!(call(&key, &Method::GET, "/sessions", None).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn api_keys_act_as_themselves() {
+        let key = api_key(ObjectId::new(), &[ApiScope::Import]);
+        assert!(matches!(key.identity, Identity::ApiKey(id) if id == key.id()));
+        assert_eq!(key.session().unwrap_err().status(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn dorm_filters_match_what_may_allows() {
+        let school = ObjectId::new();
+        let dorm = ObjectId::new();
+        let nothing = doc! { "_id": { "$in": [] } };
+
+        let key = api_key(school, &[ApiScope::ReadOnly]);
+        assert_eq!(
+            key.dorm_filter(Permission::ViewDorms),
+            Some(doc! { "$or": [{ "school_id": school }] })
+        );
+        assert_eq!(key.dorm_filter(Permission::ManageRooms), Some(nothing.clone()));
+
+        let mut person = api_key(school, &[]);
+        person.api_scopes = None;
+        for &role in ROLES {
+            person.grants = vec![grant(role, school, dorm)];
+            let expected = match role {
+                Role::SuperAdmin => None,
+                Role::ResidentAssistant => Some(doc! { "$or": [{ "_id": dorm }] }),
+                _ => Some(doc! { "$or": [{ "school_id": school }] }),
+            };
+            assert_eq!(person.dorm_filter(Permission::ViewDorms), expected, "{:?}", role);
+        }
+        person.grants = vec![grant(Role::Student, school, dorm)];
+        assert_eq!(person.dorm_filter(Permission::ManageRooms), Some(nothing));
+    }
+}
@@ -0,0 +1,410 @@
+// Room sheets are the paper lists hung on dorm doors: a heading per room
+// ("Room-513") followed by its residents ("1. Rezwan (170)"). This reads the
+// text OCR makes of them into import data. OCR text is messy, so the parser
+// takes what it can and explains, line by line, what it couldn't use.
+use std::collections::BTreeMap;
+
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    Database,
+};
+use serde::Deserialize;
+
+use crate::imports::{self, ImportOptions, RowError, Roster, StudentData};
+use crate::jobs::BackgroundJobs;
+use crate::mail::Mailer;
+use crate::ocr::{self, OcrProvider};
+use crate::roles::Principal;
+use crate::uploads;
+
+#[derive(Debug, Default)]
+pub struct ParsedSheet {
+    pub room_data: BTreeMap<String, Vec<StudentData>>,
+    // Lines that weren't used, by line number
+    pub diagnostics: Vec<RowError>,
+}
+
+// OCR mistakes letters for digits that look like them
+fn read_digits(text: &str) -> Option<String> {
+    let digits: String = text
+        .chars()
+        .map(|c| match c {
+            'O' | 'o' => '0',
+            'l' | 'I' | '|' => '1',
+            c => c,
+        })
+        .collect();
+    (!digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())).then_some(digits)
+}
+
+// "Room-513", "ROOM 513", "Rm: 5l3"
+fn room_heading(line: &str) -> Option<Result<String, String>> {
+    let lower = line.to_ascii_lowercase();
+    let keyword = ["room", "rm"].into_iter().find(|keyword| lower.starts_with(keyword))?;
+    let rest = &line[keyword.len()..];
+    // "Roommates" isn't a heading
+    if rest.starts_with(|c: char| c.is_alphabetic()) {
+        return None;
+    }
+
+    let rest = rest.trim_start_matches(|c: char| c.is_whitespace() || "-:#.".contains(c));
+    let token = rest.split_whitespace().next().unwrap_or_default();
+    if token.is_empty() {
+        return Some(Err("Room heading without a number".to_string()));
+    }
+    match read_digits(token) {
+        Some(number) => Some(Ok(number)),
+        // Numbers like "B12"
+        None if token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => Some(Ok(token.to_string())),
+        None => Some(Err(format!("Unreadable room number '{}'", token))),
+    }
+}
+
+// Strips "1.", "2)", "-" or "•" from the start of a list item
+fn strip_list_marker(line: &str) -> (bool, &str) {
+    let digits = line.find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len());
+    if digits > 0 && line[digits..].starts_with(['.', ')', ',']) {
+        return (true, line[digits + 1..].trim_start());
+    }
+    match line.strip_prefix(['-', '*', '•', '·']) {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, line),
+    }
+}
+
+// "1. Rezwan (170)", "Sajid (7)", "Ada Lovelace [12", "Grace Hopper - 13"
+fn student_line(line: &str) -> Option<Result<(String, i32), String>> {
+    let (listed, item) = strip_list_marker(line);
+
+    let (name, id) = match item.rfind(['(', '[', '{']) {
+        Some(open) => {
+            let id = item[open + 1..].trim_end_matches([')', ']', '}', ' ']);
+            (&item[..open], Some(id.trim()))
+        }
+        None => match item.rfind([':', ',']).or_else(|| item.rfind(" - ")) {
+            Some(separator) => {
+                let id = item[separator..].trim_start_matches([':', ',', ' ', '-']);
+                (&item[..separator], Some(id.trim()))
+            }
+            None => (item, None),
+        },
+    };
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let name = name.trim_end_matches([' ', '-', ':', ',']).to_string();
+
+    match id {
+        // Only a numbered or bulleted line is surely a student without an id
+        None if listed && name.chars().any(char::is_alphabetic) => Some(Err("Missing student id".to_string())),
+        None => None,
+        Some(_) if !name.chars().any(char::is_alphabetic) => Some(Err("Missing student name".to_string())),
+        Some(id) => match read_digits(id).and_then(|digits| digits.parse::<i32>().ok()) {
+            Some(id) => Some(Ok((name, id))),
+            None => Some(Err(format!("Unreadable student id '{}'", id))),
+        },
+    }
+}
+
+pub fn parse(text: &str) -> ParsedSheet {
+    let mut sheet = ParsedSheet::default();
+    let mut current_room: Option<String> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut diagnose = |message: String| {
+            sheet.diagnostics.push(RowError {
+                row: index + 1,
+                message: format!("{}: '{}'", message, line),
+            })
+        };
+
+        if let Some(heading) = room_heading(line) {
+            match heading {
+                Ok(number) => {
+                    if sheet.room_data.contains_key(&number) {
+                        diagnose(format!("Room {} appears again; its students were added to the first list", number));
+                    }
+                    sheet.room_data.entry(number.clone()).or_default();
+                    current_room = Some(number);
+                }
+                Err(message) => {
+                    // Don't put the students that follow into the previous room
+                    current_room = None;
+                    diagnose(message);
+                }
+            }
+            continue;
+        }
+
+        match (student_line(line), &current_room) {
+            (Some(Ok((name, id))), Some(room)) => {
+                let students = sheet.room_data.entry(room.clone()).or_default();
+                students.push(StudentData { name, id, email: None });
+            }
+            (Some(Ok(_)), None) => diagnose("Student listed outside a room".to_string()),
+            (Some(Err(message)), _) => diagnose(message),
+            (None, _) => diagnose("Unrecognised line".to_string()),
+        }
+    }
+    sheet
+}
+
+// Several sheets, e.g. one photo per floor, as (name, text) pairs.
+// Diagnostics say which sheet they're about.
+pub fn parse_pages(pages: &[(String, String)]) -> ParsedSheet {
+    let mut merged = ParsedSheet::default();
+    for (page, text) in pages {
+        let sheet = parse(text);
+        for (room, students) in sheet.room_data {
+            merged.room_data.entry(room).or_default().extend(students);
+        }
+        merged.diagnostics.extend(sheet.diagnostics.into_iter().map(|diagnostic| RowError {
+            row: diagnostic.row,
+            message: format!("{}: {}", page, diagnostic.message),
+        }));
+    }
+    merged
+}
+
+#[derive(Debug, Deserialize)]
+struct OcrImportRequest {
+    dorm_id: String,
+    // The text of one or more room sheets
+    text: String,
+    #[serde(flatten)]
+    options: ImportOptions,
+}
+
+fn diagnostics_response(diagnostics: &[RowError]) -> HttpResponse {
+    let diagnostics: Vec<Document> = diagnostics
+        .iter()
+        .map(|diagnostic| doc! { "row": diagnostic.row as i64, "message": &diagnostic.message })
+        .collect();
+    HttpResponse::BadRequest().json(doc! {
+        "error": "No rooms found in the text",
+        "row_errors": diagnostics,
+    })
+}
+
+// Plans an import batch from room sheet text. Always a dry run: OCR text
+// should be looked over before anything is committed.
+#[post("/admin/import-rooms/ocr-text")]
+async fn import_ocr_text(
+    http_req: HttpRequest,
+    req: web::Json<OcrImportRequest>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    principal: Principal,
+) -> impl Responder {
+    let req = req.into_inner();
+    let dorm_id = match ObjectId::parse_str(&req.dorm_id) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid dorm ID format"
+        }),
+    };
+
+    let sheet = parse(&req.text);
+    println!(
+        "Parsed {} rooms from room sheet text ({} lines not used)",
+        sheet.room_data.len(),
+        sheet.diagnostics.len()
+    );
+    if sheet.room_data.is_empty() {
+        return diagnostics_response(&sheet.diagnostics);
+    }
+
+    let roster = Roster {
+        dorm_id,
+        room_data: sheet.room_data,
+        capacities: BTreeMap::new(),
+        row_errors: sheet.diagnostics,
+        source_text: Some(req.text),
+        removals: Vec::new(),
+    };
+    let options = ImportOptions {
+        dry_run: true,
+        ..req.options
+    };
+    imports::start(&http_req, &db, &jobs, mailer.into_inner(), &principal, roster, &options).await
+}
+
+// Plans an import batch from photos of room sheets: a multipart form with
+// the images as files, plus `dorm_id` and the same options as a JSON import.
+// Always a dry run, like the text import.
+#[post("/admin/import-rooms/images")]
+async fn import_images(
+    http_req: HttpRequest,
+    payload: Multipart,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    ocr: web::Data<dyn OcrProvider>,
+    principal: Principal,
+) -> impl Responder {
+    let form = match uploads::read_form(payload).await {
+        Ok(form) => form,
+        Err(response) => return response,
+    };
+    let dorm_id = match uploads::import_dorm(&db, &principal, &form).await {
+        Ok(dorm_id) => dorm_id,
+        Err(response) => return response,
+    };
+    let options = match uploads::import_options(&form.fields) {
+        Ok(options) => ImportOptions {
+            dry_run: true,
+            ..options
+        },
+        Err(error) => return HttpResponse::BadRequest().json(doc! { "error": error }),
+    };
+    if form.files.is_empty() {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "No images uploaded"
+        });
+    }
+
+    let mut pages = Vec::new();
+    for (index, image) in form.files.into_iter().enumerate() {
+        let name = image.filename.unwrap_or_else(|| format!("image {}", index + 1));
+        match ocr::recognize(ocr.clone().into_inner(), image.bytes).await {
+            Ok(text) => pages.push((name, text)),
+            Err(e) => {
+                println!("OCR failed for {}: {:?}", name, e);
+                return HttpResponse::BadRequest().json(doc! {
+                    "error": format!("Could not read text from {}", name)
+                });
+            }
+        }
+    }
+
+    let sheet = parse_pages(&pages);
+    println!(
+        "Read {} rooms from {} room sheet images ({} lines not used)",
+        sheet.room_data.len(),
+        pages.len(),
+        sheet.diagnostics.len()
+    );
+    if sheet.room_data.is_empty() {
+        return diagnostics_response(&sheet.diagnostics);
+    }
+
+    let source_text = pages
+        .iter()
+        .map(|(name, text)| format!("--- {} ---\n{}", name, text.trim_end()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let roster = Roster {
+        dorm_id,
+        room_data: sheet.room_data,
+        capacities: BTreeMap::new(),
+        row_errors: sheet.diagnostics,
+        source_text: Some(source_text),
+        removals: Vec::new(),
+    };
+    imports::start(&http_req, &db, &jobs, mailer.into_inner(), &principal, roster, &options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ocr::FixtureOcr;
+
+    fn students(sheet: &ParsedSheet, room: &str) -> Vec<(String, i32)> {
+        sheet.room_data[room]
+            .iter()
+            .map(|student| (student.name.clone(), student.id))
+            .collect()
+    }
+
+    #[test]
+    fn parses_the_room_sheet_format() {
+        let sheet = parse("Room-513\n1. Rezwan (170)\n2. Shafin (59)\nSajid (7)\n\nRoom-514\n1.Nahid(49)\n");
+
+        assert!(sheet.diagnostics.is_empty(), "{:?}", sheet.diagnostics);
+        assert_eq!(
+            students(&sheet, "513"),
+            vec![("Rezwan".to_string(), 170), ("Shafin".to_string(), 59), ("Sajid".to_string(), 7)]
+        );
+        assert_eq!(students(&sheet, "514"), vec![("Nahid".to_string(), 49)]);
+    }
+
+    #[test]
+    fn tolerates_ocr_noise() {
+        let sheet = parse(
+            "ROOM 5l3\n\
+             1) Ada  Lovelace [12\n\
+             2, Grace Hopper - 1O\n\
+             - Alan Turing: 14\n\
+             Rm: B12\n\
+             Edsger Dijkstra {15}\n",
+        );
+
+        assert!(sheet.diagnostics.is_empty(), "{:?}", sheet.diagnostics);
+        assert_eq!(
+            students(&sheet, "513"),
+            vec![
+                ("Ada Lovelace".to_string(), 12),
+                ("Grace Hopper".to_string(), 10),
+                ("Alan Turing".to_string(), 14),
+            ]
+        );
+        assert_eq!(students(&sheet, "B12"), vec![("Edsger Dijkstra".to_string(), 15)]);
+    }
+
+    #[test]
+    fn explains_lines_it_could_not_use() {
+        let sheet = parse(
+            "Floor 5 roster\n\
+             Rezwan (170)\n\
+             Room-513\n\
+             1. Shafin\n\
+             2. Nahid (4x9)\n\
+             (172)\n\
+             Roommates are listed below\n\
+             Room-\n\
+             Sajid (7)\n\
+             Room 513\n",
+        );
+
+        let rows: Vec<usize> = sheet.diagnostics.iter().map(|diagnostic| diagnostic.row).collect();
+        assert_eq!(rows, vec![1, 2, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(sheet.diagnostics[0].message, "Unrecognised line: 'Floor 5 roster'");
+        assert_eq!(sheet.diagnostics[1].message, "Student listed outside a room: 'Rezwan (170)'");
+        assert_eq!(sheet.diagnostics[2].message, "Missing student id: '1. Shafin'");
+        assert!(sheet.diagnostics[3].message.starts_with("Unreadable student id '4x9'"));
+        assert!(sheet.diagnostics[4].message.starts_with("Missing student name"));
+        assert!(sheet.diagnostics[6].message.starts_with("Room heading without a number"));
+        assert!(sheet.diagnostics[7].message.starts_with("Student listed outside a room"));
+        assert!(sheet.diagnostics[8].message.starts_with("Room 513 appears again"));
+        assert!(sheet.room_data["513"].is_empty());
+    }
+
+    #[test]
+    fn photos_are_read_page_by_page() {
+        let ocr = FixtureOcr::new(&[
+            (b"floor 5", "Room-513\n1. Rezwan (170)\nsmudge\n"),
+            (b"floor 6", "Room-613\n1. Shafin (59)\n"),
+        ]);
+        let pages: Vec<(String, String)> = [("floor5.jpg", &b"floor 5"[..]), ("floor6.jpg", &b"floor 6"[..])]
+            .into_iter()
+            .map(|(name, image)| (name.to_string(), ocr.recognize(image).unwrap()))
+            .collect();
+
+        let sheet = parse_pages(&pages);
+
+        assert_eq!(students(&sheet, "513"), vec![("Rezwan".to_string(), 170)]);
+        assert_eq!(students(&sheet, "613"), vec![("Shafin".to_string(), 59)]);
+        assert_eq!(
+            sheet.diagnostics,
+            vec![RowError {
+                row: 3,
+                message: "floor5.jpg: Unrecognised line: 'smudge'".to_string(),
+            }]
+        );
+    }
+}
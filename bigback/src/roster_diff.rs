@@ -0,0 +1,504 @@
+// What a new room sheet changes compared with who lives in a dorm now.
+// Staff review the diff, then apply the changes they pick; applying runs as
+// an import batch, so it can be previewed with dry_run and rolled back.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::accounts;
+use crate::credentials;
+use crate::imports::{self, ExistingRoom, ExistingStudent, ImportOptions, MergePolicy, Roster, StudentData};
+use crate::jobs::BackgroundJobs;
+use crate::mail::Mailer;
+use crate::roles::{self, authorize, Permission, Principal, Scope};
+use crate::Room;
+
+// A student added to the dorm, taken out of it, or moved within it. `key`
+// names the change when applying it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StudentChange {
+    pub key: String,
+    pub name: Option<String>,
+    pub student_id: Option<i32>,
+    pub email: Option<String>,
+    pub from_room: Option<String>,
+    pub to_room: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CapacityChange {
+    pub key: String,
+    pub room: String,
+    pub from: i32,
+    pub to: i32,
+}
+
+// A room on the sheet the dorm doesn't have yet
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NewRoom {
+    pub key: String,
+    pub room: String,
+    pub capacity: Option<i32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RosterDiff {
+    pub added: Vec<StudentChange>,
+    pub removed: Vec<StudentChange>,
+    pub moved: Vec<StudentChange>,
+    pub capacity_changes: Vec<CapacityChange>,
+    pub new_rooms: Vec<NewRoom>,
+    // Students already in the room the sheet puts them in
+    pub unchanged: usize,
+    // Rows that can't be compared, e.g. a student id listed twice
+    pub problems: Vec<String>,
+}
+
+// Compares the sheet with every room of the dorm, by number, and the
+// school's accounts that the sheet's students or the dorm's occupants might
+// be. Students are matched by student id, then email, as in an import.
+pub fn diff(
+    room_data: &BTreeMap<String, Vec<StudentData>>,
+    capacities: &BTreeMap<String, i32>,
+    email_pattern: Option<&str>,
+    rooms: &BTreeMap<String, ExistingRoom>,
+    accounts: &[ExistingStudent],
+) -> RosterDiff {
+    let mut diff = RosterDiff::default();
+    let current: HashMap<&str, &str> = rooms
+        .iter()
+        .flat_map(|(number, room)| room.occupants.iter().map(move |email| (email.as_str(), number.as_str())))
+        .collect();
+    let mut seen_ids = HashSet::new();
+    let mut listed = HashSet::new();
+
+    for (number, rows) in room_data {
+        for row in rows {
+            if !seen_ids.insert(row.id) {
+                diff.problems.push(format!("Student id {} appears more than once", row.id));
+                continue;
+            }
+            let email = match (&row.email, email_pattern) {
+                (Some(email), _) => Some(email.trim().to_string()),
+                (None, Some(pattern)) => Some(credentials::render_email(pattern, row.id, &row.name)),
+                (None, None) => None,
+            };
+            let account = accounts
+                .iter()
+                .find(|account| account.student_id == Some(row.id))
+                .or_else(|| {
+                    let email = email.as_ref()?;
+                    accounts.iter().find(|account| &account.email == email)
+                });
+            let Some(email) = account.map(|account| account.email.clone()).or(email) else {
+                diff.problems.push(format!("{} ({}) has no email and there's no email pattern", row.name, row.id));
+                continue;
+            };
+            if !listed.insert(email.clone()) {
+                diff.problems.push(format!("{} appears more than once", email));
+                continue;
+            }
+
+            let from_room = current.get(email.as_str()).map(|room| room.to_string());
+            if from_room.as_deref() == Some(number.as_str()) {
+                diff.unchanged += 1;
+                continue;
+            }
+            let (kind, list) = match from_room {
+                Some(_) => ("move", &mut diff.moved),
+                None => ("add", &mut diff.added),
+            };
+            list.push(StudentChange {
+                key: format!("{}:{}", kind, row.id),
+                name: Some(row.name.clone()),
+                student_id: Some(row.id),
+                email: Some(email),
+                from_room,
+                to_room: Some(number.clone()),
+            });
+        }
+    }
+
+    for (number, room) in rooms {
+        for email in room.occupants.iter().filter(|email| !listed.contains(*email)) {
+            let account = accounts.iter().find(|account| &account.email == email);
+            diff.removed.push(StudentChange {
+                key: format!("remove:{}", email),
+                name: account.and_then(|account| account.name.clone()),
+                student_id: account.and_then(|account| account.student_id),
+                email: Some(email.clone()),
+                from_room: Some(number.clone()),
+                to_room: None,
+            });
+        }
+    }
+
+    for (number, &capacity) in capacities {
+        if let Some(room) = rooms.get(number).filter(|room| room.capacity != capacity) {
+            diff.capacity_changes.push(CapacityChange {
+                key: format!("capacity:{}", number),
+                room: number.clone(),
+                from: room.capacity,
+                to: capacity,
+            });
+        }
+    }
+    for number in room_data.keys().filter(|number| !rooms.contains_key(*number)) {
+        diff.new_rooms.push(NewRoom {
+            key: format!("room:{}", number),
+            room: number.clone(),
+            capacity: capacities.get(number).copied(),
+        });
+    }
+    diff
+}
+
+// The roster that makes just the chosen changes when imported with the merge
+// policy, or the keys that aren't in the diff
+pub fn selected(diff: &RosterDiff, dorm_id: ObjectId, keys: &[String]) -> Result<Roster, Vec<String>> {
+    let known: HashSet<&String> = diff
+        .added
+        .iter()
+        .chain(&diff.moved)
+        .chain(&diff.removed)
+        .map(|change| &change.key)
+        .chain(diff.capacity_changes.iter().map(|change| &change.key))
+        .chain(diff.new_rooms.iter().map(|room| &room.key))
+        .collect();
+    let unknown: Vec<String> = keys.iter().filter(|key| !known.contains(key)).cloned().collect();
+    if !unknown.is_empty() {
+        return Err(unknown);
+    }
+
+    let chosen = |key: &String| keys.contains(key);
+    let mut roster = Roster {
+        dorm_id,
+        room_data: BTreeMap::new(),
+        capacities: BTreeMap::new(),
+        row_errors: Vec::new(),
+        source_text: None,
+        removals: Vec::new(),
+    };
+    for change in diff.added.iter().chain(&diff.moved).filter(|change| chosen(&change.key)) {
+        let (Some(room), Some(name), Some(id)) = (&change.to_room, &change.name, change.student_id) else {
+            continue;
+        };
+        roster.room_data.entry(room.clone()).or_default().push(StudentData {
+            name: name.clone(),
+            id,
+            email: change.email.clone(),
+        });
+    }
+    for change in diff.removed.iter().filter(|change| chosen(&change.key)) {
+        if let (Some(room), Some(email)) = (&change.from_room, &change.email) {
+            roster.room_data.entry(room.clone()).or_default();
+            roster.removals.push(email.clone());
+        }
+    }
+    for change in diff.capacity_changes.iter().filter(|change| chosen(&change.key)) {
+        roster.room_data.entry(change.room.clone()).or_default();
+        roster.capacities.insert(change.room.clone(), change.to);
+    }
+    // New rooms come with their capacity from the sheet, whether they were
+    // chosen themselves or someone chosen moves in
+    for room in &diff.new_rooms {
+        if chosen(&room.key) {
+            roster.room_data.entry(room.room.clone()).or_default();
+        }
+        if let (true, Some(capacity)) = (roster.room_data.contains_key(&room.room), room.capacity) {
+            roster.capacities.insert(room.room.clone(), capacity);
+        }
+    }
+    Ok(roster)
+}
+
+// Everything in the dorm, and the accounts of its school the comparison needs
+async fn occupancy(
+    db: &Database,
+    dorm_id: ObjectId,
+    school_id: Option<ObjectId>,
+    room_data: &BTreeMap<String, Vec<StudentData>>,
+    email_pattern: Option<&str>,
+) -> Result<(BTreeMap<String, ExistingRoom>, Vec<ExistingStudent>), mongodb::error::Error> {
+    let mut rooms = BTreeMap::new();
+    let mut cursor = db.collection::<Room>("rooms").find(doc! { "dorm_id": dorm_id }, None).await?;
+    while let Some(room) = cursor.next().await {
+        let room = room?;
+        rooms.insert(
+            room.number.clone(),
+            ExistingRoom {
+                id: room.id.expect("Stored rooms have an ID"),
+                capacity: room.capacity,
+                occupants: room.current_students.into_iter().map(|student| student.name).collect(),
+            },
+        );
+    }
+
+    let ids: Vec<i32> = room_data.values().flatten().map(|student| student.id).collect();
+    let emails: Vec<String> = room_data
+        .values()
+        .flatten()
+        .filter_map(|student| match (&student.email, email_pattern) {
+            (Some(email), _) => Some(email.trim().to_string()),
+            (None, Some(pattern)) => Some(credentials::render_email(pattern, student.id, &student.name)),
+            (None, None) => None,
+        })
+        .chain(rooms.values().flat_map(|room| room.occupants.iter().cloned()))
+        .collect();
+    let mut students = Vec::new();
+    let mut cursor = accounts::collection(db)
+        .find(
+            doc! {
+                "school_id": school_id,
+                "$or": [{ "student_id": { "$in": ids } }, { "email": { "$in": emails } }],
+            },
+            None,
+        )
+        .await?;
+    while let Some(account) = cursor.next().await {
+        let account = account?;
+        students.push(ExistingStudent {
+            id: account.id.expect("Stored accounts have an ID"),
+            is_staff: account.is_staff(),
+            email: account.email,
+            name: account.name,
+            student_id: account.student_id,
+            assigned_room: account.assigned_room,
+        });
+    }
+    Ok((rooms, students))
+}
+
+// Diffs a JSON roster against a dorm the caller may import into
+async fn compare(
+    db: &Database,
+    principal: &Principal,
+    dorm_id: &str,
+    room_data: &serde_json::Value,
+    email_pattern: Option<&str>,
+) -> Result<(ObjectId, RosterDiff), HttpResponse> {
+    let dorm_oid = ObjectId::parse_str(dorm_id).map_err(|_| {
+        HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid dorm ID format"
+        })
+    })?;
+    let school_id = match roles::dorm_scope(db, dorm_oid).await {
+        Ok(Some(scope)) => {
+            authorize(principal, Permission::ImportRooms, &scope)?;
+            match scope {
+                Scope::Dorm { school_id, .. } => school_id,
+                _ => None,
+            }
+        }
+        Ok(None) => return Err(HttpResponse::NotFound().json(doc! {
+            "error": "Dorm not found"
+        })),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }));
+        }
+    };
+    if let Some(pattern) = email_pattern {
+        credentials::check_email_pattern(pattern).map_err(|error| HttpResponse::BadRequest().json(doc! { "error": error }))?;
+    }
+    let sheet = imports::read_room_data(dorm_oid, room_data)
+        .map_err(|error| HttpResponse::BadRequest().json(doc! { "error": error }))?;
+
+    match occupancy(db, dorm_oid, school_id, &sheet.room_data, email_pattern).await {
+        Ok((rooms, accounts)) => {
+            let diff = diff(&sheet.room_data, &sheet.capacities, email_pattern, &rooms, &accounts);
+            Ok((dorm_oid, diff))
+        }
+        Err(e) => {
+            println!("Error loading occupancy: {:?}", e);
+            Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DiffRequest {
+    dorm_id: String,
+    room_data: serde_json::Value,
+    email_pattern: Option<String>,
+}
+
+// Compares an import's room_data with the dorm's current occupancy without
+// changing anything
+#[post("/admin/roster-diff")]
+async fn roster_diff(
+    req: web::Json<DiffRequest>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    match compare(&db, &principal, &req.dorm_id, &req.room_data, req.email_pattern.as_deref()).await {
+        Ok((_, diff)) => HttpResponse::Ok().json(diff),
+        Err(response) => response,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyRequest {
+    dorm_id: String,
+    room_data: serde_json::Value,
+    // Keys of the changes to make, from the diff
+    changes: Vec<String>,
+    #[serde(flatten)]
+    options: ImportOptions,
+}
+
+// Makes the chosen changes of a diff as an import batch. The diff is worked
+// out again first, so changes that no longer apply are refused rather than
+// made against a different dorm than the one reviewed.
+#[post("/admin/roster-diff/apply")]
+async fn apply_roster_diff(
+    http_req: HttpRequest,
+    req: web::Json<ApplyRequest>,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    principal: Principal,
+) -> impl Responder {
+    let mut req = req.into_inner();
+    if req.changes.is_empty() {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "Choose at least one change to apply"
+        });
+    }
+    let (dorm_id, diff) =
+        match compare(&db, &principal, &req.dorm_id, &req.room_data, req.options.email_pattern.as_deref()).await {
+            Ok(compared) => compared,
+            Err(response) => return response,
+        };
+    let roster = match selected(&diff, dorm_id, &req.changes) {
+        Ok(roster) => roster,
+        Err(unknown) => return HttpResponse::Conflict().json(doc! {
+            "error": "Some changes are no longer in the diff",
+            "changes": unknown,
+        }),
+    };
+
+    // Only the chosen changes: everyone else stays where they are
+    req.options.policy = MergePolicy::Merge;
+    let mailer: Arc<dyn Mailer> = mailer.into_inner();
+    imports::start(&http_req, &db, &jobs, mailer, &principal, roster, &req.options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn student(name: &str, id: i32, email: &str) -> StudentData {
+        StudentData {
+            name: name.to_string(),
+            id,
+            email: Some(email.to_string()),
+        }
+    }
+
+    fn account(email: &str, student_id: Option<i32>, room: &str) -> ExistingStudent {
+        ExistingStudent {
+            id: ObjectId::new(),
+            email: email.to_string(),
+            name: None,
+            student_id,
+            assigned_room: Some(room.to_string()),
+            is_staff: false,
+        }
+    }
+
+    fn room(capacity: i32, occupants: &[&str]) -> ExistingRoom {
+        ExistingRoom {
+            id: ObjectId::new(),
+            capacity,
+            occupants: occupants.iter().map(|email| email.to_string()).collect(),
+        }
+    }
+
+    // Ada stays, Bob moves to 102, Cy leaves, Dee arrives, 102's capacity
+    // goes up and 103 is new
+    fn term() -> RosterDiff {
+        let sheet = BTreeMap::from([
+            ("101".to_string(), vec![student("Ada", 1, "ada@x.edu")]),
+            ("102".to_string(), vec![student("Bob", 2, "other@x.edu")]),
+            ("103".to_string(), vec![student("Dee", 4, "dee@x.edu")]),
+        ]);
+        let capacities = BTreeMap::from([("102".to_string(), 3), ("103".to_string(), 2)]);
+        let rooms = BTreeMap::from([
+            ("101".to_string(), room(2, &["ada@x.edu", "bob@x.edu"])),
+            ("102".to_string(), room(2, &["cy@x.edu"])),
+        ]);
+        let accounts = [
+            account("ada@x.edu", Some(1), "101"),
+            account("bob@x.edu", Some(2), "101"),
+            account("cy@x.edu", None, "102"),
+        ];
+        diff(&sheet, &capacities, None, &rooms, &accounts)
+    }
+
+    #[test]
+    fn diff_lists_arrivals_departures_moves_and_capacities() {
+        let diff = term();
+
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].key, "add:4");
+        assert_eq!(diff.added[0].to_room.as_deref(), Some("103"));
+        // Matched by student id, so the sheet's email doesn't matter
+        assert_eq!(diff.moved.len(), 1);
+        assert_eq!(diff.moved[0].email.as_deref(), Some("bob@x.edu"));
+        assert_eq!(diff.moved[0].from_room.as_deref(), Some("101"));
+        assert_eq!(diff.moved[0].to_room.as_deref(), Some("102"));
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].key, "remove:cy@x.edu");
+        assert_eq!(diff.capacity_changes.len(), 1);
+        assert_eq!((diff.capacity_changes[0].from, diff.capacity_changes[0].to), (2, 3));
+        assert_eq!(diff.new_rooms[0].key, "room:103");
+        assert!(diff.problems.is_empty());
+    }
+
+    #[test]
+    fn applying_makes_only_the_chosen_changes() {
+        let diff = term();
+        let dorm_id = ObjectId::new();
+
+        let roster = selected(&diff, dorm_id, &["move:2".to_string(), "remove:cy@x.edu".to_string()]).unwrap();
+        assert_eq!(roster.room_data.keys().collect::<Vec<_>>(), vec!["102"]);
+        assert_eq!(roster.room_data["102"][0].id, 2);
+        assert_eq!(roster.removals, vec!["cy@x.edu"]);
+        // 102's new capacity wasn't chosen
+        assert!(roster.capacities.is_empty());
+
+        // Adding Dee creates 103 with the sheet's capacity
+        let roster = selected(&diff, dorm_id, &["add:4".to_string()]).unwrap();
+        assert_eq!(roster.capacities, BTreeMap::from([("103".to_string(), 2)]));
+
+        let unknown = selected(&diff, dorm_id, &["add:9".to_string()]).unwrap_err();
+        assert_eq!(unknown, vec!["add:9"]);
+    }
+
+    #[test]
+    fn removals_come_out_of_planned_rosters() {
+        let rooms = HashMap::from([("102".to_string(), room(1, &["cy@x.edu", "eve@x.edu"]))]);
+        let sheet = BTreeMap::from([("102".to_string(), Vec::new())]);
+        let mut plan = imports::plan_rows(&sheet, &BTreeMap::new(), None, None, MergePolicy::Merge, &rooms, &[]);
+        assert_eq!(plan[0].warnings, vec!["2 students but a capacity of 1"]);
+
+        imports::take_out(&mut plan, &["cy@x.edu".to_string()]);
+        assert_eq!(plan[0].roster, vec!["eve@x.edu"]);
+        assert_eq!(plan[0].removed, vec!["cy@x.edu"]);
+        assert_eq!(plan[0].status, imports::RowStatus::Updated);
+        assert!(plan[0].warnings.is_empty());
+    }
+}
@@ -0,0 +1,1353 @@
+// SCIM 2.0 (RFC 7643/7644) provisioning for student accounts. Identity
+// systems push users and groups here with a per-school bearer token; users
+// become student accounts of that school and groups map to dorms (when the
+// group's name matches a dorm) or cohorts.
+use actix_web::{
+    delete, dev::Payload, error::InternalError, get, http::StatusCode, mime, patch, post, put,
+    web, FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use futures::{future::LocalBoxFuture, StreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::accounts::{self, Account};
+use crate::audit::{self, AuditEntry};
+use crate::auth;
+use crate::pagination::{Page, PageQuery};
+use crate::passwords;
+use crate::roles::{authorize, Permission, Principal, Scope};
+use crate::tokens;
+use crate::Dorm;
+
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SCIM_CONTENT_TYPE: &str = "application/scim+json";
+const MAX_PAGE_SIZE: u64 = 200;
+
+// A bearer token an identity system uses to provision one school
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScimToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub school_id: ObjectId,
+    pub name: String,
+    pub token_hash: String,
+    pub created_by: String,
+    pub created_at: DateTime,
+    pub last_used_at: Option<DateTime>,
+    pub revoked_at: Option<DateTime>,
+}
+
+// A SCIM group. Members are account ids of the same school.
+#[derive(Debug, Serialize, Deserialize)]
+struct ScimGroup {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    school_id: ObjectId,
+    display_name: String,
+    external_id: Option<String>,
+    // Set when the group is a dorm of the school; otherwise it's a cohort
+    dorm_id: Option<ObjectId>,
+    members: Vec<ObjectId>,
+}
+
+fn scim_error(status: StatusCode, scim_type: Option<&str>, detail: &str) -> HttpResponse {
+    let mut body = json!({
+        "schemas": [ERROR_SCHEMA],
+        "status": status.as_u16().to_string(),
+        "detail": detail,
+    });
+    if let Some(scim_type) = scim_type {
+        body["scimType"] = json!(scim_type);
+    }
+    HttpResponse::build(status)
+        .content_type(SCIM_CONTENT_TYPE)
+        .json(body)
+}
+
+fn internal_error(e: mongodb::error::Error) -> HttpResponse {
+    println!("SCIM database error: {:?}", e);
+    scim_error(StatusCode::INTERNAL_SERVER_ERROR, None, "Internal server error")
+}
+
+fn scim_ok(status: StatusCode, body: Value) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(SCIM_CONTENT_TYPE)
+        .json(body)
+}
+
+// SCIM clients send `application/scim+json`, which the default JSON
+// extractor would reject
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .content_type(|content_type| {
+            content_type.subtype() == mime::JSON || content_type.suffix() == Some(mime::JSON)
+        })
+        .error_handler(|err, _| {
+            let detail = err.to_string();
+            InternalError::from_response(
+                err,
+                scim_error(StatusCode::BAD_REQUEST, Some("invalidSyntax"), &detail),
+            )
+            .into()
+        })
+}
+
+// The identity system behind a SCIM request
+#[derive(Debug, Clone)]
+pub struct ScimClient {
+    pub school_id: ObjectId,
+    pub name: String,
+}
+
+impl ScimClient {
+    fn actor(&self) -> String {
+        format!("scim:{}", self.name)
+    }
+}
+
+impl FromRequest for ScimClient {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let db = req.app_data::<web::Data<Database>>().cloned();
+        let token = auth::bearer_token(req);
+
+        Box::pin(async move {
+            let unauthorized = || {
+                InternalError::from_response(
+                    "Invalid SCIM token",
+                    scim_error(StatusCode::UNAUTHORIZED, None, "Invalid or revoked token"),
+                )
+                .into()
+            };
+            let (Some(db), Some(token)) = (db, token) else {
+                return Err(unauthorized());
+            };
+
+            let result = db
+                .collection::<ScimToken>("scim_tokens")
+                .find_one_and_update(
+                    doc! { "token_hash": tokens::hash_token(&token), "revoked_at": null },
+                    doc! { "$set": { "last_used_at": DateTime::now() } },
+                    None,
+                )
+                .await;
+            match result {
+                Ok(Some(token)) => Ok(ScimClient {
+                    school_id: token.school_id,
+                    name: token.name,
+                }),
+                Ok(None) => Err(unauthorized()),
+                Err(e) => {
+                    println!("Error looking up SCIM token: {:?}", e);
+                    Err(actix_web::error::ErrorInternalServerError("Internal server error"))
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+    filter: Option<String>,
+    start_index: Option<u64>,
+    count: Option<u64>,
+}
+
+// Only `attribute eq "value"` filters are supported, which is what identity
+// systems use to look up a resource before creating it
+fn parse_filter(filter: &str) -> Option<(String, String)> {
+    let (attribute, rest) = filter.trim().split_once(char::is_whitespace)?;
+    let (operator, value) = rest.trim().split_once(char::is_whitespace)?;
+    if !operator.eq_ignore_ascii_case("eq") {
+        return None;
+    }
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((attribute.to_string(), value.replace("\\\"", "\"")))
+}
+
+fn page(query: &ListQuery) -> (u64, u64) {
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(100).min(MAX_PAGE_SIZE);
+    (start_index, count)
+}
+
+fn list_response(total: u64, start_index: u64, resources: Vec<Value>) -> HttpResponse {
+    scim_ok(
+        StatusCode::OK,
+        json!({
+            "schemas": [LIST_SCHEMA],
+            "totalResults": total,
+            "startIndex": start_index,
+            "itemsPerPage": resources.len(),
+            "Resources": resources,
+        }),
+    )
+}
+
+// Accounts SCIM may manage: students of the client's school. Staff accounts
+// are left alone so a misconfigured sync can't lock admins out.
+fn student_filter(school_id: ObjectId) -> Document {
+    doc! {
+        "school_id": school_id,
+        "roles": { "$not": { "$elemMatch": { "role": { "$ne": "student" } } } },
+    }
+}
+
+// Only a full name is stored; SCIM's given and family names are derived by
+// splitting at the first space
+fn user_resource(account: &Account) -> Value {
+    let id = account.id.expect("Stored accounts have an ID").to_hex();
+    let name = account.name.as_deref().map(|full_name| {
+        let (given, family) = full_name.split_once(' ').unwrap_or((full_name, ""));
+        json!({
+            "formatted": full_name,
+            "givenName": given,
+            "familyName": family,
+        })
+    });
+    json!({
+        "schemas": [USER_SCHEMA],
+        "id": id,
+        "externalId": account.external_id,
+        "userName": account.email,
+        "name": name,
+        "displayName": account.name,
+        "emails": [{ "value": account.email, "primary": true }],
+        "active": account.is_active(),
+        "meta": {
+            "resourceType": "User",
+            "location": format!("/api/scim/v2/Users/{}", id),
+        },
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimName {
+    formatted: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScimEmail {
+    value: String,
+    #[serde(default)]
+    primary: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimUser {
+    user_name: String,
+    external_id: Option<String>,
+    name: Option<ScimName>,
+    display_name: Option<String>,
+    #[serde(default)]
+    emails: Vec<ScimEmail>,
+    active: Option<bool>,
+}
+
+// What a SCIM user means for our account
+struct UserFields {
+    email: String,
+    name: Option<String>,
+    external_id: Option<String>,
+    active: bool,
+}
+
+impl ScimUser {
+    fn fields(&self) -> Result<UserFields, &'static str> {
+        // Accounts log in with an email, so userName is only used when it is one
+        let email = if self.user_name.contains('@') {
+            self.user_name.trim().to_string()
+        } else {
+            self.emails
+                .iter()
+                .find(|email| email.primary)
+                .or(self.emails.first())
+                .map(|email| email.value.trim().to_string())
+                .filter(|email| email.contains('@'))
+                .ok_or("userName or a primary email must be an email address")?
+        };
+
+        let joined = self.name.as_ref().and_then(|name| {
+            let parts: Vec<&str> = [name.given_name.as_deref(), name.family_name.as_deref()]
+                .into_iter()
+                .flatten()
+                .filter(|part| !part.trim().is_empty())
+                .collect();
+            (!parts.is_empty()).then(|| parts.join(" "))
+        });
+        let name = joined
+            .or_else(|| self.name.as_ref().and_then(|name| name.formatted.clone()))
+            .or_else(|| self.display_name.clone())
+            .filter(|name| !name.trim().is_empty());
+
+        Ok(UserFields {
+            email,
+            name,
+            external_id: self.external_id.clone(),
+            active: self.active.unwrap_or(true),
+        })
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_id(id: &str) -> Result<ObjectId, HttpResponse> {
+    ObjectId::parse_str(id)
+        .map_err(|_| scim_error(StatusCode::NOT_FOUND, None, "Resource not found"))
+}
+
+async fn find_student(
+    db: &Database,
+    client: &ScimClient,
+    id: &str,
+) -> Result<Account, HttpResponse> {
+    let oid = parse_id(id)?;
+    let mut filter = student_filter(client.school_id);
+    filter.insert("_id", oid);
+    match accounts::collection(db).find_one(filter, None).await {
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err(scim_error(StatusCode::NOT_FOUND, None, "User not found")),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+#[get("/Users")]
+async fn list_users(
+    query: web::Query<ListQuery>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let mut filter = student_filter(client.school_id);
+    if let Some(expression) = &query.filter {
+        match parse_filter(expression) {
+            Some((attribute, value)) if attribute.eq_ignore_ascii_case("userName") => {
+                filter.insert("email", value);
+            }
+            Some((attribute, value)) if attribute.eq_ignore_ascii_case("externalId") => {
+                filter.insert("external_id", value);
+            }
+            _ => return scim_error(
+                StatusCode::BAD_REQUEST,
+                Some("invalidFilter"),
+                "Only userName eq and externalId eq filters are supported",
+            ),
+        }
+    }
+
+    let (start_index, count) = page(&query);
+    let accounts_collection = accounts::collection(&db);
+    let total = match accounts_collection.count_documents(filter.clone(), None).await {
+        Ok(total) => total,
+        Err(e) => return internal_error(e),
+    };
+    // count=0 asks for the total only (RFC 7644 3.4.2.4); a limit of 0
+    // would mean no limit at all
+    if count == 0 {
+        return list_response(total, start_index, Vec::new());
+    }
+    let options = FindOptions::builder()
+        .sort(doc! { "_id": 1 })
+        .skip(start_index - 1)
+        .limit(count as i64)
+        .build();
+    let mut cursor = match accounts_collection.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return internal_error(e),
+    };
+
+    let mut resources = Vec::new();
+    while let Some(account) = cursor.next().await {
+        match account {
+            Ok(account) => resources.push(user_resource(&account)),
+            Err(e) => return internal_error(e),
+        }
+    }
+    list_response(total, start_index, resources)
+}
+
+#[get("/Users/{id}")]
+async fn get_user(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    match find_student(&db, &client, &id).await {
+        Ok(account) => scim_ok(StatusCode::OK, user_resource(&account)),
+        Err(response) => response,
+    }
+}
+
+async fn email_taken(
+    db: &Database,
+    school_id: ObjectId,
+    email: &str,
+    except: Option<ObjectId>,
+) -> Result<bool, mongodb::error::Error> {
+    let mut filter = doc! { "school_id": school_id, "email": email };
+    if let Some(id) = except {
+        filter.insert("_id", doc! { "$ne": id });
+    }
+    Ok(accounts::collection(db).find_one(filter, None).await?.is_some())
+}
+
+#[post("/Users")]
+async fn create_user(
+    req: HttpRequest,
+    body: web::Json<ScimUser>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let fields = match body.fields() {
+        Ok(fields) => fields,
+        Err(detail) => return scim_error(StatusCode::BAD_REQUEST, Some("invalidValue"), detail),
+    };
+    match email_taken(&db, client.school_id, &fields.email, None).await {
+        Ok(true) => return scim_error(
+            StatusCode::CONFLICT,
+            Some("uniqueness"),
+            "An account with this userName already exists",
+        ),
+        Ok(false) => {}
+        Err(e) => return internal_error(e),
+    }
+
+    // Provisioned students sign in through SSO or a password reset; nobody
+    // knows this password
+    let password = passwords::generate_password(32);
+    let mut account = Account {
+        name: fields.name,
+        external_id: fields.external_id,
+        deactivated_at: (!fields.active).then(DateTime::now),
+        ..Account::student(fields.email, passwords::hash_password(&password), Some(client.school_id))
+    };
+    match accounts::collection(&db).insert_one(&account, None).await {
+        Ok(result) => account.id = result.inserted_id.as_object_id(),
+        Err(e) => return internal_error(e),
+    }
+
+    audit::record(
+        &db,
+        AuditEntry::new("scim_user_created")
+            .actor(&client.actor())
+            .school(Some(client.school_id))
+            .target(&account.id.expect("Inserted accounts have an ID").to_hex())
+            .request(&req)
+            .details(doc! { "email": &account.email }),
+    )
+    .await;
+    scim_ok(StatusCode::CREATED, user_resource(&account))
+}
+
+// Writes a full set of user fields, deactivating (and logging out) or
+// reactivating the account as needed
+async fn save_user(
+    req: &HttpRequest,
+    db: &Database,
+    client: &ScimClient,
+    mut account: Account,
+    fields: UserFields,
+) -> HttpResponse {
+    let account_id = account.id.expect("Stored accounts have an ID");
+    match email_taken(db, client.school_id, &fields.email, Some(account_id)).await {
+        Ok(true) => return scim_error(
+            StatusCode::CONFLICT,
+            Some("uniqueness"),
+            "An account with this userName already exists",
+        ),
+        Ok(false) => {}
+        Err(e) => return internal_error(e),
+    }
+
+    let was_active = account.is_active();
+    let old_email = std::mem::replace(&mut account.email, fields.email);
+    account.name = fields.name;
+    account.external_id = fields.external_id;
+    account.deactivated_at = match (was_active, fields.active) {
+        (true, false) => Some(DateTime::now()),
+        (false, true) => None,
+        _ => account.deactivated_at,
+    };
+    if !account.is_active() {
+        account.assigned_room = None;
+    }
+
+    // Rooms first, so a failure leaves the account as it was and a retry
+    // finds the occupant under the old email
+    if let Some(update) = occupancy_update(&old_email, &account) {
+        let result = match crate::Room::in_school(db, Some(client.school_id)).await {
+            Ok(mut filter) => {
+                filter.insert("current_students.name", &old_email);
+                db.collection::<Document>("rooms").update_many(filter, update, None).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            return internal_error(e);
+        }
+    }
+
+    let result = accounts::collection(db)
+        .update_one(
+            doc! { "_id": account_id },
+            doc! { "$set": {
+                "email": &account.email,
+                "name": &account.name,
+                "external_id": &account.external_id,
+                "deactivated_at": account.deactivated_at,
+                "assigned_room": &account.assigned_room,
+            } },
+            None,
+        )
+        .await;
+    if let Err(e) = result {
+        return internal_error(e);
+    }
+
+    let action = match (was_active, account.is_active()) {
+        (true, false) => {
+            if let Err(e) = auth::revoke_all_sessions(db, account_id, None).await {
+                println!("Error revoking sessions of deactivated account: {:?}", e);
+            }
+            "scim_user_deactivated"
+        }
+        (false, true) => "scim_user_reactivated",
+        _ => "scim_user_updated",
+    };
+    audit::record(
+        db,
+        AuditEntry::new(action)
+            .actor(&client.actor())
+            .school(Some(client.school_id))
+            .target(&account_id.to_hex())
+            .request(req)
+            .details(doc! { "email": &account.email }),
+    )
+    .await;
+    scim_ok(StatusCode::OK, user_resource(&account))
+}
+
+// Occupants are matched by email, so a rename has to follow the student into
+// their room, and a deactivated student gives up their bed
+fn occupancy_update(old_email: &str, account: &Account) -> Option<Document> {
+    if !account.is_active() {
+        Some(doc! { "$pull": { "current_students": { "name": old_email } } })
+    } else if account.email != old_email {
+        Some(doc! { "$set": { "current_students.$.name": &account.email } })
+    } else {
+        None
+    }
+}
+
+#[put("/Users/{id}")]
+async fn replace_user(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ScimUser>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let account = match find_student(&db, &client, &id).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    match body.fields() {
+        Ok(fields) => save_user(&req, &db, &client, account, fields).await,
+        Err(detail) => scim_error(StatusCode::BAD_REQUEST, Some("invalidValue"), detail),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PatchOperation {
+    op: String,
+    path: Option<String>,
+    value: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PatchRequest {
+    operations: Vec<PatchOperation>,
+}
+
+// Applies a simple attribute path ("active", "name.givenName") to a resource
+fn set_path(resource: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((parent, child)) => {
+            let entry = resource
+                .entry(parent.to_string())
+                .or_insert_with(|| json!({}));
+            if !entry.is_object() {
+                *entry = json!({});
+            }
+            if let Value::Object(parent) = entry {
+                parent.insert(child.to_string(), value);
+            }
+        }
+        None => {
+            resource.insert(path.to_string(), value);
+        }
+    }
+}
+
+// Applies PATCH operations to a user's SCIM representation. Identity systems
+// mostly use this to flip `active`, with or without a path.
+fn patch_user(resource: &mut Map<String, Value>, operations: &[PatchOperation]) -> Result<(), String> {
+    for operation in operations {
+        let op = operation.op.to_ascii_lowercase();
+        let value = operation.value.clone().unwrap_or(Value::Null);
+        match (op.as_str(), operation.path.as_deref()) {
+            ("add" | "replace", None) => {
+                let Value::Object(values) = value else {
+                    return Err("Operations without a path need an object value".to_string());
+                };
+                for (path, value) in values {
+                    set_path(resource, &path, value);
+                }
+            }
+            ("add" | "replace", Some(path)) if path.starts_with("emails") => {
+                let email = match value {
+                    Value::Array(mut emails) if !emails.is_empty() => emails.remove(0),
+                    other => other,
+                };
+                let email = email.get("value").cloned().unwrap_or(email);
+                resource.insert("emails".to_string(), json!([{ "value": email, "primary": true }]));
+            }
+            ("add" | "replace", Some(path)) => set_path(resource, path, value),
+            ("remove", Some(path)) => set_path(resource, path, Value::Null),
+            _ => return Err(format!("Unsupported operation {}", operation.op)),
+        }
+    }
+    Ok(())
+}
+
+#[patch("/Users/{id}")]
+async fn update_user(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<PatchRequest>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let account = match find_student(&db, &client, &id).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    let Value::Object(mut resource) = user_resource(&account) else {
+        unreachable!("user_resource returns an object");
+    };
+    if let Err(detail) = patch_user(&mut resource, &body.operations) {
+        return scim_error(StatusCode::BAD_REQUEST, Some("invalidPath"), &detail);
+    }
+    // A PATCH that changes userName should win over the old email
+    if let Some(user_name) = resource.get("userName").cloned() {
+        let changed = user_name.as_str() != Some(account.email.as_str());
+        if changed && user_name.as_str().is_some_and(|name| name.contains('@')) {
+            resource.insert("emails".to_string(), json!([{ "value": user_name, "primary": true }]));
+        }
+    }
+
+    let patched: ScimUser = match serde_json::from_value(Value::Object(resource)) {
+        Ok(user) => user,
+        Err(e) => return scim_error(StatusCode::BAD_REQUEST, Some("invalidValue"), &e.to_string()),
+    };
+    match patched.fields() {
+        Ok(fields) => save_user(&req, &db, &client, account, fields).await,
+        Err(detail) => scim_error(StatusCode::BAD_REQUEST, Some("invalidValue"), detail),
+    }
+}
+
+// Accounts are deactivated rather than deleted so rooms and history keep
+// pointing at something
+#[delete("/Users/{id}")]
+async fn delete_user(
+    req: HttpRequest,
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let account = match find_student(&db, &client, &id).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    let fields = UserFields {
+        email: account.email.clone(),
+        name: account.name.clone(),
+        external_id: account.external_id.clone(),
+        active: false,
+    };
+    let response = save_user(&req, &db, &client, account, fields).await;
+    if response.status().is_success() {
+        HttpResponse::NoContent().finish()
+    } else {
+        response
+    }
+}
+
+fn group_resource(group: &ScimGroup) -> Value {
+    let id = group.id.expect("Stored groups have an ID").to_hex();
+    let members: Vec<Value> = group
+        .members
+        .iter()
+        .map(|member| json!({ "value": member.to_hex(), "type": "User" }))
+        .collect();
+    json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": id,
+        "externalId": group.external_id,
+        "displayName": group.display_name,
+        "members": members,
+        "meta": {
+            "resourceType": "Group",
+            "location": format!("/api/scim/v2/Groups/{}", id),
+        },
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ScimMember {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimGroupRequest {
+    display_name: String,
+    external_id: Option<String>,
+    #[serde(default)]
+    members: Vec<ScimMember>,
+}
+
+// Resolves member ids, keeping only students of the client's school
+async fn resolve_members(
+    db: &Database,
+    client: &ScimClient,
+    members: &[ScimMember],
+) -> Result<Vec<ObjectId>, HttpResponse> {
+    let ids: Vec<ObjectId> = members
+        .iter()
+        .filter_map(|member| ObjectId::parse_str(&member.value).ok())
+        .collect();
+    let mut filter = student_filter(client.school_id);
+    filter.insert("_id", doc! { "$in": &ids });
+
+    let mut cursor = accounts::collection(db)
+        .find(filter, None)
+        .await
+        .map_err(internal_error)?;
+    let mut found = Vec::new();
+    while let Some(account) = cursor.next().await {
+        let account = account.map_err(internal_error)?;
+        found.push(account.id.expect("Stored accounts have an ID"));
+    }
+    if found.len() != members.len() {
+        return Err(scim_error(
+            StatusCode::BAD_REQUEST,
+            Some("invalidValue"),
+            "One or more members aren't users of this school",
+        ));
+    }
+    Ok(found)
+}
+
+// A group named like one of the school's dorms stands for that dorm
+async fn matching_dorm(
+    db: &Database,
+    school_id: ObjectId,
+    display_name: &str,
+) -> Result<Option<ObjectId>, HttpResponse> {
+    let dorm = db
+        .collection::<Dorm>("dorms")
+        .find_one(doc! { "school_id": school_id, "name": display_name }, None)
+        .await
+        .map_err(internal_error)?;
+    Ok(dorm.and_then(|dorm| dorm.id))
+}
+
+async fn find_group(
+    db: &Database,
+    client: &ScimClient,
+    id: &str,
+) -> Result<ScimGroup, HttpResponse> {
+    let oid = parse_id(id)?;
+    match db
+        .collection::<ScimGroup>("scim_groups")
+        .find_one(doc! { "_id": oid, "school_id": client.school_id }, None)
+        .await
+    {
+        Ok(Some(group)) => Ok(group),
+        Ok(None) => Err(scim_error(StatusCode::NOT_FOUND, None, "Group not found")),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+async fn record_group_change(req: &HttpRequest, db: &Database, client: &ScimClient, action: &str, group: &ScimGroup) {
+    audit::record(
+        db,
+        AuditEntry::new(action)
+            .actor(&client.actor())
+            .school(Some(client.school_id))
+            .target(&group.id.expect("Stored groups have an ID").to_hex())
+            .request(req)
+            .details(doc! {
+                "display_name": &group.display_name,
+                "kind": if group.dorm_id.is_some() { "dorm" } else { "cohort" },
+                "dorm_id": group.dorm_id,
+                "members": group.members.len() as i64,
+            }),
+    )
+    .await;
+}
+
+#[get("/Groups")]
+async fn list_groups(
+    query: web::Query<ListQuery>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let mut filter = doc! { "school_id": client.school_id };
+    if let Some(expression) = &query.filter {
+        match parse_filter(expression) {
+            Some((attribute, value)) if attribute.eq_ignore_ascii_case("displayName") => {
+                filter.insert("display_name", value);
+            }
+            Some((attribute, value)) if attribute.eq_ignore_ascii_case("externalId") => {
+                filter.insert("external_id", value);
+            }
+            _ => return scim_error(
+                StatusCode::BAD_REQUEST,
+                Some("invalidFilter"),
+                "Only displayName eq and externalId eq filters are supported",
+            ),
+        }
+    }
+
+    let (start_index, count) = page(&query);
+    let groups = db.collection::<ScimGroup>("scim_groups");
+    let total = match groups.count_documents(filter.clone(), None).await {
+        Ok(total) => total,
+        Err(e) => return internal_error(e),
+    };
+    // count=0 asks for the total only (RFC 7644 3.4.2.4); a limit of 0
+    // would mean no limit at all
+    if count == 0 {
+        return list_response(total, start_index, Vec::new());
+    }
+    let options = FindOptions::builder()
+        .sort(doc! { "_id": 1 })
+        .skip(start_index - 1)
+        .limit(count as i64)
+        .build();
+    let mut cursor = match groups.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(e) => return internal_error(e),
+    };
+
+    let mut resources = Vec::new();
+    while let Some(group) = cursor.next().await {
+        match group {
+            Ok(group) => resources.push(group_resource(&group)),
+            Err(e) => return internal_error(e),
+        }
+    }
+    list_response(total, start_index, resources)
+}
+
+#[get("/Groups/{id}")]
+async fn get_group(
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    match find_group(&db, &client, &id).await {
+        Ok(group) => scim_ok(StatusCode::OK, group_resource(&group)),
+        Err(response) => response,
+    }
+}
+
+#[post("/Groups")]
+async fn create_group(
+    req: HttpRequest,
+    body: web::Json<ScimGroupRequest>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let groups = db.collection::<ScimGroup>("scim_groups");
+    match groups
+        .find_one(doc! { "school_id": client.school_id, "display_name": &body.display_name }, None)
+        .await
+    {
+        Ok(Some(_)) => return scim_error(
+            StatusCode::CONFLICT,
+            Some("uniqueness"),
+            "A group with this displayName already exists",
+        ),
+        Ok(None) => {}
+        Err(e) => return internal_error(e),
+    }
+
+    let members = match resolve_members(&db, &client, &body.members).await {
+        Ok(members) => members,
+        Err(response) => return response,
+    };
+    let dorm_id = match matching_dorm(&db, client.school_id, &body.display_name).await {
+        Ok(dorm_id) => dorm_id,
+        Err(response) => return response,
+    };
+
+    let mut group = ScimGroup {
+        id: None,
+        school_id: client.school_id,
+        display_name: body.display_name.clone(),
+        external_id: body.external_id.clone(),
+        dorm_id,
+        members,
+    };
+    match groups.insert_one(&group, None).await {
+        Ok(result) => group.id = result.inserted_id.as_object_id(),
+        Err(e) => return internal_error(e),
+    }
+
+    record_group_change(&req, &db, &client, "scim_group_created", &group).await;
+    scim_ok(StatusCode::CREATED, group_resource(&group))
+}
+
+async fn save_group(req: &HttpRequest, db: &Database, client: &ScimClient, group: ScimGroup) -> HttpResponse {
+    let group_id = group.id.expect("Stored groups have an ID");
+    let result = db
+        .collection::<ScimGroup>("scim_groups")
+        .replace_one(doc! { "_id": group_id, "school_id": client.school_id }, &group, None)
+        .await;
+    if let Err(e) = result {
+        return internal_error(e);
+    }
+    record_group_change(req, db, client, "scim_group_updated", &group).await;
+    scim_ok(StatusCode::OK, group_resource(&group))
+}
+
+#[put("/Groups/{id}")]
+async fn replace_group(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ScimGroupRequest>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let mut group = match find_group(&db, &client, &id).await {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+    group.members = match resolve_members(&db, &client, &body.members).await {
+        Ok(members) => members,
+        Err(response) => return response,
+    };
+    group.dorm_id = match matching_dorm(&db, client.school_id, &body.display_name).await {
+        Ok(dorm_id) => dorm_id,
+        Err(response) => return response,
+    };
+    group.display_name = body.display_name.clone();
+    group.external_id = body.external_id.clone();
+    save_group(&req, &db, &client, group).await
+}
+
+// `members[value eq "id"]` paths name a single member
+fn member_in_path(path: &str) -> Option<String> {
+    let inner = path.strip_prefix("members[")?.strip_suffix(']')?;
+    let (attribute, value) = parse_filter(inner)?;
+    (attribute == "value").then_some(value)
+}
+
+#[patch("/Groups/{id}")]
+async fn update_group(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<PatchRequest>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let mut group = match find_group(&db, &client, &id).await {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+
+    for operation in &body.operations {
+        let op = operation.op.to_ascii_lowercase();
+        let path = operation.path.as_deref().unwrap_or_default();
+        let value = operation.value.clone().unwrap_or(Value::Null);
+        let members: Vec<ScimMember> = match &value {
+            Value::Array(_) => serde_json::from_value(value.clone()).unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        match (op.as_str(), path) {
+            ("add", "members") => {
+                let added = match resolve_members(&db, &client, &members).await {
+                    Ok(added) => added,
+                    Err(response) => return response,
+                };
+                for member in added {
+                    if !group.members.contains(&member) {
+                        group.members.push(member);
+                    }
+                }
+            }
+            ("replace", "members") => {
+                group.members = match resolve_members(&db, &client, &members).await {
+                    Ok(members) => members,
+                    Err(response) => return response,
+                };
+            }
+            ("remove", "members") if members.is_empty() => group.members.clear(),
+            ("remove", "members") => {
+                let removed: Vec<String> = members.into_iter().map(|member| member.value).collect();
+                group.members.retain(|member| !removed.contains(&member.to_hex()));
+            }
+            ("remove", path) if member_in_path(path).is_some() => {
+                let removed = member_in_path(path).unwrap_or_default();
+                group.members.retain(|member| member.to_hex() != removed);
+            }
+            ("replace", "displayName") | ("replace", "") => {
+                let display_name = match &value {
+                    Value::String(name) => Some(name.clone()),
+                    Value::Object(values) => values.get("displayName").and_then(Value::as_str).map(str::to_string),
+                    _ => None,
+                };
+                let Some(display_name) = display_name else {
+                    return scim_error(StatusCode::BAD_REQUEST, Some("invalidValue"), "displayName must be a string");
+                };
+                group.dorm_id = match matching_dorm(&db, client.school_id, &display_name).await {
+                    Ok(dorm_id) => dorm_id,
+                    Err(response) => return response,
+                };
+                group.display_name = display_name;
+            }
+            ("replace", "externalId") => {
+                group.external_id = value.as_str().map(str::to_string);
+            }
+            _ => return scim_error(
+                StatusCode::BAD_REQUEST,
+                Some("invalidPath"),
+                &format!("Unsupported operation {} {}", operation.op, path),
+            ),
+        }
+    }
+
+    save_group(&req, &db, &client, group).await
+}
+
+#[delete("/Groups/{id}")]
+async fn delete_group(
+    req: HttpRequest,
+    id: web::Path<String>,
+    db: web::Data<Database>,
+    client: ScimClient,
+) -> impl Responder {
+    let group = match find_group(&db, &client, &id).await {
+        Ok(group) => group,
+        Err(response) => return response,
+    };
+    if let Err(e) = db
+        .collection::<ScimGroup>("scim_groups")
+        .delete_one(doc! { "_id": group.id, "school_id": client.school_id }, None)
+        .await
+    {
+        return internal_error(e);
+    }
+    record_group_change(&req, &db, &client, "scim_group_deleted", &group).await;
+    HttpResponse::NoContent().finish()
+}
+
+// Tells identity systems which optional parts of SCIM are supported
+#[get("/ServiceProviderConfig")]
+async fn service_provider_config() -> impl Responder {
+    scim_ok(
+        StatusCode::OK,
+        json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_PAGE_SIZE },
+            "changePassword": { "supported": false },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "Bearer token",
+                "description": "A SCIM token created by a school admin",
+            }],
+        }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateTokenRequest {
+    // What the token is for, e.g. "Okta"
+    name: String,
+}
+
+// Creates a SCIM token for a school. The token is only shown in this
+// response.
+#[post("/admin/schools/{school_id}/scim-tokens")]
+async fn create_token(
+    req: HttpRequest,
+    school_id: web::Path<String>,
+    body: web::Json<CreateTokenRequest>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match ObjectId::parse_str(school_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageSchoolSettings, &Scope::School(school_oid)) {
+        return denied;
+    }
+
+    let token = tokens::generate_token();
+    let record = ScimToken {
+        id: None,
+        school_id: school_oid,
+        name: body.name.clone(),
+        token_hash: tokens::hash_token(&token),
+        created_by: principal.email.clone(),
+        created_at: DateTime::now(),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    let token_id = match db.collection::<ScimToken>("scim_tokens").insert_one(record, None).await {
+        Ok(result) => result.inserted_id,
+        Err(e) => {
+            println!("Failed to create SCIM token: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to create SCIM token"
+            });
+        }
+    };
+
+    audit::record(
+        &db,
+        AuditEntry::new("scim_token_created")
+            .actor(&principal.email)
+            .school(Some(school_oid))
+            .target(&token_id.as_object_id().map(|id| id.to_hex()).unwrap_or_default())
+            .request(&req)
+            .details(doc! { "name": &body.name }),
+    )
+    .await;
+
+    HttpResponse::Ok().json(doc! {
+        "id": token_id,
+        "token": token,
+        "base_url": "/api/scim/v2",
+    })
+}
+
+#[get("/admin/schools/{school_id}/scim-tokens")]
+async fn list_tokens(
+    school_id: web::Path<String>,
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let school_oid = match ObjectId::parse_str(school_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid school ID"
+        }),
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageSchoolSettings, &Scope::School(school_oid)) {
+        return denied;
+    }
+
+    let page = match Page::new(&page, &["created_at", "name"], "created_at") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+    let collection = db.collection::<Document>("scim_tokens");
+    let tokens_out = page.respond(&collection, doc! { "school_id": school_oid }, |token: ScimToken| doc! {
+        "_id": token.id,
+        "name": token.name,
+        "created_by": token.created_by,
+        "created_at": token.created_at,
+        "last_used_at": token.last_used_at,
+        "revoked_at": token.revoked_at,
+    });
+    match tokens_out.await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error fetching SCIM tokens: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/admin/scim-tokens/{token_id}")]
+async fn revoke_token(
+    req: HttpRequest,
+    token_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let oid = match ObjectId::parse_str(token_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid token ID"
+        }),
+    };
+    let collection = db.collection::<ScimToken>("scim_tokens");
+
+    let token = match collection.find_one(doc! { "_id": oid }, None).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "SCIM token not found"
+        }),
+        Err(e) => {
+            println!("Error finding SCIM token: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(denied) = authorize(&principal, Permission::ManageSchoolSettings, &Scope::School(token.school_id)) {
+        return denied;
+    }
+
+    match collection
+        .update_one(
+            doc! { "_id": oid, "revoked_at": null },
+            doc! { "$set": { "revoked_at": DateTime::now() } },
+            None,
+        )
+        .await
+    {
+        Ok(_) => {
+            audit::record(
+                &db,
+                AuditEntry::new("scim_token_revoked")
+                    .actor(&principal.email)
+                    .school(Some(token.school_id))
+                    .target(&oid.to_hex())
+                    .request(&req)
+                    .details(doc! { "name": &token.name }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "SCIM token revoked"
+            })
+        }
+        Err(e) => {
+            println!("Error revoking SCIM token: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to revoke SCIM token"
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_eq_filters() {
+        assert_eq!(
+            parse_filter(r#"userName eq "ada@example.edu""#),
+            Some(("userName".to_string(), "ada@example.edu".to_string()))
+        );
+        assert_eq!(
+            parse_filter(r#"externalId EQ "00u1""#),
+            Some(("externalId".to_string(), "00u1".to_string()))
+        );
+        assert_eq!(parse_filter(r#"userName co "ada""#), None);
+        assert_eq!(parse_filter("userName eq ada"), None);
+        assert_eq!(member_in_path(r#"members[value eq "abc"]"#), Some("abc".to_string()));
+    }
+
+    fn resource() -> Map<String, Value> {
+        let Value::Object(resource) = json!({
+            "userName": "ada@example.edu",
+            "name": { "givenName": "Ada", "familyName": "Lovelace" },
+            "emails": [{ "value": "ada@example.edu", "primary": true }],
+            "active": true,
+        }) else {
+            unreachable!()
+        };
+        resource
+    }
+
+    fn operations(value: Value) -> Vec<PatchOperation> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn patch_deactivates_with_or_without_a_path() {
+        for ops in [
+            json!([{ "op": "replace", "path": "active", "value": false }]),
+            json!([{ "op": "Replace", "value": { "active": false } }]),
+        ] {
+            let mut user = resource();
+            patch_user(&mut user, &operations(ops)).unwrap();
+            let user: ScimUser = serde_json::from_value(Value::Object(user)).unwrap();
+            assert!(!user.fields().unwrap().active);
+        }
+    }
+
+    #[test]
+    fn patch_updates_names_and_emails() {
+        let mut user = resource();
+        patch_user(
+            &mut user,
+            &operations(json!([
+                { "op": "replace", "path": "name.familyName", "value": "King" },
+                { "op": "replace", "path": "emails[type eq \"work\"].value", "value": "ada@new.example.edu" },
+                { "op": "replace", "path": "userName", "value": "ada.king" },
+            ])),
+        )
+        .unwrap();
+        let fields = serde_json::from_value::<ScimUser>(Value::Object(user))
+            .unwrap()
+            .fields()
+            .unwrap();
+        assert_eq!(fields.name.as_deref(), Some("Ada King"));
+        assert_eq!(fields.email, "ada@new.example.edu");
+    }
+
+    #[test]
+    fn users_need_an_email() {
+        let user: ScimUser = serde_json::from_value(json!({ "userName": "ada" })).unwrap();
+        assert!(user.fields().is_err());
+    }
+
+    #[test]
+    fn occupancy_follows_renames_and_deactivation() {
+        let mut account = Account::student("ada@example.edu".to_string(), String::new(), None);
+        assert_eq!(occupancy_update("ada@example.edu", &account), None);
+
+        account.email = "ada.king@example.edu".to_string();
+        assert_eq!(
+            occupancy_update("ada@example.edu", &account),
+            Some(doc! { "$set": { "current_students.$.name": "ada.king@example.edu" } })
+        );
+
+        // Deactivated under the old or a new email, the bed they held goes
+        account.deactivated_at = Some(DateTime::now());
+        assert_eq!(
+            occupancy_update("ada@example.edu", &account),
+            Some(doc! { "$pull": { "current_students": { "name": "ada@example.edu" } } })
+        );
+    }
+}
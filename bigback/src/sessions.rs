@@ -0,0 +1,225 @@
+use actix_web::{delete, get, web, HttpRequest, HttpResponse, Responder};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    Database,
+};
+
+use crate::accounts;
+use crate::audit::{self, AuditEntry};
+use crate::auth::{self, Session};
+use crate::pagination::{Page, PageQuery};
+use crate::roles::{authorize, Permission, Principal, Role, Scope};
+
+fn live_sessions(account_id: ObjectId) -> Document {
+    doc! {
+        "account_id": account_id,
+        "revoked_at": null,
+        "expires_at": { "$gt": DateTime::now() },
+    }
+}
+
+// The caller's live sessions, most recently used first
+#[get("/sessions")]
+async fn list_sessions(
+    page: web::Query<PageQuery>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    if let Err(denied) = authorize(&principal, Permission::ViewOwnAccount, &Scope::Own) {
+        return denied;
+    }
+    let auth = match principal.session() {
+        Ok(auth) => auth,
+        Err(denied) => return denied,
+    };
+    let page = match Page::new(&page, &["last_seen_at", "created_at"], "-last_seen_at") {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    let filter = live_sessions(auth.account_id);
+    let current = auth.session_id;
+    let collection = db.collection::<Document>("sessions");
+    let sessions = page.respond(&collection, filter, move |session: Session| doc! {
+        "_id": session.id,
+        "device": session.device,
+        "ip": session.ip,
+        "created_at": session.created_at,
+        "last_seen_at": session.last_seen_at,
+        "expires_at": session.expires_at,
+        "current": session.id == Some(current),
+    });
+    match sessions.await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Error fetching sessions: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Logs out one of the caller's sessions, which may be the current one
+#[delete("/sessions/{session_id}")]
+async fn revoke_session(
+    req: HttpRequest,
+    session_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    if let Err(denied) = authorize(&principal, Permission::ViewOwnAccount, &Scope::Own) {
+        return denied;
+    }
+    let auth = match principal.session() {
+        Ok(auth) => auth,
+        Err(denied) => return denied,
+    };
+    let session_oid = match ObjectId::parse_str(session_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid session ID"
+        }),
+    };
+
+    // Matching on the account too keeps people away from others' sessions
+    let result = db
+        .collection::<Session>("sessions")
+        .update_one(
+            doc! {
+                "_id": session_oid,
+                "account_id": auth.account_id,
+                "revoked_at": null,
+            },
+            doc! { "$set": { "revoked_at": DateTime::now() } },
+            None,
+        )
+        .await;
+
+    match result {
+        Ok(result) if result.modified_count == 0 => HttpResponse::NotFound().json(doc! {
+            "error": "Session not found"
+        }),
+        Ok(_) => {
+            audit::record(
+                &db,
+                AuditEntry::new("session_revoked")
+                    .actor(&principal.email)
+                    .target(&auth.account_id.to_hex())
+                    .request(&req)
+                    .details(doc! { "session_id": session_oid }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Session revoked"
+            })
+        }
+        Err(e) => {
+            println!("Error revoking session: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to revoke session"
+            })
+        }
+    }
+}
+
+// Logs the caller out everywhere else
+#[delete("/sessions")]
+async fn revoke_other_sessions(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    if let Err(denied) = authorize(&principal, Permission::ViewOwnAccount, &Scope::Own) {
+        return denied;
+    }
+    let auth = match principal.session() {
+        Ok(auth) => auth,
+        Err(denied) => return denied,
+    };
+
+    match auth::revoke_all_sessions(&db, auth.account_id, Some(auth.session_id)).await {
+        Ok(revoked) => {
+            audit::record(
+                &db,
+                AuditEntry::new("sessions_revoked")
+                    .actor(&principal.email)
+                    .target(&auth.account_id.to_hex())
+                    .request(&req)
+                    .details(doc! { "revoked": revoked as i64 }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Other sessions revoked",
+                "revoked": revoked as i64,
+            })
+        }
+        Err(e) => {
+            println!("Error revoking sessions: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to revoke sessions"
+            })
+        }
+    }
+}
+
+// Logs an account of the admin's school out everywhere, e.g. when a student
+// withdraws
+#[delete("/admin/accounts/{account_id}/sessions")]
+async fn revoke_account_sessions(
+    req: HttpRequest,
+    account_id: web::Path<String>,
+    db: web::Data<Database>,
+    principal: Principal,
+) -> impl Responder {
+    let account_oid = match ObjectId::parse_str(account_id.as_str()) {
+        Ok(oid) => oid,
+        Err(_) => return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid account ID"
+        }),
+    };
+
+    let account = match accounts::collection(&db).find_one(doc! { "_id": account_oid }, None).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return HttpResponse::NotFound().json(doc! {
+            "error": "Account not found"
+        }),
+        Err(e) => {
+            println!("Error finding account: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Err(denied) = authorize(&principal, Permission::RevokeSessions, &Scope::of_school(account.school_id)) {
+        return denied;
+    }
+    // Super admins answer to nobody inside a school
+    let targets_super_admin = account.grants().iter().any(|grant| grant.role == Role::SuperAdmin);
+    if targets_super_admin && !principal.is_super_admin() {
+        return HttpResponse::Forbidden().json(doc! {
+            "error": "You don't have permission to do that"
+        });
+    }
+
+    match auth::revoke_all_sessions(&db, account_oid, None).await {
+        Ok(revoked) => {
+            audit::record(
+                &db,
+                AuditEntry::new("account_sessions_revoked")
+                    .actor(&principal.email)
+                    .school(account.school_id)
+                    .target(&account_oid.to_hex())
+                    .request(&req)
+                    .details(doc! { "email": &account.email, "revoked": revoked as i64 }),
+            )
+            .await;
+            HttpResponse::Ok().json(doc! {
+                "message": "Sessions revoked",
+                "revoked": revoked as i64,
+            })
+        }
+        Err(e) => {
+            println!("Error revoking sessions: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to revoke sessions"
+            })
+        }
+    }
+}
@@ -0,0 +1,440 @@
+// Room imports from spreadsheets. Housing offices keep rosters as CSV or
+// XLSX files with one row per student (or per empty room); the upload names
+// which column holds what, or the columns are recognised from the header row.
+// Rows that can't be read are reported by row number and left out, and the
+// rest goes through the same batch pipeline as a JSON import.
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use actix_multipart::Multipart;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use calamine::{open_workbook_from_rs, Reader, Xlsx};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    Database,
+};
+use serde::Deserialize;
+
+use crate::imports::{self, RowError, Roster, StudentData};
+use crate::jobs::BackgroundJobs;
+use crate::mail::Mailer;
+use crate::roles::Principal;
+use crate::uploads;
+
+// Header names recognised when a column isn't mapped, compared ignoring case
+const ROOM_HEADERS: &[&str] = &["room", "room number", "room no", "number"];
+const CAPACITY_HEADERS: &[&str] = &["capacity", "beds", "spaces"];
+const NAME_HEADERS: &[&str] = &["name", "student name", "student", "full name"];
+const STUDENT_ID_HEADERS: &[&str] = &["student id", "student number", "id"];
+const EMAIL_HEADERS: &[&str] = &["email", "e-mail", "email address"];
+const DORM_HEADERS: &[&str] = &["dorm", "hall", "building", "residence"];
+
+// The header of the column holding each field, for sheets whose headers
+// aren't recognised
+#[derive(Debug, Default, Deserialize)]
+pub struct ColumnMapping {
+    pub room: Option<String>,
+    pub capacity: Option<String>,
+    pub name: Option<String>,
+    pub student_id: Option<String>,
+    pub email: Option<String>,
+    pub dorm: Option<String>,
+}
+
+// Column indexes of each field
+#[derive(Debug, PartialEq)]
+pub struct Columns {
+    room: usize,
+    capacity: Option<usize>,
+    name: usize,
+    student_id: usize,
+    email: Option<usize>,
+    dorm: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Xlsx,
+}
+
+impl Format {
+    // From the file name, falling back to sniffing: XLSX files are zip
+    // archives
+    fn detect(filename: Option<&str>, bytes: &[u8]) -> Format {
+        let extension = filename
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("xlsx") => Format::Xlsx,
+            Some("csv") => Format::Csv,
+            _ if bytes.starts_with(b"PK") => Format::Xlsx,
+            _ => Format::Csv,
+        }
+    }
+}
+
+// Reads every row of the file as text, header row included
+pub fn read_cells(format: Format, bytes: &[u8], sheet: Option<&str>) -> Result<Vec<Vec<String>>, String> {
+    match format {
+        Format::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(bytes);
+            let mut rows = Vec::new();
+            for record in reader.records() {
+                let record = record.map_err(|e| format!("Invalid CSV: {}", e))?;
+                rows.push(record.iter().map(str::to_string).collect::<Vec<_>>());
+            }
+            // Spreadsheet programs often start CSV files with a byte order mark
+            if let Some(first) = rows.first_mut().and_then(|row| row.first_mut()) {
+                *first = first.trim_start_matches('\u{feff}').to_string();
+            }
+            Ok(rows)
+        }
+        Format::Xlsx => {
+            let mut workbook: Xlsx<_> =
+                open_workbook_from_rs(Cursor::new(bytes)).map_err(|e| format!("Invalid XLSX file: {}", e))?;
+            let range = match sheet {
+                Some(sheet) => workbook
+                    .worksheet_range(sheet)
+                    .map_err(|_| format!("Sheet '{}' not found", sheet))?,
+                None => workbook
+                    .worksheet_range_at(0)
+                    .ok_or("The workbook has no sheets")?
+                    .map_err(|e| format!("Invalid XLSX file: {}", e))?,
+            };
+            Ok(range
+                .rows()
+                .map(|row| row.iter().map(|cell| cell.to_string()).collect())
+                .collect())
+        }
+    }
+}
+
+fn find_column(
+    header: &[String],
+    mapped: Option<&str>,
+    known: &[&str],
+) -> Result<Option<usize>, String> {
+    let position = |name: &str| {
+        header
+            .iter()
+            .position(|cell| cell.trim().eq_ignore_ascii_case(name.trim()))
+    };
+    match mapped {
+        Some(name) => position(name)
+            .map(Some)
+            .ok_or_else(|| format!("Column '{}' not found", name)),
+        None => Ok(known.iter().find_map(|name| position(name))),
+    }
+}
+
+pub fn resolve_columns(header: &[String], mapping: &ColumnMapping) -> Result<Columns, String> {
+    let required = |mapped: &Option<String>, known: &[&str], field: &str| {
+        find_column(header, mapped.as_deref(), known)?
+            .ok_or_else(|| format!("No {} column; map it with `mapping`", field))
+    };
+    Ok(Columns {
+        room: required(&mapping.room, ROOM_HEADERS, "room number")?,
+        capacity: find_column(header, mapping.capacity.as_deref(), CAPACITY_HEADERS)?,
+        name: required(&mapping.name, NAME_HEADERS, "student name")?,
+        student_id: required(&mapping.student_id, STUDENT_ID_HEADERS, "student id")?,
+        email: find_column(header, mapping.email.as_deref(), EMAIL_HEADERS)?,
+        dorm: find_column(header, mapping.dorm.as_deref(), DORM_HEADERS)?,
+    })
+}
+
+// The dorm being imported into, as a dorm column may name it
+pub struct TargetDorm {
+    pub id: ObjectId,
+    pub name: String,
+}
+
+impl TargetDorm {
+    fn matches(&self, cell: &str) -> bool {
+        cell.eq_ignore_ascii_case(self.name.trim()) || cell == self.id.to_hex()
+    }
+}
+
+fn cell(row: &[String], column: Option<usize>) -> &str {
+    column
+        .and_then(|column| row.get(column))
+        .map(|cell| cell.trim())
+        .unwrap_or_default()
+}
+
+// A row's room, the capacity it gives that room, and its student, if any
+fn parse_row<'a>(
+    row: &'a [String],
+    columns: &Columns,
+    capacities: &BTreeMap<String, i32>,
+) -> Result<(&'a str, Option<i32>, Option<StudentData>), String> {
+    let room = cell(row, Some(columns.room));
+    if room.is_empty() {
+        return Err("Missing room number".to_string());
+    }
+
+    let capacity = match cell(row, columns.capacity) {
+        "" => None,
+        text => match text.parse::<i32>() {
+            Ok(capacity) if capacity >= 0 => Some(capacity),
+            _ => return Err(format!("Invalid capacity '{}'", text)),
+        },
+    };
+    if let (Some(capacity), Some(&previous)) = (capacity, capacities.get(room)) {
+        if capacity != previous {
+            return Err(format!("Room {} already has capacity {}", room, previous));
+        }
+    }
+
+    let name = cell(row, Some(columns.name));
+    let student_id = cell(row, Some(columns.student_id));
+    if name.is_empty() && student_id.is_empty() {
+        // A room with nobody in it yet
+        return Ok((room, capacity, None));
+    }
+    if name.is_empty() {
+        return Err("Missing student name".to_string());
+    }
+    let id = student_id
+        .parse::<i32>()
+        .map_err(|_| format!("Invalid student id '{}'", student_id))?;
+    let email = Some(cell(row, columns.email))
+        .filter(|email| !email.is_empty())
+        .map(str::to_string);
+    let student = StudentData {
+        name: name.to_string(),
+        id,
+        email,
+    };
+    Ok((room, capacity, Some(student)))
+}
+
+// Turns the rows under the header into a roster. Rows naming another dorm
+// are counted and left out, so one school-wide sheet can be uploaded dorm by
+// dorm.
+pub fn parse_rows(rows: &[Vec<String>], columns: &Columns, dorm: &TargetDorm) -> (Roster, usize) {
+    let mut roster = Roster {
+        dorm_id: dorm.id,
+        room_data: BTreeMap::new(),
+        capacities: BTreeMap::new(),
+        row_errors: Vec::new(),
+        source_text: None,
+        removals: Vec::new(),
+    };
+    let mut skipped = 0;
+
+    // The header is row 1
+    for (index, row) in rows.iter().enumerate().skip(1) {
+        if row.iter().all(|cell| cell.trim().is_empty()) {
+            continue;
+        }
+        let dorm_cell = cell(row, columns.dorm);
+        if !dorm_cell.is_empty() && !dorm.matches(dorm_cell) {
+            skipped += 1;
+            continue;
+        }
+
+        match parse_row(row, columns, &roster.capacities) {
+            Ok((room, capacity, student)) => {
+                if let Some(capacity) = capacity {
+                    roster.capacities.insert(room.to_string(), capacity);
+                }
+                roster.room_data.entry(room.to_string()).or_default().extend(student);
+            }
+            Err(message) => roster.row_errors.push(RowError {
+                row: index + 1,
+                message,
+            }),
+        }
+    }
+    (roster, skipped)
+}
+
+// Imports a roster spreadsheet into a dorm. A multipart form with the file in
+// `file`, plus `dorm_id`, optionally `mapping` (JSON naming the header of each
+// column) and `sheet`, and the same options as a JSON import.
+#[post("/admin/import-rooms/upload")]
+async fn import_spreadsheet(
+    req: HttpRequest,
+    payload: Multipart,
+    db: web::Data<Database>,
+    jobs: web::Data<BackgroundJobs>,
+    mailer: web::Data<dyn Mailer>,
+    principal: Principal,
+) -> impl Responder {
+    let form = match uploads::read_form(payload).await {
+        Ok(form) => form,
+        Err(response) => return response,
+    };
+    let dorm_id = match uploads::import_dorm(&db, &principal, &form).await {
+        Ok(dorm_id) => dorm_id,
+        Err(response) => return response,
+    };
+    let dorm_name = match db.collection::<Document>("dorms").find_one(doc! { "_id": dorm_id }, None).await {
+        Ok(dorm) => dorm
+            .and_then(|dorm| dorm.get_str("name").ok().map(str::to_string))
+            .unwrap_or_default(),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    };
+
+    let Some(file) = form.file("file") else {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "No file uploaded"
+        });
+    };
+    let mapping: ColumnMapping = match form.fields.get("mapping") {
+        Some(mapping) => match serde_json::from_str(mapping) {
+            Ok(mapping) => mapping,
+            Err(e) => return HttpResponse::BadRequest().json(doc! {
+                "error": format!("Invalid column mapping: {}", e)
+            }),
+        },
+        None => ColumnMapping::default(),
+    };
+    let options = match uploads::import_options(&form.fields) {
+        Ok(options) => options,
+        Err(error) => return HttpResponse::BadRequest().json(doc! { "error": error }),
+    };
+
+    let format = Format::detect(file.filename.as_deref(), &file.bytes);
+    let rows = match read_cells(format, &file.bytes, form.fields.get("sheet").map(String::as_str)) {
+        Ok(rows) => rows,
+        Err(error) => return HttpResponse::BadRequest().json(doc! { "error": error }),
+    };
+    let Some(header) = rows.first() else {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "The file is empty"
+        });
+    };
+    let columns = match resolve_columns(header, &mapping) {
+        Ok(columns) => columns,
+        Err(error) => return HttpResponse::BadRequest().json(doc! { "error": error }),
+    };
+
+    let target = TargetDorm {
+        id: dorm_id,
+        name: dorm_name,
+    };
+    let (roster, skipped) = parse_rows(&rows, &columns, &target);
+    println!(
+        "Read {} rooms from uploaded roster ({} rows skipped, {} row errors)",
+        roster.room_data.len(),
+        skipped,
+        roster.row_errors.len()
+    );
+    if roster.room_data.is_empty() {
+        let row_errors: Vec<Document> = roster
+            .row_errors
+            .iter()
+            .map(|error| doc! { "row": error.row as i64, "message": &error.message })
+            .collect();
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "No rows could be imported",
+            "rows_skipped": skipped as i64,
+            "row_errors": row_errors,
+        });
+    }
+
+    imports::start(&req, &db, &jobs, mailer.into_inner(), &principal, roster, &options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(csv: &str) -> Vec<Vec<String>> {
+        read_cells(Format::Csv, csv.as_bytes(), None).unwrap()
+    }
+
+    fn dorm() -> TargetDorm {
+        TargetDorm {
+            id: ObjectId::new(),
+            name: "North Hall".to_string(),
+        }
+    }
+
+    #[test]
+    fn columns_are_recognised_from_headers_or_mapped() {
+        let header = rows("\u{feff}Room,Student Name,Student ID,Email")[0].clone();
+        let columns = resolve_columns(&header, &ColumnMapping::default()).unwrap();
+        assert_eq!(
+            columns,
+            Columns {
+                room: 0,
+                capacity: None,
+                name: 1,
+                student_id: 2,
+                email: Some(3),
+                dorm: None,
+            }
+        );
+
+        let header = rows("Zimmer,Bewohner,Matrikel")[0].clone();
+        assert!(resolve_columns(&header, &ColumnMapping::default()).is_err());
+        let mapping = ColumnMapping {
+            room: Some("Zimmer".to_string()),
+            name: Some("Bewohner".to_string()),
+            student_id: Some("matrikel".to_string()),
+            ..ColumnMapping::default()
+        };
+        assert_eq!(resolve_columns(&header, &mapping).unwrap().student_id, 2);
+
+        let missing = ColumnMapping {
+            email: Some("Mail".to_string()),
+            ..mapping
+        };
+        assert_eq!(resolve_columns(&header, &missing).unwrap_err(), "Column 'Mail' not found");
+    }
+
+    #[test]
+    fn bad_rows_are_reported_and_left_out() {
+        let rows = rows(
+            "room,capacity,name,student id,email,dorm\n\
+             101,2,Ada Lovelace,1,ada@x.edu,North Hall\n\
+             101,,Grace Hopper,2,,north hall\n\
+             ,,Alan Turing,3,,\n\
+             102,two,Edsger Dijkstra,4,,\n\
+             103,3,,,,\n\
+             104,,Barbara Liskov,x,,\n\
+             101,4,Donald Knuth,5,,\n\
+             ,,,,,\n\
+             201,2,Someone Else,6,,South Hall\n",
+        );
+        let columns = resolve_columns(&rows[0], &ColumnMapping::default()).unwrap();
+
+        let (roster, skipped) = parse_rows(&rows, &columns, &dorm());
+
+        assert_eq!(skipped, 1);
+        assert_eq!(roster.room_data.keys().collect::<Vec<_>>(), ["101", "103"]);
+        assert_eq!(roster.room_data["101"].len(), 2);
+        assert_eq!(roster.room_data["101"][0].email.as_deref(), Some("ada@x.edu"));
+        assert_eq!(roster.room_data["101"][1].email, None);
+        assert!(roster.room_data["103"].is_empty());
+        assert_eq!(roster.capacities, BTreeMap::from([("101".to_string(), 2), ("103".to_string(), 3)]));
+        assert_eq!(
+            roster.row_errors,
+            vec![
+                RowError { row: 4, message: "Missing room number".to_string() },
+                RowError { row: 5, message: "Invalid capacity 'two'".to_string() },
+                RowError { row: 7, message: "Invalid student id 'x'".to_string() },
+                RowError { row: 8, message: "Room 101 already has capacity 2".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn format_comes_from_the_name_or_contents() {
+        assert_eq!(Format::detect(Some("roster.XLSX"), b"room"), Format::Xlsx);
+        assert_eq!(Format::detect(Some("roster.csv"), b"PK\x03\x04"), Format::Csv);
+        assert_eq!(Format::detect(None, b"PK\x03\x04"), Format::Xlsx);
+        assert_eq!(Format::detect(None, b"room,name"), Format::Csv);
+    }
+}
@@ -0,0 +1,216 @@
+// Streamed responses for lists too big to hold in memory. Documents are read
+// from the cursor and written to the response one at a time, as a JSON array
+// or as NDJSON (one JSON value per line), through a small buffer, so a slow
+// client holds up the cursor rather than filling memory.
+//
+// Documents that can't be read take their place in the list as
+// `{"invalid": {"id": ..., "error": ...}}`. If the database fails partway
+// through, the response is cut off without its closing bracket, so clients
+// can tell it from a complete list.
+use std::future::Future;
+
+use actix_web::{web::Bytes, HttpResponse};
+use futures::StreamExt;
+use mongodb::{bson::Document, Cursor};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::mpsc;
+
+use crate::pagination::{self, InvalidDocument};
+
+// Chunks waiting for the client, one item each
+const BUFFERED_ITEMS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Ndjson,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "json" => Some(Format::Json),
+            "ndjson" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn open(&self) -> &'static [u8] {
+        match self {
+            Format::Json => b"[",
+            Format::Ndjson => b"",
+        }
+    }
+
+    fn close(&self) -> &'static [u8] {
+        match self {
+            Format::Json => b"]\n",
+            Format::Ndjson => b"",
+        }
+    }
+
+    fn item(&self, value: &[u8], first: bool) -> Bytes {
+        let mut chunk = Vec::with_capacity(value.len() + 2);
+        match self {
+            Format::Json => {
+                if !first {
+                    chunk.push(b',');
+                }
+                chunk.extend_from_slice(value);
+            }
+            Format::Ndjson => {
+                chunk.extend_from_slice(value);
+                chunk.push(b'\n');
+            }
+        }
+        Bytes::from(chunk)
+    }
+}
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+// Writes items to a streamed response
+pub struct Sender {
+    format: Format,
+    chunks: mpsc::Sender<Chunk>,
+    first: bool,
+}
+
+impl Sender {
+    // Waits for room in the buffer. Returns false once the client has gone,
+    // after which there's no point reading further.
+    pub async fn send<T: Serialize>(&mut self, item: &T) -> bool {
+        let chunk = self.chunk(item);
+        self.push(chunk).await
+    }
+
+    fn chunk<T: Serialize>(&mut self, item: &T) -> Option<Bytes> {
+        match serde_json::to_vec(item) {
+            Ok(value) => {
+                let chunk = self.format.item(&value, self.first);
+                self.first = false;
+                Some(chunk)
+            }
+            Err(e) => {
+                println!("Error serializing streamed item: {:?}", e);
+                None
+            }
+        }
+    }
+
+    async fn push(&self, chunk: Option<Bytes>) -> bool {
+        match chunk {
+            Some(chunk) => self.chunks.send(Ok(chunk)).await.is_ok(),
+            None => true,
+        }
+    }
+
+    // Sends each document of `cursor` as `view` shows it
+    pub async fn documents<T, U>(
+        &mut self,
+        mut cursor: Cursor<Document>,
+        mut view: impl FnMut(T) -> U,
+    ) -> Result<(), mongodb::error::Error>
+    where
+        T: DeserializeOwned,
+        U: Serialize,
+    {
+        while let Some(document) = cursor.next().await {
+            // Written out before waiting, so the item needn't be Send
+            let chunk = match pagination::read::<T>(&document?) {
+                Ok(item) => self.chunk(&view(item)),
+                Err(invalid) => self.chunk(&Invalid { invalid }),
+            };
+            if !self.push(chunk).await {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Invalid {
+    invalid: InvalidDocument,
+}
+
+// Starts `produce` in the background and streams what it sends as the
+// response body
+pub fn respond<F, Fut>(format: Format, produce: F) -> HttpResponse
+where
+    F: FnOnce(Sender) -> Fut,
+    Fut: Future<Output = Result<(), mongodb::error::Error>> + Send + 'static,
+{
+    let (chunks, receiver) = mpsc::channel::<Chunk>(BUFFERED_ITEMS);
+    let sender = Sender {
+        format,
+        chunks: chunks.clone(),
+        first: true,
+    };
+    let produced = produce(sender);
+    tokio::spawn(async move {
+        let end = match produced.await {
+            Ok(()) => Ok(Bytes::from_static(format.close())),
+            Err(e) => {
+                println!("Error streaming response: {:?}", e);
+                Err(std::io::Error::other("Failed to read the list"))
+            }
+        };
+        // Fails only if the client has gone
+        let _ = chunks.send(end).await;
+    });
+
+    let body = futures::stream::once(async move { Ok::<_, std::io::Error>(Bytes::from_static(format.open())) })
+        .chain(futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (chunk, receiver))
+        }));
+    HttpResponse::Ok().content_type(format.content_type()).streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    async fn body(response: HttpResponse) -> String {
+        let bytes = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn numbers(format: Format) -> HttpResponse {
+        respond(format, |mut sender| async move {
+            for number in ["101", "102"] {
+                sender.send(&doc! { "number": number }).await;
+            }
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn items_are_written_as_an_array_or_lines() {
+        let response = numbers(Format::Json);
+        assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+        assert_eq!(body(response).await, "[{\"number\":\"101\"},{\"number\":\"102\"}]\n");
+
+        let response = numbers(Format::Ndjson);
+        assert_eq!(response.headers().get("content-type").unwrap(), "application/x-ndjson");
+        assert_eq!(body(response).await, "{\"number\":\"101\"}\n{\"number\":\"102\"}\n");
+    }
+
+    #[actix_web::test]
+    async fn failures_cut_the_response_off() {
+        let response = respond(Format::Json, |mut sender| async move {
+            sender.send(&doc! { "number": "101" }).await;
+            Err(mongodb::error::Error::custom("connection lost"))
+        });
+        let body = response.into_body();
+        assert!(actix_web::body::to_bytes(body).await.is_err());
+    }
+}
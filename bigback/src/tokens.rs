@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    Database,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// A single-use secret (invite, password reset) tied to an account. Only the
+// SHA-256 of the token is stored; the token itself goes to the user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OneTimeToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub token_hash: String,
+    pub purpose: String,
+    pub account_id: ObjectId,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub used_at: Option<DateTime>,
+}
+
+impl OneTimeToken {
+    fn new(token: &str, purpose: &str, account_id: ObjectId, ttl: Duration, now: DateTime) -> Self {
+        OneTimeToken {
+            id: None,
+            token_hash: hash_token(token),
+            purpose: purpose.to_string(),
+            account_id,
+            created_at: now,
+            expires_at: DateTime::from_millis(now.timestamp_millis() + ttl.as_millis() as i64),
+            used_at: None,
+        }
+    }
+}
+
+// Matches the token if it's for `purpose`, unused and unexpired
+fn live(purpose: &str, token: &str, now: DateTime) -> Document {
+    doc! {
+        "token_hash": hash_token(token),
+        "purpose": purpose,
+        "used_at": null,
+        "expires_at": { "$gt": now },
+    }
+}
+
+// 32 random bytes, hex encoded
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub async fn issue(
+    db: &Database,
+    purpose: &str,
+    account_id: ObjectId,
+    ttl: Duration,
+) -> Result<String, mongodb::error::Error> {
+    let token = generate_token();
+    let record = OneTimeToken::new(&token, purpose, account_id, ttl, DateTime::now());
+    db.collection::<OneTimeToken>("one_time_tokens")
+        .insert_one(record, None)
+        .await?;
+    Ok(token)
+}
+
+// Marks the token used and returns it, if it exists, matches the purpose, and
+// is neither expired nor already used. The check and the update are a single
+// operation, so a token can't be redeemed twice.
+pub async fn consume(
+    db: &Database,
+    purpose: &str,
+    token: &str,
+) -> Result<Option<OneTimeToken>, mongodb::error::Error> {
+    let now = DateTime::now();
+    db.collection::<OneTimeToken>("one_time_tokens")
+        .find_one_and_update(
+            live(purpose, token, now),
+            doc! { "$set": { "used_at": now } },
+            None,
+        )
+        .await
+}
+
+// Looks up a live token without using it up, for flows that need the same
+// token on more than one request before it's consumed
+pub async fn find_valid(
+    db: &Database,
+    purpose: &str,
+    token: &str,
+) -> Result<Option<OneTimeToken>, mongodb::error::Error> {
+    db.collection::<OneTimeToken>("one_time_tokens")
+        .find_one(live(purpose, token, DateTime::now()), None)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_expire_after_their_ttl() {
+        let now = DateTime::from_millis(1_700_000_000_000);
+        let token = generate_token();
+        let record = OneTimeToken::new(&token, "password_reset", ObjectId::new(), Duration::from_secs(60 * 60), now);
+
+        assert_eq!(record.expires_at.timestamp_millis() - now.timestamp_millis(), 60 * 60 * 1000);
+        assert_eq!(record.used_at, None);
+        // Only the hash is stored
+        assert_eq!(token.len(), 64);
+        assert_eq!(record.token_hash, hash_token(&token));
+        assert_ne!(record.token_hash, token);
+    }
+
+    // Redeeming sets used_at, which this no longer matches, so each token
+    // works once; expired tokens and other purposes never match
+    #[test]
+    fn only_unused_unexpired_tokens_of_the_purpose_match() {
+        let now = DateTime::from_millis(1_700_000_000_000);
+        assert_eq!(
+            live("password_reset", "abc", now),
+            doc! {
+                "token_hash": hash_token("abc"),
+                "purpose": "password_reset",
+                "used_at": null,
+                "expires_at": { "$gt": now },
+            }
+        );
+    }
+}
@@ -0,0 +1,558 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    Database,
+};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::accounts::{self, Account};
+use crate::audit::{self, AuditEntry};
+use crate::auth::Authenticated;
+use crate::rate_limit::{client_ip, LoginGuard};
+use crate::tokens;
+use crate::School;
+
+// RFC 6238 defaults, which is what every authenticator app expects
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// Accept the previous and next code too, for clock drift
+const DRIFT_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+pub const ENROLLMENT_TTL: Duration = Duration::from_secs(15 * 60);
+
+// TOTP enrollment stored on an account. The secret has to be kept as is to
+// compute codes; recovery codes are stored as SHA-256 hashes and removed
+// when used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TwoFactor {
+    // Base32, as shown to authenticator apps
+    pub secret: String,
+    // Unset until the first code has been verified
+    pub confirmed_at: Option<DateTime>,
+    // Time step of the last accepted code, so a code can't be replayed
+    pub last_used_step: Option<i64>,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
+impl TwoFactor {
+    pub fn is_enabled(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+}
+
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+fn issuer() -> String {
+    std::env::var("TOTP_ISSUER").unwrap_or_else(|_| "Dorm Management".to_string())
+}
+
+// otpauth:// URI for authenticator apps; the frontend renders it as a QR code
+pub fn provisioning_uri(secret: &str, email: &str) -> String {
+    let issuer = issuer();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding::encode(&issuer),
+        urlencoding::encode(email),
+        secret,
+        urlencoding::encode(&issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+fn code_at(key: &[u8], step: i64) -> String {
+    format!("{:0width$}", hotp(key, step as u64), width = DIGITS as usize)
+}
+
+fn current_step() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before 1970");
+    (now.as_secs() / STEP_SECS) as i64
+}
+
+// The time step a code belongs to, if it's valid around `step`
+fn matching_step(secret: &str, code: &str, step: i64) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    (step - DRIFT_STEPS..=step + DRIFT_STEPS).find(|&candidate| code_at(&key, candidate) == code)
+}
+
+// Codes look like "k3f9a-x7q2m"; dashes, spaces and case don't matter
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// Returns the codes to show once and the hashes to store
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let raw: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            let hash = tokens::hash_token(&raw);
+            (format!("{}-{}", &raw[..5], &raw[5..]), hash)
+        })
+        .unzip()
+}
+
+// Checks a TOTP or recovery code for an account with 2FA enabled. Both kinds
+// are single use: the TOTP step is recorded and recovery codes are removed,
+// each in one conditional update so concurrent requests can't reuse a code.
+pub async fn verify_code(
+    db: &Database,
+    account: &Account,
+    code: &str,
+) -> Result<bool, mongodb::error::Error> {
+    let Some(two_factor) = account.two_factor.as_ref().filter(|tf| tf.is_enabled()) else {
+        return Ok(false);
+    };
+    let code = code.trim();
+    let accounts_collection = accounts::collection(db);
+
+    if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        let Some(step) = matching_step(&two_factor.secret, code, current_step()) else {
+            return Ok(false);
+        };
+        let result = accounts_collection
+            .update_one(
+                doc! {
+                    "_id": account.id,
+                    "two_factor.last_used_step": { "$not": { "$gte": step } },
+                },
+                doc! { "$set": { "two_factor.last_used_step": step } },
+                None,
+            )
+            .await?;
+        return Ok(result.modified_count == 1);
+    }
+
+    let hash = tokens::hash_token(&normalize_recovery_code(code));
+    let result = accounts_collection
+        .update_one(
+            doc! { "_id": account.id, "two_factor.recovery_codes": &hash },
+            doc! { "$pull": { "two_factor.recovery_codes": &hash } },
+            None,
+        )
+        .await?;
+    if result.modified_count == 1 {
+        println!("Recovery code used for {}", account.email);
+    }
+    Ok(result.modified_count == 1)
+}
+
+pub async fn school_requires_2fa(
+    db: &Database,
+    school_id: Option<ObjectId>,
+) -> Result<bool, mongodb::error::Error> {
+    let Some(school_id) = school_id else {
+        return Ok(false);
+    };
+    let school = db
+        .collection::<School>("schools")
+        .find_one(doc! { "_id": school_id }, None)
+        .await?;
+    Ok(school.is_some_and(|school| school.require_admin_2fa))
+}
+
+// Enrollment either happens from a logged-in session, or, for staff whose
+// school requires 2FA and who therefore can't log in yet, with the
+// enrollment token the login handed out
+async fn enrolling_account(
+    db: &Database,
+    auth: Option<&Authenticated>,
+    enrollment_token: Option<&str>,
+) -> Result<Account, HttpResponse> {
+    let account_id = match (auth, enrollment_token) {
+        (Some(auth), _) => auth.account_id,
+        (None, Some(token)) => match tokens::find_valid(db, "two_factor_enrollment", token).await {
+            Ok(Some(token)) => token.account_id,
+            Ok(None) => return Err(HttpResponse::BadRequest().json(doc! {
+                "error": "Enrollment token is invalid or has expired"
+            })),
+            Err(e) => {
+                println!("Error looking up enrollment token: {:?}", e);
+                return Err(HttpResponse::InternalServerError().json(doc! {
+                    "error": "Internal server error"
+                }));
+            }
+        },
+        (None, None) => return Err(HttpResponse::Unauthorized().json(doc! {
+            "error": "Not authenticated"
+        })),
+    };
+
+    match accounts::collection(db).find_one(doc! { "_id": account_id }, None).await {
+        Ok(Some(account)) if account.is_staff() => Ok(account),
+        Ok(Some(_)) => Err(HttpResponse::Forbidden().json(doc! {
+            "error": "Two-factor authentication is only available for staff accounts"
+        })),
+        Ok(None) => Err(HttpResponse::NotFound().json(doc! {
+            "error": "Account not found"
+        })),
+        Err(e) => {
+            println!("Error finding account: {:?}", e);
+            Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SetupRequest {
+    enrollment_token: Option<String>,
+}
+
+// Starts enrollment with a fresh secret. Nothing changes for logins until
+// the first code is confirmed.
+#[post("/account/2fa/setup")]
+async fn setup(
+    auth: Option<Authenticated>,
+    req: web::Json<SetupRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let account = match enrolling_account(&db, auth.as_ref(), req.enrollment_token.as_deref()).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    if account.two_factor.as_ref().is_some_and(TwoFactor::is_enabled) {
+        return HttpResponse::Conflict().json(doc! {
+            "error": "Two-factor authentication is already enabled"
+        });
+    }
+
+    let secret = generate_secret();
+    match accounts::collection(&db)
+        .update_one(
+            doc! { "_id": account.id },
+            doc! { "$set": { "two_factor": {
+                "secret": &secret,
+                "confirmed_at": null,
+                "last_used_step": null,
+                "recovery_codes": [],
+            } } },
+            None,
+        )
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(doc! {
+            "secret": &secret,
+            "provisioning_uri": provisioning_uri(&secret, &account.email),
+        }),
+        Err(e) => {
+            println!("Error starting 2FA setup: {:?}", e);
+            HttpResponse::InternalServerError().json(doc! {
+                "error": "Failed to start two-factor setup"
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmRequest {
+    code: String,
+    enrollment_token: Option<String>,
+}
+
+// Turns 2FA on once the authenticator produces a valid code, and returns
+// the recovery codes. They are only ever shown here.
+#[post("/account/2fa/confirm")]
+async fn confirm(
+    http_req: HttpRequest,
+    auth: Option<Authenticated>,
+    req: web::Json<ConfirmRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let account = match enrolling_account(&db, auth.as_ref(), req.enrollment_token.as_deref()).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    let two_factor = match &account.two_factor {
+        Some(two_factor) if !two_factor.is_enabled() => two_factor,
+        Some(_) => return HttpResponse::Conflict().json(doc! {
+            "error": "Two-factor authentication is already enabled"
+        }),
+        None => return HttpResponse::BadRequest().json(doc! {
+            "error": "Start two-factor setup first"
+        }),
+    };
+
+    let Some(step) = matching_step(&two_factor.secret, req.code.trim(), current_step()) else {
+        return HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid code"
+        });
+    };
+
+    let (codes, hashes) = generate_recovery_codes();
+    let result = accounts::collection(&db)
+        .update_one(
+            doc! { "_id": account.id, "two_factor.confirmed_at": null },
+            doc! { "$set": {
+                "two_factor.confirmed_at": DateTime::now(),
+                "two_factor.last_used_step": step,
+                "two_factor.recovery_codes": hashes,
+            } },
+            None,
+        )
+        .await;
+    if let Err(e) = result {
+        println!("Error confirming 2FA: {:?}", e);
+        return HttpResponse::InternalServerError().json(doc! {
+            "error": "Failed to enable two-factor authentication"
+        });
+    }
+
+    if let Some(token) = &req.enrollment_token {
+        if let Err(e) = tokens::consume(&db, "two_factor_enrollment", token).await {
+            println!("Error consuming enrollment token: {:?}", e);
+        }
+    }
+
+    audit::record(
+        &db,
+        AuditEntry::new("two_factor_enabled")
+            .actor(&account.email)
+            .school(account.school_id)
+            .target(&account.id.expect("Stored accounts have an ID").to_hex())
+            .request(&http_req),
+    )
+    .await;
+
+    HttpResponse::Ok().json(doc! {
+        "message": "Two-factor authentication enabled",
+        "recovery_codes": codes,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeRequest {
+    code: String,
+}
+
+// Wrong codes count towards the account's lockout, as they do at login
+async fn session_account_with_code(
+    req: &HttpRequest,
+    db: &Database,
+    guard: &LoginGuard,
+    auth: &Authenticated,
+    code: &str,
+) -> Result<Account, HttpResponse> {
+    let account = match accounts::collection(db).find_one(doc! { "_id": auth.account_id }, None).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return Err(HttpResponse::NotFound().json(doc! {
+            "error": "Account not found"
+        })),
+        Err(e) => {
+            println!("Error finding account: {:?}", e);
+            return Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }));
+        }
+    };
+    if !account.two_factor.as_ref().is_some_and(TwoFactor::is_enabled) {
+        return Err(HttpResponse::BadRequest().json(doc! {
+            "error": "Two-factor authentication is not enabled"
+        }));
+    }
+
+    let ip = client_ip(req);
+    if let Some(blocked) = guard.check(db, &[account.login_account()], ip.as_deref()).await.blocked_response() {
+        return Err(blocked);
+    }
+    match verify_code(db, &account, code).await {
+        Ok(true) => {
+            guard.record_success(db, &account.login_account()).await;
+            Ok(account)
+        }
+        Ok(false) => {
+            guard.record_failure(db, &[account.login_account()], ip.as_deref()).await;
+            Err(HttpResponse::Unauthorized().json(doc! {
+                "error": "Invalid code"
+            }))
+        }
+        Err(e) => {
+            println!("Error verifying 2FA code: {:?}", e);
+            Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }))
+        }
+    }
+}
+
+// Replaces the recovery codes, e.g. after most have been used
+#[post("/account/2fa/recovery-codes")]
+async fn regenerate_recovery_codes(
+    http_req: HttpRequest,
+    auth: Authenticated,
+    req: web::Json<CodeRequest>,
+    db: web::Data<Database>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    let account = match session_account_with_code(&http_req, &db, &guard, &auth, &req.code).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    let (codes, hashes) = generate_recovery_codes();
+    if let Err(e) = accounts::collection(&db)
+        .update_one(
+            doc! { "_id": account.id },
+            doc! { "$set": { "two_factor.recovery_codes": hashes } },
+            None,
+        )
+        .await
+    {
+        println!("Error replacing recovery codes: {:?}", e);
+        return HttpResponse::InternalServerError().json(doc! {
+            "error": "Failed to replace recovery codes"
+        });
+    }
+
+    audit::record(
+        &db,
+        AuditEntry::new("recovery_codes_regenerated")
+            .actor(&account.email)
+            .school(account.school_id)
+            .target(&auth.account_id.to_hex())
+            .request(&http_req),
+    )
+    .await;
+
+    HttpResponse::Ok().json(doc! {
+        "recovery_codes": codes,
+    })
+}
+
+#[post("/account/2fa/disable")]
+async fn disable(
+    http_req: HttpRequest,
+    auth: Authenticated,
+    req: web::Json<CodeRequest>,
+    db: web::Data<Database>,
+    guard: web::Data<LoginGuard>,
+) -> impl Responder {
+    let account = match session_account_with_code(&http_req, &db, &guard, &auth, &req.code).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match school_requires_2fa(&db, account.school_id).await {
+        Ok(true) => return HttpResponse::Forbidden().json(doc! {
+            "error": "Your school requires two-factor authentication"
+        }),
+        Ok(false) => {}
+        Err(e) => {
+            println!("Error finding school: {:?}", e);
+            return HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            });
+        }
+    }
+
+    if let Err(e) = accounts::collection(&db)
+        .update_one(
+            doc! { "_id": account.id },
+            doc! { "$unset": { "two_factor": "" } },
+            None,
+        )
+        .await
+    {
+        println!("Error disabling 2FA: {:?}", e);
+        return HttpResponse::InternalServerError().json(doc! {
+            "error": "Failed to disable two-factor authentication"
+        });
+    }
+
+    audit::record(
+        &db,
+        AuditEntry::new("two_factor_disabled")
+            .actor(&account.email)
+            .school(account.school_id)
+            .target(&auth.account_id.to_hex())
+            .request(&http_req),
+    )
+    .await;
+
+    HttpResponse::Ok().json(doc! {
+        "message": "Two-factor authentication disabled"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1, truncated to six digits
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        let key = b"12345678901234567890";
+        for (time, expected) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(code_at(key, time / STEP_SECS as i64), expected, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_codes_within_one_step_of_drift() {
+        let secret = generate_secret();
+        let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        let now = 1_000_000;
+
+        for step in [now - 1, now, now + 1] {
+            assert_eq!(matching_step(&secret, &code_at(&key, step), now), Some(step));
+        }
+        assert_eq!(matching_step(&secret, &code_at(&key, now - 2), now), None);
+        assert_eq!(matching_step(&secret, &code_at(&key, now + 2), now), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_stored_as_hashes_of_the_normalized_code() {
+        let (codes, hashes) = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for (code, hash) in codes.iter().zip(&hashes) {
+            assert_eq!(&tokens::hash_token(&normalize_recovery_code(code)), hash);
+            assert_eq!(
+                &tokens::hash_token(&normalize_recovery_code(&code.to_uppercase().replace('-', " "))),
+                hash
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Multipart forms for imports that upload a file (spreadsheets, photos of
+// room sheets) instead of posting JSON
+use std::collections::HashMap;
+
+use actix_multipart::Multipart;
+use actix_web::HttpResponse;
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Database,
+};
+
+use crate::imports::ImportOptions;
+use crate::roles::{self, authorize, Permission, Principal};
+
+pub const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+pub struct UploadedFile {
+    pub field: String,
+    pub filename: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+pub struct UploadForm {
+    pub fields: HashMap<String, String>,
+    // Parts sent with a file name, in the order they were sent
+    pub files: Vec<UploadedFile>,
+}
+
+impl UploadForm {
+    pub fn file(&self, field: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.field == field)
+    }
+}
+
+// Reads the whole form, refusing uploads over MAX_UPLOAD_BYTES in total
+pub async fn read_form(mut payload: Multipart) -> Result<UploadForm, HttpResponse> {
+    let mut form = UploadForm {
+        fields: HashMap::new(),
+        files: Vec::new(),
+    };
+    let mut received = 0;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| {
+            HttpResponse::BadRequest().json(doc! {
+                "error": format!("Invalid upload: {}", e)
+            })
+        })?;
+        let name = field.name().unwrap_or_default().to_string();
+        let filename = field
+            .content_disposition()
+            .and_then(|disposition| disposition.get_filename())
+            .map(str::to_string);
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| {
+                HttpResponse::BadRequest().json(doc! {
+                    "error": format!("Invalid upload: {}", e)
+                })
+            })?;
+            received += chunk.len();
+            if received > MAX_UPLOAD_BYTES {
+                return Err(HttpResponse::PayloadTooLarge().json(doc! {
+                    "error": format!("Uploads are limited to {} MB", MAX_UPLOAD_BYTES / 1024 / 1024)
+                }));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        match filename {
+            Some(_) => form.files.push(UploadedFile {
+                field: name,
+                filename,
+                bytes,
+            }),
+            None => {
+                form.fields.insert(name, String::from_utf8_lossy(&bytes).into_owned());
+            }
+        }
+    }
+    Ok(form)
+}
+
+// The form's `dorm_id`, once the caller is known to be allowed to import
+// into it. Checked before a file is read as well as by the import itself, so
+// files aren't processed for someone who can't import them.
+pub async fn import_dorm(
+    db: &Database,
+    principal: &Principal,
+    form: &UploadForm,
+) -> Result<ObjectId, HttpResponse> {
+    let dorm_id = match form.fields.get("dorm_id").map(|id| ObjectId::parse_str(id.trim())) {
+        Some(Ok(oid)) => oid,
+        _ => return Err(HttpResponse::BadRequest().json(doc! {
+            "error": "Invalid dorm ID format"
+        })),
+    };
+    match roles::dorm_scope(db, dorm_id).await {
+        Ok(Some(scope)) => authorize(principal, Permission::ImportRooms, &scope)?,
+        Ok(None) => return Err(HttpResponse::NotFound().json(doc! {
+            "error": "Dorm not found"
+        })),
+        Err(e) => {
+            println!("Error finding dorm: {:?}", e);
+            return Err(HttpResponse::InternalServerError().json(doc! {
+                "error": "Internal server error"
+            }));
+        }
+    }
+    Ok(dorm_id)
+}
+
+// The same options as a JSON import, from form fields
+pub fn import_options(fields: &HashMap<String, String>) -> Result<ImportOptions, String> {
+    let mut options = serde_json::Map::new();
+    for (name, value) in fields {
+        let value = match name.as_str() {
+            "dry_run" => serde_json::Value::Bool(matches!(value.as_str(), "true" | "1" | "on")),
+            "email_pattern" | "delivery" | "batch_name" | "policy" => serde_json::Value::String(value.clone()),
+            "default_capacity" if value.trim().is_empty() => continue,
+            "default_capacity" => match value.trim().parse::<i32>() {
+                Ok(capacity) => serde_json::Value::from(capacity),
+                Err(_) => return Err(format!("Invalid default capacity '{}'", value)),
+            },
+            _ => continue,
+        };
+        options.insert(name.clone(), value);
+    }
+    serde_json::from_value(serde_json::Value::Object(options)).map_err(|e| format!("Invalid import options: {}", e))
+}